@@ -136,8 +136,13 @@ impl Block{
     pub fn header(&self) -> &Header{
         &self.header
     }
+
+    pub fn body(&self) -> &Body{
+        &self.body
+    }
 }
 
+#[derive(Serialize, Clone)]
 pub struct Header {
     hash: Hash,
     hashed_content: HeaderHashedContent,
@@ -189,6 +194,10 @@ impl Header {
         &self.hashed_content.height
     }
 
+    pub fn body_hash(&self) -> &Hash {
+        &self.hashed_content.body_hash
+    }
+
     pub fn verify(&self) -> Result<(), Error>{
         let computed_hash = self.hashed_content.hash()?;
 
@@ -202,7 +211,7 @@ impl Header {
     }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 struct HeaderHashedContent {
     nonce: Nonce,
     difficulty: Difficulty,
@@ -242,6 +251,14 @@ impl Body{
         Ok(hash(&serialized))
     }
 
+    pub fn coinbase_tx(&self) -> &CoinbaseTx {
+        &self.coinbase_tx
+    }
+
+    pub fn transactions(&self) -> &[SignedTx] {
+        &self.transactions
+    }
+
     fn verify<S>(&self, utxo_store: &S) -> Result<(), Error>
         where
             S: UtxoStore
@@ -0,0 +1,400 @@
+//! Compact block relay, in the spirit of BIP152. Instead of a whole block, a node sends its
+//! header, the coinbase transaction and a short ID for every other transaction. The peer
+//! rebuilds the block from the transactions it already holds and only requests the missing
+//! ones, at the cost of an additional round-trip. If the short IDs collide, the peer requests
+//! the whole block instead, like in BIP152.
+
+use bincode;
+use blockchain::Block;
+use blockchain::Body;
+use blockchain::Header;
+use crypto::Hash;
+use crypto::hash;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use transaction::CoinbaseTx;
+use transaction::SignedTx;
+use Error;
+
+const SHORT_ID_LEN: usize = 6;
+
+/// Identifies a transaction within a compact block. The salt of the block keeps the short IDs
+/// from colliding the same way in every block.
+#[derive(Serialize, Clone, PartialEq, Eq, Hash)]
+pub struct ShortId([u8; SHORT_ID_LEN]);
+
+impl ShortId{
+    fn new(block_hash: &Hash, salt: u64, tx_hash: &Hash) -> Result<ShortId, Error> {
+        let serialized = bincode::serialize(&(block_hash, salt, tx_hash))?;
+        let digest = hash(&serialized);
+
+        let mut bytes = [0u8; SHORT_ID_LEN];
+        bytes.clone_from_slice(&digest.as_ref()[..SHORT_ID_LEN]);
+        Ok(ShortId(bytes))
+    }
+}
+
+#[derive(Serialize)]
+pub struct CompactBlock {
+    header: Header,
+    salt: u64,
+    short_ids: Vec<ShortId>,
+    /// Like in BIP152, the coinbase transaction is always sent as the peer cannot know it.
+    prefilled_coinbase_tx: CoinbaseTx,
+}
+
+impl CompactBlock{
+    pub fn new(block: &Block, salt: u64) -> Result<CompactBlock, Error> {
+        let block_hash = block.header().hash();
+        let mut short_ids = vec![];
+        for transaction in block.body().transactions() {
+            short_ids.push(ShortId::new(block_hash, salt, &transaction.hash()?)?);
+        }
+
+        Ok(CompactBlock{
+            header: block.header().clone(),
+            salt,
+            short_ids,
+            prefilled_coinbase_tx: block.body().coinbase_tx().clone(),
+        })
+    }
+}
+
+/// Requests the transactions of a block missing from the mempool of a node.
+#[derive(Serialize)]
+pub struct GetBlockTxn {
+    block_hash: Hash,
+    /// The positions of the transactions in the block, the coinbase transaction excluded.
+    indexes: Vec<u32>,
+}
+
+/// The transactions requested with a `GetBlockTxn`, in the same order.
+#[derive(Serialize)]
+pub struct BlockTxn {
+    block_hash: Hash,
+    transactions: Vec<SignedTx>,
+}
+
+impl BlockTxn{
+    pub fn new(block: &Block, request: &GetBlockTxn) -> Result<BlockTxn, Error> {
+        if block.header().hash() != &request.block_hash {
+            return Err(Error::InvalidBlockTxn);
+        }
+
+        let mut transactions = vec![];
+        for index in &request.indexes {
+            match block.body().transactions().get(*index as usize) {
+                Some(transaction) => transactions.push(transaction.clone()),
+                None => return Err(Error::InvalidBlockTxn),
+            }
+        }
+
+        Ok(BlockTxn{
+            block_hash: request.block_hash.clone(),
+            transactions,
+        })
+    }
+}
+
+/// A block being rebuilt, waiting for some of its transactions.
+pub struct PartialBlock {
+    header: Header,
+    coinbase_tx: CoinbaseTx,
+    transactions: Vec<Option<SignedTx>>,
+}
+
+impl PartialBlock{
+    /// Completes the block with the transactions sent by the peer.
+    pub fn fill(mut self, block_txn: BlockTxn) -> Result<Block, Error> {
+        if &block_txn.block_hash != self.header.hash() {
+            return Err(Error::InvalidBlockTxn);
+        }
+
+        let mut received = block_txn.transactions.into_iter();
+        for transaction in self.transactions.iter_mut().filter(|transaction| transaction.is_none()) {
+            match received.next() {
+                Some(received_transaction) => *transaction = Some(received_transaction),
+                None => return Err(Error::InvalidBlockTxn),
+            }
+        }
+
+        if received.next().is_some() {
+            return Err(Error::InvalidBlockTxn);
+        }
+
+        self.into_block()
+    }
+
+    /// A short ID collision would put the wrong transaction in the block, this is caught by
+    /// checking the body against the header.
+    fn into_block(self) -> Result<Block, Error> {
+        let transactions = self.transactions.into_iter()
+            .map(|transaction| transaction.expect("Every transaction of the block is known."))
+            .collect();
+        let body = Body::new(self.coinbase_tx.0, transactions);
+
+        if &body.hash()? == self.header.body_hash() {
+            Ok(Block::new(self.header, body))
+        } else {
+            Err(Error::HeaderAndBodyHashMismatch)
+        }
+    }
+}
+
+pub enum Reconstruction {
+    Complete(Block),
+    /// The transactions to request from the peer before the block can be completed.
+    Incomplete(PartialBlock, GetBlockTxn),
+}
+
+/// The transactions recently relayed to a node. The oldest ones are evicted once the pool
+/// is full.
+pub struct Mempool {
+    transactions: HashMap<Hash, SignedTx>,
+    arrival_order: VecDeque<Hash>,
+    capacity: usize,
+}
+
+impl Mempool{
+    pub fn new(capacity: usize) -> Mempool {
+        Mempool{
+            transactions: HashMap::new(),
+            arrival_order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn insert(&mut self, transaction: SignedTx) -> Result<Hash, Error> {
+        let tx_hash = transaction.hash()?;
+
+        if !self.transactions.contains_key(&tx_hash) {
+            if self.arrival_order.len() >= self.capacity {
+                if let Some(oldest_tx_hash) = self.arrival_order.pop_front() {
+                    self.transactions.remove(&oldest_tx_hash);
+                }
+            }
+
+            self.arrival_order.push_back(tx_hash.clone());
+            self.transactions.insert(tx_hash.clone(), transaction);
+        }
+
+        Ok(tx_hash)
+    }
+
+    /// Rebuilds a block from the transactions of the pool. The transactions of the pool whose
+    /// short IDs collide are requested like the missing ones. Fails with `ShortIdCollision`
+    /// if two transactions of the block have the same short ID.
+    pub fn reconstruct(&self, compact_block: CompactBlock) -> Result<Reconstruction, Error> {
+        let block_hash = compact_block.header.hash().clone();
+
+        let mut block_short_ids = HashSet::new();
+        if !compact_block.short_ids.iter().all(|short_id| block_short_ids.insert(short_id)) {
+            return Err(Error::ShortIdCollision);
+        }
+
+        // PERFORMANCE The short IDs of the whole pool are computed for every block.
+        let mut short_ids = vec![];
+        for (tx_hash, transaction) in &self.transactions {
+            short_ids.push((ShortId::new(&block_hash, compact_block.salt, tx_hash)?, transaction));
+        }
+        let transactions_by_short_id = index_by_short_id(short_ids);
+
+        let transactions: Vec<Option<SignedTx>> = compact_block.short_ids.iter()
+            .map(|short_id| match transactions_by_short_id.get(short_id) {
+                Some(&Some(transaction)) => Some(transaction.clone()),
+                _ => None,
+            })
+            .collect();
+
+        let indexes: Vec<u32> = transactions.iter().enumerate()
+            .filter(|&(_index, transaction)| transaction.is_none())
+            .map(|(index, _transaction)| index as u32)
+            .collect();
+
+        let partial_block = PartialBlock{
+            header: compact_block.header,
+            coinbase_tx: compact_block.prefilled_coinbase_tx,
+            transactions,
+        };
+
+        if indexes.is_empty() {
+            partial_block.into_block().map(Reconstruction::Complete)
+        } else {
+            Ok(Reconstruction::Incomplete(partial_block, GetBlockTxn{
+                block_hash,
+                indexes,
+            }))
+        }
+    }
+}
+
+/// The transactions by short ID. The short IDs shared by several transactions are ambiguous,
+/// they map to none of them.
+fn index_by_short_id(short_ids: Vec<(ShortId, &SignedTx)>) -> HashMap<ShortId, Option<&SignedTx>> {
+    let mut transactions_by_short_id = HashMap::new();
+    for (short_id, transaction) in short_ids {
+        transactions_by_short_id.entry(short_id)
+            .and_modify(|indexed: &mut Option<&SignedTx>| *indexed = None)
+            .or_insert(Some(transaction));
+    }
+    transactions_by_short_id
+}
+
+/// The bandwidth and the latency of the compact relay of blocks, compared to sending the
+/// blocks whole.
+#[derive(Default, Debug)]
+pub struct RelayStats {
+    pub blocks: u32,
+    /// The bytes the blocks would have taken if they were sent whole.
+    pub full_block_bytes: u64,
+    /// The bytes of the compact blocks, of the exchanges of missing transactions and of the
+    /// whole blocks requested after a short ID collision.
+    pub compact_bytes: u64,
+    /// The round-trips spent requesting missing transactions or whole blocks.
+    pub extra_round_trips: u32,
+    /// The blocks requested whole because the short IDs collided.
+    pub full_block_fallbacks: u32,
+}
+
+impl RelayStats{
+    pub fn bytes_saved(&self) -> i64 {
+        self.full_block_bytes as i64 - self.compact_bytes as i64
+    }
+}
+
+/// Relays a block to a peer holding the given mempool, recording the bytes exchanged.
+/// Returns the block as rebuilt by the peer, or as requested whole if the short IDs collided.
+pub fn relay(block: &Block, salt: u64, peer_mempool: &Mempool, stats: &mut RelayStats)
+             -> Result<Block, Error>
+{
+    stats.blocks += 1;
+    let full_block_bytes = bincode::serialized_size(block.header())?
+        + bincode::serialized_size(block.body())?;
+    stats.full_block_bytes += full_block_bytes;
+
+    let compact_block = CompactBlock::new(block, salt)?;
+    stats.compact_bytes += bincode::serialized_size(&compact_block)?;
+
+    let rebuilt_block = match peer_mempool.reconstruct(compact_block) {
+        Ok(Reconstruction::Complete(block)) => Ok(block),
+        Ok(Reconstruction::Incomplete(partial_block, get_block_txn)) => {
+            let block_txn = BlockTxn::new(block, &get_block_txn)?;
+            stats.compact_bytes += bincode::serialized_size(&get_block_txn)?
+                + bincode::serialized_size(&block_txn)?;
+            stats.extra_round_trips += 1;
+
+            partial_block.fill(block_txn)
+        }
+        Err(err) => Err(err),
+    };
+
+    match rebuilt_block {
+        // A transaction of the pool took the place of one of the block.
+        Err(Error::ShortIdCollision) | Err(Error::HeaderAndBodyHashMismatch) => {
+            stats.compact_bytes += bincode::serialized_size(block.header().hash())?
+                + full_block_bytes;
+            stats.extra_round_trips += 1;
+            stats.full_block_fallbacks += 1;
+            Ok(Block::new(block.header().clone(), block.body().clone()))
+        }
+        rebuilt_block => rebuilt_block,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::Difficulty;
+    use blockchain::Nonce;
+    use crypto::KeyPairGenerator;
+    use transaction::Address;
+    use transaction::RawTx;
+    use transaction::RawTxIn;
+    use transaction::TxOut;
+
+    #[test]
+    fn rebuilds_blocks_from_the_mempool() {
+        let key_pair_generator = KeyPairGenerator::new();
+        let key_pair = key_pair_generator.random_keypair().ok().unwrap();
+        let address = Address::from_pub_key(&key_pair.pub_key());
+
+        let transactions: Vec<SignedTx> = (0..20u8).map(|index| {
+            let raw_tx = RawTx {
+                input: vec![RawTxIn{
+                    prev_tx_hash: Hash::min(),
+                    prev_tx_output_index: index,
+                }],
+                output: vec![TxOut::new(10, address.clone())],
+            };
+            SignedTx::from_raw_tx(raw_tx, vec![&key_pair]).ok().unwrap()
+        }).collect();
+
+        let body = Body::new(TxOut::new(1000, address.clone()), transactions.clone());
+        let serialized_body = bincode::serialize(&body).ok().unwrap();
+        let header = Header::new(Nonce::new(), Difficulty::min_difficulty(), Hash::min(), 1, &serialized_body)
+            .ok().unwrap();
+        let block = Block::new(header, body);
+        let block_hash = block.header().hash().clone();
+
+        // The peer holds every transaction of the block.
+        let mut mempool = Mempool::new(100);
+        for transaction in &transactions {
+            mempool.insert(transaction.clone()).ok().unwrap();
+        }
+        let mut stats = RelayStats::default();
+        let rebuilt_block = relay(&block, 1, &mempool, &mut stats).ok().unwrap();
+        assert!(rebuilt_block.header().hash() == &block_hash);
+        assert_eq!(0, stats.extra_round_trips);
+        assert!(stats.bytes_saved() > 0);
+
+        // The 5 oldest transactions were evicted and must be requested.
+        let mut mempool = Mempool::new(15);
+        for transaction in &transactions {
+            mempool.insert(transaction.clone()).ok().unwrap();
+        }
+        let mut stats = RelayStats::default();
+        let rebuilt_block = relay(&block, 2, &mempool, &mut stats).ok().unwrap();
+        assert!(rebuilt_block.body().hash().ok().unwrap() == block.body().hash().ok().unwrap());
+        assert_eq!(1, stats.extra_round_trips);
+        assert!(stats.bytes_saved() > 0);
+        assert_eq!(0, stats.full_block_fallbacks);
+
+        // The same transaction twice in a block makes its short IDs collide.
+        let body = Body::new(TxOut::new(1000, address), vec![transactions[0].clone(); 2]);
+        let serialized_body = bincode::serialize(&body).ok().unwrap();
+        let header = Header::new(Nonce::new(), Difficulty::min_difficulty(), Hash::min(), 1, &serialized_body)
+            .ok().unwrap();
+        let block = Block::new(header, body);
+        let mut stats = RelayStats::default();
+        let received_block = relay(&block, 3, &mempool, &mut stats).ok().unwrap();
+        assert!(received_block.header().hash() == block.header().hash());
+        assert_eq!(1, stats.full_block_fallbacks);
+        assert_eq!(1, stats.extra_round_trips);
+    }
+
+    #[test]
+    fn ignores_the_colliding_short_ids_of_the_mempool() {
+        let key_pair_generator = KeyPairGenerator::new();
+        let key_pair = key_pair_generator.random_keypair().ok().unwrap();
+        let address = Address::from_pub_key(&key_pair.pub_key());
+        let transactions: Vec<SignedTx> = (0..2u8).map(|index| {
+            let raw_tx = RawTx {
+                input: vec![RawTxIn{
+                    prev_tx_hash: Hash::min(),
+                    prev_tx_output_index: index,
+                }],
+                output: vec![TxOut::new(10, address.clone())],
+            };
+            SignedTx::from_raw_tx(raw_tx, vec![&key_pair]).ok().unwrap()
+        }).collect();
+
+        let transactions_by_short_id = index_by_short_id(vec![
+            (ShortId([1; SHORT_ID_LEN]), &transactions[0]),
+            (ShortId([1; SHORT_ID_LEN]), &transactions[1]),
+            (ShortId([2; SHORT_ID_LEN]), &transactions[1]),
+        ]);
+        assert!(transactions_by_short_id[&ShortId([1; SHORT_ID_LEN])].is_none());
+        assert!(transactions_by_short_id[&ShortId([2; SHORT_ID_LEN])].is_some());
+    }
+}
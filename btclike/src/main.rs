@@ -7,6 +7,7 @@ extern crate serde;
 extern crate bincode;
 
 mod blockchain;
+mod compact;
 mod crypto;
 mod transaction;
 mod wallet;
@@ -20,6 +21,16 @@ use crypto::KeyPairGenerator;
 use crypto::Hash;
 use transaction::UtxoStore;
 use blockchain::Chain;
+use blockchain::Block;
+use blockchain::Body;
+use blockchain::Header;
+use blockchain::Nonce;
+use compact::Mempool;
+use compact::RelayStats;
+use crypto::KeyPair;
+use transaction::RawTx;
+use transaction::RawTxIn;
+use transaction::SignedTx;
 
 fn main() {
     // Always print backtrace on panic.
@@ -41,10 +52,58 @@ fn main() {
         difficulty.increase();
     }
 
-    let chain = Chain::mine_new_genesis(difficulty, address).ok().unwrap();
+    let chain = Chain::mine_new_genesis(difficulty, address.clone()).ok().unwrap();
 
     chain.verify(chain.head_hash(), &EmptyUtxoStore{}).ok().unwrap();
     info!("Hello world.");
+
+    if ::std::env::args().any(|arg| arg == COMPACT_RELAY_FLAG) {
+        if let Err(err) = log_compact_relay(&wallet, address) {
+            error!("The compact relay failed: {:?}", err);
+        }
+    }
+}
+
+/// The command line flag running the compact relay demonstration, which signs and relays
+/// thousands of transactions.
+const COMPACT_RELAY_FLAG: &str = "--compact_relay";
+
+/// Relays blocks of 1000 transactions to a peer that missed more and more of them, and logs
+/// what compact blocks save compared to whole blocks.
+fn log_compact_relay(key_pair: &KeyPair, address: Address) -> Result<(), Error> {
+    let mut transactions = vec![];
+    for index in 0..1000u32 {
+        let raw_tx = RawTx {
+            input: vec![RawTxIn{
+                prev_tx_hash: Hash::min(),
+                prev_tx_output_index: (index % 256) as u8,
+            }],
+            output: vec![TxOut::new(index, address.clone())],
+        };
+        transactions.push(SignedTx::from_raw_tx(raw_tx, vec![key_pair])?);
+    }
+
+    let body = Body::new(TxOut::new(blockchain::COINBASE_AMOUNT, address), transactions.clone());
+    let serialized_body = bincode::serialize(&body)?;
+    let header = Header::new(Nonce::new(), Difficulty::min_difficulty(), Hash::min(), 1, &serialized_body)?;
+    let block = Block::new(header, body);
+
+    for missing_transactions in &[0usize, 10, 100, 1000] {
+        let mut mempool = Mempool::new(transactions.len());
+        for transaction in transactions.iter().skip(*missing_transactions) {
+            mempool.insert(transaction.clone())?;
+        }
+
+        let mut stats = RelayStats::default();
+        compact::relay(&block, *missing_transactions as u64, &mempool, &mut stats)?;
+        info!(
+            "Compact relay, {} of {} transactions missing: {} bytes instead of {}, {} saved, {} extra round-trip(s), {} whole block(s)",
+            missing_transactions, transactions.len(), stats.compact_bytes, stats.full_block_bytes,
+            stats.bytes_saved(), stats.extra_round_trips, stats.full_block_fallbacks
+        );
+    }
+
+    Ok(())
 }
 
 struct EmptyUtxoStore;
@@ -73,6 +132,8 @@ pub enum Error{
     HashIsTooHigh,
     UtxoNotFound,
     NotEnoughTokens,
+    InvalidBlockTxn,
+    ShortIdCollision,
 }
 
 impl From<bincode::Error> for Error{
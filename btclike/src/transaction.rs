@@ -126,6 +126,11 @@ impl SignedTx {
         })
     }
 
+    pub fn hash(&self) -> Result<Hash, Error> {
+        let serialized = bincode::serialize(&self)?;
+        Ok(hash(&serialized))
+    }
+
     fn clone_without_signatures(&self) -> RawTx {
         let output = self.output.clone();
         let mut input = vec![];
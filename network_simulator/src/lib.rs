@@ -1,3 +1,4 @@
+#[macro_use]
 extern crate futures;
 #[macro_use]
 extern crate log;
@@ -7,3 +8,4 @@ extern crate tokio_timer;
 
 pub mod flatten_select;
 pub mod network;
+pub mod testing;
@@ -0,0 +1,97 @@
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+
+/// A change of the network conditions, scheduled while the network runs.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NetworkEvent {
+    /// Splits the network in two: these nodes on one side, every other node on the other.
+    /// The messages between the two sides are lost. Replaces any previous partition.
+    Partition(Vec<u32>),
+    /// Ends the partition.
+    Heal,
+    /// The nodes go offline: the messages they send or should receive are lost.
+    Disconnect(Vec<u32>),
+    /// The nodes come back online.
+    Reconnect(Vec<u32>),
+}
+
+#[derive(Debug, Default)]
+struct State {
+    isolated: HashSet<u32>,
+    offline: HashSet<u32>,
+}
+
+/// The partitions and the offline nodes of a network. Clones share the same state.
+#[derive(Clone, Debug, Default)]
+pub struct Conditions {
+    state: Arc<RwLock<State>>,
+}
+
+impl Conditions {
+    pub fn new() -> Conditions {
+        Conditions::default()
+    }
+
+    pub fn apply(&self, event: &NetworkEvent) {
+        info!("Network event: {:?}", event);
+        let mut state = self.state.write().unwrap();
+        match *event {
+            NetworkEvent::Partition(ref nodes) => state.isolated = nodes.iter().cloned().collect(),
+            NetworkEvent::Heal => state.isolated.clear(),
+            NetworkEvent::Disconnect(ref nodes) => state.offline.extend(nodes),
+            NetworkEvent::Reconnect(ref nodes) => {
+                for node in nodes {
+                    state.offline.remove(node);
+                }
+            }
+        }
+    }
+
+    /// Whether a message sent from a node to another gets delivered.
+    pub fn delivers(&self, from: u32, to: u32) -> bool {
+        let state = self.state.read().unwrap();
+        !state.offline.contains(&from)
+            && !state.offline.contains(&to)
+            && state.isolated.contains(&from) == state.isolated.contains(&to)
+    }
+}
+
+/// The receiving end of a connection, subject to the conditions of the network.
+#[derive(Clone, Debug)]
+pub(crate) struct Link {
+    pub conditions: Conditions,
+    pub from: u32,
+    pub to: u32,
+}
+
+impl Link {
+    pub fn is_up(&self) -> bool {
+        self.conditions.delivers(self.from, self.to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partitions_and_churn_cut_the_links() {
+        let conditions = Conditions::new();
+        assert!(conditions.delivers(0, 1));
+
+        conditions.apply(&NetworkEvent::Partition(vec![0, 2]));
+        assert!(!conditions.delivers(0, 1));
+        assert!(!conditions.delivers(1, 2));
+        assert!(conditions.delivers(0, 2));
+        assert!(conditions.delivers(1, 3));
+
+        conditions.apply(&NetworkEvent::Heal);
+        conditions.apply(&NetworkEvent::Disconnect(vec![1]));
+        assert!(!conditions.delivers(0, 1));
+        assert!(!conditions.delivers(1, 2));
+        assert!(conditions.delivers(0, 2));
+
+        conditions.apply(&NetworkEvent::Reconnect(vec![1]));
+        assert!(conditions.delivers(0, 1));
+    }
+}
@@ -0,0 +1,348 @@
+use futures::{stream, Future, Stream};
+use network::multiplex::{Multiplexer, ProtocolId};
+use std::collections::HashMap;
+use std::ops::Add;
+use std::time::{Duration, Instant};
+use tokio_timer::Interval;
+
+/// The protocol id of the heartbeat sub-channel.
+pub const PROTOCOL: ProtocolId = "heartbeat";
+
+/// The messages of the heartbeat protocol. A `Pong` answers the `Ping` with the same sequence number.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HeartbeatMessage {
+    Ping(u64),
+    Pong(u64),
+}
+
+/// What the heartbeat protocol reports about a peer.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HeartbeatEvent {
+    /// The peer answered a ping.
+    RoundTrip { peer: u32, rtt: Duration },
+    /// The peer did not answer a ping within the timeout.
+    Unresponsive { peer: u32 },
+    /// The connection to the peer was closed.
+    Disconnected { peer: u32 },
+}
+
+/// A ping/pong protocol measuring the round-trip time to peers and detecting dead peers.
+/// It runs on its own sub-channel, so it can be plugged into any node using a `Multiplexer`.
+#[derive(Clone, Copy, Debug)]
+pub struct Heartbeat {
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl Heartbeat {
+    /// # Arguments
+    ///
+    /// `interval`: the delay between two pings sent to the same peer.
+    /// `timeout`: the delay after which an unanswered ping makes the peer unresponsive. It is
+    /// checked at every interval, so a peer is reported at most `interval` after the timeout.
+    pub fn new(interval: Duration, timeout: Duration) -> Heartbeat {
+        Heartbeat { interval, timeout }
+    }
+
+    /// Opens the heartbeat sub-channel of the connection and starts monitoring the peer.
+    /// The returned stream ends when the connection is closed.
+    pub fn monitor(
+        &self,
+        multiplexer: &mut Multiplexer,
+    ) -> impl Stream<Item = HeartbeatEvent, Error = ()> {
+        let peer = multiplexer.remote_id();
+        let (sender, receiver) = multiplexer.channel::<HeartbeatMessage>(PROTOCOL);
+        self.monitor_channel(peer, receiver, move |message| {
+            sender.unbounded_send(message).is_ok()
+        })
+    }
+
+    /// Monitors the peer over the heartbeat sub-channel: `receiver` yields the messages
+    /// received from the peer and `send` sends one to it, returning false if the connection
+    /// is closed.
+    fn monitor_channel<R, F>(
+        &self,
+        peer: u32,
+        receiver: R,
+        send: F,
+    ) -> impl Stream<Item = HeartbeatEvent, Error = ()>
+    where
+        R: Stream<Item = HeartbeatMessage, Error = ()>,
+        F: FnMut(HeartbeatMessage) -> bool + Send + 'static,
+    {
+        let mut state = MonitoringState {
+            peer,
+            send: Box::new(send),
+            timeout: self.timeout,
+            next_sequence_number: 0,
+            pending_ping: None,
+            reported_unresponsive: false,
+            closed: false,
+        };
+
+        let start_instant = Instant::now().add(self.interval);
+        let ticks = Interval::new(start_instant, self.interval)
+            .map(|_instant| Input::Tick)
+            .map_err(|timer_err| panic!("Timer error: {}", timer_err));
+
+        let mut disconnected = false;
+        receiver
+            .map(Input::Message)
+            .chain(stream::once(Ok(Input::Closed)))
+            // Merging both streams avoids the need of locking on the state by doing everything sequentially.
+            .select(ticks)
+            .map(move |input| state.handle(input))
+            // End the stream right after the disconnection was reported.
+            .take_while(move |event_option| {
+                let is_open = !disconnected;
+                if let Some(HeartbeatEvent::Disconnected { .. }) = event_option {
+                    disconnected = true;
+                }
+                Ok(is_open)
+            })
+            .filter_map(|event_option| event_option)
+    }
+}
+
+/// Opens the heartbeat sub-channel of the connection and answers the pings of the peer,
+/// without monitoring it. The returned future ends when the connection is closed.
+pub fn answer_pings(multiplexer: &mut Multiplexer) -> impl Future<Item = (), Error = ()> {
+    let (sender, receiver) = multiplexer.channel::<HeartbeatMessage>(PROTOCOL);
+
+    receiver.for_each(move |message| {
+        if let HeartbeatMessage::Ping(sequence_number) = message {
+            if sender.unbounded_send(HeartbeatMessage::Pong(sequence_number)).is_err() {
+                debug!("Connection closed, ping not answered.");
+            }
+        }
+        Ok(())
+    })
+}
+
+enum Input {
+    Message(HeartbeatMessage),
+    Tick,
+    Closed,
+}
+
+struct MonitoringState {
+    peer: u32,
+    send: Box<dyn FnMut(HeartbeatMessage) -> bool + Send>,
+    timeout: Duration,
+    next_sequence_number: u64,
+    pending_ping: Option<(u64, Instant)>,
+    reported_unresponsive: bool,
+    closed: bool,
+}
+
+impl MonitoringState {
+    fn handle(&mut self, input: Input) -> Option<HeartbeatEvent> {
+        if self.closed {
+            return None;
+        }
+
+        match input {
+            Input::Message(HeartbeatMessage::Ping(sequence_number)) => {
+                self.send(HeartbeatMessage::Pong(sequence_number))
+            }
+            Input::Message(HeartbeatMessage::Pong(sequence_number)) => match self.pending_ping {
+                Some((pending_sequence_number, sent_at))
+                    if pending_sequence_number == sequence_number =>
+                {
+                    self.pending_ping = None;
+                    self.reported_unresponsive = false;
+                    Some(HeartbeatEvent::RoundTrip {
+                        peer: self.peer,
+                        rtt: sent_at.elapsed(),
+                    })
+                }
+                _ => None,
+            },
+            Input::Tick => match self.pending_ping {
+                Some((_, sent_at)) => {
+                    if !self.reported_unresponsive && sent_at.elapsed() > self.timeout {
+                        self.reported_unresponsive = true;
+                        Some(HeartbeatEvent::Unresponsive { peer: self.peer })
+                    } else {
+                        None
+                    }
+                }
+                None => {
+                    let sequence_number = self.next_sequence_number;
+                    self.next_sequence_number += 1;
+                    self.pending_ping = Some((sequence_number, Instant::now()));
+                    self.send(HeartbeatMessage::Ping(sequence_number))
+                }
+            },
+            Input::Closed => {
+                self.closed = true;
+                Some(HeartbeatEvent::Disconnected { peer: self.peer })
+            }
+        }
+    }
+
+    fn send(&mut self, message: HeartbeatMessage) -> Option<HeartbeatEvent> {
+        if (self.send)(message) {
+            None
+        } else {
+            self.closed = true;
+            Some(HeartbeatEvent::Disconnected { peer: self.peer })
+        }
+    }
+}
+
+/// The live view of a node on the responsiveness of its peers, fed with heartbeat events.
+#[derive(Debug, Default)]
+pub struct PeerLiveness {
+    peers: HashMap<u32, PeerStatus>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerStatus {
+    /// The smoothed round-trip time, as defined for the TCP retransmission timer (RFC 6298).
+    pub smoothed_rtt: Option<Duration>,
+    pub last_rtt: Option<Duration>,
+    pub responsive: bool,
+}
+
+impl PeerLiveness {
+    pub fn new() -> PeerLiveness {
+        PeerLiveness::default()
+    }
+
+    pub fn record(&mut self, event: &HeartbeatEvent) {
+        match *event {
+            HeartbeatEvent::RoundTrip { peer, rtt } => {
+                let status = self.status_mut(peer);
+                status.smoothed_rtt = Some(match status.smoothed_rtt {
+                    Some(smoothed_rtt) => (smoothed_rtt * 7 + rtt) / 8,
+                    None => rtt,
+                });
+                status.last_rtt = Some(rtt);
+                status.responsive = true;
+            }
+            HeartbeatEvent::Unresponsive { peer } => {
+                self.status_mut(peer).responsive = false;
+            }
+            HeartbeatEvent::Disconnected { peer } => {
+                self.peers.remove(&peer);
+            }
+        }
+    }
+
+    pub fn status(&self, peer: u32) -> Option<&PeerStatus> {
+        self.peers.get(&peer)
+    }
+
+    pub fn unresponsive_peers(&self) -> Vec<u32> {
+        let mut peers: Vec<u32> = self.peers
+            .iter()
+            .filter(|(_peer, status)| !status.responsive)
+            .map(|(peer, _status)| *peer)
+            .collect();
+        peers.sort();
+        peers
+    }
+
+    fn status_mut(&mut self, peer: u32) -> &mut PeerStatus {
+        self.peers.entry(peer).or_insert(PeerStatus {
+            smoothed_rtt: None,
+            last_rtt: None,
+            responsive: true,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+    use network::transport::connection_pair;
+    use std::sync::{Arc, Mutex};
+    use tokio;
+
+    fn run_monitoring(answering: bool, number_of_events: u64) -> Vec<HeartbeatEvent> {
+        let heartbeat = Heartbeat::new(Duration::from_millis(10), Duration::from_millis(30));
+        let events = Arc::new(Mutex::new(vec![]));
+        let events_clone = events.clone();
+
+        tokio::run(::futures::future::lazy(move || {
+            let (one, other) = connection_pair();
+            let mut one = Multiplexer::new(one);
+            let mut other = Multiplexer::new(other);
+
+            let one_events = heartbeat.monitor(&mut one);
+            // Keep the sub-channel open without answering when the peer should not answer.
+            let (_other_sender, _other_receiver) = if answering {
+                tokio::spawn(answer_pings(&mut other));
+                (None, None)
+            } else {
+                let (sender, receiver) = other.channel::<HeartbeatMessage>(PROTOCOL);
+                (Some(sender), Some(receiver))
+            };
+
+            tokio::spawn(one.run());
+            tokio::spawn(other.run().map(move |()| {
+                drop(_other_sender);
+                drop(_other_receiver);
+            }));
+
+            one_events.take(number_of_events).collect().map(move |received| {
+                *events_clone.lock().unwrap() = received;
+            })
+        }));
+
+        let events = events.lock().unwrap().clone();
+        events
+    }
+
+    #[test]
+    fn measures_the_round_trip_time() {
+        let events = run_monitoring(true, 3);
+
+        assert_eq!(3, events.len());
+        for event in events {
+            match event {
+                HeartbeatEvent::RoundTrip { peer, rtt } => {
+                    assert_eq!(1, peer);
+                    assert!(rtt < Duration::from_secs(1));
+                }
+                other => panic!("Unexpected event: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn detects_unresponsive_peers() {
+        let events = run_monitoring(false, 1);
+
+        assert_eq!(vec![HeartbeatEvent::Unresponsive { peer: 1 }], events);
+    }
+
+    #[test]
+    fn liveness_tracks_peer_status() {
+        let mut liveness = PeerLiveness::new();
+        liveness.record(&HeartbeatEvent::RoundTrip {
+            peer: 1,
+            rtt: Duration::from_millis(80),
+        });
+        liveness.record(&HeartbeatEvent::RoundTrip {
+            peer: 1,
+            rtt: Duration::from_millis(160),
+        });
+        liveness.record(&HeartbeatEvent::RoundTrip {
+            peer: 2,
+            rtt: Duration::from_millis(10),
+        });
+        liveness.record(&HeartbeatEvent::Unresponsive { peer: 2 });
+
+        let status = liveness.status(1).unwrap();
+        assert_eq!(Some(Duration::from_millis(90)), status.smoothed_rtt);
+        assert_eq!(Some(Duration::from_millis(160)), status.last_rtt);
+        assert_eq!(vec![2], liveness.unresponsive_peers());
+
+        liveness.record(&HeartbeatEvent::Disconnected { peer: 2 });
+        assert!(liveness.status(2).is_none());
+        assert!(liveness.unresponsive_peers().is_empty());
+    }
+}
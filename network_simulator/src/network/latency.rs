@@ -0,0 +1,143 @@
+use futures::{Async, Future, Poll, Stream};
+use rand::{self, Rng};
+use std::cmp;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tokio_timer::Delay;
+
+/// The time it takes for a message to go through a connection: a base delay plus a random
+/// jitter, drawn uniformly for every message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Latency {
+    base: Duration,
+    jitter: Duration,
+}
+
+impl Latency {
+    pub fn new(base: Duration, jitter: Duration) -> Latency {
+        Latency { base, jitter }
+    }
+
+    /// The longest a message can be delayed.
+    pub fn max(&self) -> Duration {
+        self.base + self.jitter
+    }
+
+    pub fn sample(&self) -> Duration {
+        let jitter_nanos =
+            self.jitter.as_secs() * 1_000_000_000 + u64::from(self.jitter.subsec_nanos());
+        if jitter_nanos == 0 {
+            self.base
+        } else {
+            let jitter = rand::thread_rng().gen_range(0, jitter_nanos + 1);
+            self.base + Duration::from_nanos(jitter)
+        }
+    }
+}
+
+/// Delays the items of a stream by the latency. The items keep their order, like on a TCP
+/// connection: an item is never delivered before the previous one.
+pub struct LatentStream<S>
+where
+    S: Stream,
+{
+    inner: S,
+    inner_done: bool,
+    latency: Option<Latency>,
+    in_flight: VecDeque<(Instant, S::Item)>,
+    delay: Option<Delay>,
+}
+
+impl<S> LatentStream<S>
+where
+    S: Stream,
+{
+    /// Without latency, the items go through as soon as they are polled.
+    pub fn new(inner: S, latency: Option<Latency>) -> LatentStream<S> {
+        LatentStream {
+            inner,
+            inner_done: false,
+            latency,
+            in_flight: VecDeque::new(),
+            delay: None,
+        }
+    }
+}
+
+impl<S> Stream for LatentStream<S>
+where
+    S: Stream,
+{
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        let latency = match self.latency {
+            Some(latency) => latency,
+            None => return self.inner.poll(),
+        };
+
+        while !self.inner_done {
+            match self.inner.poll()? {
+                Async::Ready(Some(item)) => {
+                    let sent_at = Instant::now();
+                    let delivered_at = match self.in_flight.back() {
+                        Some(&(previous_at, _)) => {
+                            cmp::max(previous_at, sent_at + latency.sample())
+                        }
+                        None => sent_at + latency.sample(),
+                    };
+                    self.in_flight.push_back((delivered_at, item));
+                }
+                Async::Ready(None) => self.inner_done = true,
+                Async::NotReady => break,
+            }
+        }
+
+        let delivered_at = match self.in_flight.front() {
+            Some(&(delivered_at, _)) => delivered_at,
+            None if self.inner_done => return Ok(Async::Ready(None)),
+            None => return Ok(Async::NotReady),
+        };
+
+        if delivered_at > Instant::now() {
+            let delay = self.delay.get_or_insert_with(|| Delay::new(delivered_at));
+            // Polling registers the timer for the current task.
+            match delay.poll() {
+                Ok(Async::Ready(())) => {}
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(err) => panic!("Timer error: {}", err),
+            }
+        }
+
+        self.delay = None;
+        let (_delivered_at, item) = self.in_flight.pop_front().unwrap();
+        Ok(Async::Ready(Some(item)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use std::sync::{Arc, Mutex};
+    use tokio;
+
+    #[test]
+    fn delays_the_items_in_order() {
+        let latency = Latency::new(Duration::from_millis(30), Duration::from_millis(20));
+        let latent_stream = LatentStream::new(stream::iter_ok::<_, ()>(0..10), Some(latency));
+
+        let result = Arc::new(Mutex::new(None));
+        let result_clone = result.clone();
+        let start = Instant::now();
+        tokio::run(latent_stream.collect().map(move |received| {
+            *result_clone.lock().unwrap() = Some((received, start.elapsed()));
+        }));
+
+        let (received, elapsed) = result.lock().unwrap().take().unwrap();
+        assert_eq!((0..10).collect::<Vec<_>>(), received);
+        assert!(elapsed >= Duration::from_millis(30));
+        assert!(elapsed < Duration::from_millis(500));
+    }
+}
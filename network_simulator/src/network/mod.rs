@@ -1,6 +1,11 @@
 use futures::{stream, Future, Stream};
+use network::conditions::{Conditions, NetworkEvent};
+use network::latency::Latency;
+use network::rate_limit::RateLimit;
+use network::stop::Stop;
+use network::topology::Topology;
 use network::transport::MPSCAddress;
-pub use network::transport::MPSCConnection;
+pub use network::transport::{ConnectionReceiver, MPSCConnection, MessageCounter};
 use network::transport::MPSCTransport;
 use rand::{self, Rng};
 use std::collections::HashSet;
@@ -11,11 +16,18 @@ use tokio;
 use tokio_timer::Delay;
 
 pub trait Node<M> {
-    fn run<S>(self, connection_stream: S) -> Box<Future<Item = (), Error = ()> + Send>
+    fn run<S>(self, connection_stream: S) -> Box<dyn Future<Item = (), Error = ()> + Send>
     where
         S: Stream<Item = MPSCConnection<M>, Error = ()> + Send + 'static;
 }
 
+pub mod conditions;
+pub mod heartbeat;
+pub mod latency;
+pub mod multiplex;
+pub mod rate_limit;
+pub mod stop;
+pub mod topology;
 pub mod transport;
 
 pub struct Network<M>
@@ -23,6 +35,9 @@ where
     M: Clone + Send + 'static,
 {
     transports: Vec<MPSCTransport<M>>,
+    conditions: Conditions,
+    scheduled_events: Vec<(Duration, NetworkEvent)>,
+    stop: Stop,
 }
 
 impl<M> Network<M>
@@ -70,7 +85,113 @@ where
             }
         }
 
-        Network { transports }
+        Network {
+            transports,
+            conditions: Conditions::new(),
+            scheduled_events: vec![],
+            stop: Stop::new(),
+        }
+    }
+
+    /// A network with the connections of the topology, each initiated by the source of its
+    /// edge. The nodes of the topology must be numbered from zero.
+    pub fn from_topology(topology: &Topology) -> Network<M> {
+        let size = topology.nodes().len() as u32;
+        assert!(
+            topology.nodes().iter().all(|node_id| *node_id < size),
+            "The nodes of the topology must be numbered from zero."
+        );
+
+        let mut transports: Vec<MPSCTransport<M>> = (0..size).map(MPSCTransport::new).collect();
+        for (node_id, seed_id) in topology.edges() {
+            let seed_address = transports[*seed_id as usize].address().clone();
+            transports[*node_id as usize].include_seed(seed_address);
+        }
+
+        Network {
+            transports,
+            conditions: Conditions::new(),
+            scheduled_events: vec![],
+            stop: Stop::new(),
+        }
+    }
+
+    /// The connection graph of the network. An edge goes from the node initiating the
+    /// connection to its seed, even though connections are bidirectional.
+    pub fn topology(&self) -> Topology {
+        let nodes = self.transports
+            .iter()
+            .map(|transport| *transport.address().id())
+            .collect();
+        let edges = self.transports
+            .iter()
+            .flat_map(|transport| {
+                let node_id = *transport.address().id();
+                transport.seeds().iter().map(move |seed| (node_id, *seed.id()))
+            })
+            .collect();
+
+        Topology::undirected(nodes, edges)
+    }
+
+    /// Makes the node initiate a connection to the seed when the network runs, on top of the
+    /// random connections. Nothing changes if they are already connected.
+    pub fn connect(&mut self, node_id: u32, seed_id: u32) {
+        assert_ne!(node_id, seed_id, "A node cannot connect to itself.");
+        let seed_address = self.transports[seed_id as usize].address().clone();
+        let already_connected = self.transports[node_id as usize]
+            .seeds()
+            .contains(&seed_address)
+            || self.transports[seed_id as usize]
+                .seeds()
+                .iter()
+                .any(|seed| *seed.id() == node_id);
+
+        if !already_connected {
+            self.transports[node_id as usize].include_seed(seed_address);
+        }
+    }
+
+    /// A way to stop the network before the end of its run.
+    pub fn stop_handle(&self) -> Stop {
+        self.stop.clone()
+    }
+
+    /// Counts every message delivered to the nodes of the network.
+    pub fn count_delivered_messages(&mut self) -> MessageCounter {
+        let message_counter = MessageCounter::new();
+        for transport in &mut self.transports {
+            transport.set_message_counter(message_counter.clone());
+        }
+
+        message_counter
+    }
+
+    /// Attaches a rate limit to every connection of the network. Each connection gets its
+    /// own token bucket but the throttling is reported to the `ThrottleStats` of `rate_limit`.
+    pub fn set_rate_limit(&mut self, rate_limit: &RateLimit) {
+        for transport in &mut self.transports {
+            transport.set_rate_limit(rate_limit.clone());
+        }
+    }
+
+    /// Delays every message by the latency, drawn independently for each message.
+    pub fn set_latency(&mut self, latency: Latency) {
+        for transport in &mut self.transports {
+            transport.set_latency(latency);
+        }
+    }
+
+    /// Changes the conditions of the network once it has run for the given time. The events
+    /// scheduled after the end of the run never happen.
+    pub fn schedule(&mut self, after: Duration, event: NetworkEvent) {
+        if self.scheduled_events.is_empty() {
+            for transport in &mut self.transports {
+                transport.set_conditions(self.conditions.clone());
+            }
+        }
+
+        self.scheduled_events.push((after, event));
     }
 
     pub fn run<N, F>(self, node_factory: F, for_duration: Duration)
@@ -79,25 +200,45 @@ where
         F: Fn() -> N + Send + 'static,
     {
         let nodes = self.transports;
+        let conditions = self.conditions;
+        let events_stop = self.stop.clone();
+        let nodes_stop = self.stop;
+        let start = Instant::now();
+        let events_future = stream::iter_ok(self.scheduled_events).for_each(move |(after, event)| {
+            let conditions = conditions.clone();
+            let event_future = Delay::new(start + after)
+                .map(move |()| conditions.apply(&event))
+                .map_err(|err| panic!("Timer error: {}", err));
+            tokio::spawn(with_timeout(event_future, for_duration, &events_stop));
+            Ok(())
+        });
+
         let nodes_future = stream::iter_ok(nodes).for_each(move |transport| {
             debug!("Starting a new node.");
 
             let node_future = node_factory().run(transport.run());
-            tokio::spawn(with_timeout(node_future, for_duration))
+            tokio::spawn(with_timeout(node_future, for_duration, &nodes_stop))
         });
 
-        tokio::run(nodes_future);
+        tokio::run(events_future.and_then(|()| nodes_future));
     }
 }
 
-fn with_timeout<F>(future: F, timeout: Duration) -> impl Future<Item = (), Error = ()>
+/// Runs the future until the timeout or until the network is stopped.
+fn with_timeout<F>(future: F, timeout: Duration, stop: &Stop) -> impl Future<Item = (), Error = ()>
 where
     F: Future<Item = (), Error = ()>,
 {
     let delay_future =
         Delay::new(Instant::now().add(timeout)).map_err(|err| panic!("Timer error: {}", err));
 
-    future.select(delay_future).map(|_| {}).map_err(|_| {})
+    future
+        .select(delay_future)
+        .map(|_| {})
+        .map_err(|_| {})
+        .select(stop.stopped())
+        .map(|_| {})
+        .map_err(|_| {})
 }
 
 impl<M> MPSCTransport<M>
@@ -150,28 +291,32 @@ where
 mod tests {
     use super::*;
     use futures::Future;
-    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-    use std::sync::Arc;
+    use testing::{Harness, Probe};
 
     #[derive(Clone, Debug)]
     pub struct Message {}
 
+    #[derive(Clone, Debug, Default)]
+    pub struct TestNodeState {
+        notified_of_start: bool,
+        connections_established: usize,
+        received_messages: usize,
+    }
+
     pub struct TestNode {
-        received_messages: Arc<AtomicUsize>,
-        connections_established: Arc<AtomicUsize>,
-        notified_of_start: Arc<AtomicBool>,
+        probe: Probe<TestNodeState>,
     }
 
     impl Node<Message> for TestNode {
-        fn run<S>(self, connection_stream: S) -> Box<Future<Item = (), Error = ()> + Send>
+        fn run<S>(self, connection_stream: S) -> Box<dyn Future<Item = (), Error = ()> + Send>
         where
             S: Stream<Item = MPSCConnection<Message>, Error = ()> + Send + 'static,
         {
-            self.notified_of_start.store(true, Ordering::Relaxed);
+            self.probe.update(|state| state.notified_of_start = true);
 
             let connection_future = connection_stream.for_each(move |connection| {
-                self.connections_established.fetch_add(1, Ordering::Relaxed);
-                let received_messages = self.received_messages.clone();
+                self.probe.update(|state| state.connections_established += 1);
+                let probe = self.probe.clone();
                 let (sender, receiver) = connection.split();
 
                 // Send one message per connection received for each node.
@@ -181,7 +326,7 @@ mod tests {
 
                 let reception = receiver
                     .into_future().map(move |(_message, _tail)| {
-                        received_messages.fetch_add(1, Ordering::Relaxed);
+                        probe.update(|state| state.received_messages += 1);
                         drop(sender); // This will drop the connection too.
                     })
                     .map_err(|_| panic!());
@@ -192,6 +337,78 @@ mod tests {
         }
     }
 
+    #[test]
+    fn can_describe_the_topology() {
+        let network: Network<Message> = Network::new(16, 2);
+        let topology = network.topology();
+
+        assert_eq!(16, topology.nodes().len());
+        assert!(topology.edges().len() <= 16 * 2);
+        for (source, target) in topology.edges() {
+            assert_ne!(source, target);
+        }
+    }
+
+    #[test]
+    fn can_add_connections() {
+        let mut network: Network<Message> = Network::new(16, 1);
+        for seed_id in 1..16 {
+            network.connect(0, seed_id);
+        }
+        network.connect(3, 0);
+
+        let topology = network.topology();
+        let edges_of_the_first_node = topology
+            .edges()
+            .iter()
+            .filter(|(source, target)| *source == 0 || *target == 0)
+            .count();
+        assert_eq!(15, edges_of_the_first_node);
+        assert!(topology.is_connected());
+    }
+
+    #[test]
+    fn can_rebuild_a_network_from_its_topology() {
+        let network: Network<Message> = Network::new(16, 2);
+        let topology = network.topology();
+
+        let rebuilt: Network<Message> = Network::from_topology(&topology);
+        assert_eq!(topology, rebuilt.topology());
+    }
+
+    struct StoppingNode {
+        stop: Stop,
+    }
+
+    impl Node<Message> for StoppingNode {
+        fn run<S>(self, _connection_stream: S) -> Box<dyn Future<Item = (), Error = ()> + Send>
+        where
+            S: Stream<Item = MPSCConnection<Message>, Error = ()> + Send + 'static,
+        {
+            self.stop.stop();
+            Box::new(::futures::future::empty())
+        }
+    }
+
+    #[test]
+    fn can_stop_before_the_end_of_the_run() {
+        let network: Network<Message> = Network::new(4, 1);
+        let stop = network.stop_handle();
+        assert!(!stop.is_stopped());
+
+        let start = Instant::now();
+        let node_stop = stop.clone();
+        network.run(
+            move || StoppingNode {
+                stop: node_stop.clone(),
+            },
+            Duration::from_secs(60),
+        );
+
+        assert!(stop.is_stopped());
+        assert!(start.elapsed() < Duration::from_secs(30));
+    }
+
     #[test]
     fn can_create_a_network() {
         new_network_test(4, 1);
@@ -203,31 +420,39 @@ mod tests {
     fn new_network_test(network_size: u32, initiated_connections: u8) {
         let network = Network::new(network_size, initiated_connections);
 
-        let global_number_of_received_messages = Arc::new(AtomicUsize::new(0));
-        let notified_of_start = Arc::new(AtomicBool::new(false));
-        let connections_established = Arc::new(AtomicUsize::new(0));
+        let harness = Harness::start(
+            network,
+            || {
+                let probe = Probe::new(TestNodeState::default());
+                (
+                    TestNode {
+                        probe: probe.clone(),
+                    },
+                    probe,
+                )
+            },
+            Duration::from_secs(5),
+        );
 
-        let received_messages_clone = global_number_of_received_messages.clone();
-        let notified_of_start_clone = notified_of_start.clone();
-        let connections_established_clone = connections_established.clone();
+        // A node may not find any seed left to connect to, so the expectations are
+        // based on the connections that were actually defined.
+        let number_of_connections = harness.topology().edges().len();
+        assert!(number_of_connections <= network_size as usize * initiated_connections as usize);
+        if initiated_connections > 1 {
+            harness.assert_connected();
+        }
 
-        network.run(
-            move || TestNode {
-                received_messages: received_messages_clone.clone(),
-                notified_of_start: notified_of_start_clone.clone(),
-                connections_established: connections_established_clone.clone(),
+        let expected = number_of_connections * 2;
+        harness.assert_messages_delivered(expected, Duration::from_secs(5));
+        harness.assert_eventually(
+            |nodes| {
+                nodes.iter().all(|node| node.notified_of_start)
+                    && nodes.iter().map(|node| node.connections_established).sum::<usize>() == expected
+                    && nodes.iter().map(|node| node.received_messages).sum::<usize>() == expected
             },
             Duration::from_secs(5),
         );
 
-        assert_eq!(
-            network_size as usize * 2 * initiated_connections as usize,
-            connections_established.load(Ordering::Relaxed)
-        );
-        assert_eq!(
-            network_size as usize * 2 * initiated_connections as usize,
-            global_number_of_received_messages.load(Ordering::Relaxed)
-        );
-        assert!(notified_of_start.load(Ordering::Relaxed));
+        harness.join();
     }
 }
@@ -0,0 +1,281 @@
+use futures::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::{Async, Future, Poll, Stream};
+use network::transport::{ConnectionReceiver, MPSCConnection};
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+type Payload = Arc<dyn Any + Send + Sync>;
+
+/// Identifies a protocol on a connection. Several protocols can use the same message type.
+pub type ProtocolId = &'static str;
+
+type SendObserver = Arc<dyn Fn(ProtocolId, &(dyn Any + Send + Sync)) + Send + Sync>;
+
+/// The message type of a network whose connections are shared by several protocols.
+/// The payload is routed to the sub-channel that was opened for its protocol.
+#[derive(Clone)]
+pub struct MultiplexedMessage {
+    protocol: ProtocolId,
+    payload: Payload,
+}
+
+pub type MultiplexedConnection = MPSCConnection<MultiplexedMessage>;
+
+/// Splits a connection into typed sub-channels, one per protocol.
+///
+/// Sub-channels must be opened with `channel` before the multiplexer is run, the messages
+/// of a protocol with no open sub-channel are discarded.
+pub struct Multiplexer {
+    remote_id: u32,
+    sender: UnboundedSender<MultiplexedMessage>,
+    receiver: ConnectionReceiver<MultiplexedMessage>,
+    routes: HashMap<ProtocolId, UnboundedSender<Payload>>,
+    observer: Option<SendObserver>,
+}
+
+impl Multiplexer {
+    pub fn new(connection: MultiplexedConnection) -> Multiplexer {
+        let remote_id = connection.remote_id();
+        let (sender, receiver) = connection.split();
+
+        Multiplexer {
+            remote_id,
+            sender,
+            receiver,
+            routes: HashMap::new(),
+            observer: None,
+        }
+    }
+
+    pub fn remote_id(&self) -> u32 {
+        self.remote_id
+    }
+
+    /// Calls the observer with every message sent on the sub-channels, e.g. to measure the
+    /// traffic. Panics if a sub-channel was already opened.
+    pub fn observe_sent<F>(&mut self, observer: F)
+    where
+        F: Fn(ProtocolId, &(dyn Any + Send + Sync)) + Send + Sync + 'static,
+    {
+        if !self.routes.is_empty() {
+            panic!("The sent messages must be observed before any sub-channel is opened.")
+        }
+
+        self.observer = Some(Arc::new(observer));
+    }
+
+    /// Opens the sub-channel of the protocol, carrying messages of type `P`.
+    /// Panics if a sub-channel was already opened for this protocol.
+    pub fn channel<P>(&mut self, protocol: ProtocolId) -> (ProtocolSender<P>, ProtocolReceiver<P>)
+    where
+        P: Clone + Send + Sync + 'static,
+    {
+        let (route_sender, route_receiver) = mpsc::unbounded();
+
+        if self.routes.insert(protocol, route_sender).is_some() {
+            panic!("A sub-channel was already opened for the protocol {}.", protocol)
+        }
+
+        let sender = ProtocolSender {
+            sender: self.sender.clone(),
+            protocol,
+            observer: self.observer.clone(),
+            message_type: PhantomData,
+        };
+        let receiver = ProtocolReceiver {
+            receiver: route_receiver,
+            message_type: PhantomData,
+        };
+
+        (sender, receiver)
+    }
+
+    /// Routes the incoming messages to their sub-channel until the connection is closed.
+    /// The returned future is expected to be spawned.
+    pub fn run(self) -> impl Future<Item = (), Error = ()> {
+        let routes = self.routes;
+
+        self.receiver.for_each(move |message| {
+            match routes.get(&message.protocol) {
+                Some(route) => {
+                    if route.unbounded_send(message.payload).is_err() {
+                        debug!("Sub-channel closed, message discarded.");
+                    }
+                }
+                None => debug!(
+                    "No sub-channel for the protocol {}, message discarded.",
+                    message.protocol
+                ),
+            }
+
+            Ok(())
+        })
+    }
+}
+
+/// The sending half of a sub-channel. Clones share the underlying connection.
+pub struct ProtocolSender<P> {
+    sender: UnboundedSender<MultiplexedMessage>,
+    protocol: ProtocolId,
+    observer: Option<SendObserver>,
+    message_type: PhantomData<fn(P)>,
+}
+
+impl<P> Clone for ProtocolSender<P> {
+    fn clone(&self) -> ProtocolSender<P> {
+        ProtocolSender {
+            sender: self.sender.clone(),
+            protocol: self.protocol,
+            observer: self.observer.clone(),
+            message_type: PhantomData,
+        }
+    }
+}
+
+impl<P> ProtocolSender<P>
+where
+    P: Send + Sync + 'static,
+{
+    pub fn unbounded_send(&self, message: P) -> Result<(), SendError> {
+        if let Some(ref observer) = self.observer {
+            observer(self.protocol, &message);
+        }
+
+        let multiplexed_message = MultiplexedMessage {
+            protocol: self.protocol,
+            payload: Arc::new(message),
+        };
+
+        self.sender
+            .unbounded_send(multiplexed_message)
+            .map_err(|_err| SendError)
+    }
+}
+
+/// The connection was closed by the remote peer.
+#[derive(Debug)]
+pub struct SendError;
+
+impl Display for SendError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "send failed because the connection is closed")
+    }
+}
+
+/// The receiving half of a sub-channel.
+pub struct ProtocolReceiver<P> {
+    receiver: UnboundedReceiver<Payload>,
+    message_type: PhantomData<fn() -> P>,
+}
+
+impl<P> Stream for ProtocolReceiver<P>
+where
+    P: Clone + Send + Sync + 'static,
+{
+    type Item = P;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<P>, ()> {
+        let payload = match try_ready!(self.receiver.poll()) {
+            Some(payload) => payload,
+            None => return Ok(Async::Ready(None)),
+        };
+
+        match payload.downcast::<P>() {
+            Ok(message) => {
+                // The payload is usually not shared anymore, cloning is only a fallback.
+                let message = Arc::try_unwrap(message).unwrap_or_else(|message| (*message).clone());
+                Ok(Async::Ready(Some(message)))
+            }
+            Err(_payload) => panic!("The payload does not match the type of its sub-channel."),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use network::transport::connection_pair;
+    use std::sync::Mutex;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Ping(u32);
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Text(String);
+
+    #[test]
+    fn routes_messages_to_their_protocol() {
+        let (one, other) = connection_pair();
+
+        let mut one = Multiplexer::new(one);
+        let (ping_sender, _) = one.channel::<Ping>("ping");
+        let (text_sender, _) = one.channel::<Text>("text");
+        drop(one);
+
+        let mut other = Multiplexer::new(other);
+        let (_, ping_receiver) = other.channel::<Ping>("ping");
+        let (_, text_receiver) = other.channel::<Text>("text");
+
+        ping_sender.unbounded_send(Ping(1)).unwrap();
+        text_sender.unbounded_send(Text("one".to_owned())).unwrap();
+        ping_sender.unbounded_send(Ping(2)).unwrap();
+        drop(ping_sender);
+        drop(text_sender);
+
+        let (((), pings), texts) = other
+            .run()
+            .join(ping_receiver.collect())
+            .join(text_receiver.collect())
+            .wait()
+            .unwrap();
+
+        assert_eq!(vec![Ping(1), Ping(2)], pings);
+        assert_eq!(vec![Text("one".to_owned())], texts);
+    }
+
+    #[test]
+    fn protocols_can_share_a_message_type() {
+        let (one, other) = connection_pair();
+
+        let mut one = Multiplexer::new(one);
+        let sent = Arc::new(Mutex::new(vec![]));
+        let sent_clone = sent.clone();
+        one.observe_sent(move |protocol, _message| sent_clone.lock().unwrap().push(protocol));
+        let (greeting_sender, _) = one.channel::<Text>("greeting");
+        let (farewell_sender, _) = one.channel::<Text>("farewell");
+        drop(one);
+
+        let mut other = Multiplexer::new(other);
+        let (_, greeting_receiver) = other.channel::<Text>("greeting");
+        let (_, farewell_receiver) = other.channel::<Text>("farewell");
+
+        greeting_sender.unbounded_send(Text("hello".to_owned())).unwrap();
+        farewell_sender.unbounded_send(Text("bye".to_owned())).unwrap();
+        drop(greeting_sender);
+        drop(farewell_sender);
+
+        let (((), greetings), farewells) = other
+            .run()
+            .join(greeting_receiver.collect())
+            .join(farewell_receiver.collect())
+            .wait()
+            .unwrap();
+
+        assert_eq!(vec![Text("hello".to_owned())], greetings);
+        assert_eq!(vec![Text("bye".to_owned())], farewells);
+        assert_eq!(vec!["greeting", "farewell"], *sent.lock().unwrap());
+    }
+
+    #[test]
+    #[should_panic]
+    fn cannot_open_the_same_protocol_twice() {
+        let (one, _other) = connection_pair::<MultiplexedMessage>();
+        let mut one = Multiplexer::new(one);
+        let _first = one.channel::<Ping>("ping");
+        let _second = one.channel::<Text>("ping");
+    }
+}
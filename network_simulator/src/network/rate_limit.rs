@@ -0,0 +1,265 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// What a connection does with a message received while its token bucket is empty.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExceededPolicy {
+    /// The message is discarded.
+    Drop,
+    /// The message is held back until a token is available. Message order is preserved.
+    /// Beyond the maximum number of delayed messages of the limit, the message is dropped.
+    Delay,
+    /// The connection is closed. The remote peer will notice it on its next send.
+    Disconnect,
+}
+
+/// Counts the messages affected by a rate limit. Clones share the same counters,
+/// so a single instance can aggregate the throttling of every connection of a network.
+#[derive(Clone, Debug, Default)]
+pub struct ThrottleStats {
+    dropped: Arc<AtomicUsize>,
+    delayed: Arc<AtomicUsize>,
+    disconnections: Arc<AtomicUsize>,
+}
+
+impl ThrottleStats {
+    pub fn new() -> ThrottleStats {
+        ThrottleStats::default()
+    }
+
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn delayed(&self) -> usize {
+        self.delayed.load(Ordering::Relaxed)
+    }
+
+    pub fn disconnections(&self) -> usize {
+        self.disconnections.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, policy: ExceededPolicy) {
+        let counter = match policy {
+            ExceededPolicy::Drop => &self.dropped,
+            ExceededPolicy::Delay => &self.delayed,
+            ExceededPolicy::Disconnect => &self.disconnections,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The number of messages a connection holds back by default under the `Delay` policy.
+pub const DEFAULT_MAX_DELAYED_MESSAGES: usize = 1000;
+
+/// The configuration of a token bucket rate limiter. Every connection it is attached
+/// to gets its own bucket, but they all report to the same `ThrottleStats`.
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    capacity: u32,
+    refill_interval: Duration,
+    policy: ExceededPolicy,
+    max_delayed: usize,
+    stats: ThrottleStats,
+}
+
+impl RateLimit {
+    /// # Arguments
+    ///
+    /// `capacity`: the maximum number of messages that can be received in a burst.
+    /// `refill_interval`: the time it takes for the bucket to get a token back.
+    /// `policy`: what to do with a message received while the bucket is empty.
+    pub fn new(capacity: u32, refill_interval: Duration, policy: ExceededPolicy) -> RateLimit {
+        if capacity == 0 {
+            panic!("A rate limit requires a capacity of at least one message.")
+        }
+
+        RateLimit {
+            capacity,
+            refill_interval,
+            policy,
+            max_delayed: DEFAULT_MAX_DELAYED_MESSAGES,
+            stats: ThrottleStats::new(),
+        }
+    }
+
+    /// Sets the maximum number of messages a connection holds back under the `Delay` policy.
+    pub fn with_max_delayed(mut self, max_delayed: usize) -> RateLimit {
+        self.max_delayed = max_delayed;
+        self
+    }
+
+    pub fn policy(&self) -> ExceededPolicy {
+        self.policy
+    }
+
+    pub fn stats(&self) -> &ThrottleStats {
+        &self.stats
+    }
+
+    pub(crate) fn limiter(&self) -> RateLimiter {
+        RateLimiter {
+            bucket: TokenBucket::new(self.capacity, self.refill_interval, Instant::now()),
+            policy: self.policy,
+            max_delayed: self.max_delayed,
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+/// The per-connection state of a rate limit.
+pub(crate) struct RateLimiter {
+    bucket: TokenBucket,
+    policy: ExceededPolicy,
+    max_delayed: usize,
+    stats: ThrottleStats,
+}
+
+impl RateLimiter {
+    /// Takes a token if one is available and no message is held back, otherwise returns
+    /// the policy to apply after having recorded it. `delayed` is the number of messages
+    /// the connection already holds back: a message cannot overtake them.
+    pub fn acquire(&mut self, delayed: usize) -> Result<(), ExceededPolicy> {
+        if delayed == 0 && self.bucket.try_acquire(Instant::now()) {
+            return Ok(());
+        }
+
+        let policy = match self.policy {
+            ExceededPolicy::Delay if delayed >= self.max_delayed => ExceededPolicy::Drop,
+            policy => policy,
+        };
+        self.stats.record(policy);
+        Err(policy)
+    }
+
+    /// Same as `acquire` but for a message that was already delayed once: it is not recorded again.
+    pub fn acquire_delayed(&mut self) -> bool {
+        self.bucket.try_acquire(Instant::now())
+    }
+
+    pub fn next_token_at(&self) -> Instant {
+        self.bucket.next_token_at()
+    }
+}
+
+struct TokenBucket {
+    capacity: u32,
+    tokens: u32,
+    refill_interval: Duration,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: u32, refill_interval: Duration, now: Instant) -> TokenBucket {
+        TokenBucket {
+            capacity,
+            tokens: capacity,
+            refill_interval,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if self.tokens == self.capacity {
+            self.last_refill = now;
+            return;
+        }
+
+        let elapsed = now.duration_since(self.last_refill).as_nanos();
+        let interval = self.refill_interval.as_nanos().max(1);
+        let new_tokens = elapsed / interval;
+
+        if new_tokens > 0 {
+            let missing_tokens = u128::from(self.capacity - self.tokens);
+            if new_tokens >= missing_tokens {
+                self.tokens = self.capacity;
+                self.last_refill = now;
+            } else {
+                self.tokens += new_tokens as u32;
+                self.last_refill += self.refill_interval * new_tokens as u32;
+            }
+        }
+    }
+
+    fn try_acquire(&mut self, now: Instant) -> bool {
+        self.refill(now);
+
+        if self.tokens > 0 {
+            self.tokens -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn next_token_at(&self) -> Instant {
+        self.last_refill + self.refill_interval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_bursts_up_to_its_capacity() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(3, Duration::from_millis(100), start);
+
+        assert!(bucket.try_acquire(start));
+        assert!(bucket.try_acquire(start));
+        assert!(bucket.try_acquire(start));
+        assert!(!bucket.try_acquire(start));
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2, Duration::from_millis(100), start);
+        assert!(bucket.try_acquire(start));
+        assert!(bucket.try_acquire(start));
+
+        let later = start + Duration::from_millis(150);
+        assert!(bucket.try_acquire(later));
+        assert!(!bucket.try_acquire(later));
+        assert_eq!(start + Duration::from_millis(200), bucket.next_token_at());
+
+        let much_later = start + Duration::from_secs(10);
+        assert!(bucket.try_acquire(much_later));
+        assert!(bucket.try_acquire(much_later));
+        assert!(!bucket.try_acquire(much_later));
+    }
+
+    #[test]
+    fn stats_are_shared_between_limiters() {
+        let rate_limit = RateLimit::new(1, Duration::from_secs(3600), ExceededPolicy::Drop);
+        let mut one = rate_limit.limiter();
+        let mut other = rate_limit.limiter();
+
+        assert_eq!(Ok(()), one.acquire(0));
+        assert_eq!(Err(ExceededPolicy::Drop), one.acquire(0));
+        assert_eq!(Ok(()), other.acquire(0));
+        assert_eq!(Err(ExceededPolicy::Drop), other.acquire(0));
+
+        assert_eq!(2, rate_limit.stats().dropped());
+        assert_eq!(0, rate_limit.stats().delayed());
+        assert_eq!(0, rate_limit.stats().disconnections());
+    }
+
+    #[test]
+    fn drops_the_messages_beyond_the_delayed_ones() {
+        let rate_limit =
+            RateLimit::new(1, Duration::from_secs(3600), ExceededPolicy::Delay).with_max_delayed(2);
+        let mut limiter = rate_limit.limiter();
+
+        assert_eq!(Ok(()), limiter.acquire(0));
+        assert_eq!(Err(ExceededPolicy::Delay), limiter.acquire(0));
+        assert_eq!(Err(ExceededPolicy::Delay), limiter.acquire(1));
+        assert_eq!(Err(ExceededPolicy::Drop), limiter.acquire(2));
+
+        assert_eq!(2, rate_limit.stats().delayed());
+        assert_eq!(1, rate_limit.stats().dropped());
+    }
+}
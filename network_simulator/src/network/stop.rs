@@ -0,0 +1,49 @@
+use futures::future::{self, Either, Shared};
+use futures::sync::oneshot::{self, Receiver, Sender};
+use futures::Future;
+use std::sync::{Arc, Mutex};
+
+/// Stops a running network before the end of its duration. Clones share the same state, so
+/// a node can be handed a clone to stop the whole network once something happens.
+#[derive(Clone)]
+pub struct Stop {
+    sender: Arc<Mutex<Option<Sender<()>>>>,
+    receiver: Shared<Receiver<()>>,
+}
+
+impl Stop {
+    pub fn new() -> Stop {
+        let (sender, receiver) = oneshot::channel();
+        Stop {
+            sender: Arc::new(Mutex::new(Some(sender))),
+            receiver: receiver.shared(),
+        }
+    }
+
+    /// Stops the network. Only the first call has an effect.
+    pub fn stop(&self) {
+        if let Some(sender) = self.sender.lock().unwrap().take() {
+            debug!("Stopping the network.");
+            let _ = sender.send(());
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.sender.lock().unwrap().is_none()
+    }
+
+    /// Completes once the network is stopped.
+    pub fn stopped(&self) -> impl Future<Item = (), Error = ()> {
+        self.receiver.clone().then(|result| match result {
+            Ok(_) => Either::A(future::ok(())),
+            // The network is never stopped once every handle is dropped.
+            Err(_canceled) => Either::B(future::empty()),
+        })
+    }
+}
+
+impl Default for Stop {
+    fn default() -> Stop {
+        Stop::new()
+    }
+}
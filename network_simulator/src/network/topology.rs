@@ -0,0 +1,217 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// Numeric attributes attached to the nodes of a graph when exporting it, by node id then
+/// by attribute name.
+pub type NodeAttributes = BTreeMap<u32, BTreeMap<String, u64>>;
+
+/// A graph of nodes identified by their address id.
+/// The network connection graph is undirected, a directed graph is rather used for things
+/// flowing through the network, like the propagation of a message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Topology {
+    nodes: Vec<u32>,
+    edges: Vec<(u32, u32)>,
+    directed: bool,
+}
+
+impl Topology {
+    pub fn undirected(nodes: Vec<u32>, edges: Vec<(u32, u32)>) -> Topology {
+        Topology {
+            nodes,
+            edges,
+            directed: false,
+        }
+    }
+
+    pub fn directed(nodes: Vec<u32>, edges: Vec<(u32, u32)>) -> Topology {
+        Topology {
+            nodes,
+            edges,
+            directed: true,
+        }
+    }
+
+    pub fn nodes(&self) -> &[u32] {
+        &self.nodes
+    }
+
+    pub fn edges(&self) -> &[(u32, u32)] {
+        &self.edges
+    }
+
+    /// Whether every node can be reached from any other node. The direction of the edges
+    /// is ignored, so a directed graph only has to be weakly connected.
+    pub fn is_connected(&self) -> bool {
+        let mut neighbours: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+        for (source, target) in &self.edges {
+            neighbours.entry(*source).or_default().push(*target);
+            neighbours.entry(*target).or_default().push(*source);
+        }
+
+        let mut reached = BTreeSet::new();
+        let mut to_visit: Vec<u32> = self.nodes.iter().take(1).cloned().collect();
+        while let Some(node) = to_visit.pop() {
+            if reached.insert(node) {
+                if let Some(node_neighbours) = neighbours.get(&node) {
+                    to_visit.extend(node_neighbours);
+                }
+            }
+        }
+
+        self.nodes.iter().all(|node| reached.contains(node))
+    }
+
+    /// Exports the graph in the Graphviz DOT language.
+    pub fn to_dot(&self, attributes: &NodeAttributes) -> String {
+        let (graph_type, edge_operator) = if self.directed {
+            ("digraph", "->")
+        } else {
+            ("graph", "--")
+        };
+
+        let mut dot = String::new();
+        writeln!(dot, "{} network {{", graph_type).unwrap();
+
+        for node in &self.nodes {
+            write!(dot, "    {}", node).unwrap();
+            if let Some(node_attributes) = attributes.get(node) {
+                let formatted: Vec<String> = node_attributes
+                    .iter()
+                    .map(|(name, value)| format!("{}={}", name, value))
+                    .collect();
+                write!(dot, " [{}]", formatted.join(", ")).unwrap();
+            }
+            writeln!(dot, ";").unwrap();
+        }
+
+        for (source, target) in &self.edges {
+            writeln!(dot, "    {} {} {};", source, edge_operator, target).unwrap();
+        }
+
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+
+    /// Exports the graph in the Graph Exchange XML Format, supported by Gephi.
+    pub fn to_gexf(&self, attributes: &NodeAttributes) -> String {
+        let attribute_names: BTreeSet<&String> = attributes
+            .values()
+            .flat_map(|node_attributes| node_attributes.keys())
+            .collect();
+        let attribute_ids: BTreeMap<&String, usize> = attribute_names
+            .into_iter()
+            .enumerate()
+            .map(|(id, name)| (name, id))
+            .collect();
+
+        let edge_type = if self.directed {
+            "directed"
+        } else {
+            "undirected"
+        };
+
+        let mut gexf = String::new();
+        writeln!(gexf, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+        writeln!(
+            gexf,
+            r#"<gexf xmlns="http://www.gexf.net/1.2draft" version="1.2">"#
+        ).unwrap();
+        writeln!(
+            gexf,
+            r#"  <graph mode="static" defaultedgetype="{}">"#,
+            edge_type
+        ).unwrap();
+
+        writeln!(gexf, r#"    <attributes class="node">"#).unwrap();
+        for (name, id) in &attribute_ids {
+            writeln!(
+                gexf,
+                r#"      <attribute id="{}" title="{}" type="long"/>"#,
+                id, name
+            ).unwrap();
+        }
+        writeln!(gexf, "    </attributes>").unwrap();
+
+        writeln!(gexf, "    <nodes>").unwrap();
+        for node in &self.nodes {
+            writeln!(gexf, r#"      <node id="{}" label="{}">"#, node, node).unwrap();
+            if let Some(node_attributes) = attributes.get(node) {
+                writeln!(gexf, "        <attvalues>").unwrap();
+                for (name, value) in node_attributes {
+                    writeln!(
+                        gexf,
+                        r#"          <attvalue for="{}" value="{}"/>"#,
+                        attribute_ids[name], value
+                    ).unwrap();
+                }
+                writeln!(gexf, "        </attvalues>").unwrap();
+            }
+            writeln!(gexf, "      </node>").unwrap();
+        }
+        writeln!(gexf, "    </nodes>").unwrap();
+
+        writeln!(gexf, "    <edges>").unwrap();
+        for (id, (source, target)) in self.edges.iter().enumerate() {
+            writeln!(
+                gexf,
+                r#"      <edge id="{}" source="{}" target="{}"/>"#,
+                id, source, target
+            ).unwrap();
+        }
+        writeln!(gexf, "    </edges>").unwrap();
+
+        writeln!(gexf, "  </graph>").unwrap();
+        writeln!(gexf, "</gexf>").unwrap();
+        gexf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attributes() -> NodeAttributes {
+        let mut attributes = NodeAttributes::new();
+        let mut node_attributes = BTreeMap::new();
+        node_attributes.insert("height".to_owned(), 5);
+        node_attributes.insert("blocks_mined".to_owned(), 2);
+        attributes.insert(1, node_attributes);
+        attributes
+    }
+
+    #[test]
+    fn can_export_to_dot() {
+        let topology = Topology::undirected(vec![0, 1, 2], vec![(0, 1), (2, 1)]);
+
+        assert_eq!(
+            "graph network {\n    0;\n    1 [blocks_mined=2, height=5];\n    2;\n    0 -- 1;\n    2 -- 1;\n}\n",
+            topology.to_dot(&attributes())
+        );
+
+        let topology = Topology::directed(vec![0, 1], vec![(0, 1)]);
+        assert!(topology.to_dot(&NodeAttributes::new()).contains("digraph network {"));
+        assert!(topology.to_dot(&NodeAttributes::new()).contains("0 -> 1;"));
+    }
+
+    #[test]
+    fn can_check_the_connectivity() {
+        assert!(Topology::undirected(vec![0, 1, 2], vec![(0, 1), (2, 1)]).is_connected());
+        assert!(Topology::directed(vec![0, 1, 2], vec![(0, 1), (2, 1)]).is_connected());
+        assert!(!Topology::undirected(vec![0, 1, 2], vec![(0, 1)]).is_connected());
+        assert!(Topology::undirected(vec![], vec![]).is_connected());
+    }
+
+    #[test]
+    fn can_export_to_gexf() {
+        let topology = Topology::directed(vec![0, 1], vec![(0, 1)]);
+        let gexf = topology.to_gexf(&attributes());
+
+        assert!(gexf.contains(r#"defaultedgetype="directed""#));
+        assert!(gexf.contains(r#"<attribute id="0" title="blocks_mined" type="long"/>"#));
+        assert!(gexf.contains(r#"<attribute id="1" title="height" type="long"/>"#));
+        assert!(gexf.contains(r#"<attvalue for="1" value="5"/>"#));
+        assert!(gexf.contains(r#"<node id="0" label="0">"#));
+        assert!(gexf.contains(r#"<edge id="0" source="0" target="1"/>"#));
+    }
+}
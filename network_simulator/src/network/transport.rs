@@ -1,8 +1,14 @@
 use futures::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
-use futures::Stream;
-use std::collections::HashMap;
+use futures::{Async, Future, Poll, Stream};
+use network::conditions::{Conditions, Link};
+use network::latency::{Latency, LatentStream};
+use network::rate_limit::{ExceededPolicy, RateLimit, RateLimiter};
+use std::collections::{HashMap, VecDeque};
 use std::hash::Hash;
 use std::hash::Hasher;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio_timer::Delay;
 
 #[derive(Debug)]
 enum TransportMessage<M> {
@@ -37,16 +43,173 @@ impl<M> MPSCAddress<M> {
 }
 
 pub struct MPSCConnection<M> {
+    remote_id: u32,
     sender: UnboundedSender<M>,
-    receiver: UnboundedReceiver<M>,
+    receiver: ConnectionReceiver<M>,
 }
 
 impl<M> MPSCConnection<M> {
-    pub fn split(self) -> (UnboundedSender<M>, UnboundedReceiver<M>) {
+    /// The id of the address of the peer at the other end of the connection.
+    pub fn remote_id(&self) -> u32 {
+        self.remote_id
+    }
+
+    pub fn split(self) -> (UnboundedSender<M>, ConnectionReceiver<M>) {
         (self.sender, self.receiver)
     }
 }
 
+/// The receiving half of a connection.
+/// When a rate limit is attached to the transport, the messages exceeding it are handled
+/// here, according to the `ExceededPolicy` of the limit. The latency and the conditions of
+/// the network apply here too.
+pub struct ConnectionReceiver<M> {
+    receiver: Option<LatentStream<UnboundedReceiver<M>>>,
+    rate_limiter: Option<RateLimiter>,
+    delayed: VecDeque<M>,
+    delay: Option<Delay>,
+    message_counter: Option<MessageCounter>,
+    link: Option<Link>,
+}
+
+impl<M> ConnectionReceiver<M> {
+    fn new(
+        receiver: UnboundedReceiver<M>,
+        rate_limit: Option<&RateLimit>,
+        message_counter: Option<&MessageCounter>,
+        latency: Option<Latency>,
+        link: Option<Link>,
+    ) -> ConnectionReceiver<M> {
+        ConnectionReceiver {
+            receiver: Some(LatentStream::new(receiver, latency)),
+            rate_limiter: rate_limit.map(RateLimit::limiter),
+            delayed: VecDeque::new(),
+            delay: None,
+            message_counter: message_counter.cloned(),
+            link,
+        }
+    }
+
+    fn deliver(&self, message: Option<M>) -> Poll<Option<M>, ()> {
+        if let (Some(_), Some(message_counter)) = (&message, &self.message_counter) {
+            message_counter.increment();
+        }
+
+        Ok(Async::Ready(message))
+    }
+
+    /// Polls the messages held back by the `Delay` policy. They always go first in order to
+    /// preserve the order of the messages.
+    fn poll_delayed(&mut self) -> Poll<Option<M>, ()> {
+        if let Some(ref mut delay) = self.delay {
+            if let Async::NotReady = delay
+                .poll()
+                .map_err(|err| panic!("Timer error: {}", err))?
+            {
+                return Ok(Async::NotReady);
+            }
+        }
+        self.delay = None;
+
+        let acquired = match self.rate_limiter {
+            Some(ref mut rate_limiter) => rate_limiter.acquire_delayed(),
+            None => true,
+        };
+
+        if acquired {
+            let message = self.delayed.pop_front();
+            self.deliver(message)
+        } else {
+            let rate_limiter = self.rate_limiter.as_ref().unwrap();
+            self.delay = Some(Delay::new(rate_limiter.next_token_at()));
+            // Poll once more so the timer gets registered for the current task.
+            self.poll_delayed()
+        }
+    }
+}
+
+impl<M> Stream for ConnectionReceiver<M> {
+    type Item = M;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<M>, ()> {
+        loop {
+            // The connection keeps reading while messages are held back, so the messages
+            // beyond the capacity of the delay queue are dropped instead of piling up.
+            let message = match self.receiver {
+                Some(ref mut receiver) => receiver.poll()?,
+                None => Async::Ready(None),
+            };
+
+            let message = match message {
+                Async::Ready(Some(message)) => message,
+                Async::Ready(None) => {
+                    self.receiver = None;
+                    if self.delayed.is_empty() {
+                        return Ok(Async::Ready(None));
+                    }
+                    return self.poll_delayed();
+                }
+                Async::NotReady => {
+                    if self.delayed.is_empty() {
+                        return Ok(Async::NotReady);
+                    }
+                    return self.poll_delayed();
+                }
+            };
+
+            if let Some(ref link) = self.link {
+                if !link.is_up() {
+                    debug!("Link from {} to {} down, message lost.", link.from, link.to);
+                    continue;
+                }
+            }
+
+            let delayed = self.delayed.len();
+            let acquired = match self.rate_limiter {
+                Some(ref mut rate_limiter) => rate_limiter.acquire(delayed),
+                None => Ok(()),
+            };
+
+            match acquired {
+                Ok(()) => return self.deliver(Some(message)),
+                Err(ExceededPolicy::Drop) => {
+                    debug!("Rate limit exceeded, message dropped.");
+                }
+                Err(ExceededPolicy::Delay) => {
+                    self.delayed.push_back(message);
+                }
+                Err(ExceededPolicy::Disconnect) => {
+                    debug!("Rate limit exceeded, closing the connection.");
+                    // Dropping the receiver closes the channel, the sender will fail from now on.
+                    self.receiver = None;
+                    return Ok(Async::Ready(None));
+                }
+            }
+        }
+    }
+}
+
+/// Counts the messages delivered to the nodes. Clones share the same count.
+#[derive(Clone, Debug, Default)]
+pub struct MessageCounter {
+    count: Arc<AtomicUsize>,
+}
+
+impl MessageCounter {
+    pub fn new() -> MessageCounter {
+        MessageCounter::default()
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn increment(&self) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
 pub struct MPSCTransport<M>
 where
     M: Clone + Send,
@@ -54,6 +217,10 @@ where
     address: MPSCAddress<M>,
     transport_receiver: UnboundedReceiver<TransportMessage<M>>,
     seeds: Vec<MPSCAddress<M>>,
+    rate_limit: Option<RateLimit>,
+    message_counter: Option<MessageCounter>,
+    latency: Option<Latency>,
+    conditions: Option<Conditions>,
 }
 
 impl<M> MPSCTransport<M>
@@ -72,6 +239,10 @@ where
             address,
             transport_receiver: channel_receiver,
             seeds: vec![],
+            rate_limit: None,
+            message_counter: None,
+            latency: None,
+            conditions: None,
         }
     }
 
@@ -83,9 +254,44 @@ where
         self.seeds.push(address);
     }
 
+    pub fn seeds(&self) -> &[MPSCAddress<M>] {
+        &self.seeds
+    }
+
+    /// Limits the rate of the messages received from each connection of this transport.
+    pub fn set_rate_limit(&mut self, rate_limit: RateLimit) {
+        self.rate_limit = Some(rate_limit);
+    }
+
+    /// Counts every message delivered through the connections of this transport.
+    pub fn set_message_counter(&mut self, message_counter: MessageCounter) {
+        self.message_counter = Some(message_counter);
+    }
+
+    /// Delays the messages received from each connection of this transport.
+    pub fn set_latency(&mut self, latency: Latency) {
+        self.latency = Some(latency);
+    }
+
+    /// Drops the messages received while the conditions cut the link to the peer.
+    pub fn set_conditions(&mut self, conditions: Conditions) {
+        self.conditions = Some(conditions);
+    }
+
     pub fn run(self) -> impl Stream<Item = MPSCConnection<M>, Error = ()> {
         let self_address = self.address;
         let self_address_id = self_address.id;
+        let rate_limit = self.rate_limit;
+        let message_counter = self.message_counter;
+        let latency = self.latency;
+        let conditions = self.conditions;
+        let link_from = move |remote_id| {
+            conditions.clone().map(|conditions| Link {
+                conditions,
+                from: remote_id,
+                to: self_address_id,
+            })
+        };
         let mut connections = HashMap::new();
 
         for remote_address in &self.seeds {
@@ -114,8 +320,15 @@ where
                     ) = mpsc::unbounded::<M>();
 
                     let connection = MPSCConnection {
+                        remote_id: remote_address.id,
                         sender: remote_connection_sender,
-                        receiver: connection_receiver,
+                        receiver: ConnectionReceiver::new(
+                            connection_receiver,
+                            rate_limit.as_ref(),
+                            message_counter.as_ref(),
+                            latency,
+                            link_from(remote_address.id),
+                        ),
                     };
 
                     let ack_message = TransportMessage::Ack(self_address_id, connection_sender);
@@ -129,7 +342,17 @@ where
                         &self_address_id, &address_id
                     );
                     if let Some(receiver) = connections.remove(&address_id) {
-                        MPSCConnection { sender, receiver }
+                        MPSCConnection {
+                            remote_id: address_id,
+                            sender,
+                            receiver: ConnectionReceiver::new(
+                                receiver,
+                                rate_limit.as_ref(),
+                                message_counter.as_ref(),
+                                latency,
+                                link_from(address_id),
+                            ),
+                        }
                     } else {
                         panic!("Could not find the connection to acknowledge.")
                     }
@@ -138,8 +361,111 @@ where
     }
 }
 
+/// Two ends of the same connection between the addresses 0 and 1, for testing purposes.
+pub fn connection_pair<M>() -> (MPSCConnection<M>, MPSCConnection<M>) {
+    let (one_sender, other_receiver) = mpsc::unbounded();
+    let (other_sender, one_receiver) = mpsc::unbounded();
+
+    let one = MPSCConnection {
+        remote_id: 1,
+        sender: one_sender,
+        receiver: ConnectionReceiver::new(one_receiver, None, None, None, None),
+    };
+    let other = MPSCConnection {
+        remote_id: 0,
+        sender: other_sender,
+        receiver: ConnectionReceiver::new(other_receiver, None, None, None, None),
+    };
+
+    (one, other)
+}
+
 pub fn send_or_panic<M>(sender: &UnboundedSender<M>, message: M) {
     if let Err(_err) = sender.unbounded_send(message) {
         panic!("{}", _err)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+    use network::rate_limit::ExceededPolicy;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tokio;
+
+    fn flooded_receiver(
+        number_of_messages: u32,
+        rate_limit: &RateLimit,
+    ) -> (UnboundedSender<u32>, ConnectionReceiver<u32>) {
+        let (sender, receiver) = mpsc::unbounded();
+        for i in 0..number_of_messages {
+            send_or_panic(&sender, i);
+        }
+
+        (sender, ConnectionReceiver::new(receiver, Some(rate_limit), None, None, None))
+    }
+
+    #[test]
+    fn drops_messages_exceeding_the_rate_limit() {
+        let rate_limit = RateLimit::new(3, Duration::from_secs(3600), ExceededPolicy::Drop);
+        let (sender, receiver) = flooded_receiver(10, &rate_limit);
+        drop(sender);
+
+        let received = receiver.collect().wait().unwrap();
+        assert_eq!(vec![0, 1, 2], received);
+        assert_eq!(7, rate_limit.stats().dropped());
+    }
+
+    #[test]
+    fn disconnects_peers_exceeding_the_rate_limit() {
+        let rate_limit = RateLimit::new(3, Duration::from_secs(3600), ExceededPolicy::Disconnect);
+        let (sender, receiver) = flooded_receiver(10, &rate_limit);
+
+        let received = receiver.collect().wait().unwrap();
+        assert_eq!(vec![0, 1, 2], received);
+        assert_eq!(1, rate_limit.stats().disconnections());
+        assert!(sender.unbounded_send(10).is_err());
+    }
+
+    #[test]
+    fn delays_messages_exceeding_the_rate_limit() {
+        let refill_interval = Duration::from_millis(20);
+        let rate_limit = RateLimit::new(2, refill_interval, ExceededPolicy::Delay);
+        let (sender, receiver) = flooded_receiver(5, &rate_limit);
+        drop(sender);
+
+        let result = Arc::new(Mutex::new(None));
+        let result_clone = result.clone();
+        let start = Instant::now();
+        tokio::run(receiver.collect().map(move |received| {
+            *result_clone.lock().unwrap() = Some((received, start.elapsed()));
+        }));
+
+        let (received, elapsed) = result.lock().unwrap().take().unwrap();
+        assert_eq!(vec![0, 1, 2, 3, 4], received);
+        assert!(elapsed >= refill_interval * 3);
+        assert_eq!(3, rate_limit.stats().delayed());
+    }
+
+    #[test]
+    fn drops_the_messages_beyond_the_delay_queue() {
+        let refill_interval = Duration::from_millis(20);
+        let rate_limit =
+            RateLimit::new(2, refill_interval, ExceededPolicy::Delay).with_max_delayed(2);
+        let (sender, receiver) = flooded_receiver(10, &rate_limit);
+        drop(sender);
+
+        let result = Arc::new(Mutex::new(None));
+        let result_clone = result.clone();
+        tokio::run(receiver.collect().map(move |received| {
+            *result_clone.lock().unwrap() = Some(received);
+        }));
+
+        let received = result.lock().unwrap().take().unwrap();
+        assert_eq!(vec![0, 1, 2, 3], received);
+        assert_eq!(2, rate_limit.stats().delayed());
+        assert_eq!(6, rate_limit.stats().dropped());
+    }
+}
@@ -0,0 +1,194 @@
+//! Helpers for the integration tests of simulated networks.
+//!
+//! A `Harness` runs a network in the background while the test thread asserts on the state
+//! of its nodes. Nodes expose their state through a `Probe`. When an assertion fails, a
+//! snapshot of the state of every node is printed before panicking.
+
+use network::topology::Topology;
+use network::{MessageCounter, Network, Node};
+use std::fmt::{Debug, Write};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const POLLING_INTERVAL_IN_MILLIS: u64 = 10;
+
+/// A shared view on the state of a node. The node updates it, the harness inspects it.
+pub struct Probe<S> {
+    state: Arc<Mutex<S>>,
+}
+
+impl<S> Probe<S> {
+    pub fn new(state: S) -> Probe<S> {
+        Probe::shared(Arc::new(Mutex::new(state)))
+    }
+
+    /// Creates a probe on a state that is already shared by the node.
+    pub fn shared(state: Arc<Mutex<S>>) -> Probe<S> {
+        Probe { state }
+    }
+
+    pub fn update<F>(&self, update: F)
+    where
+        F: FnOnce(&mut S),
+    {
+        update(&mut self.state.lock().unwrap());
+    }
+}
+
+impl<S> Probe<S>
+where
+    S: Clone,
+{
+    pub fn state(&self) -> S {
+        self.state.lock().unwrap().clone()
+    }
+}
+
+impl<S> Clone for Probe<S> {
+    fn clone(&self) -> Probe<S> {
+        Probe {
+            state: self.state.clone(),
+        }
+    }
+}
+
+/// Runs a network in a background thread and asserts on the state of its nodes.
+pub struct Harness<S> {
+    probes: Arc<Mutex<Vec<Probe<S>>>>,
+    message_counter: MessageCounter,
+    topology: Topology,
+    run: Option<JoinHandle<()>>,
+}
+
+impl<S> Harness<S>
+where
+    S: Clone + Debug + Send + 'static,
+{
+    /// Starts running the network for the given duration.
+    /// The node factory returns every node along with the probe on its state. Nodes are
+    /// created in the order of their address id, which is also the order of the states given
+    /// to the predicates.
+    pub fn start<M, N, F>(mut network: Network<M>, node_factory: F, duration: Duration) -> Harness<S>
+    where
+        M: Clone + Send + 'static,
+        N: Node<M> + Sync + Send + 'static,
+        F: Fn() -> (N, Probe<S>) + Send + 'static,
+    {
+        let probes = Arc::new(Mutex::new(vec![]));
+        let message_counter = network.count_delivered_messages();
+        let topology = network.topology();
+
+        let factory_probes = probes.clone();
+        let run = thread::spawn(move || {
+            network.run(
+                move || {
+                    let (node, probe) = node_factory();
+                    factory_probes.lock().unwrap().push(probe);
+                    node
+                },
+                duration,
+            )
+        });
+
+        Harness {
+            probes,
+            message_counter,
+            topology,
+            run: Some(run),
+        }
+    }
+
+    pub fn topology(&self) -> &Topology {
+        &self.topology
+    }
+
+    /// Waits until the predicate holds for the states of the nodes. Panics if it still does
+    /// not after the timeout.
+    pub fn assert_eventually<P>(&self, predicate: P, timeout: Duration)
+    where
+        P: Fn(&[S]) -> bool,
+    {
+        if !self.wait_for(|states| predicate(states), timeout) {
+            self.fail(&format!(
+                "The predicate did not hold within {:?}.",
+                timeout
+            ));
+        }
+    }
+
+    /// Waits until at least `expected` messages were delivered to the nodes. Panics if it
+    /// is still not the case after the timeout.
+    pub fn assert_messages_delivered(&self, expected: usize, timeout: Duration) {
+        if !self.wait_for(|_states| self.message_counter.count() >= expected, timeout) {
+            self.fail(&format!(
+                "Expected {} delivered messages within {:?}, got {}.",
+                expected,
+                timeout,
+                self.message_counter.count()
+            ));
+        }
+    }
+
+    /// Checks that every node of the network can be reached from any other node.
+    pub fn assert_connected(&self) {
+        if !self.topology.is_connected() {
+            self.fail(&format!(
+                "The network is partitioned. Edges: {:?}",
+                self.topology.edges()
+            ));
+        }
+    }
+
+    /// Waits for the end of the run.
+    pub fn join(mut self) {
+        if let Some(run) = self.run.take() {
+            run.join().expect("The network run panicked.");
+        }
+    }
+
+    fn states(&self) -> Vec<S> {
+        self.probes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|probe| probe.state())
+            .collect()
+    }
+
+    fn wait_for<P>(&self, predicate: P, timeout: Duration) -> bool
+    where
+        P: Fn(&[S]) -> bool,
+    {
+        let start = Instant::now();
+
+        loop {
+            // Wait for every node to be started so the predicates always see the whole network.
+            let states = self.states();
+            if states.len() == self.topology.nodes().len() && predicate(&states) {
+                return true;
+            }
+
+            if start.elapsed() > timeout {
+                return false;
+            }
+
+            thread::sleep(Duration::from_millis(POLLING_INTERVAL_IN_MILLIS));
+        }
+    }
+
+    fn fail(&self, message: &str) -> ! {
+        let mut snapshot = String::new();
+        for (node_id, state) in self.states().iter().enumerate() {
+            writeln!(snapshot, "  #{:05}: {:?}", node_id, state).unwrap();
+        }
+
+        println!(
+            "{}\nDelivered messages: {}\nNode states:\n{}",
+            message,
+            self.message_counter.count(),
+            snapshot
+        );
+        panic!("{}", message)
+    }
+}
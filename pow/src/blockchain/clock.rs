@@ -0,0 +1,87 @@
+use blockchain::Block;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const CLOCK_ERROR_TIMESTAMP_TOO_FAR_IN_THE_FUTURE: &str = "Timestamp too far in the future";
+
+/// The current time in milliseconds since the Unix epoch, as used in block timestamps.
+pub fn unix_time_millis() -> u64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("The system clock is set before the Unix epoch.");
+    since_epoch.as_secs() * 1000 + u64::from(since_epoch.subsec_millis())
+}
+
+/// The clock of a node. Real nodes do not agree on the current time, so every simulated node
+/// can be given a skew from the real time. The clock is used to timestamp the mined blocks
+/// and to reject the blocks coming from too far in the future.
+#[derive(Clone, Copy, Debug)]
+pub struct Clock {
+    skew_millis: i64,
+    max_future_drift: Duration,
+}
+
+impl Clock {
+    /// # Arguments
+    ///
+    /// `skew_millis`: the difference between the clock of the node and the real time.
+    /// `max_future_drift`: how far ahead of the node's time a block timestamp can be.
+    /// Bitcoin allows two hours.
+    pub fn new(skew_millis: i64, max_future_drift: Duration) -> Clock {
+        Clock {
+            skew_millis,
+            max_future_drift,
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        (unix_time_millis() as i64 + self.skew_millis) as u64
+    }
+
+    /// Checks that the block was not mined too far in the future, according to this clock.
+    /// Unlike the other validation rules, the result depends on the time and on the node.
+    pub fn check_future_drift(&self, block: &Block) -> Result<(), &'static str> {
+        let max_future_drift = self.max_future_drift.as_secs() * 1000
+            + u64::from(self.max_future_drift.subsec_millis());
+
+        if block.timestamp() <= self.now() + max_future_drift {
+            Ok(())
+        } else {
+            Err(CLOCK_ERROR_TIMESTAMP_TOO_FAR_IN_THE_FUTURE)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::pow::Nonce;
+    use blockchain::{Chain, Difficulty};
+    use std::sync::Arc;
+
+    fn block_at(timestamp: u64) -> Block {
+        let genesis = Chain::init_new(Difficulty::min_difficulty(), None);
+        Block::new(
+            1,
+            Nonce::new(),
+            &Arc::new(Difficulty::min_difficulty()),
+            genesis.head().hash().clone(),
+            1,
+            timestamp,
+        )
+    }
+
+    #[test]
+    fn rejects_blocks_from_the_future() {
+        let clock = Clock::new(0, Duration::from_secs(10));
+        let now = unix_time_millis();
+
+        assert!(clock.check_future_drift(&block_at(now)).is_ok());
+        assert!(clock.check_future_drift(&block_at(now + 5_000)).is_ok());
+        assert!(clock.check_future_drift(&block_at(now + 60_000)).is_err());
+
+        // A clock running ahead accepts the blocks of the future.
+        let skewed_clock = Clock::new(60_000, Duration::from_secs(10));
+        assert!(skewed_clock.now() >= now + 60_000);
+        assert!(skewed_clock.check_future_drift(&block_at(now + 60_000)).is_ok());
+    }
+}
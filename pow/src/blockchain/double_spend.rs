@@ -0,0 +1,359 @@
+//! The double-spend attack. The attacker pays a merchant in the block following the fork height,
+//! then secretly mines an alternative chain from the fork height. Once the merchant saw
+//! enough confirmations and the secret chain is stronger than the public one, the attacker
+//! releases it: the honest nodes switch to it and the payment disappears from the main chain.
+
+use blockchain::node::{peer_events, validate_received_chain, NodeEvent, Peer, ValidBlocks};
+use blockchain::recorder::NodeRecorder;
+use blockchain::{mining_stream, Chain, Clock};
+use futures::{future, Future, Stream};
+use netsim::network::multiplex::{MultiplexedConnection, MultiplexedMessage};
+use netsim::network::Node;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How the attack ended.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttackOutcome {
+    /// The secret chain was released and replaced this number of public blocks.
+    Succeeded { reorg_depth: u32 },
+    /// The secret chain fell too far behind.
+    Abandoned,
+}
+
+/// What the attacker does with the chain it just mined or received.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    Nothing,
+    /// The attacker behaves honestly and relays the chain.
+    Relay(Arc<Chain>),
+    /// The secret chain is released.
+    Release(Arc<Chain>),
+}
+
+/// The state machine of the attack, independent from the network.
+pub struct DoubleSpendAttack {
+    fork_height: u32,
+    confirmations: u32,
+    max_deficit: Option<u32>,
+    public_chain: Arc<Chain>,
+    /// The chain mined in secret, while the attack is in progress.
+    secret_chain: Option<Arc<Chain>>,
+    outcome: Option<AttackOutcome>,
+}
+
+impl DoubleSpendAttack {
+    /// # Arguments
+    ///
+    /// `fork_height`: the height of the last block shared by the public and the secret chains.
+    /// `confirmations`: the number of public blocks the merchant waits for after the fork height.
+    /// `max_deficit`: how far behind the public chain the secret chain can fall before the
+    /// attack is abandoned. The attacker never gives up if there is none.
+    pub fn new(
+        genesis_chain: Arc<Chain>,
+        fork_height: u32,
+        confirmations: u32,
+        max_deficit: Option<u32>,
+    ) -> DoubleSpendAttack {
+        let mut attack = DoubleSpendAttack {
+            fork_height,
+            confirmations,
+            max_deficit,
+            public_chain: genesis_chain,
+            secret_chain: None,
+            outcome: None,
+        };
+        attack.start_if_possible();
+        attack
+    }
+
+    /// The chain the attacker mines on.
+    pub fn mining_chain(&self) -> &Arc<Chain> {
+        self.secret_chain.as_ref().unwrap_or(&self.public_chain)
+    }
+
+    pub fn public_chain(&self) -> &Arc<Chain> {
+        &self.public_chain
+    }
+
+    pub fn outcome(&self) -> Option<&AttackOutcome> {
+        self.outcome.as_ref()
+    }
+
+    pub fn mined(&mut self, chain: Arc<Chain>) -> Action {
+        if self.secret_chain.is_some() {
+            self.secret_chain = Some(chain);
+            self.release_if_stronger()
+        } else {
+            self.public_chain = chain.clone();
+            self.start_if_possible();
+            Action::Relay(chain)
+        }
+    }
+
+    pub fn received(&mut self, chain: Arc<Chain>) -> Action {
+        if !chain.stronger_than(&self.public_chain) {
+            return Action::Nothing;
+        }
+
+        self.public_chain = chain.clone();
+        if self.secret_chain.is_none() {
+            self.start_if_possible();
+            return Action::Relay(chain);
+        }
+
+        let deficit = i64::from(self.public_chain.height()) - i64::from(self.mining_chain().height());
+        match self.max_deficit {
+            Some(max_deficit) if deficit > i64::from(max_deficit) => {
+                self.secret_chain = None;
+                self.outcome = Some(AttackOutcome::Abandoned);
+                Action::Relay(chain)
+            }
+            _ => self.release_if_stronger(),
+        }
+    }
+
+    fn start_if_possible(&mut self) {
+        if self.outcome.is_none() && self.secret_chain.is_none() {
+            self.secret_chain = Chain::ancestor(&self.public_chain, self.fork_height);
+        }
+    }
+
+    fn release_if_stronger(&mut self) -> Action {
+        let confirmed = self.public_chain.height() >= self.fork_height + self.confirmations;
+        let secret_chain = self.secret_chain
+            .clone()
+            .expect("The attack is in progress.");
+
+        if confirmed && secret_chain.stronger_than(&self.public_chain) {
+            self.outcome = Some(AttackOutcome::Succeeded {
+                reorg_depth: self.public_chain.height() - self.fork_height,
+            });
+            self.secret_chain = None;
+            self.public_chain = secret_chain.clone();
+            Action::Release(secret_chain)
+        } else {
+            Action::Nothing
+        }
+    }
+}
+
+/// The probability for an attacker with the hashrate share `q` to ever catch up with the
+/// honest chain once the merchant saw `confirmations` blocks, as computed in the section 11
+/// of the Bitcoin paper. The attacker never gives up.
+pub fn success_probability(q: f64, confirmations: u32) -> f64 {
+    let p = 1f64 - q;
+    if q >= p {
+        return 1f64;
+    }
+
+    let lambda = f64::from(confirmations) * q / p;
+    let mut poisson = (-lambda).exp();
+    let mut probability = 1f64;
+    for k in 0..=confirmations {
+        if k > 0 {
+            poisson *= lambda / f64::from(k);
+        }
+        probability -= poisson * (1f64 - (q / p).powi((confirmations - k) as i32));
+    }
+    probability
+}
+
+/// A node running a double-spend attack. It behaves honestly before and after the attack.
+pub struct DoubleSpendNode {
+    node_id: u32,
+    mining_attempt_delay: Duration,
+    hashrate: u32,
+    attack: DoubleSpendAttack,
+    clock: Clock,
+    recorder: NodeRecorder,
+    valid_blocks: ValidBlocks,
+}
+
+impl DoubleSpendNode {
+    pub fn new(
+        node_id: u32,
+        mining_attempt_delay: Duration,
+        hashrate: u32,
+        attack: DoubleSpendAttack,
+        clock: Clock,
+        recorder: NodeRecorder,
+    ) -> DoubleSpendNode {
+        DoubleSpendNode {
+            node_id,
+            mining_attempt_delay,
+            hashrate,
+            valid_blocks: ValidBlocks::new(attack.public_chain()),
+            attack,
+            clock,
+            recorder,
+        }
+    }
+
+    fn act(&self, action: Action, peers: &mut Vec<Peer>) {
+        let chain = match action {
+            Action::Nothing => return,
+            Action::Relay(chain) => chain,
+            Action::Release(chain) => {
+                info!(
+                    "[#{:05}] Released the secret chain: {:?}, height {}, outcome {:?}",
+                    self.node_id,
+                    chain.head().hash(),
+                    chain.height(),
+                    self.attack.outcome()
+                );
+                chain
+            }
+        };
+
+        for peer in peers.iter_mut() {
+            if chain.stronger_than(peer.last_known_chain()) {
+                peer.send(&chain);
+            }
+        }
+        peers.retain(|peer| !peer.is_closed());
+    }
+}
+
+impl Node<MultiplexedMessage> for DoubleSpendNode {
+    fn run<S>(mut self, connection_stream: S) -> Box<dyn Future<Item = (), Error = ()> + Send>
+    where
+        S: Stream<Item = MultiplexedConnection, Error = ()> + Send + 'static,
+    {
+        let (mining_stream, updater) = mining_stream(
+            self.node_id,
+            self.attack.mining_chain().clone(),
+            self.mining_attempt_delay,
+            self.hashrate,
+            self.clock,
+            self.recorder.mining_halt(),
+        );
+
+        let peer_stream = peer_events(
+            self.node_id,
+            self.attack.public_chain().clone(),
+            self.recorder.clone(),
+            None,
+            connection_stream,
+        );
+
+        let mut peers = vec![];
+        let mut mining_chain = self.attack.mining_chain().clone();
+        let routing_future = peer_stream
+            .select(mining_stream.map(NodeEvent::MinedChain))
+            .for_each(move |node_event| {
+                let action = match node_event {
+                    NodeEvent::Peer(mut peer) => {
+                        peer.send(self.attack.public_chain());
+                        if !peer.is_closed() {
+                            peers.push(peer);
+                        }
+                        Action::Nothing
+                    }
+                    NodeEvent::MinedChain(chain) => {
+                        self.recorder.mined(&chain);
+                        self.attack.mined(chain)
+                    }
+                    NodeEvent::ChainRemoteUpdate(peer_id, chain) => {
+                        match validate_received_chain(&chain, &self.clock, &mut self.valid_blocks) {
+                            Ok(()) => {
+                                self.recorder.received(&chain, peer_id);
+                                self.attack.received(chain)
+                            }
+                            Err(err) => {
+                                error!("Invalid chain: {}", err);
+                                Action::Nothing
+                            }
+                        }
+                    }
+                    // The attacker does not take part in pools and only relays whole chains.
+                    NodeEvent::Work(..)
+                    | NodeEvent::Submitted(..)
+                    | NodeEvent::Relay(..)
+                    | NodeEvent::Validated(..)
+                    | NodeEvent::Heartbeat(..)
+                    | NodeEvent::PeerClosed(..)
+                    | NodeEvent::RequestTimeouts => Action::Nothing,
+                };
+
+                self.act(action, &mut peers);
+
+                if *self.attack.mining_chain() != mining_chain {
+                    mining_chain = self.attack.mining_chain().clone();
+                    self.recorder.adopted(&mining_chain);
+                    updater.mine_new_chain(mining_chain.clone());
+                }
+
+                future::ok(())
+            });
+
+        Box::new(routing_future)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::testing::expand;
+    use blockchain::Difficulty;
+
+    const ATTACKER: u32 = 0;
+    const HONEST: u32 = 1;
+
+    fn genesis() -> Arc<Chain> {
+        Arc::new(Chain::init_new(Difficulty::min_difficulty(), None))
+    }
+
+    #[test]
+    fn releases_the_secret_chain_after_the_confirmations() {
+        let genesis = genesis();
+        let mut attack = DoubleSpendAttack::new(genesis.clone(), 1, 2, None);
+
+        // Honest until the fork height.
+        let public = expand(&genesis, HONEST);
+        assert_eq!(Action::Relay(public.clone()), attack.received(public.clone()));
+        assert_eq!(&public, attack.mining_chain());
+
+        // The payment is in the next public block, the attacker mines in secret.
+        let mut secret = public.clone();
+        for _i in 0..3 {
+            secret = expand(&secret, ATTACKER);
+            assert_eq!(Action::Nothing, attack.mined(secret.clone()));
+        }
+
+        // The secret chain is stronger, but the payment is not confirmed yet.
+        let public = expand(&public, HONEST);
+        assert_eq!(Action::Nothing, attack.received(public.clone()));
+        let public = expand(&public, HONEST);
+        assert_eq!(Action::Release(secret.clone()), attack.received(public.clone()));
+
+        assert_eq!(
+            Some(&AttackOutcome::Succeeded { reorg_depth: 2 }),
+            attack.outcome()
+        );
+        assert_eq!(&secret, attack.mining_chain());
+    }
+
+    #[test]
+    fn reproduces_the_success_probabilities_of_the_bitcoin_paper() {
+        assert!((success_probability(0.1, 5) - 0.0009137).abs() < 1e-7);
+        assert!((success_probability(0.3, 5) - 0.1773523).abs() < 1e-7);
+        assert!((success_probability(0.1, 0) - 1f64).abs() < 1e-9);
+        assert_eq!(1f64, success_probability(0.6, 10));
+    }
+
+    #[test]
+    fn abandons_when_too_far_behind() {
+        let genesis = genesis();
+        let mut attack = DoubleSpendAttack::new(genesis.clone(), 0, 1, Some(1));
+        assert_eq!(&genesis, attack.mining_chain());
+
+        let public = expand(&genesis, HONEST);
+        assert_eq!(Action::Nothing, attack.received(public.clone()));
+
+        let public = expand(&public, HONEST);
+        assert_eq!(Action::Relay(public.clone()), attack.received(public.clone()));
+        assert_eq!(Some(&AttackOutcome::Abandoned), attack.outcome());
+        assert_eq!(&public, attack.mining_chain());
+    }
+}
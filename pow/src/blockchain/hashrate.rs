@@ -0,0 +1,84 @@
+use rand;
+
+/// A node cannot make more mining attempts per round than this, so that a single draw from a
+/// heavy-tailed distribution cannot stall the simulation.
+pub const MAX_HASHRATE: u32 = 10000;
+
+/// How the mining power is spread among the nodes. The hashrate of a node is the number of
+/// mining attempts it makes per round, a round lasting the mining attempt delay.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HashrateDistribution {
+    /// Every node makes the same number of attempts per round.
+    Uniform(u32),
+    /// The hashrate of every node, by node id. The nodes that are not listed make one attempt
+    /// per round.
+    Explicit(Vec<u32>),
+    /// Hashrates drawn from a Pareto distribution with this shape and a minimum of one attempt
+    /// per round. The lower the shape, the more the mining power is concentrated on a few
+    /// nodes, like it is on a few pools in Bitcoin. A shape of 1.16 gives the 80/20 rule.
+    Pareto(f64),
+}
+
+impl HashrateDistribution {
+    /// The hashrate of every node of a network of the given size, by node id.
+    pub fn hashrates(&self, number_of_nodes: u32) -> Vec<u32> {
+        (0..number_of_nodes as usize)
+            .map(|node_id| match *self {
+                HashrateDistribution::Uniform(hashrate) => hashrate,
+                HashrateDistribution::Explicit(ref hashrates) => {
+                    hashrates.get(node_id).cloned().unwrap_or(1)
+                }
+                HashrateDistribution::Pareto(shape) => pareto(shape),
+            })
+            .map(|hashrate| hashrate.clamp(1, MAX_HASHRATE))
+            .collect()
+    }
+}
+
+/// Draws a value from a Pareto distribution with the given shape and a minimum of 1.
+fn pareto(shape: f64) -> u32 {
+    // Inverse transform sampling, the uniform value must not be 0.
+    let uniform = 1f64 - rand::random::<f64>();
+    let value = uniform.powf(-1f64 / shape);
+
+    if value >= f64::from(MAX_HASHRATE) {
+        MAX_HASHRATE
+    } else {
+        value as u32
+    }
+}
+
+/// The share of the blocks every node is expected to mine, by node id.
+pub fn expected_shares(hashrates: &[u32]) -> Vec<f64> {
+    let total: f64 = hashrates.iter().map(|hashrate| f64::from(*hashrate)).sum();
+    hashrates
+        .iter()
+        .map(|hashrate| f64::from(*hashrate) / total)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_distribute_the_hashrate() {
+        assert_eq!(vec![3, 3, 3], HashrateDistribution::Uniform(3).hashrates(3));
+        assert_eq!(
+            vec![5, 1, 2, 1],
+            HashrateDistribution::Explicit(vec![5, 0, 2]).hashrates(4)
+        );
+
+        let hashrates = HashrateDistribution::Pareto(1.16).hashrates(1000);
+        assert_eq!(1000, hashrates.len());
+        assert!(hashrates
+            .iter()
+            .all(|hashrate| *hashrate >= 1 && *hashrate <= MAX_HASHRATE));
+        // Heavy tailed: many nodes have the minimum, a few have much more.
+        assert!(hashrates.iter().filter(|hashrate| **hashrate == 1).count() > 400);
+        assert!(*hashrates.iter().max().unwrap() > 10);
+
+        let shares = expected_shares(&[1, 3]);
+        assert_eq!(vec![0.25, 0.75], shares);
+    }
+}
@@ -0,0 +1,128 @@
+use blockchain::{Block, Chain, Hash, BLOCK_HEADER_SIZE};
+use netsim::network::heartbeat::{self, HeartbeatMessage};
+use netsim::network::multiplex::ProtocolId;
+use std::any::Any;
+use std::sync::Arc;
+
+/// The size of the envelope of every message, in bytes: the network magic, the command, the
+/// length and the checksum of a Bitcoin message.
+const MESSAGE_HEADER_SIZE: u64 = 24;
+/// The size of a block announcement or request: a type and a hash.
+const INVENTORY_ENTRY_SIZE: u64 = 36;
+const HASH_SIZE: u64 = 32;
+/// The size of the nonce of a ping or a pong.
+const NONCE_SIZE: u64 = 8;
+
+/// The sub-channel carrying the strongest chain known by the sender, relayed whole between
+/// full nodes.
+pub const CHAIN_PROTOCOL: ProtocolId = "chain";
+/// The sub-channel between a pool operator and its members.
+pub const POOL_PROTOCOL: ProtocolId = "pool";
+/// The sub-channel of the headers-first relay.
+pub const RELAY_PROTOCOL: ProtocolId = "headers_first";
+
+/// The messages exchanged by a pool operator and its members.
+#[derive(Clone, Debug)]
+pub enum PoolMessage {
+    /// The operator asks one of its members to mine on this chain.
+    Work(Arc<Chain>),
+    /// A member submits the chain it mined to its operator.
+    Submit(Arc<Chain>),
+}
+
+impl PoolMessage {
+    /// The name of the type of the message.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            PoolMessage::Work(_) => "work",
+            PoolMessage::Submit(_) => "submit",
+        }
+    }
+
+    /// The number of bytes the message would take on a real network, given the size of the
+    /// transactions of every block.
+    pub fn size(&self, block_payload_size: u64) -> u64 {
+        let body_size = match *self {
+            // Only a block template is sent to the pool members.
+            PoolMessage::Work(_) => BLOCK_HEADER_SIZE,
+            PoolMessage::Submit(_) => BLOCK_HEADER_SIZE + block_payload_size,
+        };
+
+        MESSAGE_HEADER_SIZE + body_size
+    }
+}
+
+/// The messages of the headers-first relay.
+#[derive(Clone, Debug)]
+pub enum RelayMessage {
+    /// The hashes of new blocks known by the sender.
+    Inv(Vec<Hash>),
+    /// Asks for the headers following the first of these hashes that is part of the best
+    /// chain of the peer. The hashes go from the head to the genesis block.
+    GetHeaders(Vec<Hash>),
+    /// Consecutive headers, from the lowest to the highest.
+    Headers(Vec<Block>),
+    /// Asks for the blocks with these hashes.
+    GetData(Vec<Hash>),
+    /// A requested block.
+    Block(Block),
+}
+
+impl RelayMessage {
+    /// The name of the type of the message.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            RelayMessage::Inv(_) => "inv",
+            RelayMessage::GetHeaders(_) => "getheaders",
+            RelayMessage::Headers(_) => "headers",
+            RelayMessage::GetData(_) => "getdata",
+            RelayMessage::Block(_) => "block",
+        }
+    }
+
+    /// The number of bytes the message would take on a real network, given the size of the
+    /// transactions of every block.
+    pub fn size(&self, block_payload_size: u64) -> u64 {
+        let body_size = match *self {
+            RelayMessage::Block(_) => BLOCK_HEADER_SIZE + block_payload_size,
+            RelayMessage::Inv(ref hashes) | RelayMessage::GetData(ref hashes) => {
+                hashes.len() as u64 * INVENTORY_ENTRY_SIZE
+            }
+            // The locator and the stop hash.
+            RelayMessage::GetHeaders(ref hashes) => (hashes.len() as u64 + 1) * HASH_SIZE,
+            RelayMessage::Headers(ref headers) => headers.len() as u64 * BLOCK_HEADER_SIZE,
+        };
+
+        MESSAGE_HEADER_SIZE + body_size
+    }
+}
+
+/// The type and the size of a message sent on one of the sub-channels of the nodes, given the
+/// size of the transactions of every block. `None` if the protocol is unknown.
+pub fn measure(
+    protocol: ProtocolId,
+    message: &(dyn Any + Send + Sync),
+    block_payload_size: u64,
+) -> Option<(&'static str, u64)> {
+    match protocol {
+        CHAIN_PROTOCOL => message.downcast_ref::<Arc<Chain>>().map(|chain| {
+            let block_size = BLOCK_HEADER_SIZE + block_payload_size;
+            let body_size = (u64::from(chain.height()) + 1) * block_size;
+            ("chain", MESSAGE_HEADER_SIZE + body_size)
+        }),
+        POOL_PROTOCOL => message
+            .downcast_ref::<PoolMessage>()
+            .map(|message| (message.kind(), message.size(block_payload_size))),
+        RELAY_PROTOCOL => message
+            .downcast_ref::<RelayMessage>()
+            .map(|message| (message.kind(), message.size(block_payload_size))),
+        heartbeat::PROTOCOL => message.downcast_ref::<HeartbeatMessage>().map(|message| {
+            let kind = match *message {
+                HeartbeatMessage::Ping(_) => "ping",
+                HeartbeatMessage::Pong(_) => "pong",
+            };
+            (kind, MESSAGE_HEADER_SIZE + NONCE_SIZE)
+        }),
+        _ => None,
+    }
+}
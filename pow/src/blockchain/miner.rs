@@ -1,6 +1,7 @@
-use blockchain::{pow::Nonce, Block, Chain};
+use blockchain::{pow::Nonce, Block, Chain, Clock};
 use futures::sync::mpsc::{self, UnboundedSender};
 use futures::Stream;
+use netsim::network::stop::Stop;
 use std::ops::Add;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
@@ -10,14 +11,16 @@ struct MiningState {
     chain: Arc<Chain>,
     nonce: Nonce,
     node_id: u32,
+    clock: Clock,
 }
 
 impl MiningState {
-    pub fn new(node_id: u32, chain: Arc<Chain>) -> MiningState {
+    pub fn new(node_id: u32, chain: Arc<Chain>, clock: Clock) -> MiningState {
         MiningState {
             chain,
             nonce: Nonce::new(),
             node_id,
+            clock,
         }
     }
 }
@@ -39,17 +42,28 @@ impl MiningStateUpdater {
     }
 }
 
+/// Returns a stream of the blocks mined by the node, along with a way to change the chain
+/// being mined.
+///
+/// # Arguments
+///
+/// `attempt_delay`: the delay between two mining rounds.
+/// `attempts_per_round`: the number of hashes computed in a round, the hashrate of the node.
+/// `halt`: no more block is mined once it is stopped.
 pub fn mining_stream(
     node_id: u32,
     chain: Arc<Chain>,
     attempt_delay: Duration,
+    attempts_per_round: u32,
+    clock: Clock,
+    halt: Stop,
 ) -> (
     impl Stream<Item = Arc<Chain>, Error = ()>,
     MiningStateUpdater,
 ) {
     let (updater_sender, updater_receiver) = mpsc::unbounded();
 
-    let mut state = MiningState::new(node_id, chain);
+    let mut state = MiningState::new(node_id, chain, clock);
 
     let mining_state_updater = MiningStateUpdater::new(updater_sender);
 
@@ -60,22 +74,22 @@ pub fn mining_stream(
         // Now we can mine or update the state.
         .map(move |chain_update_option|{
             if let Some(chain_update) = chain_update_option{
-                if chain_update.stronger_than(&state.chain) {
-                    state.chain = chain_update.clone();
-                    state.nonce = Nonce::new();
-                }
+                // The node already applied its fork choice rule.
+                state.chain = chain_update.clone();
+                state.nonce = Nonce::new();
 
                 None
 
+            } else if halt.is_stopped() {
+                None
             } else {
-                match mine(&mut state){
-                    MiningResult::Success(mined_new_chain) => {
-                        Some(mined_new_chain)
-                    }
-                    MiningResult::Failure => {
-                        None
+                for _attempt in 0..attempts_per_round {
+                    if let MiningResult::Success(mined_new_chain) = mine(&mut state) {
+                        return Some(mined_new_chain);
                     }
                 }
+
+                None
             }
         })
         // Filter it so only the mined blocks are returned.
@@ -104,18 +118,27 @@ fn mine(state: &mut MiningState) -> MiningResult {
     state.nonce.increment();
 
     let head_hash = state.chain.head().hash().clone();
-    let difficulty = &state.chain.head().difficulty;
+    let difficulty = state.chain.next_difficulty();
     let new_height = state.chain.height() + 1;
     let block = Block::new(
         state.node_id,
         state.nonce.clone(),
-        difficulty,
+        &difficulty,
         head_hash,
         new_height,
+        // The clock of the node may be late, but the block would be invalid otherwise.
+        state.clock.now().max(state.chain.median_time_past() + 1),
     );
 
     match Chain::expand(&state.chain, block) {
         Ok(mined_chain) => {
+            if !difficulty.eq(&state.chain.head().difficulty) {
+                info!(
+                    "[N#{}] Difficulty retargeted at height {}: {:?}",
+                    state.node_id, new_height, difficulty
+                );
+            }
+
             debug!(
                 "[N#{}] Mined a new block with height: {}",
                 state.node_id,
@@ -1,15 +1,46 @@
+mod clock;
+pub mod double_spend;
+pub mod hashrate;
+mod message;
 mod miner;
 mod node;
+mod pool;
 mod pow;
-
+pub mod recorder;
+mod relay;
+pub mod selfish;
+pub mod store;
+mod strategy;
+mod tree;
+mod validation;
+
+pub use self::clock::{unix_time_millis, Clock};
+pub use self::double_spend::DoubleSpendNode;
+pub use self::message::{
+    PoolMessage, RelayMessage, CHAIN_PROTOCOL, POOL_PROTOCOL, RELAY_PROTOCOL,
+};
 pub use self::miner::{mining_stream, MiningStateUpdater};
-pub use self::node::PowNode;
-pub use self::pow::Difficulty;
-use blockchain::pow::{Hash, Nonce};
+pub use self::node::{PowNode, Reorg};
+pub use self::relay::{HeadersFirstRelay, RelayProtocol};
+pub use self::pool::{Pool, PoolMemberNode};
+pub use self::pow::{Difficulty, Hash};
+pub use self::selfish::SelfishPowNode;
+pub use self::strategy::StrategyNode;
+pub use self::tree::{BlockTree, Fork};
+pub use self::validation::{validation_stream, ValidationDelay, ValidationQueue};
+use blockchain::pow::Nonce;
 use ring::digest::SHA256_OUTPUT_LEN;
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug, Formatter};
 use std::sync::Arc;
+use std::time::Duration;
 use std::u32::MAX as U32_MAX;
 
+/// The size of the fields of a block, in bytes. The rest of a block, its transactions, is
+/// only simulated by a payload size.
+pub const BLOCK_HEADER_SIZE: u64 = 120;
+
+#[derive(Clone)]
 pub struct Block {
     /// in order to protect these fields to being tampered with, all of them
     /// are used as a the hash input.
@@ -34,6 +65,9 @@ pub struct Block {
     /// different blocks. It has other benefits, like helping identifying a block
     /// or preventing us from having to count all the blocks one by one.
     height: u32,
+    /// The time at which the block was mined, in milliseconds since the Unix epoch. The
+    /// timestamps are used to adjust the difficulty to the hashrate of the network.
+    timestamp: u64,
 }
 
 const HEAD_ERROR_INVALID_HASH: &str = "Invalid hash";
@@ -46,12 +80,14 @@ impl Block {
         difficulty: &Arc<Difficulty>,
         previous_block_hash: Hash,
         height: u32,
+        timestamp: u64,
     ) -> Block {
         let hash = Hash::new(
             node_id,
             &nonce,
             difficulty,
             height,
+            timestamp,
             previous_block_hash.bytes(),
         );
         Block {
@@ -61,11 +97,12 @@ impl Block {
             difficulty: difficulty.clone(),
             height,
             previous_block_hash,
+            timestamp,
         }
     }
 
     /// The genesis block is the first block of the chain. It is the same for all nodes.
-    pub fn genesis_block(difficulty: Arc<Difficulty>) -> Block {
+    pub fn genesis_block(difficulty: Arc<Difficulty>, timestamp: u64) -> Block {
         let nonce = Nonce::new();
         let genesis_node_id = U32_MAX;
         let height = 0;
@@ -74,6 +111,7 @@ impl Block {
             &nonce,
             &difficulty,
             height,
+            timestamp,
             &[0u8; SHA256_OUTPUT_LEN],
         );
         Block {
@@ -82,6 +120,7 @@ impl Block {
             difficulty,
             previous_block_hash: hash.clone(),
             height,
+            timestamp,
             hash,
         }
     }
@@ -94,6 +133,7 @@ impl Block {
                 &self.nonce,
                 &self.difficulty,
                 self.height,
+                self.timestamp,
                 &self.previous_block_hash.bytes(),
             );
 
@@ -110,23 +150,166 @@ impl Block {
     pub fn hash(&self) -> &Hash {
         &self.hash
     }
+
+    pub fn difficulty(&self) -> &Arc<Difficulty> {
+        &self.difficulty
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// The id of the node that mined the block.
+    pub fn node_id(&self) -> u32 {
+        self.node_id
+    }
+
+    pub fn previous_block_hash(&self) -> &Hash {
+        &self.previous_block_hash
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+}
+
+impl Debug for Block {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Block {{ height: {}, hash: {:?} }}", self.height, self.hash)
+    }
+}
+
+/// The rules adjusting the difficulty to the hashrate of the network, like in Bitcoin.
+/// Every `interval` blocks, the threshold is multiplied by the ratio between the time it took
+/// to mine the previous window and the time it should have taken. The ratio is clamped
+/// to `[1 / max_adjustment_factor, max_adjustment_factor]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Retarget {
+    interval: u32,
+    target_block_interval: Duration,
+    max_adjustment_factor: u32,
+}
+
+impl Retarget {
+    pub fn new(interval: u32, target_block_interval: Duration, max_adjustment_factor: u32) -> Retarget {
+        assert!(interval > 0, "The retarget interval must be at least one block.");
+        assert!(
+            max_adjustment_factor > 0,
+            "The maximum adjustment factor must be at least 1."
+        );
+
+        Retarget {
+            interval,
+            target_block_interval,
+            max_adjustment_factor,
+        }
+    }
+
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    pub fn target_block_interval(&self) -> Duration {
+        self.target_block_interval
+    }
+
+    /// The difficulty of the block following a complete window, given the timestamps of the
+    /// first and last blocks of the window.
+    fn next_difficulty(&self, difficulty: &Difficulty, window_start: u64, window_end: u64) -> Difficulty {
+        let target_block_interval = self.target_block_interval.as_secs() * 1000
+            + u64::from(self.target_block_interval.subsec_millis());
+        let expected_timespan = (target_block_interval * u64::from(self.interval)).max(1);
+        let max_adjustment_factor = u64::from(self.max_adjustment_factor);
+
+        // Timestamps are not guaranteed to increase, a negative timespan is clamped too.
+        let actual_timespan = window_end
+            .saturating_sub(window_start)
+            .max(expected_timespan / max_adjustment_factor)
+            .min(expected_timespan * max_adjustment_factor);
+
+        let mut next_difficulty = difficulty.clone();
+        next_difficulty.scale(actual_timespan, expected_timespan);
+        next_difficulty
+    }
+}
+
+/// How a node chooses between two different chains with the same work.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ForkChoice {
+    /// Keep the chain that was received first, like Bitcoin Core.
+    FirstSeen,
+    /// Flip a coin on every tie.
+    Random,
+    /// Prefer the chain whose head has the lowest hash. All nodes make the same choice.
+    LowestHash,
+    /// Switch to the chain received last with the given probability. In a selfish mining
+    /// race, this is the share of the honest nodes mining on the attacker's block, the gamma
+    /// of Eyal and Sirer.
+    #[serde(skip_deserializing)]
+    Switch(f64),
+}
+
+impl ForkChoice {
+    /// Whether the candidate chain should replace the current one.
+    pub fn prefers(&self, candidate: &Chain, current: &Chain) -> bool {
+        if candidate.stronger_than(current) {
+            return true;
+        }
+
+        if current.stronger_than(candidate) || candidate.head.hash == current.head.hash {
+            return false;
+        }
+
+        match *self {
+            ForkChoice::FirstSeen => false,
+            ForkChoice::Random => ::rand::random(),
+            ForkChoice::LowestHash => candidate.head.hash < current.head.hash,
+            ForkChoice::Switch(probability) => ::rand::random::<f64>() < probability,
+        }
+    }
+
+    /// Whether a node relays the chains with the same work as its own, even those it does not
+    /// adopt. This is required for a random choice to be made by every node.
+    pub fn relays_ties(&self) -> bool {
+        match *self {
+            ForkChoice::FirstSeen | ForkChoice::LowestHash => false,
+            ForkChoice::Random | ForkChoice::Switch(_) => true,
+        }
+    }
 }
 
 pub struct Chain {
     head: Block,
     tail: Option<Arc<Chain>>,
+    /// Shared by all the chains expanded from the same genesis chain.
+    retarget: Option<Arc<Retarget>>,
+    /// The expected number of hashes computed to mine all the blocks of the chain.
+    work: f64,
 }
 
 const CHAIN_ERROR_HASH_MISMATCH: &str = "Hash mismatch";
 const CHAIN_ERROR_HEIGHT_MISMATCH: &str = "Height mismatch";
 const CHAIN_ERROR_INVALID_GENESIS: &str = "Invalid genesis";
 const CHAIN_ERROR_INVALID_DIFFICULTY: &str = "Invalid difficulty";
+const CHAIN_ERROR_TIMESTAMP_TOO_EARLY: &str = "Timestamp not after the median time past";
+
+/// The number of blocks whose timestamps are used to compute the median time past, like in Bitcoin.
+const MEDIAN_TIME_PAST_BLOCKS: usize = 11;
 
 impl Chain {
-    pub fn init_new(difficulty: Difficulty) -> Chain {
+    /// Creates a genesis chain. The difficulty stays constant unless retarget rules are given.
+    pub fn init_new(difficulty: Difficulty, retarget: Option<Retarget>) -> Chain {
+        let genesis_block = Block::genesis_block(Arc::new(difficulty), unix_time_millis());
+        Chain::from_genesis_block(genesis_block, retarget)
+    }
+
+    fn from_genesis_block(genesis_block: Block, retarget: Option<Retarget>) -> Chain {
         Chain {
-            head: Block::genesis_block(Arc::new(difficulty)),
+            work: genesis_block.difficulty.expected_hashes(),
+            head: genesis_block,
             tail: None,
+            retarget: retarget.map(Arc::new),
         }
     }
 
@@ -143,8 +326,10 @@ impl Chain {
     /// Will succeed even if the block is invalid or the hashes do not match.
     fn unvalidated_expand(chain: &Arc<Chain>, block: Block) -> Chain {
         Chain {
+            work: chain.work + block.difficulty.expected_hashes(),
             head: block,
             tail: Some(chain.clone()),
+            retarget: chain.retarget.clone(),
         }
     }
 
@@ -159,11 +344,131 @@ impl Chain {
         self.head.height
     }
 
+    /// The difficulty adjustment rules of the chain, the difficulty is constant if there are none.
+    pub fn retarget(&self) -> Option<&Retarget> {
+        self.retarget.as_deref()
+    }
+
+    /// Iterates over the chain from the head to the genesis block. Every item is the chain
+    /// ending with the block at this height.
+    pub fn iter(&self) -> ChainIter<'_> {
+        ChainIter { next: Some(self) }
+    }
+
+    /// The chain ending with the block at the given height, if the chain is high enough.
+    pub fn ancestor(chain: &Arc<Chain>, height: u32) -> Option<Arc<Chain>> {
+        if height > chain.height() {
+            return None;
+        }
+
+        let mut ancestor = chain.clone();
+        while ancestor.height() > height {
+            ancestor = ancestor
+                .tail
+                .clone()
+                .expect("Only the genesis block has no parent.");
+        }
+
+        Some(ancestor)
+    }
+
+    /// The height of the last block shared by both chains. Both chains are expected to start
+    /// from the same genesis block.
+    pub fn common_ancestor_height(&self, other: &Chain) -> u32 {
+        let mut one = self;
+        let mut other = other;
+
+        while one.head.hash != other.head.hash {
+            let (higher, lower) = if one.height() >= other.height() {
+                (one, other)
+            } else {
+                (other, one)
+            };
+
+            match higher.tail {
+                Some(ref tail) => {
+                    one = tail;
+                    other = lower;
+                }
+                None => return 0,
+            }
+        }
+
+        one.height()
+    }
+
+    /// The number of blocks of the chain above the given height mined by every node, the
+    /// genesis block always excluded.
+    pub fn blocks_by_miner(&self, above_height: u32) -> BTreeMap<u32, u32> {
+        let mut blocks_by_miner = BTreeMap::new();
+        for chain in self.iter().take_while(|chain| chain.height() > above_height) {
+            *blocks_by_miner.entry(chain.head.node_id).or_insert(0) += 1;
+        }
+        blocks_by_miner
+    }
+
+    /// The difficulty the next block must be mined with.
+    pub fn next_difficulty(&self) -> Arc<Difficulty> {
+        let retarget = match self.retarget {
+            Some(ref retarget) => retarget,
+            None => return self.head.difficulty.clone(),
+        };
+
+        let next_height = self.height() + 1;
+        let position_in_window = next_height % retarget.interval;
+        if position_in_window > 0 {
+            return self.head.difficulty.clone();
+        }
+
+        // Like in Bitcoin, the window spans from the first to the last block of the period.
+        let window_start = self.iter()
+            .find(|chain| chain.height() == next_height - retarget.interval)
+            .expect("The window cannot start before the genesis block.");
+
+        Arc::new(retarget.next_difficulty(
+            &self.head.difficulty,
+            window_start.head.timestamp,
+            self.head.timestamp,
+        ))
+    }
+
+    /// The median of the timestamps of the last blocks of the chain. The timestamp of the next
+    /// block must be greater, which keeps timestamps from drifting into the past while
+    /// tolerating clocks that are not perfectly in sync.
+    pub fn median_time_past(&self) -> u64 {
+        let mut timestamps: Vec<u64> = self.iter()
+            .take(MEDIAN_TIME_PAST_BLOCKS)
+            .map(|chain| chain.head.timestamp)
+            .collect();
+        timestamps.sort();
+        timestamps[timestamps.len() / 2]
+    }
+
+    /// The time elapsed between every block and the previous one according to their timestamps,
+    /// from the genesis block to the head. An interval is negative when a block claims to
+    /// have been mined before its parent.
+    pub fn block_intervals(&self) -> Vec<i64> {
+        let mut intervals: Vec<i64> = self.iter()
+            .filter_map(|chain| {
+                chain.tail.as_ref().map(|tail| {
+                    chain.head.timestamp as i64 - tail.head.timestamp as i64
+                })
+            })
+            .collect();
+        intervals.reverse();
+        intervals
+    }
+
+    /// The cumulative work of the chain, as an expected number of hashes.
+    pub fn work(&self) -> f64 {
+        self.work
+    }
+
+    /// Whether this chain has more work than the other one. With a constant difficulty, the
+    /// strongest chain is also the longest, but this is not the case anymore once the
+    /// difficulty is retargeted.
     pub fn stronger_than(&self, other: &Chain) -> bool {
-        // Since this is a constant difficulty simulation, the strongest chain is the longest.
-        // This is not the case with a dynamic difficulty like in the Bitcoin network where the
-        // strongest chain is the chain with the most work.
-        self.height() > other.height()
+        self.work > other.work
     }
 
     fn hashes_match(chain: &Arc<Chain>, block: &Block) -> bool {
@@ -171,19 +476,34 @@ impl Chain {
     }
 
     /// Checks that the chain is valid from head to tail and that it starts from the genesis block.
-    /// The current implementation is not the most efficient but is efficient enough
-    /// for this simulation.
     pub fn validate(&self) -> Result<(), &'static str> {
-        if let Err(err) = self.validate_head() {
-            return Err(err);
+        self.validate_until(|_hash| false)
+    }
+
+    /// Checks the blocks of the chain from the head down to the first one known to be valid,
+    /// or down to the genesis block if there is none. The chain is walked iteratively,
+    /// whatever its height.
+    pub fn validate_until<F>(&self, is_known_valid: F) -> Result<(), &'static str>
+    where
+        F: Fn(&Hash) -> bool,
+    {
+        let mut chain = self;
+        loop {
+            if is_known_valid(&chain.head.hash) {
+                return Ok(());
+            }
+
+            chain.validate_head()?;
+            match chain.tail {
+                Some(ref tail) => chain = tail,
+                None => return chain.validate_genesis(),
+            }
         }
+    }
 
-        if let Some(ref tail) = self.tail {
-            Chain::validate(tail)
-        } else if self.head
-            .hash()
-            .eq(Block::genesis_block(self.head.difficulty.clone()).hash())
-        {
+    fn validate_genesis(&self) -> Result<(), &'static str> {
+        let genesis_block = Block::genesis_block(self.head.difficulty.clone(), self.head.timestamp);
+        if self.head.hash().eq(genesis_block.hash()) {
             Ok(())
         } else {
             Err(CHAIN_ERROR_INVALID_GENESIS)
@@ -196,10 +516,12 @@ impl Chain {
                 Ok(()) => {
                     if self.height() == tail.height() + 1 {
                         if Chain::hashes_match(tail, &self.head) {
-                            if tail.head.difficulty.eq(&self.head.difficulty) {
-                                Ok(())
-                            } else {
+                            if !tail.next_difficulty().eq(&self.head.difficulty) {
                                 Err(CHAIN_ERROR_INVALID_DIFFICULTY)
+                            } else if self.head.timestamp <= tail.median_time_past() {
+                                Err(CHAIN_ERROR_TIMESTAMP_TOO_EARLY)
+                            } else {
+                                Ok(())
                             }
                         } else {
                             Err(CHAIN_ERROR_HASH_MISMATCH)
@@ -216,13 +538,82 @@ impl Chain {
     }
 }
 
+/// Drops the blocks of the chain iteratively, the default recursive drop would overflow the
+/// stack on long chains. The blocks still shared with other chains are left to them.
+impl Drop for Chain {
+    fn drop(&mut self) {
+        let mut tail = self.tail.take();
+        while let Some(chain) = tail {
+            tail = match Arc::try_unwrap(chain) {
+                Ok(mut chain) => chain.tail.take(),
+                Err(_shared_chain) => None,
+            };
+        }
+    }
+}
+
+/// Two chains are equal if they have the same head, its hash covers the rest of the chain.
+impl PartialEq for Chain {
+    fn eq(&self, other: &Chain) -> bool {
+        self.head.hash == other.head.hash
+    }
+}
+
+impl Debug for Chain {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Chain {{ height: {}, head: {:?} }}", self.height(), self.head.hash)
+    }
+}
+
+pub struct ChainIter<'a> {
+    next: Option<&'a Chain>,
+}
+
+impl<'a> Iterator for ChainIter<'a> {
+    type Item = &'a Chain;
+
+    fn next(&mut self) -> Option<&'a Chain> {
+        let current = self.next;
+        self.next = current.and_then(|chain| chain.tail.as_deref());
+        current
+    }
+}
+
+/// Helpers shared by the tests of the blockchain modules.
+#[cfg(test)]
+pub mod testing {
+    use super::pow::Nonce;
+    use super::{unix_time_millis, Block, Chain};
+    use std::sync::Arc;
+
+    /// Expands the chain with a block of the node, timestamped now. The block is not mined,
+    /// its nonce does not meet the difficulty unless it is the minimum one.
+    pub fn expand(chain: &Arc<Chain>, node_id: u32) -> Arc<Chain> {
+        expand_at(chain, node_id, unix_time_millis())
+    }
+
+    /// Expands the chain with a block of the node with the given timestamp, without mining
+    /// it.
+    pub fn expand_at(chain: &Arc<Chain>, node_id: u32, timestamp: u64) -> Arc<Chain> {
+        let block = Block::new(
+            node_id,
+            Nonce::new(),
+            &chain.next_difficulty(),
+            chain.head().hash().clone(),
+            chain.height() + 1,
+            timestamp,
+        );
+        Arc::new(Chain::unvalidated_expand(chain, block))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     fn decapitate(chain: Arc<Chain>) -> (Option<Arc<Chain>>, Block) {
         match Arc::try_unwrap(chain) {
-            Ok(chain) => (chain.tail, chain.head),
+            Ok(mut chain) => (chain.tail.take(), chain.head.clone()),
             Err(_err) => panic!(),
         }
     }
@@ -235,6 +626,22 @@ mod tests {
 
         assert!(chain.validate().is_ok());
         assert_eq!(5, chain.height());
+
+        let heights: Vec<u32> = chain.iter().map(|chain| chain.height()).collect();
+        assert_eq!(vec![5, 4, 3, 2, 1, 0], heights);
+
+        let ancestor = Chain::ancestor(&chain, 2).unwrap();
+        assert_eq!(2, ancestor.height());
+        assert!(Chain::ancestor(&chain, 6).is_none());
+
+        assert_eq!(2, chain.common_ancestor_height(&ancestor));
+        let fork = mine_next_block(ancestor, node_id + 1, &mut Nonce::new());
+        assert_eq!(2, chain.common_ancestor_height(&fork));
+        assert_eq!(2, fork.common_ancestor_height(&chain));
+
+        assert_eq!(Some(&5), chain.blocks_by_miner(0).get(&node_id));
+        assert_eq!(1, chain.blocks_by_miner(0).len());
+        assert_eq!(Some(&3), chain.blocks_by_miner(2).get(&node_id));
     }
 
     #[test]
@@ -263,6 +670,170 @@ mod tests {
         assert!(Chain::unvalidated_expand(&chain, block).validate().is_err());
     }
 
+    #[test]
+    fn retargets_difficulty() {
+        let mut difficulty = Difficulty::min_difficulty();
+        difficulty.increase();
+        let retarget = Retarget::new(4, Duration::from_secs(1), 4);
+        let mut chain = Arc::new(Chain::init_new(difficulty.clone(), Some(retarget)));
+        let genesis_timestamp = chain.head().timestamp();
+        let mut nonce = Nonce::new();
+
+        // Blocks are mined 10 times faster than the target, the adjustment is clamped to 4.
+        for height in 1..4u64 {
+            assert_eq!(difficulty, *chain.next_difficulty());
+            let timestamp = genesis_timestamp + height * 100;
+            let block = mine_block(&chain, &chain.next_difficulty(), timestamp, &mut nonce);
+            chain = Chain::expand(&chain, block).unwrap();
+        }
+
+        let mut expected_difficulty = difficulty.clone();
+        expected_difficulty.increase();
+        expected_difficulty.increase();
+        assert_eq!(expected_difficulty, *chain.next_difficulty());
+
+        let timestamp = genesis_timestamp + 400;
+        let block = mine_block(&chain, &Arc::new(difficulty), timestamp, &mut nonce);
+        assert_eq!(
+            Err(CHAIN_ERROR_INVALID_DIFFICULTY),
+            Chain::unvalidated_expand(&chain, block).validate()
+        );
+
+        let block = mine_block(&chain, &chain.next_difficulty(), timestamp, &mut nonce);
+        chain = Chain::expand(&chain, block).unwrap();
+        assert!(chain.validate().is_ok());
+        assert_eq!(expected_difficulty, *chain.next_difficulty());
+    }
+
+    #[test]
+    fn timestamps_follow_the_median_time_past() {
+        let (mut chain, _node_id, mut nonce) = init_chain();
+        let genesis_timestamp = chain.head().timestamp();
+        let difficulty = chain.next_difficulty();
+
+        for height in 1..12u64 {
+            let timestamp = genesis_timestamp + height * 1000;
+            let block = mine_block(&chain, &difficulty, timestamp, &mut nonce);
+            chain = Chain::expand(&chain, block).unwrap();
+        }
+        assert_eq!(genesis_timestamp + 6000, chain.median_time_past());
+
+        let block = mine_block(&chain, &difficulty, genesis_timestamp + 6000, &mut nonce);
+        assert_eq!(
+            Err(CHAIN_ERROR_TIMESTAMP_TOO_EARLY),
+            Chain::unvalidated_expand(&chain, block).validate()
+        );
+
+        // A block can still be older than its parent.
+        let block = mine_block(&chain, &difficulty, genesis_timestamp + 6001, &mut nonce);
+        chain = Chain::expand(&chain, block).unwrap();
+        assert!(chain.validate().is_ok());
+
+        let mut expected_intervals = vec![1000; 11];
+        expected_intervals.push(-4999);
+        assert_eq!(expected_intervals, chain.block_intervals());
+    }
+
+    #[test]
+    fn validates_long_chains_down_to_the_known_blocks() {
+        let (mut chain, _node_id, mut nonce) = init_chain();
+        let genesis_timestamp = chain.head().timestamp();
+        let difficulty = chain.next_difficulty();
+
+        for height in 1..50_001u64 {
+            let block = mine_block(&chain, &difficulty, genesis_timestamp + height, &mut nonce);
+            chain = Arc::new(Chain::unvalidated_expand(&chain, block));
+        }
+        assert!(chain.validate().is_ok());
+
+        // A forged block below a known block is not checked.
+        let (_nonce, mut block, tail) = init_decapitated_chain();
+        nonce.increment();
+        block.nonce = nonce;
+        let forged_chain = Arc::new(Chain::unvalidated_expand(&tail, block));
+        let chain = mine_next_block(forged_chain.clone(), 1, &mut Nonce::new());
+        assert!(chain.validate().is_err());
+        assert!(chain
+            .validate_until(|hash| hash == forged_chain.head().hash())
+            .is_ok());
+    }
+
+    fn mine_block(chain: &Arc<Chain>, difficulty: &Arc<Difficulty>, timestamp: u64, nonce: &mut Nonce) -> Block {
+        loop {
+            nonce.increment();
+            let block = Block::new(
+                1,
+                nonce.clone(),
+                difficulty,
+                chain.head().hash().clone(),
+                chain.height() + 1,
+                timestamp,
+            );
+
+            if block.validate().is_ok() {
+                return block;
+            }
+        }
+    }
+
+    #[test]
+    fn the_strongest_chain_has_the_most_work() {
+        let (chain, node_id, mut nonce) = init_chain();
+        let long_chain = mine_5_blocks(chain.clone(), node_id, &mut nonce);
+
+        let mut difficulty = (**chain.head().difficulty()).clone();
+        for _i in 0..8 {
+            difficulty.increase();
+        }
+        let block = Block::new(
+            node_id,
+            nonce,
+            &Arc::new(difficulty),
+            chain.head().hash().clone(),
+            1,
+            unix_time_millis(),
+        );
+        let short_chain = Chain::unvalidated_expand(&chain, block);
+
+        // The genesis block and every mined block are worth 2 hashes.
+        assert_eq!(12f64, long_chain.work());
+        assert!(short_chain.stronger_than(&long_chain));
+        assert!(!long_chain.stronger_than(&short_chain));
+        assert!(!long_chain.stronger_than(&long_chain));
+    }
+
+    #[test]
+    fn ties_follow_the_fork_choice_rule() {
+        let (chain, node_id, mut nonce) = init_chain();
+        let one = mine_next_block(chain.clone(), node_id, &mut nonce);
+        let other = mine_next_block(chain.clone(), node_id, &mut nonce);
+        let (lowest, highest) = if one.head().hash() < other.head().hash() {
+            (one, other)
+        } else {
+            (other, one)
+        };
+
+        assert!(!ForkChoice::FirstSeen.prefers(&lowest, &highest));
+        assert!(!ForkChoice::FirstSeen.prefers(&highest, &lowest));
+        assert!(ForkChoice::LowestHash.prefers(&lowest, &highest));
+        assert!(!ForkChoice::LowestHash.prefers(&highest, &lowest));
+
+        for fork_choice in &[ForkChoice::FirstSeen, ForkChoice::Random, ForkChoice::LowestHash] {
+            assert!(fork_choice.prefers(&lowest, &chain));
+            assert!(!fork_choice.prefers(&chain, &lowest));
+            assert!(!fork_choice.prefers(&lowest, &lowest));
+        }
+    }
+
+    fn mine_next_block(chain: Arc<Chain>, node_id: u32, nonce: &mut Nonce) -> Arc<Chain> {
+        let height = chain.height();
+        let mut mined = chain;
+        while mined.height() == height {
+            mined = try_to_mine_next_block(mined, node_id, nonce);
+        }
+        mined
+    }
+
     fn init_decapitated_chain() -> (Nonce, Block, Arc<Chain>) {
         let (mut chain, node_id, mut nonce) = init_chain();
         chain = mine_5_blocks(chain, node_id, &mut nonce);
@@ -276,9 +847,10 @@ mod tests {
         let block = Block::new(
             node_id,
             nonce.clone(),
-            &chain.head().difficulty,
+            &chain.next_difficulty(),
             chain.head().hash().clone(),
             chain.height() + 1,
+            unix_time_millis().max(chain.median_time_past() + 1),
         );
 
         match Chain::expand(&chain, block) {
@@ -300,7 +872,7 @@ mod tests {
     fn init_chain() -> (Arc<Chain>, u32, Nonce) {
         let mut difficulty = Difficulty::min_difficulty();
         difficulty.increase();
-        let chain = Chain::init_new(difficulty);
+        let chain = Chain::init_new(difficulty, None);
         let chain = Arc::new(chain);
         let node_id = 1;
         let nonce = Nonce::new();
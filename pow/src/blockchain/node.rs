@@ -1,19 +1,162 @@
-use blockchain::{mining_stream, Chain, MiningStateUpdater};
-use futures::sync::mpsc::UnboundedSender;
+use blockchain::recorder::NodeRecorder;
+use blockchain::relay::REQUEST_TIMEOUT;
+use blockchain::{
+    mining_stream, validation_stream, Chain, Clock, ForkChoice, Hash, HeadersFirstRelay,
+    MiningStateUpdater, PoolMessage, RelayMessage, ValidationDelay, ValidationQueue,
+    CHAIN_PROTOCOL, POOL_PROTOCOL, RELAY_PROTOCOL,
+};
 use futures::{self, future, Future, Stream};
 use netsim::flatten_select;
-use netsim::network::{MPSCConnection, Node};
+use netsim::network::heartbeat::{self, Heartbeat, HeartbeatEvent};
+use netsim::network::multiplex::{
+    MultiplexedConnection, MultiplexedMessage, Multiplexer, ProtocolSender, SendError,
+};
+use netsim::network::Node;
+use std::collections::{HashMap, HashSet};
+use std::iter::FromIterator;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio_timer::Interval;
 
-/// Contains a sink to the peer and information about the peer state.
+/// How many blocks below the highest one a hash is remembered to avoid duplicates. The
+/// chains this deep are neither relayed nor competing anymore.
+const RECENT_HEIGHTS: u32 = 100;
+
+/// The hashes of the recent blocks, the older ones being forgotten.
+#[derive(Clone, Default)]
+struct RecentHashes {
+    heights: HashMap<Hash, u32>,
+    highest: u32,
+}
+
+impl RecentHashes {
+    /// Returns false if the hash was already remembered.
+    fn insert(&mut self, hash: Hash, height: u32) -> bool {
+        if height > self.highest {
+            self.highest = height;
+            let highest = self.highest;
+            self.heights.retain(|_hash, height| *height + RECENT_HEIGHTS > highest);
+        }
+
+        self.heights.insert(hash, height).is_none()
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.heights.contains_key(hash)
+    }
+}
+
+/// Contains the sinks to the peer, one per protocol, and information about the peer state.
 #[derive(Clone)]
 pub struct Peer {
-    sender: UnboundedSender<Arc<Chain>>,
+    id: u32,
+    chain_sender: ProtocolSender<Arc<Chain>>,
+    pool_sender: ProtocolSender<PoolMessage>,
+    relay_sender: ProtocolSender<RelayMessage>,
     last_known_chain: Arc<Chain>,
+    /// The heads of the recent chains sent to the peer, so a chain is never sent twice.
+    sent: RecentHashes,
     is_closed: bool,
 }
 
+impl Peer {
+    /// Opens the sub-channels of the node protocols on the connection to the peer.
+    pub fn new(multiplexer: &mut Multiplexer, genesis_chain: Arc<Chain>) -> (Peer, PeerReceivers) {
+        let (chain_sender, chain_receiver) = multiplexer.channel(CHAIN_PROTOCOL);
+        let (pool_sender, pool_receiver) = multiplexer.channel(POOL_PROTOCOL);
+        let (relay_sender, relay_receiver) = multiplexer.channel(RELAY_PROTOCOL);
+        let peer_id = multiplexer.remote_id();
+
+        let receivers = chain_receiver
+            .map(move |chain| NodeEvent::ChainRemoteUpdate(peer_id, chain))
+            .select(pool_receiver.map(move |message| match message {
+                PoolMessage::Work(chain) => NodeEvent::Work(peer_id, chain),
+                PoolMessage::Submit(chain) => NodeEvent::Submitted(peer_id, chain),
+            }))
+            .select(relay_receiver.map(move |message| NodeEvent::Relay(peer_id, message)));
+
+        let peer = Peer {
+            id: peer_id,
+            chain_sender,
+            pool_sender,
+            relay_sender,
+            last_known_chain: genesis_chain,
+            sent: RecentHashes::default(),
+            is_closed: false,
+        };
+
+        (peer, Box::new(receivers))
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn last_known_chain(&self) -> &Arc<Chain> {
+        &self.last_known_chain
+    }
+
+    /// Sends the chain to the peer, unless it was already sent.
+    pub fn send(&mut self, chain: &Arc<Chain>) {
+        if self.sent.insert(chain.head().hash().clone(), chain.height()) {
+            let result = self.chain_sender.unbounded_send(chain.clone());
+            self.sent_chain(chain, result);
+        }
+    }
+
+    /// Announces the head of the chain to the peer, unless it was already sent. The peer
+    /// downloads the missing blocks with the headers-first relay.
+    pub fn announce(&mut self, chain: &Arc<Chain>) {
+        if self.sent.insert(chain.head().hash().clone(), chain.height()) {
+            let inv = RelayMessage::Inv(vec![chain.head().hash().clone()]);
+            let result = self.relay_sender.unbounded_send(inv);
+            self.sent_chain(chain, result);
+        }
+    }
+
+    /// Sends a message of the headers-first relay. Returns false if the connection is lost.
+    pub fn send_relay_message(&mut self, message: RelayMessage) -> bool {
+        let result = self.relay_sender.unbounded_send(message);
+        self.check_sent(result)
+    }
+
+    /// Asks the pool member to mine on the chain. Returns false if the connection is lost.
+    pub fn send_work(&mut self, chain: &Arc<Chain>) -> bool {
+        let result = self.pool_sender.unbounded_send(PoolMessage::Work(chain.clone()));
+        self.check_sent(result)
+    }
+
+    /// Submits a mined chain to the pool operator. Returns false if the connection is lost.
+    pub fn submit(&mut self, chain: Arc<Chain>) -> bool {
+        let result = self.pool_sender.unbounded_send(PoolMessage::Submit(chain));
+        self.check_sent(result)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.is_closed
+    }
+
+    fn sent_chain(&mut self, chain: &Arc<Chain>, result: Result<(), SendError>) {
+        if self.check_sent(result) {
+            self.last_known_chain = chain.clone();
+        }
+    }
+
+    fn check_sent(&mut self, result: Result<(), SendError>) -> bool {
+        match result {
+            Ok(()) => true,
+            Err(err) => {
+                info!("Lost connection to #{:05}: {}", self.id, err);
+                self.is_closed = true;
+                false
+            }
+        }
+    }
+}
+
+/// The events of the messages received from a peer, for every protocol of the node.
+pub type PeerReceivers = Box<dyn Stream<Item = NodeEvent, Error = ()> + Send>;
+
 /// Represents the events that can happen in a Proof of Work
 /// blockchain node.
 /// This enum helps us manipulate everything in the same stream, avoiding
@@ -21,122 +164,507 @@ pub struct Peer {
 pub enum NodeEvent {
     Peer(Peer),
     MinedChain(Arc<Chain>),
-    ChainRemoteUpdate(Arc<Chain>),
+    /// A chain received from the peer with the given id.
+    ChainRemoteUpdate(u32, Arc<Chain>),
+    /// A chain to mine on, assigned by the pool operator with the given id.
+    Work(u32, Arc<Chain>),
+    /// A chain mined by the pool member with the given id.
+    Submitted(u32, Arc<Chain>),
+    /// A message of the headers-first relay, received from the peer with the given id.
+    Relay(u32, RelayMessage),
+    /// A chain received from the peer with the given id, once the validation delay is over.
+    Validated(u32, Arc<Chain>),
+    /// What the heartbeat found out about a peer, if the node monitors its peers.
+    Heartbeat(HeartbeatEvent),
+    /// The connection to the peer with the given id was closed.
+    PeerClosed(u32),
+    /// The requests of the headers-first relay past their deadline can be given up.
+    RequestTimeouts,
+}
+
+/// Merges the incoming connections into a single stream of events. Every connection is
+/// multiplexed, a sub-channel per protocol, and yields a `NodeEvent::Peer` first, then the
+/// messages received from the peer, then a `NodeEvent::PeerClosed` once the connection is
+/// closed. The peers and the messages sent are recorded.
+///
+/// With a heartbeat, the peers are pinged and the events of the heartbeat are merged too.
+/// Without one, the node still answers the pings of its peers.
+pub fn peer_events<S>(
+    node_id: u32,
+    genesis_chain: Arc<Chain>,
+    recorder: NodeRecorder,
+    heartbeat: Option<Heartbeat>,
+    connection_stream: S,
+) -> impl Stream<Item = NodeEvent, Error = ()>
+where
+    S: Stream<Item = MultiplexedConnection, Error = ()> + Send + 'static,
+{
+    let peer_stream = connection_stream.map(move |connection| {
+        debug!("[#{:05}] Connection received.", node_id);
+        let mut multiplexer = Multiplexer::new(connection);
+        let traffic = recorder.traffic();
+        multiplexer.observe_sent(move |protocol, message| traffic.sent(protocol, message));
+
+        let (peer, receivers) = Peer::new(&mut multiplexer, genesis_chain.clone());
+        let peer_id = peer.id();
+        recorder.peer_connected(peer_id);
+        let recorder = recorder.clone();
+        let monitoring: Box<dyn Stream<Item = NodeEvent, Error = ()> + Send> = match heartbeat {
+            Some(heartbeat) => Box::new(heartbeat.monitor(&mut multiplexer).map(NodeEvent::Heartbeat)),
+            None => Box::new(no_events(heartbeat::answer_pings(&mut multiplexer))),
+        };
+        // The sub-channels end along with the connection.
+        let routing = no_events(multiplexer.run());
+
+        // Send a peer first, then every update received, until the connection is closed.
+        futures::stream::once(Ok(NodeEvent::Peer(peer)))
+            .chain(receivers.select(monitoring).select(routing))
+            .chain(
+                future::lazy(move || {
+                    recorder.peer_closed(peer_id);
+                    Ok(NodeEvent::PeerClosed(peer_id))
+                })
+                .into_stream(),
+            )
+    });
+
+    // Flatten this stream so all incoming traffic is considered a single stream.
+    flatten_select::new(peer_stream)
+}
+
+/// Runs the future along with the events of a peer.
+fn no_events<F>(future: F) -> impl Stream<Item = NodeEvent, Error = ()>
+where
+    F: Future<Item = (), Error = ()>,
+{
+    future.into_stream().filter_map(|()| None)
+}
+
+/// A switch of the best chain of a node to a chain that is not an extension of it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reorg {
+    /// The number of blocks rolled back.
+    pub depth: u32,
+    pub old_tip: Hash,
+    pub new_tip: Hash,
+}
+
+impl Reorg {
+    /// The reorg caused by the switch from the old chain to the new one, if the new one
+    /// does not extend the old one.
+    pub fn new(old_chain: &Chain, new_chain: &Chain) -> Option<Reorg> {
+        let depth = old_chain.height() - old_chain.common_ancestor_height(new_chain);
+
+        if depth > 0 {
+            Some(Reorg {
+                depth,
+                old_tip: old_chain.head().hash().clone(),
+                new_tip: new_chain.head().hash().clone(),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// The hashes of the blocks a node knows to be valid.
+pub struct ValidBlocks {
+    hashes: HashSet<Hash>,
+}
+
+impl ValidBlocks {
+    pub fn new(genesis_chain: &Chain) -> ValidBlocks {
+        let mut hashes = HashSet::new();
+        hashes.insert(genesis_chain.head().hash().clone());
+        ValidBlocks { hashes }
+    }
+
+    /// The number of blocks of the chain the node does not know to be valid.
+    pub fn unknown_blocks(&self, chain: &Chain) -> u32 {
+        chain
+            .iter()
+            .take_while(|chain| !self.hashes.contains(chain.head().hash()))
+            .count() as u32
+    }
+
+    /// Marks the blocks of a valid chain as valid.
+    pub fn insert(&mut self, chain: &Chain) {
+        for chain in chain.iter() {
+            if !self.hashes.insert(chain.head().hash().clone()) {
+                break;
+            }
+        }
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.hashes.contains(hash)
+    }
+}
+
+/// Checks a chain received from a peer, down to the first block the node knows to be valid.
+/// Unlike the chains mined by the node, the timestamps of these blocks must also be checked
+/// against the clock of the node.
+pub fn validate_received_chain(
+    chain: &Chain,
+    clock: &Clock,
+    valid_blocks: &mut ValidBlocks,
+) -> Result<(), &'static str> {
+    for chain in chain
+        .iter()
+        .take_while(|chain| !valid_blocks.contains(chain.head().hash()))
+    {
+        clock.check_future_drift(chain.head())?;
+    }
+    chain.validate_until(|hash| valid_blocks.contains(hash))?;
+    valid_blocks.insert(chain);
+    Ok(())
 }
 
 pub struct PowNode {
     node_id: u32,
     mining_attempt_delay: Duration,
+    /// The number of mining attempts made every `mining_attempt_delay`.
+    hashrate: u32,
     chain: Arc<Chain>,
+    fork_choice: ForkChoice,
+    clock: Clock,
+    recorder: NodeRecorder,
+    /// The ids of the members of the pool operated by the node, if any.
+    pool_member_ids: HashSet<u32>,
+    /// The chains are sent whole to the peers if there is none.
+    headers_first_relay: Option<HeadersFirstRelay>,
+    valid_blocks: ValidBlocks,
+    /// The chains received from the peers are validated instantly if there is none.
+    validation_delay: Option<ValidationDelay>,
+    /// Whether the chains are relayed to the peers before being validated, once the proof
+    /// of work of their head is checked.
+    relays_before_validation: bool,
+    /// The heads of the chains waiting to be validated.
+    validating: HashSet<Hash>,
+    /// The heads of the competing chains of the recent forks seen, so that a fork relayed by
+    /// several peers is only counted once.
+    fork_heads: RecentHashes,
+    /// The peers are not monitored if there is none.
+    heartbeat: Option<Heartbeat>,
+    /// The peers that did not answer a ping in time, restored if they answer again.
+    unresponsive_peers: Vec<Peer>,
 }
 
 impl PowNode {
-    pub fn new(node_id: u32, genesis_chain: Arc<Chain>, mining_attempt_delay: Duration) -> PowNode {
+    pub fn new(
+        node_id: u32,
+        genesis_chain: Arc<Chain>,
+        mining_attempt_delay: Duration,
+        hashrate: u32,
+        fork_choice: ForkChoice,
+        clock: Clock,
+        recorder: NodeRecorder,
+    ) -> PowNode {
         PowNode {
             node_id,
+            valid_blocks: ValidBlocks::new(&genesis_chain),
             chain: genesis_chain,
             mining_attempt_delay,
+            hashrate,
+            fork_choice,
+            clock,
+            recorder,
+            pool_member_ids: HashSet::new(),
+            headers_first_relay: None,
+            validation_delay: None,
+            relays_before_validation: false,
+            validating: HashSet::new(),
+            fork_heads: RecentHashes::default(),
+            heartbeat: None,
+            unresponsive_peers: vec![],
+        }
+    }
+
+    /// Makes the node ping its peers to measure their round-trip time, and stop relaying to
+    /// the peers that do not answer within the timeout of the heartbeat, until they answer
+    /// again, or whose connection is closed.
+    pub fn monitor_peers(mut self, heartbeat: Heartbeat) -> PowNode {
+        self.heartbeat = Some(heartbeat);
+        self
+    }
+
+    /// Updates the peers with an event of the heartbeat. The node stops relaying to an
+    /// unresponsive peer but keeps it aside, and restores it if it answers a ping again. Only
+    /// the unresponsive peers are recorded as dead, the connections are also closed when the
+    /// network stops.
+    fn update_peers(
+        &mut self,
+        event: &HeartbeatEvent,
+        peers: &mut Vec<Peer>,
+        members: &mut Vec<Peer>,
+    ) {
+        match *event {
+            HeartbeatEvent::RoundTrip { peer, rtt } => {
+                self.recorder.round_trip(rtt);
+
+                if let Some(index) = self.unresponsive_peers.iter().position(|p| p.id() == peer) {
+                    let mut restored = self.unresponsive_peers.remove(index);
+                    info!("[#{:05}] Restored the peer #{:05}", self.node_id, peer);
+                    self.recorder.dead_peer_restored();
+
+                    // Catch the peer up with what it missed meanwhile.
+                    if self.pool_member_ids.contains(&peer) {
+                        if restored.send_work(&self.chain) {
+                            members.push(restored);
+                        }
+                    } else {
+                        self.relay(&mut restored, &self.chain);
+                        if !restored.is_closed() {
+                            peers.push(restored);
+                        }
+                    }
+                }
+            }
+            HeartbeatEvent::Unresponsive { peer } => {
+                let dropped = peers
+                    .iter()
+                    .position(|p| p.id() == peer)
+                    .map(|index| peers.remove(index))
+                    .or_else(|| {
+                        members
+                            .iter()
+                            .position(|member| member.id() == peer)
+                            .map(|index| members.remove(index))
+                    });
+
+                if let Some(dropped) = dropped {
+                    info!("[#{:05}] Dropped the dead peer #{:05}", self.node_id, peer);
+                    self.recorder.dead_peer_dropped();
+                    self.unresponsive_peers.push(dropped);
+                    self.request_again(peer, peers);
+                }
+            }
+            HeartbeatEvent::Disconnected { peer } => self.forget_peer(peer, peers, members),
         }
     }
 
+    /// Forgets a peer whose connection was closed.
+    fn forget_peer(&mut self, peer_id: u32, peers: &mut Vec<Peer>, members: &mut Vec<Peer>) {
+        peers.retain(|peer| peer.id() != peer_id);
+        members.retain(|member| member.id() != peer_id);
+        self.unresponsive_peers.retain(|peer| peer.id() != peer_id);
+        self.request_again(peer_id, peers);
+    }
+
+    /// Requests the blocks requested from a dropped peer from the other peers, with the
+    /// headers-first relay.
+    fn request_again(&mut self, dropped_peer_id: u32, peers: &mut [Peer]) {
+        if let Some(ref mut relay) = self.headers_first_relay {
+            send_relay_messages(relay.peer_dropped(dropped_peer_id, Instant::now()), peers);
+        }
+    }
+
+    /// Makes the node the operator of a mining pool. The members mine on the chain of the
+    /// operator and submit their blocks to it, the operator validates and relays them.
+    pub fn operate_pool(mut self, member_ids: &[u32]) -> PowNode {
+        self.pool_member_ids = HashSet::from_iter(member_ids.iter().cloned());
+        self
+    }
+
+    /// Makes the node announce its new chains to its peers instead of sending them whole.
+    /// The peers are expected to use the headers-first relay too.
+    pub fn relay_headers_first(mut self) -> PowNode {
+        self.headers_first_relay = Some(HeadersFirstRelay::new(&self.chain));
+        self
+    }
+
+    /// Makes the node take some time to validate the chains received from its peers, during
+    /// which their new blocks are neither mined on nor, unless `relays_before_validation`,
+    /// relayed. Only applies to the chains sent whole.
+    pub fn delay_validation(
+        mut self,
+        delay: ValidationDelay,
+        relays_before_validation: bool,
+    ) -> PowNode {
+        self.validation_delay = Some(delay);
+        self.relays_before_validation = relays_before_validation;
+        self
+    }
+
+    /// Sends the chain to the peer, or announces it with the headers-first relay.
+    fn relay(&self, peer: &mut Peer, chain: &Arc<Chain>) {
+        if self.headers_first_relay.is_some() {
+            peer.announce(chain);
+        } else {
+            peer.send(chain);
+        }
+    }
+
+    /// Relays the chain to the peers whose last known chain has less work, or as much if
+    /// `relays_ties`.
+    fn relay_to_peers(&self, chain: &Arc<Chain>, peers: &mut Vec<Peer>, relays_ties: bool) {
+        peers.iter_mut().for_each(|peer| {
+            let peer_is_behind = chain.stronger_than(peer.last_known_chain())
+                || (relays_ties && !peer.last_known_chain().stronger_than(chain));
+            if peer_is_behind {
+                self.relay(peer, chain);
+            }
+        });
+
+        peers.retain(|peer| !peer.is_closed());
+    }
+
+    /// Queues a chain received from a peer for validation. With `relays_before_validation`,
+    /// it is relayed right away if the proof of work of its head is valid.
+    fn queue_validation(
+        &mut self,
+        peer_id: u32,
+        chain: Arc<Chain>,
+        peers: &mut Vec<Peer>,
+        validation_queue: &mut ValidationQueue,
+    ) {
+        if !self.validating.insert(chain.head().hash().clone()) {
+            // Already received from another peer.
+            return;
+        }
+        self.recorder.validation_started();
+
+        if self.relays_before_validation && chain.head().validate().is_ok() {
+            self.relay_to_peers(&chain, peers, false);
+        }
+
+        let new_blocks = self.valid_blocks.unknown_blocks(&chain);
+        validation_queue.push(peer_id, chain, new_blocks);
+    }
+
     /// Propagates the new chain to peers and to the mining stream.
     /// The propagation only happens if the update is a stronger chain
-    /// than the known one of either the peer or the mining stream.
+    /// than the known one of either the peer or the mining stream. A chain with the same work
+    /// is only propagated if the fork choice rule made the node adopt it, or if the rule
+    /// requires every node to see the ties.
     fn propagate(
         &mut self,
         chain: Arc<Chain>,
         peers: &mut Vec<Peer>,
+        members: &mut Vec<Peer>,
         mining_state_updater: &MiningStateUpdater,
     ) {
+        self.valid_blocks.insert(&chain);
+        if let Some(ref mut relay) = self.headers_first_relay {
+            relay.insert(&chain);
+        }
+
         let chain_height = chain.height();
+        let is_tie = !chain.stronger_than(&self.chain) && !self.chain.stronger_than(&chain);
+        let is_fork = is_tie
+            && chain.head.hash() != self.chain.head.hash()
+            && !self.fork_heads.contains(chain.head.hash());
+        let adopted = self.fork_choice.prefers(&chain, &self.chain);
+        let relays_ties = adopted || self.fork_choice.relays_ties();
 
-        peers.iter_mut().for_each(|peer| {
-            if chain.stronger_than(&peer.last_known_chain) {
-                match &peer.sender.unbounded_send(chain.clone()) {
-                    Ok(()) => {
-                        peer.last_known_chain = chain.clone();
-                    }
-                    Err(err) => {
-                        info!("Lost connection: {}", err);
-                        peer.is_closed = true;
-                    }
-                }
-            }
-        });
+        self.relay_to_peers(&chain, peers, relays_ties);
 
-        peers.retain(|peer| !peer.is_closed);
+        if is_fork {
+            self.fork_heads.insert(chain.head.hash().clone(), chain_height);
+            self.fork_heads
+                .insert(self.chain.head.hash().clone(), self.chain.height());
+            self.recorder.fork_seen();
+            info!(
+                "[#{:05}] Natural fork detected: {:?} <> {:?}",
+                self.node_id,
+                chain.head.hash(),
+                self.chain.head.hash()
+            );
+        }
+
+        if adopted {
+            if let Some(reorg) = Reorg::new(&self.chain, &chain) {
+                info!(
+                    "[#{:05}] Reorg of depth {}: {:?} -> {:?}",
+                    self.node_id, reorg.depth, reorg.old_tip, reorg.new_tip
+                );
+                self.recorder.reorg(reorg);
+            }
 
-        if chain.stronger_than(&self.chain) {
             mining_state_updater.mine_new_chain(chain.clone());
+            for member in members.iter_mut() {
+                member.send_work(&chain);
+            }
+            members.retain(|member| !member.is_closed());
+            self.recorder.adopted(&chain);
             self.chain = chain;
             debug!(
                 "[#{:05}]  New chain with height: {}",
                 self.node_id, chain_height
             );
-        } else if chain_height == self.chain.height() {
-            let new_hash = chain.head.hash();
-            let current_hash = self.chain.head.hash();
-
-            if new_hash != current_hash {
-                info!(
-                    "[#{:05}] Natural fork detected: {:?} <> {:?}",
-                    self.node_id, new_hash, current_hash
-                );
-            }
         }
     }
 }
 
-impl Node<Arc<Chain>> for PowNode {
-    fn run<S>(mut self, connection_stream: S) -> Box<Future<Item = (), Error = ()> + Send>
+impl Node<MultiplexedMessage> for PowNode {
+    fn run<S>(mut self, connection_stream: S) -> Box<dyn Future<Item = (), Error = ()> + Send>
     where
-        S: Stream<Item = MPSCConnection<Arc<Chain>>, Error = ()> + Send + 'static,
+        S: Stream<Item = MultiplexedConnection, Error = ()> + Send + 'static,
     {
         // Start a mining stream.
         let (
             mining_stream, // This stream will yield valid blocks.
             updater,       // This provides a way to warn the miner that it should mine a new chain
-        ) = mining_stream(self.node_id, self.chain.clone(), self.mining_attempt_delay);
-
-        let node_id = self.node_id;
-        let genesis_chain = self.chain.clone();
-        let peer_stream = connection_stream.map(move |connection| {
-            debug!("[#{:05}] Connection received.", node_id);
-            let (sender, receiver) = connection.split();
-
-            let reception = receiver
-                .map(|chain| NodeEvent::ChainRemoteUpdate(chain))
-                .map_err(|_| panic!());
-
-            // Send a peer first, then every update received.
-            futures::stream::once(Ok(NodeEvent::Peer(Peer {
-                sender,
-                last_known_chain: genesis_chain.clone(),
-                is_closed: false,
-            }))).chain(reception)
-        });
-        // Flatten this stream so all incoming traffic is considered a single stream.
-        let peer_stream = flatten_select::new(peer_stream);
+        ) = mining_stream(
+            self.node_id,
+            self.chain.clone(),
+            self.mining_attempt_delay,
+            self.hashrate,
+            self.clock,
+            self.recorder.mining_halt(),
+        );
+
+        let peer_stream = peer_events(
+            self.node_id,
+            self.chain.clone(),
+            self.recorder.clone(),
+            self.heartbeat,
+            connection_stream,
+        );
+
+        // Without validation delay, nothing is ever queued.
+        let (validated_stream, mut validation_queue) = validation_stream(
+            self.validation_delay
+                .unwrap_or(ValidationDelay::Fixed(Duration::from_secs(0))),
+        );
+
+        // The requests of the headers-first relay are checked for timeouts regularly.
+        let request_timeouts: Box<dyn Stream<Item = NodeEvent, Error = ()> + Send> =
+            if self.headers_first_relay.is_some() {
+                Box::new(
+                    Interval::new(Instant::now() + REQUEST_TIMEOUT, REQUEST_TIMEOUT)
+                        .map(|_instant| NodeEvent::RequestTimeouts)
+                        .map_err(|timer_err| panic!("Timer error: {}", timer_err)),
+                )
+            } else {
+                Box::new(futures::stream::empty())
+            };
 
         // Joining all these streams helps us avoid concurrency issues, the use of locking and
         // complicated lifetime management.
         let mut peers = vec![];
+        let mut members = vec![];
         let routing_future = peer_stream
             .select(
                 // This merges the events coming from peers with the events of new mined nodes.
-                mining_stream.map(move |chain| NodeEvent::MinedChain(chain)),
+                mining_stream.map(NodeEvent::MinedChain),
             )
+            .select(validated_stream.map(|(peer_id, chain)| NodeEvent::Validated(peer_id, chain)))
+            .select(request_timeouts)
             .for_each(move |node_event| {
                 match node_event {
-                    NodeEvent::Peer(peer) => {
-                        match &peer.sender.unbounded_send(self.chain.clone()) {
-                            Ok(()) => {
-                                peers.push(peer);
-                                debug!("[#{:05}] New peer. Total: {}", self.node_id, peers.len());
-                            }
-                            Err(err) => {
-                                debug!("[#{:05}] Peer lost: {}", self.node_id, err);
-                            }
+                    NodeEvent::Peer(mut peer) if self.pool_member_ids.contains(&peer.id()) => {
+                        if peer.send_work(&self.chain) {
+                            members.push(peer);
+                        }
+                    }
+                    NodeEvent::Peer(mut peer) => {
+                        self.relay(&mut peer, &self.chain);
+                        if peer.is_closed() {
+                            debug!("[#{:05}] Peer lost.", self.node_id);
+                        } else {
+                            peers.push(peer);
+                            debug!("[#{:05}] New peer. Total: {}", self.node_id, peers.len());
                         }
                     }
                     NodeEvent::MinedChain(chain) => {
@@ -146,14 +674,83 @@ impl Node<Arc<Chain>> for PowNode {
                             chain.head().hash(),
                             chain.height()
                         );
-                        self.propagate(chain, &mut peers, &updater);
+                        self.recorder.mined(&chain);
+                        self.propagate(chain, &mut peers, &mut members, &updater);
+                    }
+                    NodeEvent::ChainRemoteUpdate(peer_id, chain)
+                        if self.validation_delay.is_some()
+                            && self.valid_blocks.unknown_blocks(&chain) > 0 =>
+                    {
+                        self.queue_validation(peer_id, chain, &mut peers, &mut validation_queue);
+                    }
+                    NodeEvent::ChainRemoteUpdate(peer_id, chain)
+                    | NodeEvent::Validated(peer_id, chain) => {
+                        if self.validating.remove(chain.head().hash()) {
+                            self.recorder.validation_ended();
+                        }
+                        match validate_received_chain(&chain, &self.clock, &mut self.valid_blocks) {
+                            Ok(()) => {
+                                self.recorder.received(&chain, peer_id);
+                                self.propagate(chain, &mut peers, &mut members, &updater);
+                            }
+                            Err(err) => error!("Invalid chain: {}", err),
+                        }
                     }
-                    NodeEvent::ChainRemoteUpdate(chain) => match chain.validate() {
-                        Ok(()) => {
-                            self.propagate(chain, &mut peers, &updater);
+                    NodeEvent::Submitted(member_id, chain) => {
+                        if !self.pool_member_ids.contains(&member_id) {
+                            error!("[#{:05}] Block submitted by a stranger: #{:05}", self.node_id, member_id);
+                        } else {
+                            match validate_received_chain(&chain, &self.clock, &mut self.valid_blocks) {
+                                Ok(()) => {
+                                    info!(
+                                        "[#{:05}] Pool member #{:05} mined a new block: {:?}, height {}",
+                                        self.node_id,
+                                        member_id,
+                                        chain.head().hash(),
+                                        chain.height()
+                                    );
+                                    self.recorder.received(&chain, member_id);
+                                    self.propagate(chain, &mut peers, &mut members, &updater);
+                                }
+                                Err(err) => error!("Invalid submitted chain: {}", err),
+                            }
                         }
-                        Err(err) => error!("Invalid chain: {}", err),
-                    },
+                    }
+                    NodeEvent::Relay(peer_id, message) => {
+                        let (messages, chains) = match self.headers_first_relay {
+                            Some(ref mut relay) => relay.receive(
+                                peer_id,
+                                message,
+                                &self.chain,
+                                &self.clock,
+                                Instant::now(),
+                            ),
+                            None => {
+                                debug!("[#{:05}] Ignored a relay message from #{:05}", self.node_id, peer_id);
+                                (vec![], vec![])
+                            }
+                        };
+
+                        send_relay_messages(messages, &mut peers);
+                        for chain in chains {
+                            self.recorder.received(&chain, peer_id);
+                            self.propagate(chain, &mut peers, &mut members, &updater);
+                        }
+                    }
+                    NodeEvent::Work(peer_id, _chain) => {
+                        debug!("[#{:05}] Ignored work sent by #{:05}", self.node_id, peer_id);
+                    }
+                    NodeEvent::Heartbeat(event) => {
+                        self.update_peers(&event, &mut peers, &mut members);
+                    }
+                    NodeEvent::PeerClosed(peer_id) => {
+                        self.forget_peer(peer_id, &mut peers, &mut members);
+                    }
+                    NodeEvent::RequestTimeouts => {
+                        if let Some(ref mut relay) = self.headers_first_relay {
+                            send_relay_messages(relay.expire(Instant::now()), &mut peers);
+                        }
+                    }
                 }
 
                 future::ok(())
@@ -162,3 +759,226 @@ impl Node<Arc<Chain>> for PowNode {
         Box::new(routing_future)
     }
 }
+
+/// Sends the messages of the headers-first relay to their recipients, if they are still peers.
+fn send_relay_messages(messages: Vec<(u32, RelayMessage)>, peers: &mut [Peer]) {
+    for (peer_id, message) in messages {
+        if let Some(peer) = peers.iter_mut().find(|peer| peer.id() == peer_id) {
+            peer.send_relay_message(message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::testing::{expand, expand_at};
+    use blockchain::recorder::Recorder;
+    use blockchain::{unix_time_millis, Difficulty};
+    use netsim::network::transport::connection_pair;
+    use netsim::network::Network;
+    use netsim::testing::Harness;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn detects_reorgs() {
+        let genesis = Arc::new(Chain::init_new(Difficulty::min_difficulty(), None));
+        let old_chain = expand(&expand(&expand(&genesis, 0), 0), 0);
+        let fork = Chain::ancestor(&old_chain, 1).unwrap();
+        let new_chain = expand(&expand(&expand(&fork, 1), 1), 1);
+
+        assert_eq!(None, Reorg::new(&genesis, &old_chain));
+        assert_eq!(
+            Some(Reorg {
+                depth: 2,
+                old_tip: old_chain.head().hash().clone(),
+                new_tip: new_chain.head().hash().clone(),
+            }),
+            Reorg::new(&old_chain, &new_chain)
+        );
+    }
+
+    #[test]
+    fn remembers_the_valid_blocks() {
+        let mut difficulty = Difficulty::min_difficulty();
+        for _i in 0..32 {
+            difficulty.increase();
+        }
+        let genesis = Arc::new(Chain::init_new(difficulty, None));
+        let clock = Clock::new(0, Duration::from_secs(7200));
+        let mut valid_blocks = ValidBlocks::new(&genesis);
+
+        // The nonces of these blocks do not meet the difficulty.
+        let chain = expand(&expand(&genesis, 0), 0);
+        assert!(validate_received_chain(&chain, &clock, &mut valid_blocks).is_err());
+        assert!(!valid_blocks.contains(chain.head().hash()));
+
+        // Only the blocks the node does not know yet are checked.
+        valid_blocks.insert(&chain);
+        assert!(validate_received_chain(&chain, &clock, &mut valid_blocks).is_ok());
+        assert!(valid_blocks.contains(genesis.head().hash()));
+    }
+
+    #[test]
+    fn rejects_blocks_from_the_future_below_the_head() {
+        let genesis = Arc::new(Chain::init_new(Difficulty::min_difficulty(), None));
+        let clock = Clock::new(0, Duration::from_secs(10));
+        let now = unix_time_millis();
+
+        let chain = expand_at(&expand_at(&genesis, 0, now + 1), 0, now + 2);
+        assert!(validate_received_chain(&chain, &clock, &mut ValidBlocks::new(&genesis)).is_ok());
+
+        let chain = expand_at(&genesis, 0, now + 1);
+        let chain = expand_at(&expand_at(&chain, 0, now + 3_600_000), 0, now + 2);
+        assert!(clock.check_future_drift(chain.head()).is_ok());
+        let mut valid_blocks = ValidBlocks::new(&genesis);
+        assert!(validate_received_chain(&chain, &clock, &mut valid_blocks).is_err());
+        assert!(!valid_blocks.contains(chain.head().hash()));
+    }
+
+    #[test]
+    fn counts_every_fork_once() {
+        let genesis = Arc::new(Chain::init_new(Difficulty::min_difficulty(), None));
+        let recorder = Recorder::new();
+        let node_recorder = recorder.node(0, genesis.clone());
+        let probe = node_recorder.probe();
+        let mut node = PowNode::new(
+            0,
+            genesis.clone(),
+            Duration::from_millis(1),
+            1,
+            ForkChoice::FirstSeen,
+            Clock::new(0, Duration::from_secs(7200)),
+            node_recorder,
+        );
+        let (sender, _receiver) = futures::sync::mpsc::unbounded();
+        let mining_state_updater = MiningStateUpdater::new(sender);
+
+        let chain = expand(&genesis, 1);
+        let competing_chain = expand(&genesis, 2);
+        node.propagate(chain, &mut vec![], &mut vec![], &mining_state_updater);
+        // The competing chain is relayed by several peers.
+        for _peer in 0..3 {
+            node.propagate(
+                competing_chain.clone(),
+                &mut vec![],
+                &mut vec![],
+                &mining_state_updater,
+            );
+        }
+        assert_eq!(1, probe.state().forks_seen);
+    }
+
+    #[test]
+    fn forgets_the_old_hashes() {
+        let genesis = Arc::new(Chain::init_new(Difficulty::min_difficulty(), None));
+        let old_chain = expand(&genesis, 0);
+        let mut hashes = RecentHashes::default();
+
+        assert!(hashes.insert(old_chain.head().hash().clone(), 1));
+        assert!(!hashes.insert(old_chain.head().hash().clone(), 1));
+        assert!(hashes.insert(genesis.head().hash().clone(), RECENT_HEIGHTS));
+        assert!(hashes.contains(old_chain.head().hash()));
+
+        hashes.insert(expand(&genesis, 1).head().hash().clone(), RECENT_HEIGHTS + 1);
+        assert!(!hashes.contains(old_chain.head().hash()));
+        assert_eq!(2, hashes.heights.len());
+    }
+
+    #[test]
+    fn drops_the_peers_reported_by_the_heartbeat() {
+        let genesis = Arc::new(Chain::init_new(Difficulty::min_difficulty(), None));
+        let recorder = Recorder::new();
+        let node_recorder = recorder.node(0, genesis.clone());
+        let probe = node_recorder.probe();
+        let mut node = PowNode::new(
+            0,
+            genesis.clone(),
+            Duration::from_millis(1),
+            1,
+            ForkChoice::FirstSeen,
+            Clock::new(0, Duration::from_secs(7200)),
+            node_recorder,
+        )
+        .operate_pool(&[3]);
+        let (connection, _other) = connection_pair();
+        let (connected_peer, _receivers) = Peer::new(&mut Multiplexer::new(connection), genesis);
+        let peer = |id| Peer {
+            id,
+            ..connected_peer.clone()
+        };
+        let ids = |peers: &Vec<Peer>| peers.iter().map(Peer::id).collect::<Vec<u32>>();
+
+        let mut peers = vec![peer(1), peer(2), peer(4)];
+        let mut members = vec![peer(3)];
+        let unresponsive = |peer| HeartbeatEvent::Unresponsive { peer };
+        let round_trip = |peer| HeartbeatEvent::RoundTrip {
+            peer,
+            rtt: Duration::from_millis(10),
+        };
+        node.update_peers(&unresponsive(1), &mut peers, &mut members);
+        node.update_peers(&unresponsive(3), &mut peers, &mut members);
+        // Already dropped.
+        node.update_peers(&unresponsive(1), &mut peers, &mut members);
+        // Not dead, the connection was closed.
+        node.update_peers(&HeartbeatEvent::Disconnected { peer: 4 }, &mut peers, &mut members);
+
+        assert_eq!(vec![2], ids(&peers));
+        assert!(members.is_empty());
+        assert_eq!(2, probe.state().dead_peers_dropped);
+
+        // The dropped peers answer again.
+        node.update_peers(&round_trip(1), &mut peers, &mut members);
+        node.update_peers(&round_trip(3), &mut peers, &mut members);
+        node.update_peers(&round_trip(2), &mut peers, &mut members);
+
+        assert_eq!(vec![2, 1], ids(&peers));
+        assert_eq!(vec![3], ids(&members));
+        assert_eq!(2, probe.state().dead_peers_restored);
+        assert_eq!(3, probe.state().round_trip_times.len());
+    }
+
+    #[test]
+    fn nodes_reach_consensus() {
+        let mut difficulty = Difficulty::min_difficulty();
+        for _i in 0..12 {
+            difficulty.increase();
+        }
+
+        let chain = Arc::new(Chain::init_new(difficulty, None));
+        let node_id = AtomicUsize::new(0);
+        let recorder = Recorder::new();
+
+        let harness = Harness::start(
+            Network::new(8, 2),
+            move || {
+                let node_id = node_id.fetch_add(1, Ordering::Relaxed) as u32;
+                let node_recorder = recorder.node(node_id, chain.clone());
+                let probe = node_recorder.probe();
+                let node = PowNode::new(
+                    node_id,
+                    chain.clone(),
+                    Duration::from_millis(1),
+                    1,
+                    ForkChoice::LowestHash,
+                    Clock::new(0, Duration::from_secs(7200)),
+                    node_recorder,
+                );
+                (node, probe)
+            },
+            Duration::from_secs(10),
+        );
+
+        harness.assert_eventually(
+            |records| {
+                let tip = records[0].best_chain.head().hash();
+                records.iter().all(|record| {
+                    record.best_chain.height() >= 2 && record.best_chain.head().hash() == tip
+                })
+            },
+            Duration::from_secs(10),
+        );
+
+        harness.join();
+    }
+}
@@ -0,0 +1,188 @@
+//! Mining pools. The members of a pool contribute their hashrate but are not full nodes: they
+//! neither validate nor relay chains. They mine on the chain assigned by the pool operator and
+//! submit the blocks they find to it, the operator being the one broadcasting them.
+
+use blockchain::node::{peer_events, NodeEvent, Peer};
+use blockchain::recorder::NodeRecorder;
+use blockchain::{mining_stream, Chain, Clock};
+use futures::{future, Future, Stream};
+use netsim::network::multiplex::{MultiplexedConnection, MultiplexedMessage};
+use netsim::network::Node;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The nodes of a mining pool.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pool {
+    pub operator_id: u32,
+    pub member_ids: Vec<u32>,
+}
+
+impl Pool {
+    /// Assigns the highest node ids to the pools, the operator of each pool being followed
+    /// by its members. Returns `None` if the network is too small.
+    pub fn layout(
+        number_of_nodes: u32,
+        number_of_pools: u32,
+        members_per_pool: u32,
+    ) -> Option<Vec<Pool>> {
+        let pool_size = members_per_pool + 1;
+        if number_of_pools * pool_size > number_of_nodes {
+            return None;
+        }
+
+        let first_id = number_of_nodes - number_of_pools * pool_size;
+        let pools = (0..number_of_pools)
+            .map(|pool_index| {
+                let operator_id = first_id + pool_index * pool_size;
+                Pool {
+                    operator_id,
+                    member_ids: (operator_id + 1..operator_id + pool_size).collect(),
+                }
+            })
+            .collect();
+
+        Some(pools)
+    }
+
+    /// Whether the node is the operator or a member of the pool.
+    pub fn contains(&self, node_id: u32) -> bool {
+        self.operator_id == node_id || self.member_ids.contains(&node_id)
+    }
+}
+
+/// A member of a mining pool. Its connections to other nodes than the operator are dropped.
+pub struct PoolMemberNode {
+    node_id: u32,
+    operator_id: u32,
+    genesis_chain: Arc<Chain>,
+    mining_attempt_delay: Duration,
+    hashrate: u32,
+    clock: Clock,
+    recorder: NodeRecorder,
+}
+
+impl PoolMemberNode {
+    pub fn new(
+        node_id: u32,
+        operator_id: u32,
+        genesis_chain: Arc<Chain>,
+        mining_attempt_delay: Duration,
+        hashrate: u32,
+        clock: Clock,
+        recorder: NodeRecorder,
+    ) -> PoolMemberNode {
+        PoolMemberNode {
+            node_id,
+            operator_id,
+            genesis_chain,
+            mining_attempt_delay,
+            hashrate,
+            clock,
+            recorder,
+        }
+    }
+}
+
+impl Node<MultiplexedMessage> for PoolMemberNode {
+    fn run<S>(self, connection_stream: S) -> Box<dyn Future<Item = (), Error = ()> + Send>
+    where
+        S: Stream<Item = MultiplexedConnection, Error = ()> + Send + 'static,
+    {
+        let (mining_stream, updater) = mining_stream(
+            self.node_id,
+            self.genesis_chain.clone(),
+            self.mining_attempt_delay,
+            self.hashrate,
+            self.clock,
+            self.recorder.mining_halt(),
+        );
+
+        let peer_stream = peer_events(
+            self.node_id,
+            self.genesis_chain.clone(),
+            self.recorder.clone(),
+            None,
+            connection_stream,
+        );
+
+        let node_id = self.node_id;
+        let operator_id = self.operator_id;
+        let recorder = self.recorder;
+        let mut operator: Option<Peer> = None;
+        let routing_future = peer_stream
+            .select(mining_stream.map(NodeEvent::MinedChain))
+            .for_each(move |node_event| {
+                match node_event {
+                    NodeEvent::Peer(peer) => {
+                        if peer.id() == operator_id {
+                            operator = Some(peer);
+                        }
+                    }
+                    NodeEvent::Work(peer_id, chain) => {
+                        if peer_id == operator_id {
+                            recorder.adopted(&chain);
+                            updater.mine_new_chain(chain);
+                        }
+                    }
+                    NodeEvent::MinedChain(chain) => {
+                        debug!(
+                            "[#{:05}] Submitting a new block: {:?}, height {}",
+                            node_id,
+                            chain.head().hash(),
+                            chain.height()
+                        );
+                        recorder.mined(&chain);
+                        // Keep mining on it until the operator assigns new work.
+                        recorder.adopted(&chain);
+                        updater.mine_new_chain(chain.clone());
+
+                        match operator {
+                            Some(ref mut operator) => {
+                                operator.submit(chain);
+                            }
+                            None => error!("[#{:05}] Not connected to the pool operator.", node_id),
+                        }
+                    }
+                    // Pool members do not take part in the relay of chains.
+                    NodeEvent::ChainRemoteUpdate(..)
+                    | NodeEvent::Submitted(..)
+                    | NodeEvent::Relay(..)
+                    | NodeEvent::Validated(..)
+                    | NodeEvent::Heartbeat(..)
+                    | NodeEvent::PeerClosed(..)
+                    | NodeEvent::RequestTimeouts => {}
+                }
+
+                future::ok(())
+            });
+
+        Box::new(routing_future)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_lay_out_the_pools() {
+        let pools = Pool::layout(10, 2, 3).unwrap();
+        assert_eq!(
+            vec![
+                Pool {
+                    operator_id: 2,
+                    member_ids: vec![3, 4, 5],
+                },
+                Pool {
+                    operator_id: 6,
+                    member_ids: vec![7, 8, 9],
+                },
+            ],
+            pools
+        );
+        assert!(pools[0].contains(2) && pools[0].contains(5) && !pools[0].contains(6));
+
+        assert_eq!(None, Pool::layout(10, 3, 3));
+    }
+}
@@ -3,9 +3,12 @@ use std::cmp::Ordering;
 use std::fmt::Debug;
 use std::fmt::Error;
 use std::fmt::Formatter;
+use std::hash::Hasher;
 use std::u8::MAX as U8_MAX;
 
 const DIFFICULTY_BYTES_LEN: usize = SHA256_OUTPUT_LEN;
+const DIFFICULTY_LIMBS_LEN: usize = DIFFICULTY_BYTES_LEN / 4;
+
 #[derive(Clone, PartialEq, Eq)]
 pub struct Difficulty {
     threshold: [u8; SHA256_OUTPUT_LEN],
@@ -17,6 +20,14 @@ impl Difficulty {
         Difficulty { threshold: array }
     }
 
+    pub fn from_threshold(threshold: [u8; SHA256_OUTPUT_LEN]) -> Difficulty {
+        Difficulty { threshold }
+    }
+
+    pub fn threshold(&self) -> &[u8] {
+        &self.threshold
+    }
+
     pub fn increase(&mut self) {
         self.divide_threshold_by_two()
     }
@@ -45,6 +56,54 @@ impl Difficulty {
             self.threshold[next_index] = U8_MAX / 2;
         }
     }
+
+    /// The expected number of hashes to compute before finding one below the threshold.
+    pub fn expected_hashes(&self) -> f64 {
+        let threshold = self.threshold
+            .iter()
+            .fold(0f64, |number, byte| number * 256f64 + f64::from(*byte));
+        2f64.powi(8 * DIFFICULTY_BYTES_LEN as i32) / (threshold + 1f64)
+    }
+
+    /// Multiplies the threshold by `numerator / denominator`. A ratio above 1 makes mining
+    /// easier, the threshold cannot exceed the minimum difficulty nor drop to zero.
+    pub fn scale(&mut self, numerator: u64, denominator: u64) {
+        assert!(denominator > 0, "Cannot scale the difficulty by a zero denominator.");
+
+        // The threshold is a big-endian 256 bits number, it is handled as 32 bits limbs with
+        // two more limbs to hold the overflow of the multiplication.
+        let mut limbs = [0u32; DIFFICULTY_LIMBS_LEN + 2];
+        for (index, chunk) in self.threshold.chunks(4).enumerate() {
+            limbs[index + 2] = read_u32(chunk);
+        }
+
+        let mut carry = 0u128;
+        for limb in limbs.iter_mut().rev() {
+            let product = u128::from(*limb) * u128::from(numerator) + carry;
+            *limb = product as u32;
+            carry = product >> 32;
+        }
+
+        let mut remainder = 0u128;
+        for limb in limbs.iter_mut() {
+            let dividend = (remainder << 32) | u128::from(*limb);
+            *limb = (dividend / u128::from(denominator)) as u32;
+            remainder = dividend % u128::from(denominator);
+        }
+
+        if limbs[0] != 0 || limbs[1] != 0 {
+            *self = Difficulty::min_difficulty();
+            return;
+        }
+
+        for (index, limb) in limbs[2..].iter().enumerate() {
+            write_u32(&mut self.threshold, *limb, index * 4);
+        }
+
+        if self.threshold.iter().all(|byte| *byte == 0) {
+            self.threshold[DIFFICULTY_BYTES_LEN - 1] = 1;
+        }
+    }
 }
 
 impl Debug for Difficulty {
@@ -64,12 +123,14 @@ impl Hash {
         nonce: &Nonce,
         difficulty: &Difficulty,
         height: u32,
+        timestamp: u64,
         previous_hash: &[u8],
     ) -> Hash {
         let difficulty_bytes = difficulty.threshold.as_ref();
         let mut data_to_hash = [0u8; 8 // Length of the nonce field.
             + 4 // Length of the node_id field.
             + 4 // Length of the height field.
+            + 8 // Length of the timestamp field.
             + SHA256_OUTPUT_LEN // Length of the hash.
             + DIFFICULTY_BYTES_LEN];
 
@@ -78,8 +139,10 @@ impl Hash {
         write_array(&mut data_to_hash, &nonce.0, 0);
         write_u32(&mut data_to_hash, node_id, 8);
         write_u32(&mut data_to_hash, height, 12);
-        write_array(&mut data_to_hash, &previous_hash, 16);
-        write_array(&mut data_to_hash, &difficulty_bytes, 16 + SHA256_OUTPUT_LEN);
+        write_u32(&mut data_to_hash, (timestamp >> 32) as u32, 16);
+        write_u32(&mut data_to_hash, timestamp as u32, 20);
+        write_array(&mut data_to_hash, previous_hash, 24);
+        write_array(&mut data_to_hash, difficulty_bytes, 24 + SHA256_OUTPUT_LEN);
 
         let digest = digest::digest(&SHA256, &data_to_hash);
 
@@ -109,6 +172,13 @@ fn write_u32(to_array: &mut [u8], number: u32, index: usize) {
     to_array[index + 3] = (number & 0xff) as u8;
 }
 
+fn read_u32(from_array: &[u8]) -> u32 {
+    (u32::from(from_array[0]) << 24)
+        | (u32::from(from_array[1]) << 16)
+        | (u32::from(from_array[2]) << 8)
+        | u32::from(from_array[3])
+}
+
 fn write_array(to_array: &mut [u8], array: &[u8], index: usize) {
     let array_len = array.len();
     to_array[index..(array_len + index)].clone_from_slice(&array[..array_len])
@@ -126,6 +196,26 @@ impl PartialEq for Hash {
     }
 }
 
+impl Eq for Hash {}
+
+impl PartialOrd for Hash {
+    fn partial_cmp(&self, other: &Hash) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Hash {
+    fn cmp(&self, other: &Hash) -> Ordering {
+        self.bytes().cmp(other.bytes())
+    }
+}
+
+impl ::std::hash::Hash for Hash {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.bytes().hash(state);
+    }
+}
+
 fn less_than_u8(one: &[u8], other: &[u8]) -> bool {
     // Still, we assume that `one` and `other` have the same length.
     let len = one.len();
@@ -148,6 +238,14 @@ impl Nonce {
         Nonce([0u8; 8])
     }
 
+    pub fn from_bytes(bytes: [u8; 8]) -> Nonce {
+        Nonce(bytes)
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.0
+    }
+
     pub fn increment(&mut self) {
         let mut index_to_increment = self.0.len() - 1;
 
@@ -185,7 +283,7 @@ mod tests {
         let mut nonce = Nonce::new();
         for _i in 0..100 {
             nonce.increment();
-            let hash = Hash::new(1, &nonce, &difficulty, 1, &[0u8; SHA256_OUTPUT_LEN]);
+            let hash = Hash::new(1, &nonce, &difficulty, 1, 0, &[0u8; SHA256_OUTPUT_LEN]);
             assert_eq!(true, hash.less_than(&difficulty));
         }
     }
@@ -202,7 +300,7 @@ mod tests {
         let mut nonce = Nonce::new();
         for _i in 0..number_of_tries {
             nonce.increment();
-            let hash = Hash::new(1, &nonce, &difficulty, 1, &[0u8; SHA256_OUTPUT_LEN]);
+            let hash = Hash::new(1, &nonce, &difficulty, 1, 0, &[0u8; SHA256_OUTPUT_LEN]);
 
             if hash.less_than(&difficulty) {
                 number_of_valid_hashes += 1;
@@ -212,4 +310,41 @@ mod tests {
         assert!(number_of_valid_hashes < number_of_tries / 7);
         assert!(number_of_valid_hashes > number_of_tries / 9);
     }
+
+    #[test]
+    fn can_compute_the_expected_number_of_hashes() {
+        let mut difficulty = Difficulty::min_difficulty();
+        assert_eq!(1f64, difficulty.expected_hashes());
+
+        difficulty.increase();
+        assert_eq!(2f64, difficulty.expected_hashes());
+
+        for _i in 0..6 {
+            difficulty.increase();
+        }
+        assert_eq!(128f64, difficulty.expected_hashes());
+    }
+
+    #[test]
+    fn can_scale_difficulty() {
+        let mut halved = Difficulty::min_difficulty();
+        halved.increase();
+
+        let mut scaled = Difficulty::min_difficulty();
+        scaled.scale(1, 2);
+        assert_eq!(halved, scaled);
+
+        let mut quartered = halved.clone();
+        quartered.increase();
+        scaled.scale(3, 6);
+        assert_eq!(quartered, scaled);
+
+        // Going back may lose the lowest bit.
+        scaled.scale(4, 1);
+        assert_eq!(0xff, scaled.threshold[0]);
+        assert_eq!(0xfc, scaled.threshold[SHA256_OUTPUT_LEN - 1]);
+
+        scaled.scale(1000, 1);
+        assert_eq!(Difficulty::min_difficulty(), scaled);
+    }
 }
@@ -0,0 +1,471 @@
+use blockchain::message::measure;
+use blockchain::{BlockTree, Chain, Hash, Reorg};
+use netsim::network::multiplex::ProtocolId;
+use netsim::network::stop::Stop;
+use netsim::network::topology::{NodeAttributes, Topology};
+use netsim::testing::Probe;
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::{self, Debug, Formatter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Records what happens on every node during a run, so it can be analysed once the network
+/// stops. Every node gets its own `NodeRecorder`, the shared lock is only taken when a
+/// node is registered.
+#[derive(Clone)]
+pub struct Recorder {
+    start: Instant,
+    nodes: Arc<Mutex<BTreeMap<u32, Arc<Mutex<NodeRecord>>>>>,
+    traffic: Traffic,
+    /// Stops the network once a node adopts a chain of this height.
+    stop_at_height: Option<(u32, Stop)>,
+    /// Stops the miners of every node, the network keeps relaying.
+    mining_halt: Stop,
+    /// The chains received by the nodes and still being validated.
+    pending_validations: Arc<AtomicUsize>,
+}
+
+impl Recorder {
+    pub fn new() -> Recorder {
+        Recorder::with_block_payload_size(0)
+    }
+
+    /// A recorder measuring the traffic as if every block carried this many bytes of
+    /// transactions.
+    pub fn with_block_payload_size(block_payload_size: u64) -> Recorder {
+        Recorder {
+            start: Instant::now(),
+            nodes: Arc::new(Mutex::new(BTreeMap::new())),
+            traffic: Traffic::new(block_payload_size),
+            stop_at_height: None,
+            mining_halt: Stop::new(),
+            pending_validations: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Stops the network once a node adopts a chain of the given height, the blocks at this
+    /// height still propagating.
+    pub fn stop_at_height(mut self, height: u32, stop: Stop) -> Recorder {
+        self.stop_at_height = Some((height, stop));
+        self
+    }
+
+    pub fn traffic(&self) -> &Traffic {
+        &self.traffic
+    }
+
+    /// Halts the miners of every node, see `NodeRecorder::mining_halt`.
+    pub fn mining_halt(&self) -> Stop {
+        self.mining_halt.clone()
+    }
+
+    /// The number of chains received by the nodes and still being validated.
+    pub fn pending_validations(&self) -> usize {
+        self.pending_validations.load(Ordering::Relaxed)
+    }
+
+    pub fn node(&self, node_id: u32, genesis_chain: Arc<Chain>) -> NodeRecorder {
+        self.node_from(node_id, genesis_chain.clone(), genesis_chain)
+    }
+
+    /// The recorder of a node starting from a chain that extends the root, the last block
+    /// shared by the chains every node starts from.
+    pub fn node_from(&self, node_id: u32, root: Arc<Chain>, chain: Arc<Chain>) -> NodeRecorder {
+        let root_height = root.height();
+        let starting_blocks = chain
+            .iter()
+            .take_while(|chain| chain.height() > root_height)
+            .map(|chain| chain.head().hash().clone())
+            .collect();
+        let mut block_tree = BlockTree::new(root);
+        block_tree.set_best(&chain, Duration::from_secs(0));
+        let record = Arc::new(Mutex::new(NodeRecord {
+            node_id,
+            block_tree,
+            best_chain: chain,
+            blocks_mined: 0,
+            forks_seen: 0,
+            reorgs: vec![],
+            round_trip_times: vec![],
+            dead_peers_dropped: 0,
+            dead_peers_restored: 0,
+            peers: BTreeSet::new(),
+            deliveries: HashMap::new(),
+        }));
+
+        self.nodes
+            .lock()
+            .unwrap()
+            .insert(node_id, record.clone());
+
+        NodeRecorder {
+            start: self.start,
+            record,
+            traffic: self.traffic.clone(),
+            stop_at_height: self.stop_at_height.clone(),
+            mining_halt: self.mining_halt.clone(),
+            pending_validations: self.pending_validations.clone(),
+            root_height,
+            starting_blocks: Arc::new(starting_blocks),
+        }
+    }
+
+    /// A copy of the records of every node, sorted by node id.
+    pub fn records(&self) -> Vec<NodeRecord> {
+        self.nodes
+            .lock()
+            .unwrap()
+            .values()
+            .map(|record| record.lock().unwrap().clone())
+            .collect()
+    }
+}
+
+impl Default for Recorder {
+    fn default() -> Recorder {
+        Recorder::new()
+    }
+}
+
+#[derive(Clone)]
+pub struct NodeRecord {
+    pub node_id: u32,
+    pub best_chain: Arc<Chain>,
+    pub blocks_mined: u32,
+    pub forks_seen: u32,
+    /// Every switch of the node to a chain that was not an extension of its best chain.
+    pub reorgs: Vec<Reorg>,
+    /// The round-trip times to the peers measured by the heartbeat, if the node monitors them.
+    pub round_trip_times: Vec<Duration>,
+    /// The peers the node stopped relaying to because they did not answer its pings.
+    pub dead_peers_dropped: u32,
+    /// The dropped peers the node relays to again because they answered a ping later on.
+    pub dead_peers_restored: u32,
+    /// The peers the node is connected to.
+    pub peers: BTreeSet<u32>,
+    /// When and from which peer every block received or mined by the node during the run was
+    /// first received, the blocks it started from left out.
+    pub deliveries: HashMap<Hash, Delivery>,
+    /// Every block known by the node, the side branches included.
+    pub block_tree: BlockTree,
+}
+
+impl Debug for NodeRecord {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "height: {}, head: {:?}, blocks mined: {}, forks seen: {}, reorgs: {}, blocks known: {}, stale blocks: {}",
+            self.best_chain.height(),
+            self.best_chain.head().hash(),
+            self.blocks_mined,
+            self.forks_seen,
+            self.reorgs.len(),
+            self.deliveries.len(),
+            self.block_tree.stale_blocks()
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Delivery {
+    /// The peer that delivered the block first, `None` for the blocks mined by the node.
+    pub from: Option<u32>,
+    /// The time elapsed between the start of the run and the delivery.
+    pub at: Duration,
+    pub height: u32,
+}
+
+#[derive(Clone)]
+pub struct NodeRecorder {
+    start: Instant,
+    record: Arc<Mutex<NodeRecord>>,
+    traffic: Traffic,
+    stop_at_height: Option<(u32, Stop)>,
+    mining_halt: Stop,
+    pending_validations: Arc<AtomicUsize>,
+    /// The height of the root, the blocks up to it were mined before the run.
+    root_height: u32,
+    /// The blocks above the root the node started with, mined before the run too.
+    starting_blocks: Arc<HashSet<Hash>>,
+}
+
+impl NodeRecorder {
+    pub fn traffic(&self) -> Traffic {
+        self.traffic.clone()
+    }
+
+    /// Stopped once the miners must stop, for the network to settle before the end of a run.
+    pub fn mining_halt(&self) -> Stop {
+        self.mining_halt.clone()
+    }
+
+    pub fn mined(&self, chain: &Arc<Chain>) {
+        let mut record = self.record.lock().unwrap();
+        record.blocks_mined += 1;
+        self.deliver(&mut record, chain, None);
+    }
+
+    /// Records the delivery of the blocks of a valid chain received from a peer.
+    pub fn received(&self, chain: &Arc<Chain>, from: u32) {
+        let mut record = self.record.lock().unwrap();
+        self.deliver(&mut record, chain, Some(from));
+    }
+
+    pub fn adopted(&self, chain: &Arc<Chain>) {
+        let mut record = self.record.lock().unwrap();
+        record.best_chain = chain.clone();
+        let at = self.start.elapsed();
+        record.block_tree.set_best(chain, at);
+
+        if let Some((height, ref stop)) = self.stop_at_height {
+            if chain.height() >= height && !stop.is_stopped() {
+                info!("[#{:05}] Reached the stop height {}", record.node_id, height);
+                stop.stop();
+            }
+        }
+    }
+
+    pub fn reorg(&self, reorg: Reorg) {
+        self.record.lock().unwrap().reorgs.push(reorg);
+    }
+
+    pub fn fork_seen(&self) {
+        self.record.lock().unwrap().forks_seen += 1;
+    }
+
+    pub fn round_trip(&self, rtt: Duration) {
+        self.record.lock().unwrap().round_trip_times.push(rtt);
+    }
+
+    pub fn dead_peer_dropped(&self) {
+        self.record.lock().unwrap().dead_peers_dropped += 1;
+    }
+
+    pub fn dead_peer_restored(&self) {
+        self.record.lock().unwrap().dead_peers_restored += 1;
+    }
+
+    pub fn peer_connected(&self, peer_id: u32) {
+        self.record.lock().unwrap().peers.insert(peer_id);
+    }
+
+    pub fn peer_closed(&self, peer_id: u32) {
+        self.record.lock().unwrap().peers.remove(&peer_id);
+    }
+
+    pub fn validation_started(&self) {
+        self.pending_validations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn validation_ended(&self) {
+        self.pending_validations.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// A probe on the record, for the test harness of the network simulator.
+    pub fn probe(&self) -> Probe<NodeRecord> {
+        Probe::shared(self.record.clone())
+    }
+
+    fn deliver(&self, record: &mut NodeRecord, chain: &Arc<Chain>, from: Option<u32>) {
+        let at = self.start.elapsed();
+        record.block_tree.insert(chain, at);
+
+        // Most of the time, only the head of the chain is new. The blocks the node started
+        // from are not deliveries.
+        for chain in chain.iter() {
+            let hash = chain.head().hash();
+            if chain.height() <= self.root_height
+                || self.starting_blocks.contains(hash)
+                || record.deliveries.contains_key(hash)
+            {
+                break;
+            }
+
+            record.deliveries.insert(
+                hash.clone(),
+                Delivery {
+                    from,
+                    at,
+                    height: chain.height(),
+                },
+            );
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct MessageStats {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+/// Counts the messages sent by the nodes and their size, by message type.
+#[derive(Clone)]
+pub struct Traffic {
+    block_payload_size: u64,
+    stats: Arc<Mutex<BTreeMap<&'static str, MessageStats>>>,
+}
+
+impl Traffic {
+    pub fn new(block_payload_size: u64) -> Traffic {
+        Traffic {
+            block_payload_size,
+            stats: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// Counts a message sent on one of the sub-channels of a connection.
+    pub fn sent(&self, protocol: ProtocolId, message: &(dyn Any + Send + Sync)) {
+        let (kind, size) = match measure(protocol, message, self.block_payload_size) {
+            Some(kind_and_size) => kind_and_size,
+            None => return,
+        };
+
+        let mut stats = self.stats.lock().unwrap();
+        let message_stats = stats.entry(kind).or_default();
+        message_stats.messages += 1;
+        message_stats.bytes += size;
+    }
+
+    /// The messages sent so far, by message type.
+    pub fn stats(&self) -> BTreeMap<&'static str, MessageStats> {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// The number of messages sent so far, those of the heartbeat excluded.
+    pub fn relayed_messages(&self) -> u64 {
+        self.stats
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(kind, _stats)| **kind != "ping" && **kind != "pong")
+            .map(|(_kind, stats)| stats.messages)
+            .sum()
+    }
+}
+
+/// The hash and height of every block mined during the run, by miner.
+pub fn mined_blocks(records: &[NodeRecord]) -> Vec<(u32, Hash, u32)> {
+    let mut mined_blocks = vec![];
+
+    for record in records {
+        for (hash, delivery) in &record.deliveries {
+            if delivery.from.is_none() {
+                mined_blocks.push((record.node_id, hash.clone(), delivery.height));
+            }
+        }
+    }
+
+    mined_blocks.sort_by_key(|(node_id, _hash, height)| (*height, *node_id));
+    mined_blocks
+}
+
+/// The propagation tree of a block: an edge goes from the peer that first delivered the
+/// block to the node that received it. The attributes give the delivery time of every node.
+pub fn propagation_tree(records: &[NodeRecord], hash: &Hash) -> (Topology, NodeAttributes) {
+    let mut nodes = vec![];
+    let mut edges = vec![];
+    let mut attributes = NodeAttributes::new();
+
+    for record in records {
+        if let Some(delivery) = record.deliveries.get(hash) {
+            nodes.push(record.node_id);
+            if let Some(from) = delivery.from {
+                edges.push((from, record.node_id));
+            }
+
+            let mut node_attributes = BTreeMap::new();
+            node_attributes.insert(
+                "received_at_ms".to_owned(),
+                duration_as_millis(delivery.at),
+            );
+            attributes.insert(record.node_id, node_attributes);
+        }
+    }
+
+    (Topology::directed(nodes, edges), attributes)
+}
+
+/// The final chain height, the number of blocks mined and the number of forks seen by every node.
+pub fn node_attributes(records: &[NodeRecord]) -> NodeAttributes {
+    records
+        .iter()
+        .map(|record| {
+            let mut node_attributes = BTreeMap::new();
+            node_attributes.insert(
+                "height".to_owned(),
+                u64::from(record.best_chain.height()),
+            );
+            node_attributes.insert("blocks_mined".to_owned(), u64::from(record.blocks_mined));
+            node_attributes.insert("forks_seen".to_owned(), u64::from(record.forks_seen));
+            (record.node_id, node_attributes)
+        })
+        .collect()
+}
+
+/// The strongest of the best chains of the given nodes.
+pub fn strongest_chain<'a, I>(records: I) -> Option<&'a Arc<Chain>>
+where
+    I: Iterator<Item = &'a NodeRecord>,
+{
+    records
+        .map(|record| &record.best_chain)
+        .fold(None, |best: Option<&Arc<Chain>>, chain| match best {
+            Some(best) if !chain.stronger_than(best) => Some(best),
+            _ => Some(chain),
+        })
+}
+
+/// The number of reorgs of every depth experienced by the given nodes.
+pub fn reorg_histogram<'a, I>(records: I) -> BTreeMap<u32, u32>
+where
+    I: Iterator<Item = &'a NodeRecord>,
+{
+    let mut histogram = BTreeMap::new();
+    for record in records {
+        for reorg in &record.reorgs {
+            *histogram.entry(reorg.depth).or_insert(0) += 1;
+        }
+    }
+    histogram
+}
+
+/// How long before the median of the other nodes the given node received the block, in
+/// milliseconds. The `excluded` nodes are left out of the median too. Returns `None` if the
+/// node or none of the others received the block.
+pub fn head_start(
+    records: &[NodeRecord],
+    hash: &Hash,
+    node_id: u32,
+    excluded: &[u32],
+) -> Option<i64> {
+    let received_at = |record: &NodeRecord| {
+        record
+            .deliveries
+            .get(hash)
+            .map(|delivery| duration_as_millis(delivery.at) as i64)
+    };
+
+    let node_received_at = records
+        .iter()
+        .find(|record| record.node_id == node_id)
+        .and_then(&received_at)?;
+
+    let mut others_received_at: Vec<i64> = records
+        .iter()
+        .filter(|record| record.node_id != node_id && !excluded.contains(&record.node_id))
+        .filter_map(&received_at)
+        .collect();
+    if others_received_at.is_empty() {
+        return None;
+    }
+
+    others_received_at.sort();
+    Some(others_received_at[others_received_at.len() / 2] - node_received_at)
+}
+
+fn duration_as_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
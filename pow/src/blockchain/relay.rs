@@ -0,0 +1,455 @@
+use blockchain::{Block, Chain, Clock, Hash, RelayMessage};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The maximum number of headers sent in reply to a single request, as in Bitcoin. The rest
+/// is requested again at the next announcement.
+const MAX_HEADERS: usize = 2000;
+/// The number of hashes of a locator before the steps between them start doubling.
+const LOCATOR_DENSE_HASHES: u32 = 10;
+/// How long a peer has to answer a request for headers or blocks before the request is
+/// given up, and the blocks requested from another peer.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// The maximum number of blocks received before their parent that are kept, the oldest are
+/// dropped first.
+const MAX_ORPHANS: usize = 100;
+
+/// How the nodes relay the chains to their peers.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayProtocol {
+    /// The whole chain is sent to the peers, which validate it entirely.
+    FullChain,
+    /// The new blocks are announced by hash, the peers download the headers they miss and
+    /// then the blocks. Only the new blocks are validated.
+    HeadersFirst,
+}
+
+/// The state of the headers-first relay of a node, independent from the network. The peers
+/// are identified by their node id.
+pub struct HeadersFirstRelay {
+    /// Every valid chain known by the node, by the hash of its head.
+    known: HashMap<Hash, Arc<Chain>>,
+    /// The announced blocks the headers were requested for.
+    announced: HashMap<Hash, InFlight>,
+    /// The blocks requested and not received yet.
+    requested: HashMap<Hash, InFlight>,
+    /// The peers that sent the headers of the blocks requested, which can be asked for the
+    /// blocks again.
+    providers: HashMap<Hash, Vec<u32>>,
+    /// The blocks received before their parent, from the oldest to the latest.
+    orphans: Vec<Block>,
+}
+
+/// A request sent to a peer, waiting for its answer.
+struct InFlight {
+    peer_id: u32,
+    deadline: Instant,
+}
+
+impl HeadersFirstRelay {
+    pub fn new(genesis_chain: &Arc<Chain>) -> HeadersFirstRelay {
+        let mut known = HashMap::new();
+        known.insert(genesis_chain.head().hash().clone(), genesis_chain.clone());
+
+        HeadersFirstRelay {
+            known,
+            announced: HashMap::new(),
+            requested: HashMap::new(),
+            providers: HashMap::new(),
+            orphans: vec![],
+        }
+    }
+
+    /// Makes the blocks of a valid chain available to the peers.
+    pub fn insert(&mut self, chain: &Arc<Chain>) {
+        let mut next = Some(chain.clone());
+        while let Some(chain) = next {
+            if self.known.contains_key(chain.head().hash()) {
+                break;
+            }
+            next = chain.tail.clone();
+            self.known.insert(chain.head().hash().clone(), chain);
+        }
+    }
+
+    /// Handles a message of the relay received from a peer, at the instant `now`. Returns the
+    /// messages to send, along with the id of their recipient, and the new valid chains,
+    /// ending with the blocks received.
+    pub fn receive(
+        &mut self,
+        peer_id: u32,
+        message: RelayMessage,
+        best_chain: &Arc<Chain>,
+        clock: &Clock,
+        now: Instant,
+    ) -> (Vec<(u32, RelayMessage)>, Vec<Arc<Chain>>) {
+        let deadline = now + REQUEST_TIMEOUT;
+        match message {
+            RelayMessage::Inv(hashes) => {
+                let mut is_new = false;
+                for hash in hashes {
+                    if !self.known.contains_key(&hash)
+                        && !self.requested.contains_key(&hash)
+                        && !self.announced.contains_key(&hash)
+                    {
+                        self.announced.insert(hash, InFlight { peer_id, deadline });
+                        is_new = true;
+                    }
+                }
+
+                if is_new {
+                    (vec![(peer_id, RelayMessage::GetHeaders(locator(best_chain)))], vec![])
+                } else {
+                    (vec![], vec![])
+                }
+            }
+            RelayMessage::GetHeaders(locator) => {
+                let headers = self.headers_after(&locator, best_chain);
+                if headers.is_empty() {
+                    (vec![], vec![])
+                } else {
+                    (vec![(peer_id, RelayMessage::Headers(headers))], vec![])
+                }
+            }
+            RelayMessage::Headers(headers) => {
+                let mut missing = vec![];
+                for header in headers {
+                    let hash = header.hash();
+                    self.announced.remove(hash);
+                    if self.known.contains_key(hash) {
+                        continue;
+                    }
+
+                    let providers = self.providers.entry(hash.clone()).or_default();
+                    if !providers.contains(&peer_id) {
+                        providers.push(peer_id);
+                    }
+                    if !self.requested.contains_key(hash) {
+                        self.requested.insert(hash.clone(), InFlight { peer_id, deadline });
+                        missing.push(hash.clone());
+                    }
+                }
+
+                if missing.is_empty() {
+                    (vec![], vec![])
+                } else {
+                    (vec![(peer_id, RelayMessage::GetData(missing))], vec![])
+                }
+            }
+            RelayMessage::GetData(hashes) => {
+                let blocks = hashes
+                    .iter()
+                    .filter_map(|hash| self.known.get(hash))
+                    .map(|chain| (peer_id, RelayMessage::Block(chain.head().clone())))
+                    .collect();
+                (blocks, vec![])
+            }
+            RelayMessage::Block(block) => (vec![], self.connect(block, clock)),
+        }
+    }
+
+    /// Gives up the requests whose deadline passed at the instant `now`, and requests the
+    /// blocks again from the other peers that sent their headers. Returns the requests to
+    /// send, along with the id of their recipient. The announcements given up are requested
+    /// again at the next one.
+    pub fn expire(&mut self, now: Instant) -> Vec<(u32, RelayMessage)> {
+        self.announced.retain(|_hash, in_flight| in_flight.deadline > now);
+        let expired: Vec<(Hash, u32)> = self
+            .requested
+            .iter()
+            .filter(|(_hash, in_flight)| in_flight.deadline <= now)
+            .map(|(hash, in_flight)| (hash.clone(), in_flight.peer_id))
+            .collect();
+        self.request_again(expired, now)
+    }
+
+    /// Gives up the requests sent to a peer that was dropped, and requests the blocks again
+    /// from the other peers that sent their headers, at the instant `now`. Returns the
+    /// requests to send, along with the id of their recipient.
+    pub fn peer_dropped(&mut self, peer_id: u32, now: Instant) -> Vec<(u32, RelayMessage)> {
+        self.announced.retain(|_hash, in_flight| in_flight.peer_id != peer_id);
+        for providers in self.providers.values_mut() {
+            providers.retain(|provider_id| *provider_id != peer_id);
+        }
+        let dropped: Vec<(Hash, u32)> = self
+            .requested
+            .iter()
+            .filter(|(_hash, in_flight)| in_flight.peer_id == peer_id)
+            .map(|(hash, in_flight)| (hash.clone(), in_flight.peer_id))
+            .collect();
+        self.request_again(dropped, now)
+    }
+
+    /// Requests the blocks from the next peer that sent their headers, the one that failed
+    /// to answer being forgotten. The blocks nobody else can provide are forgotten, so that
+    /// the next announcement requests them again.
+    fn request_again(
+        &mut self,
+        failed: Vec<(Hash, u32)>,
+        now: Instant,
+    ) -> Vec<(u32, RelayMessage)> {
+        let mut requests: HashMap<u32, Vec<Hash>> = HashMap::new();
+        for (hash, failed_peer_id) in failed {
+            self.requested.remove(&hash);
+            let next_peer_id = match self.providers.get_mut(&hash) {
+                Some(providers) => {
+                    providers.retain(|peer_id| *peer_id != failed_peer_id);
+                    providers.first().cloned()
+                }
+                None => None,
+            };
+
+            match next_peer_id {
+                Some(peer_id) => {
+                    let deadline = now + REQUEST_TIMEOUT;
+                    self.requested.insert(hash.clone(), InFlight { peer_id, deadline });
+                    requests.entry(peer_id).or_default().push(hash);
+                }
+                None => {
+                    self.providers.remove(&hash);
+                }
+            }
+        }
+
+        requests
+            .into_iter()
+            .map(|(peer_id, hashes)| (peer_id, RelayMessage::GetData(hashes)))
+            .collect()
+    }
+
+    /// The headers of the best chain following the fork point with the chain described by
+    /// the locator.
+    fn headers_after(&self, locator: &[Hash], best_chain: &Arc<Chain>) -> Vec<Block> {
+        let fork_height = locator
+            .iter()
+            .filter_map(|hash| self.known.get(hash))
+            .find(|chain| {
+                Chain::ancestor(best_chain, chain.height()).as_ref() == Some(*chain)
+            })
+            .map(|chain| chain.height())
+            .unwrap_or(0);
+
+        let mut headers: Vec<Block> = best_chain
+            .iter()
+            .take_while(|chain| chain.height() > fork_height)
+            .map(|chain| chain.head().clone())
+            .collect();
+        headers.reverse();
+        headers.truncate(MAX_HEADERS);
+        headers
+    }
+
+    /// Validates the block against its parent, along with the orphans waiting for it. A
+    /// block received before its parent is kept if its proof of work is valid.
+    fn connect(&mut self, block: Block, clock: &Clock) -> Vec<Arc<Chain>> {
+        self.requested.remove(block.hash());
+        self.announced.remove(block.hash());
+        self.providers.remove(block.hash());
+        if self.known.contains_key(block.hash()) {
+            return vec![];
+        }
+
+        let parent = match self.known.get(block.previous_block_hash()) {
+            Some(parent) => parent.clone(),
+            None => {
+                if let Err(err) = block.validate() {
+                    error!("Invalid orphan block: {}", err);
+                } else if !self.orphans.iter().any(|orphan| orphan.hash() == block.hash()) {
+                    if self.orphans.len() >= MAX_ORPHANS {
+                        self.orphans.remove(0);
+                    }
+                    self.orphans.push(block);
+                }
+                return vec![];
+            }
+        };
+
+        let mut chains = vec![];
+        let mut to_connect = vec![(parent, block)];
+        while let Some((parent, block)) = to_connect.pop() {
+            let expanded = clock
+                .check_future_drift(&block)
+                .and_then(|()| Chain::expand(&parent, block));
+            match expanded {
+                Ok(chain) => {
+                    let hash = chain.head().hash().clone();
+                    self.known.insert(hash, chain.clone());
+                    let (children, orphans) = self
+                        .orphans
+                        .drain(..)
+                        .partition(|orphan| orphan.previous_block_hash() == chain.head().hash());
+                    self.orphans = orphans;
+                    for child in children {
+                        to_connect.push((chain.clone(), child));
+                    }
+                    chains.push(chain);
+                }
+                Err(err) => error!("Invalid block: {}", err),
+            }
+        }
+
+        chains
+    }
+}
+
+/// The hashes of some blocks of the chain, from the head to the genesis block: the latest
+/// ones, then with exponentially growing steps, like Bitcoin block locators.
+fn locator(chain: &Arc<Chain>) -> Vec<Hash> {
+    let mut hashes = vec![];
+    let mut step = 1;
+    let mut next_height = chain.height();
+
+    for chain in chain.iter() {
+        if chain.height() == next_height || chain.height() == 0 {
+            hashes.push(chain.head().hash().clone());
+            if hashes.len() as u32 >= LOCATOR_DENSE_HASHES {
+                step *= 2;
+            }
+            next_height = next_height.saturating_sub(step);
+        }
+    }
+
+    hashes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::pow::Nonce;
+    use blockchain::{unix_time_millis, Difficulty};
+    use std::time::Duration;
+
+    fn mine(chain: &Arc<Chain>, node_id: u32) -> Arc<Chain> {
+        let mut nonce = Nonce::new();
+        loop {
+            nonce.increment();
+            let block = Block::new(
+                node_id,
+                nonce.clone(),
+                &chain.next_difficulty(),
+                chain.head().hash().clone(),
+                chain.height() + 1,
+                unix_time_millis().max(chain.median_time_past() + 1),
+            );
+            if let Ok(chain) = Chain::expand(chain, block) {
+                return chain;
+            }
+        }
+    }
+
+    #[test]
+    fn downloads_the_missing_blocks() {
+        let clock = Clock::new(0, Duration::from_secs(7200));
+        let now = Instant::now();
+        let genesis = Arc::new(Chain::init_new(Difficulty::min_difficulty(), None));
+        let mut chain = genesis.clone();
+        for _i in 0..3 {
+            chain = mine(&chain, 0);
+        }
+
+        let mut sender = HeadersFirstRelay::new(&genesis);
+        sender.insert(&chain);
+        let mut receiver = HeadersFirstRelay::new(&genesis);
+        let exchange = |relay: &mut HeadersFirstRelay, peer_id, message, best_chain| {
+            let (replies, chains) = relay.receive(peer_id, message, best_chain, &clock, now);
+            assert!(chains.is_empty());
+            replies.into_iter().map(|(_peer_id, reply)| reply).collect::<Vec<_>>()
+        };
+
+        // Announcement, then headers.
+        let inv = RelayMessage::Inv(vec![chain.head().hash().clone()]);
+        let mut replies = exchange(&mut receiver, 1, inv.clone(), &genesis);
+        assert_eq!(1, replies.len());
+        assert!(exchange(&mut receiver, 1, inv, &genesis).is_empty());
+
+        let mut replies = exchange(&mut sender, 2, replies.remove(0), &chain);
+        let mut replies = exchange(&mut receiver, 1, replies.remove(0), &genesis);
+        let mut blocks = exchange(&mut sender, 2, replies.remove(0), &chain);
+        assert_eq!(3, blocks.len());
+
+        // The blocks can be received in any order.
+        let last_block = blocks.remove(2);
+        blocks.insert(0, last_block);
+        let received: Vec<Arc<Chain>> = blocks
+            .into_iter()
+            .flat_map(|block| receiver.receive(1, block, &genesis, &clock, now).1)
+            .collect();
+        assert_eq!(3, received.len());
+        assert_eq!(Some(&chain), received.last());
+
+        // A locator goes from the head to the genesis block.
+        assert_eq!(chain.head().hash(), &locator(&chain)[0]);
+        assert_eq!(genesis.head().hash(), locator(&chain).last().unwrap());
+    }
+
+    #[test]
+    fn requests_the_blocks_again_from_another_peer() {
+        let clock = Clock::new(0, Duration::from_secs(7200));
+        let now = Instant::now();
+        let genesis = Arc::new(Chain::init_new(Difficulty::min_difficulty(), None));
+        let chain = mine(&mine(&genesis, 0), 0);
+        let headers = RelayMessage::Headers(
+            chain.iter().take(2).map(|chain| chain.head().clone()).collect(),
+        );
+        let requested_from = |requests: Vec<(u32, RelayMessage)>| -> Vec<(u32, usize)> {
+            requests
+                .into_iter()
+                .map(|(peer_id, request)| match request {
+                    RelayMessage::GetData(hashes) => (peer_id, hashes.len()),
+                    other => panic!("Unexpected request: {:?}", other),
+                })
+                .collect()
+        };
+
+        let mut relay = HeadersFirstRelay::new(&genesis);
+        let (requests, _chains) = relay.receive(1, headers.clone(), &genesis, &clock, now);
+        assert_eq!(vec![(1, 2)], requested_from(requests));
+        // The blocks are already requested from the first peer.
+        let (requests, _chains) = relay.receive(2, headers.clone(), &genesis, &clock, now);
+        assert!(requests.is_empty());
+        let (requests, _chains) = relay.receive(3, headers, &genesis, &clock, now);
+        assert!(requests.is_empty());
+
+        assert!(relay.expire(now + REQUEST_TIMEOUT / 2).is_empty());
+        let requests = relay.expire(now + REQUEST_TIMEOUT);
+        assert_eq!(vec![(2, 2)], requested_from(requests));
+        let requests = relay.peer_dropped(2, now + REQUEST_TIMEOUT);
+        assert_eq!(vec![(3, 2)], requested_from(requests));
+
+        // Nobody else can provide the blocks, they are forgotten.
+        assert!(relay.peer_dropped(3, now + REQUEST_TIMEOUT).is_empty());
+        assert!(relay.requested.is_empty());
+        assert!(relay.providers.is_empty());
+    }
+
+    #[test]
+    fn bounds_the_orphans() {
+        let clock = Clock::new(0, Duration::from_secs(7200));
+        let genesis = Arc::new(Chain::init_new(Difficulty::min_difficulty(), None));
+        let mut chain = mine(&genesis, 0);
+        let mut relay = HeadersFirstRelay::new(&genesis);
+        for _i in 0..MAX_ORPHANS + 1 {
+            chain = mine(&chain, 0);
+            relay.connect(chain.head().clone(), &clock);
+        }
+        assert_eq!(MAX_ORPHANS, relay.orphans.len());
+
+        // A block with an invalid proof of work is not kept.
+        let mut difficulty = Difficulty::min_difficulty();
+        for _i in 0..64 {
+            difficulty.increase();
+        }
+        let invalid_block = Block::new(
+            0,
+            Nonce::new(),
+            &Arc::new(difficulty),
+            chain.head().hash().clone(),
+            chain.height() + 1,
+            unix_time_millis(),
+        );
+        relay.connect(invalid_block.clone(), &clock);
+        assert!(!relay.orphans.iter().any(|orphan| orphan.hash() == invalid_block.hash()));
+    }
+}
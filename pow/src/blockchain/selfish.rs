@@ -0,0 +1,348 @@
+//! The selfish mining attack described by Eyal and Sirer in "Majority is not Enough: Bitcoin
+//! Mining is Vulnerable" (2014). The attacker keeps the blocks it mines on a private fork and
+//! releases them only to override the blocks of the honest nodes, wasting their work.
+
+use blockchain::node::{peer_events, validate_received_chain, NodeEvent, Peer, ValidBlocks};
+use blockchain::recorder::NodeRecorder;
+use blockchain::{mining_stream, Chain, Clock};
+use futures::{future, Future, Stream};
+use netsim::network::multiplex::{MultiplexedConnection, MultiplexedMessage};
+use netsim::network::Node;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// What the attacker does after receiving a stronger chain from the honest nodes.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reaction {
+    /// The chain is already known, nothing changes.
+    Ignore,
+    /// The honest chain is ahead, the attacker gives up its private fork and mines on it.
+    Adopt,
+    /// The attacker releases this part of its private fork.
+    Publish(Arc<Chain>),
+}
+
+/// The state machine of the attack, independent from the network. The constant difficulty
+/// makes the height a measure of the work.
+pub struct SelfishMining {
+    private_chain: Arc<Chain>,
+    /// The strongest chain known by the honest nodes, including the blocks released by the attacker.
+    public_chain: Arc<Chain>,
+    /// The number of blocks of the private fork that were not released yet, in a race or not.
+    private_branch_length: u32,
+}
+
+impl SelfishMining {
+    pub fn new(genesis_chain: Arc<Chain>) -> SelfishMining {
+        SelfishMining {
+            private_chain: genesis_chain.clone(),
+            public_chain: genesis_chain,
+            private_branch_length: 0,
+        }
+    }
+
+    /// The chain the attacker mines on.
+    pub fn private_chain(&self) -> &Arc<Chain> {
+        &self.private_chain
+    }
+
+    pub fn public_chain(&self) -> &Arc<Chain> {
+        &self.public_chain
+    }
+
+    /// The attacker mined a new block on its private fork. Returns the chain to release, if any.
+    pub fn mined(&mut self, chain: Arc<Chain>) -> Option<Arc<Chain>> {
+        let previous_lead = self.lead();
+        self.private_chain = chain;
+        self.private_branch_length += 1;
+
+        if previous_lead == 0 && self.private_branch_length == 2 {
+            // The attacker was racing the honest nodes and just won the race.
+            self.private_branch_length = 0;
+            let private_chain = self.private_chain.clone();
+            Some(self.publish(private_chain))
+        } else {
+            None
+        }
+    }
+
+    /// A chain was received from the honest nodes.
+    pub fn received(&mut self, chain: Arc<Chain>) -> Reaction {
+        if !chain.stronger_than(&self.public_chain) {
+            return Reaction::Ignore;
+        }
+
+        let is_private_block =
+            Chain::ancestor(&self.private_chain, chain.height()).as_ref() == Some(&chain);
+        self.public_chain = chain.clone();
+        if is_private_block {
+            // A released block coming back.
+            return Reaction::Ignore;
+        }
+
+        match self.lead() {
+            lead if lead < 0 => {
+                self.private_chain = chain;
+                self.private_branch_length = 0;
+                Reaction::Adopt
+            }
+            0 => {
+                // Same length, release the last block and race the honest nodes.
+                let private_chain = self.private_chain.clone();
+                Reaction::Publish(self.publish(private_chain))
+            }
+            1 => {
+                // The honest nodes almost caught up, release everything to win.
+                self.private_branch_length = 0;
+                let private_chain = self.private_chain.clone();
+                Reaction::Publish(self.publish(private_chain))
+            }
+            _ => {
+                // Still comfortably ahead, only override the new honest block.
+                let first_unpublished = Chain::ancestor(&self.private_chain, chain.height())
+                    .expect("The private chain is longer than the public chain.");
+                Reaction::Publish(self.publish(first_unpublished))
+            }
+        }
+    }
+
+    fn lead(&self) -> i64 {
+        i64::from(self.private_chain.height()) - i64::from(self.public_chain.height())
+    }
+
+    fn publish(&mut self, chain: Arc<Chain>) -> Arc<Chain> {
+        if chain.stronger_than(&self.public_chain) {
+            self.public_chain = chain.clone();
+        }
+        chain
+    }
+}
+
+/// The share of the blocks of the main chain a selfish miner with the hashrate share `alpha`
+/// is expected to get, `gamma` being the share of the honest nodes mining on its blocks
+/// during a race.
+pub fn expected_revenue_share(alpha: f64, gamma: f64) -> f64 {
+    let numerator = alpha * (1f64 - alpha).powi(2) * (4f64 * alpha + gamma * (1f64 - 2f64 * alpha))
+        - alpha.powi(3);
+    let denominator = 1f64 - alpha * (1f64 + (2f64 - alpha) * alpha);
+    numerator / denominator
+}
+
+/// The hashrate share above which selfish mining pays more than honest mining.
+pub fn profitability_threshold(gamma: f64) -> f64 {
+    (1f64 - gamma) / (3f64 - 2f64 * gamma)
+}
+
+/// A node mining selfishly. It relays the honest chains it adopts, like an honest node would.
+pub struct SelfishPowNode {
+    node_id: u32,
+    mining_attempt_delay: Duration,
+    hashrate: u32,
+    strategy: SelfishMining,
+    clock: Clock,
+    recorder: NodeRecorder,
+    valid_blocks: ValidBlocks,
+}
+
+impl SelfishPowNode {
+    pub fn new(
+        node_id: u32,
+        genesis_chain: Arc<Chain>,
+        mining_attempt_delay: Duration,
+        hashrate: u32,
+        clock: Clock,
+        recorder: NodeRecorder,
+    ) -> SelfishPowNode {
+        SelfishPowNode {
+            node_id,
+            mining_attempt_delay,
+            hashrate,
+            valid_blocks: ValidBlocks::new(&genesis_chain),
+            strategy: SelfishMining::new(genesis_chain),
+            clock,
+            recorder,
+        }
+    }
+
+    fn release(&self, chain: &Arc<Chain>, peers: &mut Vec<Peer>) {
+        info!(
+            "[#{:05}] Released the private chain up to height {}: {:?}",
+            self.node_id,
+            chain.height(),
+            chain.head().hash()
+        );
+
+        for peer in peers.iter_mut() {
+            peer.send(chain);
+        }
+        peers.retain(|peer| !peer.is_closed());
+    }
+}
+
+impl Node<MultiplexedMessage> for SelfishPowNode {
+    fn run<S>(mut self, connection_stream: S) -> Box<dyn Future<Item = (), Error = ()> + Send>
+    where
+        S: Stream<Item = MultiplexedConnection, Error = ()> + Send + 'static,
+    {
+        let (mining_stream, updater) = mining_stream(
+            self.node_id,
+            self.strategy.private_chain().clone(),
+            self.mining_attempt_delay,
+            self.hashrate,
+            self.clock,
+            self.recorder.mining_halt(),
+        );
+
+        let peer_stream = peer_events(
+            self.node_id,
+            self.strategy.private_chain().clone(),
+            self.recorder.clone(),
+            None,
+            connection_stream,
+        );
+
+        let mut peers = vec![];
+        let routing_future = peer_stream
+            .select(mining_stream.map(NodeEvent::MinedChain))
+            .for_each(move |node_event| {
+                match node_event {
+                    NodeEvent::Peer(mut peer) => {
+                        peer.send(self.strategy.public_chain());
+                        if !peer.is_closed() {
+                            peers.push(peer);
+                        }
+                    }
+                    NodeEvent::MinedChain(chain) => {
+                        info!(
+                            "[#{:05}] Mined a private block: {:?}, height {}",
+                            self.node_id,
+                            chain.head().hash(),
+                            chain.height()
+                        );
+                        self.recorder.mined(&chain);
+                        self.recorder.adopted(&chain);
+                        updater.mine_new_chain(chain.clone());
+
+                        if let Some(released) = self.strategy.mined(chain) {
+                            self.release(&released, &mut peers);
+                        }
+                    }
+                    NodeEvent::ChainRemoteUpdate(peer_id, chain) => {
+                        match validate_received_chain(&chain, &self.clock, &mut self.valid_blocks) {
+                            Ok(()) => {
+                                self.recorder.received(&chain, peer_id);
+                                match self.strategy.received(chain.clone()) {
+                                    Reaction::Ignore => {}
+                                    Reaction::Adopt => {
+                                        self.recorder.adopted(&chain);
+                                        updater.mine_new_chain(chain.clone());
+                                        for peer in peers.iter_mut() {
+                                            if chain.stronger_than(peer.last_known_chain()) {
+                                                peer.send(&chain);
+                                            }
+                                        }
+                                        peers.retain(|peer| !peer.is_closed());
+                                    }
+                                    Reaction::Publish(released) => {
+                                        self.release(&released, &mut peers);
+                                    }
+                                }
+                            }
+                            Err(err) => error!("Invalid chain: {}", err),
+                        }
+                    }
+                    // The selfish miner does not take part in pools and only relays whole chains.
+                    NodeEvent::Work(..)
+                    | NodeEvent::Submitted(..)
+                    | NodeEvent::Relay(..)
+                    | NodeEvent::Validated(..)
+                    | NodeEvent::Heartbeat(..)
+                    | NodeEvent::PeerClosed(..)
+                    | NodeEvent::RequestTimeouts => {}
+                }
+
+                future::ok(())
+            });
+
+        Box::new(routing_future)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::testing::expand;
+    use blockchain::Difficulty;
+
+    const ATTACKER: u32 = 0;
+    const HONEST: u32 = 1;
+
+    fn genesis() -> Arc<Chain> {
+        Arc::new(Chain::init_new(Difficulty::min_difficulty(), None))
+    }
+
+    #[test]
+    fn races_the_honest_nodes() {
+        let genesis = genesis();
+        let mut strategy = SelfishMining::new(genesis.clone());
+
+        let private = expand(&genesis, ATTACKER);
+        assert_eq!(None, strategy.mined(private.clone()).map(|chain| chain.height()));
+
+        // The honest nodes catch up, the attacker releases its block.
+        let honest = expand(&genesis, HONEST);
+        assert_eq!(Reaction::Publish(private.clone()), strategy.received(honest));
+
+        // The attacker wins the race by mining the next block.
+        let private = expand(&private, ATTACKER);
+        assert_eq!(Some(2), strategy.mined(private.clone()).map(|chain| chain.height()));
+        assert_eq!(&private, strategy.public_chain());
+    }
+
+    #[test]
+    fn overrides_the_honest_blocks_when_ahead() {
+        let genesis = genesis();
+        let mut strategy = SelfishMining::new(genesis.clone());
+
+        let mut private = genesis.clone();
+        for _i in 0..3 {
+            private = expand(&private, ATTACKER);
+            assert!(strategy.mined(private.clone()).is_none());
+        }
+
+        // Far ahead: only the block at the height of the honest chain is released.
+        let honest = expand(&genesis, HONEST);
+        match strategy.received(honest.clone()) {
+            Reaction::Publish(released) => assert_eq!(1, released.height()),
+            other => panic!("Unexpected reaction: {:?}", other),
+        }
+
+        // The released block coming back is ignored.
+        let echo = Chain::ancestor(&private, 1).unwrap();
+        assert_eq!(Reaction::Ignore, strategy.received(echo));
+
+        // The lead drops to one block: everything is released.
+        let honest = expand(&honest, HONEST);
+        assert_eq!(Reaction::Publish(private.clone()), strategy.received(honest.clone()));
+
+        // The honest nodes are now ahead, the attacker adopts their chain.
+        let honest = expand(&expand(&honest, HONEST), HONEST);
+        assert_eq!(Reaction::Adopt, strategy.received(honest.clone()));
+        assert_eq!(&honest, strategy.private_chain());
+    }
+
+    #[test]
+    fn reproduces_the_profitability_threshold() {
+        assert!((profitability_threshold(0f64) - 1f64 / 3f64).abs() < 1e-9);
+        assert!((profitability_threshold(0.5) - 0.25).abs() < 1e-9);
+        assert_eq!(0f64, profitability_threshold(1f64));
+
+        for gamma in &[0f64, 0.5, 1f64] {
+            let threshold = profitability_threshold(*gamma);
+            let at_threshold = expected_revenue_share(threshold, *gamma);
+            assert!((at_threshold - threshold).abs() < 1e-9);
+            assert!(expected_revenue_share(threshold + 0.05, *gamma) > threshold + 0.05);
+        }
+        assert!(expected_revenue_share(0.2, 0f64) < 0.2);
+    }
+}
@@ -0,0 +1,369 @@
+//! Chain files: a chain saved at the end of a run, to be loaded as the starting state of
+//! another one.
+//!
+//! A file starts with a header, followed by the blocks from the genesis block up, each in a
+//! record of `BLOCK_HEADER_SIZE` bytes, and ends with an index of the blocks sorted by hash.
+//! All the numbers are big-endian.
+//!
+//! ```text
+//! header: magic (8) | version (4) | number of blocks (4) | retarget (17) | index offset (8)
+//! block:  height (4) | node id (4) | timestamp (8) | nonce (8) | difficulty (32)
+//!         | previous block hash (32) | hash (32)
+//! index:  hash (32) | height (4), for every block
+//! ```
+//!
+//! The blocks are validated again when loaded, and checked against the index: a file cannot
+//! forge a chain.
+
+use blockchain::pow::Nonce;
+use blockchain::{Block, Chain, Difficulty, Hash, Retarget, BLOCK_HEADER_SIZE};
+use ring::digest::SHA256_OUTPUT_LEN;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+const MAGIC: &[u8; 8] = b"POWCHAIN";
+const VERSION: u32 = 1;
+const HEADER_SIZE: u64 = 8 + 4 + 4 + RETARGET_SIZE + 8;
+/// A flag, the interval, the target block interval in milliseconds and the maximum
+/// adjustment factor.
+const RETARGET_SIZE: u64 = 1 + 4 + 8 + 4;
+const INDEX_ENTRY_SIZE: usize = SHA256_OUTPUT_LEN + 4;
+
+/// Writes the chain to a new file, replacing any existing one.
+pub fn save<P: AsRef<Path>>(chain: &Chain, path: P) -> io::Result<()> {
+    let mut blocks: Vec<&Block> = chain.iter().map(|chain| chain.head()).collect();
+    blocks.reverse();
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_be_bytes())?;
+    writer.write_all(&(blocks.len() as u32).to_be_bytes())?;
+    write_retarget(&mut writer, chain.retarget.as_deref())?;
+    let index_offset = HEADER_SIZE + blocks.len() as u64 * BLOCK_HEADER_SIZE;
+    writer.write_all(&index_offset.to_be_bytes())?;
+
+    for block in &blocks {
+        write_block(&mut writer, block)?;
+    }
+
+    let mut index: Vec<(&[u8], u32)> = blocks
+        .iter()
+        .map(|block| (block.hash().bytes(), block.height()))
+        .collect();
+    index.sort();
+    for (hash, height) in index {
+        writer.write_all(hash)?;
+        writer.write_all(&height.to_be_bytes())?;
+    }
+
+    writer.flush()
+}
+
+/// Loads the whole chain saved in the file.
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Arc<Chain>> {
+    let mut chain_file = ChainFile::open(path)?;
+    let height = chain_file.height();
+    chain_file.load(height)
+}
+
+/// A chain file whose header and index were read, the blocks are only read when loaded.
+pub struct ChainFile {
+    file: File,
+    number_of_blocks: u32,
+    retarget: Option<Retarget>,
+    /// The hashes of the blocks and their heights, sorted by hash.
+    index: Vec<([u8; SHA256_OUTPUT_LEN], u32)>,
+}
+
+impl ChainFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<ChainFile> {
+        let mut file = File::open(path)?;
+
+        let mut magic = [0u8; 8];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("Not a chain file"));
+        }
+        if read_u32(&mut file)? != VERSION {
+            return Err(invalid_data("Unsupported chain file version"));
+        }
+        let number_of_blocks = read_u32(&mut file)?;
+        if number_of_blocks == 0 {
+            return Err(invalid_data("Empty chain file"));
+        }
+        let retarget = read_retarget(&mut file)?;
+        let index_offset = read_u64(&mut file)?;
+        if index_offset != HEADER_SIZE + u64::from(number_of_blocks) * BLOCK_HEADER_SIZE {
+            return Err(invalid_data("Invalid index offset"));
+        }
+
+        let index_size = u64::from(number_of_blocks) * INDEX_ENTRY_SIZE as u64;
+        if index_offset + index_size != file.metadata()?.len() {
+            return Err(invalid_data("Invalid chain file size"));
+        }
+
+        file.seek(SeekFrom::Start(index_offset))?;
+        let mut index_bytes = vec![0u8; index_size as usize];
+        file.read_exact(&mut index_bytes)?;
+        let index = index_bytes
+            .chunks(INDEX_ENTRY_SIZE)
+            .map(|entry| {
+                let mut hash = [0u8; SHA256_OUTPUT_LEN];
+                hash.copy_from_slice(&entry[..SHA256_OUTPUT_LEN]);
+                let mut height = [0u8; 4];
+                height.copy_from_slice(&entry[SHA256_OUTPUT_LEN..]);
+                (hash, u32::from_be_bytes(height))
+            })
+            .collect::<Vec<([u8; SHA256_OUTPUT_LEN], u32)>>();
+        if !index.windows(2).all(|entries| entries[0].0 < entries[1].0) {
+            return Err(invalid_data("Index not sorted by hash"));
+        }
+
+        Ok(ChainFile {
+            file,
+            number_of_blocks,
+            retarget,
+            index,
+        })
+    }
+
+    /// The height of the head of the saved chain.
+    pub fn height(&self) -> u32 {
+        self.number_of_blocks - 1
+    }
+
+    /// The height of the block with the given hash, if it was saved in the file.
+    pub fn height_of(&self, hash: &Hash) -> Option<u32> {
+        self.index
+            .binary_search_by(|(indexed_hash, _height)| indexed_hash[..].cmp(hash.bytes()))
+            .ok()
+            .map(|position| self.index[position].1)
+    }
+
+    /// Reads and validates the blocks up to the given height, returns the chain they form.
+    pub fn load(&mut self, height: u32) -> io::Result<Arc<Chain>> {
+        if height > self.height() {
+            return Err(invalid_data("No block at this height in the chain file"));
+        }
+
+        self.file.seek(SeekFrom::Start(HEADER_SIZE))?;
+        let mut reader = BufReader::new(&self.file);
+
+        let genesis_record = read_block(&mut reader)?;
+        let genesis_block = Block::genesis_block(
+            Arc::new(genesis_record.difficulty),
+            genesis_record.timestamp,
+        );
+        if genesis_record.height != 0 || genesis_record.hash[..] != *genesis_block.hash().bytes() {
+            return Err(invalid_data("Invalid genesis block"));
+        }
+        self.check_indexed(&genesis_block)?;
+        let mut chain = Arc::new(Chain::from_genesis_block(
+            genesis_block,
+            self.retarget.clone(),
+        ));
+
+        for _height in 0..height {
+            let record = read_block(&mut reader)?;
+            if record.previous_block_hash[..] != *chain.head().hash().bytes() {
+                return Err(invalid_data("Block not linked to the previous one"));
+            }
+
+            // Consecutive blocks share their difficulty, unless it was retargeted.
+            let difficulty = if **chain.head().difficulty() == record.difficulty {
+                chain.head().difficulty().clone()
+            } else {
+                Arc::new(record.difficulty)
+            };
+            let block = Block::new(
+                record.node_id,
+                record.nonce,
+                &difficulty,
+                chain.head().hash().clone(),
+                record.height,
+                record.timestamp,
+            );
+            if record.hash[..] != *block.hash().bytes() {
+                return Err(invalid_data("Block hash mismatch"));
+            }
+            self.check_indexed(&block)?;
+
+            chain = Chain::expand(&chain, block).map_err(invalid_data)?;
+        }
+
+        Ok(chain)
+    }
+
+    /// Checks that the index gives the height of the block.
+    fn check_indexed(&self, block: &Block) -> io::Result<()> {
+        if self.height_of(block.hash()) == Some(block.height()) {
+            Ok(())
+        } else {
+            Err(invalid_data("Block not in the index"))
+        }
+    }
+}
+
+/// The fields of a block as saved, its hash still to be checked.
+struct BlockRecord {
+    height: u32,
+    node_id: u32,
+    timestamp: u64,
+    nonce: Nonce,
+    difficulty: Difficulty,
+    previous_block_hash: [u8; SHA256_OUTPUT_LEN],
+    hash: [u8; SHA256_OUTPUT_LEN],
+}
+
+fn write_block<W: Write>(writer: &mut W, block: &Block) -> io::Result<()> {
+    writer.write_all(&block.height.to_be_bytes())?;
+    writer.write_all(&block.node_id.to_be_bytes())?;
+    writer.write_all(&block.timestamp.to_be_bytes())?;
+    writer.write_all(block.nonce.bytes())?;
+    writer.write_all(block.difficulty.threshold())?;
+    writer.write_all(block.previous_block_hash.bytes())?;
+    writer.write_all(block.hash.bytes())
+}
+
+fn read_block<R: Read>(reader: &mut R) -> io::Result<BlockRecord> {
+    let height = read_u32(reader)?;
+    let node_id = read_u32(reader)?;
+    let timestamp = read_u64(reader)?;
+    let mut nonce = [0u8; 8];
+    reader.read_exact(&mut nonce)?;
+    let mut threshold = [0u8; SHA256_OUTPUT_LEN];
+    reader.read_exact(&mut threshold)?;
+    let mut previous_block_hash = [0u8; SHA256_OUTPUT_LEN];
+    reader.read_exact(&mut previous_block_hash)?;
+    let mut hash = [0u8; SHA256_OUTPUT_LEN];
+    reader.read_exact(&mut hash)?;
+
+    Ok(BlockRecord {
+        height,
+        node_id,
+        timestamp,
+        nonce: Nonce::from_bytes(nonce),
+        difficulty: Difficulty::from_threshold(threshold),
+        previous_block_hash,
+        hash,
+    })
+}
+
+fn write_retarget<W: Write>(writer: &mut W, retarget: Option<&Retarget>) -> io::Result<()> {
+    match retarget {
+        Some(retarget) => {
+            let target_block_interval = retarget.target_block_interval.as_millis() as u64;
+            writer.write_all(&[1])?;
+            writer.write_all(&retarget.interval.to_be_bytes())?;
+            writer.write_all(&target_block_interval.to_be_bytes())?;
+            writer.write_all(&retarget.max_adjustment_factor.to_be_bytes())
+        }
+        None => writer.write_all(&[0u8; RETARGET_SIZE as usize]),
+    }
+}
+
+fn read_retarget<R: Read>(reader: &mut R) -> io::Result<Option<Retarget>> {
+    let mut flag = [0u8];
+    reader.read_exact(&mut flag)?;
+    let interval = read_u32(reader)?;
+    let target_block_interval = Duration::from_millis(read_u64(reader)?);
+    let max_adjustment_factor = read_u32(reader)?;
+
+    match flag[0] {
+        0 => Ok(None),
+        1 if interval > 0 && max_adjustment_factor > 0 => Ok(Some(Retarget::new(
+            interval,
+            target_block_interval,
+            max_adjustment_factor,
+        ))),
+        _ => Err(invalid_data("Invalid retarget rules")),
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn saves_and_loads_a_chain() {
+        let mut difficulty = Difficulty::min_difficulty();
+        difficulty.increase();
+        let retarget = Retarget::new(5, Duration::from_millis(10), 4);
+        let mut chain = Arc::new(Chain::init_new(difficulty, Some(retarget.clone())));
+        let genesis_timestamp = chain.head().timestamp();
+        let mut nonce = Nonce::new();
+        for height in 1..=20u32 {
+            let timestamp = genesis_timestamp + u64::from(height) * 3;
+            loop {
+                nonce.increment();
+                let block = Block::new(
+                    height % 3,
+                    nonce.clone(),
+                    &chain.next_difficulty(),
+                    chain.head().hash().clone(),
+                    height,
+                    timestamp,
+                );
+                if let Ok(expanded) = Chain::expand(&chain, block) {
+                    chain = expanded;
+                    break;
+                }
+            }
+        }
+
+        let path = env::temp_dir().join(format!("pow_chain_{}.bin", genesis_timestamp));
+        save(&chain, &path).unwrap();
+        assert_eq!(
+            HEADER_SIZE + 21 * (BLOCK_HEADER_SIZE + INDEX_ENTRY_SIZE as u64),
+            fs::metadata(&path).unwrap().len()
+        );
+
+        let loaded = load(&path).unwrap();
+        assert_eq!(*chain, *loaded);
+        assert_eq!(chain.work(), loaded.work());
+        assert_eq!(Some(&retarget), loaded.retarget.as_deref());
+
+        let mut chain_file = ChainFile::open(&path).unwrap();
+        let ancestor = Chain::ancestor(&chain, 12).unwrap();
+        assert_eq!(Some(12), chain_file.height_of(ancestor.head().hash()));
+        assert_eq!(*ancestor, *chain_file.load(12).unwrap());
+        assert!(chain_file.load(21).is_err());
+
+        // A tampered index is rejected, and so is a truncated file.
+        let bytes = fs::read(&path).unwrap();
+        let mut tampered = bytes.clone();
+        tampered[(HEADER_SIZE + 21 * BLOCK_HEADER_SIZE) as usize + SHA256_OUTPUT_LEN + 3] ^= 1;
+        fs::write(&path, tampered).unwrap();
+        assert_eq!(ErrorKind::InvalidData, load(&path).unwrap_err().kind());
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert_eq!(ErrorKind::InvalidData, load(&path).unwrap_err().kind());
+
+        // A tampered block is rejected.
+        let mut bytes = bytes;
+        bytes[(HEADER_SIZE + 7 * BLOCK_HEADER_SIZE) as usize + 4] ^= 1;
+        fs::write(&path, bytes).unwrap();
+        assert_eq!(ErrorKind::InvalidData, load(&path).unwrap_err().kind());
+        fs::remove_file(&path).unwrap();
+    }
+}
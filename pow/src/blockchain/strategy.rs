@@ -0,0 +1,27 @@
+use blockchain::{DoubleSpendNode, PoolMemberNode, PowNode, SelfishPowNode};
+use futures::{Future, Stream};
+use netsim::network::multiplex::{MultiplexedConnection, MultiplexedMessage};
+use netsim::network::Node;
+
+/// Lets nodes following different mining strategies run in the same network. The nodes are
+/// boxed, their sizes differ a lot.
+pub enum StrategyNode {
+    Honest(Box<PowNode>),
+    Selfish(Box<SelfishPowNode>),
+    DoubleSpend(Box<DoubleSpendNode>),
+    PoolMember(Box<PoolMemberNode>),
+}
+
+impl Node<MultiplexedMessage> for StrategyNode {
+    fn run<S>(self, connection_stream: S) -> Box<dyn Future<Item = (), Error = ()> + Send>
+    where
+        S: Stream<Item = MultiplexedConnection, Error = ()> + Send + 'static,
+    {
+        match self {
+            StrategyNode::Honest(node) => (*node).run(connection_stream),
+            StrategyNode::Selfish(node) => (*node).run(connection_stream),
+            StrategyNode::DoubleSpend(node) => (*node).run(connection_stream),
+            StrategyNode::PoolMember(node) => (*node).run(connection_stream),
+        }
+    }
+}
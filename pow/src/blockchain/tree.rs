@@ -0,0 +1,258 @@
+use blockchain::{Chain, Hash};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Clone)]
+struct TreeBlock {
+    /// The chain ending with the block.
+    chain: Arc<Chain>,
+    parent: Option<Hash>,
+    children: Vec<Hash>,
+    /// When the block was first seen, since the start of the run.
+    seen_at: Duration,
+}
+
+/// Every valid block seen by a node, the side branches included, indexed by hash.
+#[derive(Clone)]
+pub struct BlockTree {
+    blocks: HashMap<Hash, TreeBlock>,
+    /// The blocks without children: the head of the best chain and of every side branch.
+    tips: HashSet<Hash>,
+    best_chain: Arc<Chain>,
+    /// The height of the first block of the tree, which is not always the genesis block.
+    root_height: u32,
+    /// When the tips of the side branches fell behind the best chain.
+    resolved_at: HashMap<Hash, Duration>,
+}
+
+/// A side branch of the tree: blocks that are not part of the best chain.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fork {
+    /// The height of the last block the side branch shares with the best chain.
+    pub fork_height: u32,
+    pub tip: Hash,
+    /// The number of blocks of the side branch.
+    pub depth: u32,
+    /// When the first block of the side branch was seen.
+    pub seen_at: Duration,
+    /// When the best chain got more work than the side branch, if it did. The fork is still
+    /// a tie otherwise.
+    pub resolved_at: Option<Duration>,
+}
+
+impl BlockTree {
+    /// A tree starting from the head of the chain, the blocks below it are not tracked.
+    pub fn new(genesis_chain: Arc<Chain>) -> BlockTree {
+        let genesis_hash = genesis_chain.head().hash().clone();
+        let mut blocks = HashMap::new();
+        blocks.insert(
+            genesis_hash.clone(),
+            TreeBlock {
+                chain: genesis_chain.clone(),
+                parent: None,
+                children: vec![],
+                seen_at: Duration::from_secs(0),
+            },
+        );
+
+        let mut tips = HashSet::new();
+        tips.insert(genesis_hash);
+
+        BlockTree {
+            blocks,
+            tips,
+            root_height: genesis_chain.height(),
+            best_chain: genesis_chain,
+            resolved_at: HashMap::new(),
+        }
+    }
+
+    /// Adds the blocks of the chain that are not known yet. The chain must share the genesis
+    /// block of the tree. Returns the number of blocks added.
+    pub fn insert(&mut self, chain: &Arc<Chain>, at: Duration) -> usize {
+        let mut new_chains = vec![];
+        let mut parent = None;
+        let mut next = Some(chain.clone());
+        while let Some(chain) = next {
+            let hash = chain.head().hash();
+            if self.blocks.contains_key(hash) {
+                parent = Some(hash.clone());
+                break;
+            }
+            next = chain.tail.clone();
+            new_chains.push(chain);
+        }
+
+        let added = new_chains.len();
+        for chain in new_chains.into_iter().rev() {
+            let hash = chain.head().hash().clone();
+            let parent_hash = parent.expect("The chain does not share the genesis block.");
+            self.blocks
+                .get_mut(&parent_hash)
+                .expect("The parent block is known.")
+                .children
+                .push(hash.clone());
+            self.tips.remove(&parent_hash);
+            self.tips.insert(hash.clone());
+            self.blocks.insert(
+                hash.clone(),
+                TreeBlock {
+                    chain,
+                    parent: Some(parent_hash),
+                    children: vec![],
+                    seen_at: at,
+                },
+            );
+            parent = Some(hash);
+        }
+
+        if added > 0 {
+            self.update_resolutions(at);
+        }
+        added
+    }
+
+    /// Makes the chain the best chain, adding its blocks to the tree if needed.
+    pub fn set_best(&mut self, chain: &Arc<Chain>, at: Duration) {
+        self.insert(chain, at);
+        self.best_chain = chain.clone();
+        self.resolved_at.remove(chain.head().hash());
+        self.update_resolutions(at);
+    }
+
+    pub fn best_chain(&self) -> &Arc<Chain> {
+        &self.best_chain
+    }
+
+    pub fn get(&self, hash: &Hash) -> Option<&Arc<Chain>> {
+        self.blocks.get(hash).map(|block| &block.chain)
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// The number of known blocks that are not part of the best chain.
+    pub fn stale_blocks(&self) -> usize {
+        self.blocks.len() - (self.best_chain.height() - self.root_height) as usize - 1
+    }
+
+    /// The blocks without children: the head of the best chain and of every side branch.
+    pub fn tips(&self) -> Vec<&Arc<Chain>> {
+        self.tips.iter().map(|tip| &self.blocks[tip].chain).collect()
+    }
+
+    /// Every side branch, by order of appearance.
+    pub fn forks(&self) -> Vec<Fork> {
+        let best_chain_blocks: HashSet<&Hash> = self
+            .best_chain
+            .iter()
+            .map(|chain| chain.head().hash())
+            .collect();
+
+        let mut forks: Vec<Fork> = self
+            .tips
+            .iter()
+            .filter(|tip| !best_chain_blocks.contains(tip))
+            .map(|tip| {
+                let mut first_block = &self.blocks[tip];
+                let mut depth = 1;
+                while let Some(ref parent) = first_block.parent {
+                    if best_chain_blocks.contains(parent) {
+                        break;
+                    }
+                    first_block = &self.blocks[parent];
+                    depth += 1;
+                }
+
+                Fork {
+                    fork_height: first_block.chain.height() - 1,
+                    tip: tip.clone(),
+                    depth,
+                    seen_at: first_block.seen_at,
+                    resolved_at: self.resolved_at.get(tip).cloned(),
+                }
+            })
+            .collect();
+
+        forks.sort_by_key(|fork| (fork.seen_at, fork.fork_height));
+        forks
+    }
+
+    /// Marks the side branches that the best chain overtook as resolved.
+    fn update_resolutions(&mut self, at: Duration) {
+        for tip in &self.tips {
+            if !self.resolved_at.contains_key(tip)
+                && self.best_chain.stronger_than(&self.blocks[tip].chain)
+            {
+                self.resolved_at.insert(tip.clone(), at);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::testing::expand;
+    use blockchain::Difficulty;
+
+    fn millis(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn keeps_track_of_the_forks() {
+        let genesis = Arc::new(Chain::init_new(Difficulty::min_difficulty(), None));
+        let mut tree = BlockTree::new(genesis.clone());
+
+        let first = expand(&genesis, 1);
+        tree.set_best(&first, millis(1));
+
+        // A tie at height 1, then a side branch of two blocks from it.
+        let tie = expand(&genesis, 2);
+        assert_eq!(1, tree.insert(&tie, millis(2)));
+        let side_branch = expand(&expand(&tie, 2), 2);
+        assert_eq!(2, tree.insert(&side_branch, millis(3)));
+        assert_eq!(0, tree.insert(&side_branch, millis(4)));
+
+        let forks = tree.forks();
+        assert_eq!(1, forks.len());
+        assert_eq!((0, 3, None), (forks[0].fork_height, forks[0].depth, forks[0].resolved_at));
+        assert_eq!(5, tree.len());
+        assert_eq!(3, tree.stale_blocks());
+
+        // Reorg to the side branch: the former best chain is a fork that fell behind.
+        tree.set_best(&side_branch, millis(5));
+        let forks = tree.forks();
+        assert_eq!(1, forks.len());
+        assert_eq!(first.head().hash(), &forks[0].tip);
+        assert_eq!(
+            (0, 1, Some(millis(5))),
+            (forks[0].fork_height, forks[0].depth, forks[0].resolved_at)
+        );
+        assert_eq!(1, tree.stale_blocks());
+        assert_eq!(2, tree.tips().len());
+        assert_eq!(Some(&tie), tree.get(tie.head().hash()));
+    }
+
+    #[test]
+    fn can_start_above_the_genesis_block() {
+        let genesis = Arc::new(Chain::init_new(Difficulty::min_difficulty(), None));
+        let start = expand(&expand(&genesis, 1), 1);
+        let mut tree = BlockTree::new(start.clone());
+        assert_eq!(0, tree.stale_blocks());
+
+        let best = expand(&start, 1);
+        tree.set_best(&best, millis(1));
+        tree.insert(&expand(&start, 2), millis(2));
+        assert_eq!(3, tree.len());
+        assert_eq!(1, tree.stale_blocks());
+        assert_eq!(2, tree.forks()[0].fork_height);
+    }
+}
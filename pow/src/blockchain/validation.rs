@@ -0,0 +1,103 @@
+use blockchain::{Chain, BLOCK_HEADER_SIZE};
+use futures::sync::mpsc::{self, UnboundedSender};
+use futures::{Future, Stream};
+use std::cmp;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_timer::Delay;
+
+const BYTES_PER_MEGABYTE: u64 = 1_000_000;
+
+/// How long a node takes to validate a block received from a peer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValidationDelay {
+    Fixed(Duration),
+    /// Proportional to the size of the blocks, their header included.
+    Proportional {
+        per_megabyte: Duration,
+        block_payload_size: u64,
+    },
+}
+
+impl ValidationDelay {
+    pub fn per_block(&self) -> Duration {
+        match *self {
+            ValidationDelay::Fixed(delay) => delay,
+            ValidationDelay::Proportional {
+                per_megabyte,
+                block_payload_size,
+            } => {
+                let block_size = BLOCK_HEADER_SIZE + block_payload_size;
+                per_megabyte.mul_f64(block_size as f64 / BYTES_PER_MEGABYTE as f64)
+            }
+        }
+    }
+}
+
+/// The chains received by a node, waiting to be validated. The node validates one block at a
+/// time: a chain waits for the chains queued before it.
+pub struct ValidationQueue {
+    sender: UnboundedSender<(Instant, u32, Arc<Chain>)>,
+    delay: ValidationDelay,
+    busy_until: Instant,
+}
+
+impl ValidationQueue {
+    /// Queues a chain received from a peer, whose given number of new blocks must be
+    /// validated.
+    pub fn push(&mut self, peer_id: u32, chain: Arc<Chain>, new_blocks: u32) {
+        let start = cmp::max(Instant::now(), self.busy_until);
+        self.busy_until = start + self.delay.per_block() * new_blocks;
+
+        if let Err(err) = self
+            .sender
+            .unbounded_send((self.busy_until, peer_id, chain))
+        {
+            panic!("Could not queue the validation of a chain: {}", err)
+        }
+    }
+}
+
+/// Returns a stream of the chains queued for validation, yielded with the id of the peer
+/// that sent them once the validation delay is over.
+pub fn validation_stream(
+    delay: ValidationDelay,
+) -> (
+    impl Stream<Item = (u32, Arc<Chain>), Error = ()>,
+    ValidationQueue,
+) {
+    let (sender, receiver) = mpsc::unbounded();
+
+    // The chains come out in the order they were queued, the validation of a chain ending
+    // after the previous ones.
+    let stream = receiver.and_then(|(validated_at, peer_id, chain)| {
+        Delay::new(validated_at)
+            .map(move |()| (peer_id, chain))
+            .map_err(|err| panic!("Timer error: {}", err))
+    });
+
+    let queue = ValidationQueue {
+        sender,
+        delay,
+        busy_until: Instant::now(),
+    };
+
+    (stream, queue)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_delay_can_depend_on_the_block_size() {
+        let delay = ValidationDelay::Fixed(Duration::from_millis(20));
+        assert_eq!(Duration::from_millis(20), delay.per_block());
+
+        let delay = ValidationDelay::Proportional {
+            per_megabyte: Duration::from_millis(100),
+            block_payload_size: 2_000_000 - BLOCK_HEADER_SIZE,
+        };
+        assert_eq!(Duration::from_millis(200), delay.per_block());
+    }
+}
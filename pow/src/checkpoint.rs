@@ -0,0 +1,293 @@
+//! Checkpoints: the state of the network at the end of a run, saved to a directory so that
+//! another run can resume from it, possibly with other parameters.
+//!
+//! A checkpoint keeps the best chain of every node, its hashrate and the peers it was still
+//! connected to. The directory holds a `checkpoint.json` file and the distinct chains of the
+//! nodes, in the format of the `store` module.
+//!
+//! The run must be drained, see `SimulationConfig::drain`: its miners are halted and the
+//! network runs on until no chain is in flight or being validated, so there are no messages
+//! nor validation queues to keep. No checkpoint is taken of a network that did not settle
+//! before the end of the run. Some state is not kept:
+//! * the nonces of the miners. Every mining attempt has the same odds whatever the nonce,
+//!   and the nonce is reset every time a node switches chains anyway.
+//! * which node initiated a connection. The node with the lowest id initiates it when the
+//!   run resumes.
+//! * the private chains of the attackers. A run with an attack cannot resume from a
+//!   checkpoint.
+//! * the records of the run, the report of the resumed run only covers the rest of it.
+
+use blockchain::store;
+use blockchain::{Chain, Hash};
+use netsim::network::topology::Topology;
+use serde_json;
+use simulation::SimulationResult;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
+
+const CHECKPOINT_FILE: &str = "checkpoint.json";
+
+#[derive(Clone)]
+pub struct Checkpoint {
+    /// The best chain of every node, by node id.
+    pub chains: Vec<Arc<Chain>>,
+    /// The mining attempts per round of every node, by node id.
+    pub hashrates: Vec<u32>,
+    /// The connections still open at the end of the run.
+    pub topology: Topology,
+}
+
+/// The content of `checkpoint.json`.
+#[derive(Deserialize, Serialize)]
+struct CheckpointFile {
+    /// The files of the distinct chains of the nodes.
+    chains: Vec<String>,
+    nodes: Vec<NodeEntry>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct NodeEntry {
+    node_id: u32,
+    hashrate: u32,
+    /// The index of the best chain of the node in the chain files.
+    chain: usize,
+    /// The nodes the node was connected to.
+    peers: Vec<u32>,
+}
+
+impl Checkpoint {
+    /// The state of the network at the end of the drained run. Fails if the network did not
+    /// settle before the end of the run, the chains in flight would be lost.
+    pub fn new(result: &SimulationResult) -> Result<Checkpoint, String> {
+        if !result.drained {
+            return Err("The network was not drained before the end of the run".to_owned());
+        }
+
+        let peers: Vec<BTreeSet<u32>> = result
+            .records
+            .iter()
+            .map(|record| record.peers.clone())
+            .collect();
+
+        Ok(Checkpoint {
+            chains: result
+                .records
+                .iter()
+                .map(|record| record.best_chain.clone())
+                .collect(),
+            hashrates: result.hashrates.clone(),
+            topology: connections(&peers),
+        })
+    }
+
+    pub fn number_of_nodes(&self) -> u32 {
+        self.chains.len() as u32
+    }
+
+    /// Writes the checkpoint to the directory, created if needed.
+    pub fn save<P: AsRef<Path>>(&self, directory: P) -> io::Result<()> {
+        let directory = directory.as_ref();
+        fs::create_dir_all(directory)?;
+
+        // The nodes mostly share a few chains, every distinct chain is saved once.
+        let mut peers = vec![vec![]; self.chains.len()];
+        for (node_id, peer_id) in self.topology.edges() {
+            peers[*node_id as usize].push(*peer_id);
+            peers[*peer_id as usize].push(*node_id);
+        }
+
+        let mut chain_files = vec![];
+        let mut chain_indexes: HashMap<&Hash, usize> = HashMap::new();
+        let mut nodes = vec![];
+        for (node_id, chain) in self.chains.iter().enumerate() {
+            let next_index = chain_indexes.len();
+            let index = *chain_indexes
+                .entry(chain.head().hash())
+                .or_insert(next_index);
+            if index == next_index {
+                let chain_file = format!("chain_{}.bin", index);
+                store::save(chain, directory.join(&chain_file))?;
+                chain_files.push(chain_file);
+            }
+
+            nodes.push(NodeEntry {
+                node_id: node_id as u32,
+                hashrate: self.hashrates[node_id],
+                chain: index,
+                peers: peers[node_id].clone(),
+            });
+        }
+
+        let checkpoint_file = CheckpointFile {
+            chains: chain_files,
+            nodes,
+        };
+        let content = serde_json::to_string_pretty(&checkpoint_file)
+            .expect("Could not serialize the checkpoint");
+        fs::write(directory.join(CHECKPOINT_FILE), content)
+    }
+
+    /// Reads a checkpoint from the directory, the chains are validated again.
+    pub fn load<P: AsRef<Path>>(directory: P) -> io::Result<Checkpoint> {
+        let directory = directory.as_ref();
+        let content = fs::read_to_string(directory.join(CHECKPOINT_FILE))?;
+        let checkpoint_file: CheckpointFile = serde_json::from_str(&content)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+
+        let chains = checkpoint_file
+            .chains
+            .iter()
+            .map(|chain_file| store::load(directory.join(chain_file)))
+            .collect::<io::Result<Vec<Arc<Chain>>>>()?;
+
+        let number_of_nodes = checkpoint_file.nodes.len() as u32;
+        let mut node_chains = vec![];
+        let mut hashrates = vec![];
+        let mut peers = vec![];
+        for (node_id, node) in checkpoint_file.nodes.iter().enumerate() {
+            let chain = chains.get(node.chain);
+            let valid_peers = node
+                .peers
+                .iter()
+                .all(|peer_id| *peer_id < number_of_nodes && *peer_id != node.node_id);
+            match chain {
+                Some(chain) if node.node_id == node_id as u32 && valid_peers => {
+                    node_chains.push(chain.clone());
+                    hashrates.push(node.hashrate);
+                    peers.push(node.peers.iter().cloned().collect::<BTreeSet<u32>>());
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Invalid checkpoint entry for the node #{:05}", node.node_id),
+                    ))
+                }
+            }
+        }
+
+        // Both ends of a connection know each other.
+        let symmetric = peers.iter().enumerate().all(|(node_id, node_peers)| {
+            node_peers
+                .iter()
+                .all(|peer_id| peers[*peer_id as usize].contains(&(node_id as u32)))
+        });
+        if !symmetric {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Invalid connection in the checkpoint",
+            ));
+        }
+
+        Ok(Checkpoint {
+            chains: node_chains,
+            hashrates,
+            topology: connections(&peers),
+        })
+    }
+}
+
+/// The connections between the nodes given their peers, by node id. A connection is only
+/// kept if both of its ends still have the other one as a peer, it goes from the node with the
+/// lowest id.
+fn connections(peers: &[BTreeSet<u32>]) -> Topology {
+    let edges = peers
+        .iter()
+        .enumerate()
+        .flat_map(|(node_id, node_peers)| {
+            let node_id = node_id as u32;
+            node_peers
+                .iter()
+                .filter(move |peer_id| {
+                    **peer_id > node_id && peers[**peer_id as usize].contains(&node_id)
+                })
+                .map(move |peer_id| (node_id, *peer_id))
+        })
+        .collect();
+
+    Topology::undirected((0..peers.len() as u32).collect(), edges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::hashrate::HashrateDistribution;
+    use simulation::{self, Attack, SelfishMiningConfig};
+    use std::env;
+    use std::time::Duration;
+
+    #[test]
+    fn resumes_a_run_from_its_checkpoint() {
+        let mut config = simulation::test_config();
+        config.difficulty_factor = 6;
+        config.duration = Duration::from_secs(60);
+        config.stop_at_height = Some(3);
+        config.drain = true;
+
+        let result = simulation::run(&config).unwrap();
+        assert!(result.report.consensus_height >= 3);
+        assert!(result.report.consensus_height < 10);
+
+        assert!(result.drained);
+        let checkpoint = Checkpoint::new(&result).unwrap();
+        // The network was drained, the peers agree on the work of the best chain.
+        for (node_id, peer_id) in checkpoint.topology.edges() {
+            let connected = |one: &u32, other: &u32| {
+                result.topology.edges().contains(&(*one, *other))
+            };
+            assert!(connected(node_id, peer_id) || connected(peer_id, node_id));
+            assert_eq!(
+                checkpoint.chains[*node_id as usize].work(),
+                checkpoint.chains[*peer_id as usize].work()
+            );
+        }
+        let directory = env::temp_dir().join(format!(
+            "pow_checkpoint_{:?}",
+            result.records[0].best_chain.head().hash()
+        ));
+        checkpoint.save(&directory).unwrap();
+        let loaded = Checkpoint::load(&directory).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(checkpoint.chains, loaded.chains);
+        assert_eq!(checkpoint.hashrates, loaded.hashrates);
+        assert_eq!(checkpoint.topology, loaded.topology);
+
+        config.checkpoint = Some(loaded);
+        config.stop_at_height = None;
+        config.duration = Duration::from_secs(1);
+        let mut resumed = simulation::run(&config).unwrap();
+        assert_eq!(checkpoint.topology, resumed.topology);
+        assert_eq!(result.hashrates, resumed.hashrates);
+        for (record, resumed_record) in result.records.iter().zip(&resumed.records) {
+            assert!(resumed_record.best_chain.height() >= record.best_chain.height());
+        }
+        // The chains in flight would be lost.
+        resumed.drained = false;
+        assert!(Checkpoint::new(&resumed).is_err());
+
+        config.hashrate_distribution = Some(HashrateDistribution::Uniform(2));
+        assert_eq!(vec![2; 8], simulation::run(&config).unwrap().hashrates);
+
+        config.attack = Some(Attack::Selfish(SelfishMiningConfig {
+            hashrate_share: 0.3,
+            gamma: 0f64,
+        }));
+        assert!(simulation::run(&config).is_err());
+        config.attack = None;
+        config.number_of_nodes = 9;
+        assert!(simulation::run(&config).is_err());
+    }
+
+    #[test]
+    fn keeps_the_connections_known_by_both_ends() {
+        let peers: Vec<BTreeSet<u32>> = vec![
+            [1, 2].iter().cloned().collect(),
+            [0, 2].iter().cloned().collect(),
+            [1].iter().cloned().collect(),
+        ];
+
+        assert_eq!(vec![(0, 1), (1, 2)], connections(&peers).edges().to_vec());
+    }
+}
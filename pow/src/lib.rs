@@ -0,0 +1,25 @@
+#[macro_use]
+extern crate log;
+extern crate futures;
+extern crate network_simulator as netsim;
+extern crate rand;
+extern crate ring;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate tokio_timer;
+extern crate toml;
+
+pub mod blockchain;
+pub mod checkpoint;
+pub mod report;
+pub mod scenario;
+mod simulation;
+pub mod sweep;
+
+pub use blockchain::{Block, Chain, PowNode};
+pub use simulation::{
+    run, Attack, DoubleSpendConfig, RateLimitConfig, SelfishMiningConfig, SimulationConfig,
+    SimulationResult, ATTACKER_NODE_ID,
+};
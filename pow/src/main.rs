@@ -2,24 +2,24 @@ extern crate clap;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
-extern crate futures;
-extern crate network_simulator as netsim;
-extern crate ring;
-extern crate tokio_timer;
+extern crate pow_blockchain_simulation as pow;
 
-pub mod blockchain;
-
-use blockchain::{Chain, Difficulty, PowNode};
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use log::LevelFilter;
-use netsim::network::Network;
+use pow::blockchain::recorder;
+use pow::blockchain::store;
+use pow::checkpoint::Checkpoint;
+use pow::report::{self, ReportFormat};
+use pow::scenario::{self, Scenario};
+use pow::sweep::{self, Parameter};
+use pow::{SimulationConfig, SimulationResult};
 use std::cmp::PartialOrd;
 use std::fmt::Debug;
+use std::fs;
 use std::num::ParseIntError;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
+use std::thread;
 
 fn main() {
     // Always print backtrace on panic.
@@ -30,12 +30,19 @@ fn main() {
         .filter_level(LevelFilter::Info)
         .init();
 
-    let matches = App::new("Proof-of-Work Blockchain Network Simulation")
+    let app = App::new("Proof-of-Work Blockchain Network Simulation")
         .version("0.1")
         .author("Pierre L. <pierre.larger@gmail.com>")
         .about("Simulates a Proof-of-Work blockchain network")
         .arg(
-            Arg::with_name("number_of_nodes")
+            Arg::with_name("scenario")
+                .long("scenario")
+                .value_name("SCENARIO_FILE")
+                .help("Reads the parameters of the run from a TOML file, the command line arguments taking precedence.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("network_size")
                 .short("n")
                 .long("network_size")
                 .value_name("NUMBER_OF_NODES")
@@ -43,14 +50,28 @@ fn main() {
                 .takes_value(true),
         )
         .arg(
-            Arg::with_name("initiated_connections_per_node")
+            Arg::with_name("connections")
                 .short("c")
                 .long("connections")
                 .value_name("INITIATED_CONNECTIONS_PER_NODE")
                 .takes_value(true),
         )
         .arg(
-            Arg::with_name("difficulty_factor")
+            Arg::with_name("latency")
+                .long("latency")
+                .value_name("LATENCY_IN_MILLIS")
+                .help("The time it takes for a message to reach a peer.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("latency_jitter")
+                .long("latency_jitter")
+                .value_name("LATENCY_JITTER_IN_MILLIS")
+                .help("A random delay up to this value, added to the latency of every message.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("difficulty")
                 .short("d")
                 .long("difficulty")
                 .value_name("DIFFICULTY_FACTOR")
@@ -73,79 +94,450 @@ fn main() {
                 .help("The delay between every attempt of a node to mine a new block.")
                 .takes_value(true),
         )
-        .get_matches();
-
-    let number_of_nodes: u32 = parse_unsigned_integer(
-        matches.value_of("number_of_nodes"),
-        "2048",
-        100000,
-        "Invalid number of nodes, expected [1-100000]",
-    );
+        .arg(
+            Arg::with_name("retarget_interval")
+                .short("r")
+                .long("retarget_interval")
+                .value_name("RETARGET_INTERVAL_IN_BLOCKS")
+                .help("The number of blocks between two difficulty adjustments. 0 keeps the difficulty constant.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("target_block_interval")
+                .short("t")
+                .long("target_block_interval")
+                .value_name("TARGET_BLOCK_INTERVAL_IN_MILLIS")
+                .help("The block interval the difficulty adjustments aim for.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_adjustment_factor")
+                .short("a")
+                .long("max_adjustment_factor")
+                .value_name("MAX_ADJUSTMENT_FACTOR")
+                .help("The maximum factor by which the difficulty can change in a single adjustment.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("load_chain")
+                .long("load_chain")
+                .value_name("CHAIN_FILE")
+                .help("Starts from a chain saved by a previous run, with its difficulty and retarget rules.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .value_name("CHECKPOINT_DIRECTORY")
+                .help("Resumes a run from its checkpoint: the chains, the connections and the hashrates of the nodes. The size of the network is the one of the checkpoint.")
+                .conflicts_with("load_chain")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("checkpoint_height")
+                .long("checkpoint_height")
+                .value_name("HEIGHT")
+                .help("Stops the run once a node adopts a chain of this height.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fork_choice")
+                .short("f")
+                .long("fork_choice")
+                .value_name("FORK_CHOICE")
+                .help("How nodes choose between two chains with the same work.")
+                .possible_values(&["first_seen", "random", "lowest_hash"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_clock_skew")
+                .short("k")
+                .long("max_clock_skew")
+                .value_name("MAX_CLOCK_SKEW_IN_MILLIS")
+                .help("Every node gets a random clock skew, up to this value ahead or behind.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_future_drift")
+                .long("max_future_drift")
+                .value_name("MAX_FUTURE_DRIFT_IN_MILLIS")
+                .help("How far ahead of the clock of a node a block timestamp can be.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("hashrate_distribution")
+                .long("hashrate_distribution")
+                .value_name("HASHRATE_DISTRIBUTION")
+                .help("How the mining attempts per round are spread among the nodes.")
+                .possible_values(&["uniform", "pareto"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pareto_shape")
+                .long("pareto_shape")
+                .value_name("PARETO_SHAPE")
+                .help("The shape of the Pareto hashrate distribution. The lower, the more concentrated.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("hashrates")
+                .long("hashrates")
+                .value_name("HASHRATES")
+                .conflicts_with("hashrate_distribution")
+                .help("The comma-separated mining attempts per round of the first nodes. The other nodes make one.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pools")
+                .long("pools")
+                .value_name("NUMBER_OF_POOLS")
+                .help("The number of mining pools. Their members mine for the operator instead of taking part in the network.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pool_members")
+                .long("pool_members")
+                .value_name("MEMBERS_PER_POOL")
+                .requires("pools")
+                .help("The number of members of every mining pool, the operator excluded.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("selfish_hashrate_share")
+                .long("selfish_hashrate_share")
+                .value_name("SELFISH_HASHRATE_SHARE")
+                .help("Makes the first node a selfish miner with this share of the hashrate, between 0 and 1 excluded.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("gamma")
+                .long("gamma")
+                .value_name("GAMMA")
+                .requires("selfish_hashrate_share")
+                .help("The probability for an honest node to switch to the selfish miner's block in a race. Replaces the fork choice rule.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("double_spend_hashrate_share")
+                .long("double_spend_hashrate_share")
+                .value_name("DOUBLE_SPEND_HASHRATE_SHARE")
+                .conflicts_with("selfish_hashrate_share")
+                .help("Makes the first node run a double-spend attack with this share of the hashrate, between 0 and 1 excluded.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("double_spend_fork_height")
+                .long("double_spend_fork_height")
+                .value_name("FORK_HEIGHT")
+                .requires("double_spend_hashrate_share")
+                .help("The height from which the double-spend attacker mines its secret chain. The payment is in the next block.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("double_spend_confirmations")
+                .long("double_spend_confirmations")
+                .value_name("CONFIRMATIONS")
+                .requires("double_spend_hashrate_share")
+                .help("The number of blocks the merchant waits for before accepting the payment.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("double_spend_max_deficit")
+                .long("double_spend_max_deficit")
+                .value_name("MAX_DEFICIT")
+                .requires("double_spend_hashrate_share")
+                .help("How many blocks behind the public chain the attacker can fall before giving up. Never gives up by default.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("relay")
+                .long("relay")
+                .value_name("RELAY_PROTOCOL")
+                .conflicts_with_all(&["selfish_hashrate_share", "double_spend_hashrate_share"])
+                .help("How the nodes relay new blocks: whole chains, or announcements followed by the download of the missing headers and blocks.")
+                .possible_values(&["full_chain", "headers_first"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("block_size")
+                .long("block_size")
+                .value_name("BLOCK_PAYLOAD_SIZE_IN_BYTES")
+                .help("The size of the transactions of every block, used to count the bytes sent.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("validation_delay")
+                .long("validation_delay")
+                .value_name("VALIDATION_DELAY_IN_MILLIS")
+                .conflicts_with("relay")
+                .help("The time it takes for a node to validate a block received from a peer before mining on it and relaying it.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("validation_delay_per_megabyte")
+                .long("validation_delay_per_megabyte")
+                .value_name("VALIDATION_DELAY_IN_MILLIS_PER_MEGABYTE")
+                .conflicts_with_all(&["relay", "validation_delay"])
+                .help("Makes the validation delay proportional to the block size, header included.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("relay_before_validation")
+                .long("relay_before_validation")
+                .help("Makes the nodes relay the blocks once their proof of work is checked, before validating them.")
+        )
+        .arg(
+            Arg::with_name("compare_validation_modes")
+                .long("compare_validation_modes")
+                .conflicts_with("relay_before_validation")
+                .help("Runs the simulation twice, relaying the blocks after then before validating them, and compares the fork rates.")
+        )
+        .arg(
+            Arg::with_name("rate_limit")
+                .long("rate_limit")
+                .value_name("MESSAGES")
+                .help("Limits the rate of the messages received from every connection with a token bucket of this capacity.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rate_limit_refill_interval")
+                .long("rate_limit_refill_interval")
+                .value_name("REFILL_INTERVAL_IN_MILLIS")
+                .requires("rate_limit")
+                .help("The time it takes for a connection to be allowed one more message, 100 milliseconds by default.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rate_limit_policy")
+                .long("rate_limit_policy")
+                .value_name("POLICY")
+                .requires("rate_limit")
+                .help("What a node does with the messages beyond the rate limit: drop them, delay them or close the connection.")
+                .possible_values(&["drop", "delay", "disconnect"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("heartbeat_interval")
+                .long("heartbeat_interval")
+                .value_name("HEARTBEAT_INTERVAL_IN_MILLIS")
+                .help("Makes the honest nodes ping their peers at this interval, and stop relaying to the ones that do not answer.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("heartbeat_timeout")
+                .long("heartbeat_timeout")
+                .value_name("HEARTBEAT_TIMEOUT_IN_MILLIS")
+                .requires("heartbeat_interval")
+                .help("The time after which a peer that did not answer a ping is considered dead, three intervals by default.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("report_format")
+                .long("report_format")
+                .value_name("REPORT_FORMAT")
+                .help("The format of the report printed at the end of the run.")
+                .possible_values(&["text", "json"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("report_file")
+                .long("report_file")
+                .value_name("REPORT_FILE")
+                .help("Also writes the report to this file, both reports when comparing the validation modes.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("export_directory")
+                .short("e")
+                .long("export_directory")
+                .value_name("EXPORT_DIRECTORY")
+                .help("Exports the network topology and the block propagation trees to this directory.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("save_chain")
+                .long("save_chain")
+                .value_name("CHAIN_FILE")
+                .help("Saves the best chain at the end of the run to this file.")
+                .conflicts_with("compare_validation_modes")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("checkpoint")
+                .long("checkpoint")
+                .value_name("CHECKPOINT_DIRECTORY")
+                .help("Saves the state of the network at the end of the run to this directory, to resume the run later. The miners stop at the end of the run and the network runs on until the blocks in flight are delivered.")
+                .conflicts_with("compare_validation_modes")
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("sweep")
+                .about("Runs the simulation for every combination of the values of some parameters and writes the statistics of the runs to a CSV file. The arguments preceding the subcommand apply to every run.")
+                .arg(
+                    Arg::with_name("parameter")
+                        .short("p")
+                        .long("parameter")
+                        .value_name("NAME=START:END:STEP|NAME=VALUE,VALUE,...")
+                        .help("A parameter to vary, by the long name of its argument. The end of a range is included.")
+                        .required(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("repetitions")
+                        .long("repetitions")
+                        .value_name("REPETITIONS")
+                        .help("The number of runs of every combination.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("parallel_runs")
+                        .long("parallel_runs")
+                        .value_name("PARALLEL_RUNS")
+                        .help("The number of runs at the same time. Defaults to the number of CPUs.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .value_name("CSV_FILE")
+                        .help("The file the statistics of the runs are written to.")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        );
 
-    let initiated_connections_per_node: u8 = parse_unsigned_integer(
-        matches.value_of("initiated_connections_per_node"),
-        "3",
-        255,
-        "Invalid number of initiated connections per node, expected [1-255]",
-    );
+    let matches = app.get_matches();
+    let scenario = scenario(&matches);
 
-    let difficulty_factor: u8 = parse_unsigned_integer(
-        matches.value_of("difficulty_factor"),
-        "15",
-        224,
-        "Invalid difficulty factor, expected [1-224]",
-    );
+    if let Some(sweep_matches) = matches.subcommand_matches("sweep") {
+        // The arguments preceding the subcommand apply to every run.
+        run_sweep(&scenario, sweep_matches);
+        return;
+    }
 
-    let duration_in_seconds: u64 = parse_unsigned_integer(
-        matches.value_of("duration_in_seconds"),
-        "30",
-        999999,
-        "Invalid duration in seconds, expected [1-999999]",
-    );
+    let mut config =
+        SimulationConfig::from_scenario(&scenario).unwrap_or_else(|err| panic!("{}", err));
+    let output = &scenario.output;
+    let report_format = output.report_format.unwrap_or(ReportFormat::Text);
 
-    let mining_delay: u64 = parse_unsigned_integer(
-        matches.value_of("mining_delay"),
-        "10",
-        999999,
-        "Invalid hash duration in milliseconds, expected [1-999999]",
-    );
+    if scenario.relay.compare_validation_modes == Some(true) {
+        let validate_then_relay = run(&config).report;
+        config.relay_before_validation = true;
+        let relay_then_validate = run(&config).report;
+        let reports = [
+            ("validate_then_relay", &validate_then_relay),
+            ("relay_then_validate", &relay_then_validate),
+        ];
+        print_report(
+            &report::render_comparison(&reports, report_format),
+            &output.report_file,
+        );
+        info!(
+            "Orphan rate when validating then relaying: {:.2}% ({} of {} blocks), when relaying before validating: {:.2}% ({} of {} blocks)",
+            validate_then_relay.orphan_rate,
+            validate_then_relay.orphaned_blocks,
+            validate_then_relay.blocks_mined,
+            relay_then_validate.orphan_rate,
+            relay_then_validate.orphaned_blocks,
+            relay_then_validate.blocks_mined
+        );
+    } else {
+        let result = run(&config);
+        print_report(&result.report.render(report_format), &output.report_file);
+        if let Some(ref path) = output.save_chain {
+            save_chain(&result, path);
+        }
+        if let Some(ref directory) = output.checkpoint {
+            let checkpoint = Checkpoint::new(&result).unwrap_or_else(|err| panic!("{}", err));
+            checkpoint.save(directory).unwrap_or_else(|err| {
+                panic!("Could not save the checkpoint to {}: {}", directory.display(), err)
+            });
+            info!("Saved the checkpoint to {}", directory.display());
+        }
+    }
+}
 
-    pow_network_simulation(
-        number_of_nodes,
-        initiated_connections_per_node,
-        difficulty_factor,
-        Duration::from_secs(duration_in_seconds),
-        Duration::from_millis(mining_delay),
-    )
+/// Runs the network, panicking if the configuration is not valid or the export of the run
+/// cannot be written.
+fn run(config: &SimulationConfig) -> SimulationResult {
+    pow::run(config).unwrap_or_else(|err| panic!("{}", err))
 }
 
-pub fn pow_network_simulation(
-    number_of_nodes: u32,
-    initiated_connections_per_node: u8,
-    difficulty_factor: u8,
-    duration: Duration,
-    mining_attempt_delay: Duration,
-) {
-    // Set up a chain.
-    let mut difficulty = Difficulty::min_difficulty();
-    for _i in 0u8..difficulty_factor {
-        difficulty.increase();
+/// The scenario of the run, if any, overridden by the command line arguments.
+fn scenario(matches: &ArgMatches) -> Scenario {
+    let mut scenario = match matches.value_of("scenario") {
+        Some(path) => Scenario::load(path).unwrap_or_else(|err| panic!("{}", err)),
+        None => Scenario::default(),
+    };
+
+    for parameter in scenario::PARAMETERS.iter() {
+        let value = match matches.value_of(parameter) {
+            Some(value) => value,
+            // A flag.
+            None if matches.is_present(parameter) => "true",
+            None => continue,
+        };
+        scenario
+            .set(parameter, value)
+            .unwrap_or_else(|err| panic!("{}", err));
     }
 
-    info!("Chain difficulty threshold: {:?}", difficulty);
+    scenario
+}
 
-    let chain = Arc::new(Chain::init_new(difficulty));
-    let node_id = AtomicUsize::new(0);
+fn save_chain(result: &SimulationResult, path: &Path) {
+    let chain = recorder::strongest_chain(result.records.iter()).expect("No chain to save");
+    store::save(chain, path)
+        .unwrap_or_else(|err| panic!("Could not save the chain to {}: {}", path.display(), err));
+    info!("Saved the chain of height {} to {}", chain.height(), path.display());
+}
 
-    // Run the blockchain network.
-    let network = Network::new(number_of_nodes, initiated_connections_per_node);
-    network.run(
-        move || {
-            let node_id = node_id.fetch_add(1, Ordering::Relaxed) as u32;
-            PowNode::new(node_id, chain.clone(), mining_attempt_delay)
-        },
-        duration,
+fn print_report(rendered_report: &str, report_file: &Option<PathBuf>) {
+    println!("{}", rendered_report);
+    if let Some(ref report_file) = *report_file {
+        write_file(report_file, rendered_report);
+    }
+}
+
+/// Runs the simulation for every combination of the parameters of the sweep on top of the
+/// scenario, then writes the statistics of the runs.
+fn run_sweep(scenario: &Scenario, sweep_matches: &ArgMatches) {
+    let parameters: Vec<Parameter> = sweep_matches
+        .values_of("parameter")
+        .expect("No parameter to sweep")
+        .map(|parameter| Parameter::parse(parameter).unwrap_or_else(|err| panic!("{}", err)))
+        .collect();
+    let repetitions: u32 = parse_unsigned_integer(
+        sweep_matches.value_of("repetitions"),
+        "5",
+        999999,
+        "Invalid number of repetitions, expected [1-999999]",
+    );
+    let default_parallel_runs = thread::available_parallelism()
+        .map(|parallelism| parallelism.get())
+        .unwrap_or(1)
+        .to_string();
+    let parallel_runs: usize = parse_unsigned_integer(
+        sweep_matches.value_of("parallel_runs"),
+        &default_parallel_runs,
+        1024,
+        "Invalid number of parallel runs, expected [1-1024]",
     );
+    let output = PathBuf::from(sweep_matches.value_of("output").expect("No output file"));
+
+    let results = sweep::run(scenario, &parameters, repetitions, parallel_runs)
+        .unwrap_or_else(|err| panic!("{}", err));
+    write_file(&output, &sweep::to_csv(&parameters, &results));
+    info!("Wrote the statistics of the sweep to {}", output.display());
+}
+
+fn write_file(path: &Path, content: &str) {
+    if let Err(err) = fs::write(path, content) {
+        panic!("Could not write {}: {}", path.display(), err)
+    }
 }
 
 pub fn parse_unsigned_integer<I>(
@@ -0,0 +1,642 @@
+//! The summary of a run, built from the records of the nodes once the network stopped.
+
+use blockchain::recorder::{self, MessageStats, NodeRecord};
+use blockchain::Fork;
+use netsim::network::rate_limit::ThrottleStats;
+use serde_json;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+/// The shares of the nodes a block must reach for its propagation time to be measured.
+const PROPAGATION_SHARES: [u32; 3] = [50, 90, 100];
+/// The share of the nodes a block must reach to be considered known by the miners, when
+/// computing the expected orphan rate.
+const MODEL_PROPAGATION_SHARE: u32 = 50;
+/// How far, in percentage points, the orphan rate can always be from the expected one.
+const MODEL_TOLERANCE: f64 = 1.0;
+/// The number of blocks mined under which the orphan rate is not compared to the model.
+const MODEL_MIN_BLOCKS: usize = 20;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportFormat {
+    /// Human-readable text.
+    Text,
+    Json,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Report {
+    pub number_of_nodes: usize,
+    /// The height of the strongest chain known by a node.
+    pub consensus_height: u32,
+    pub best_tip: String,
+    /// The percentage of the nodes whose best chain ends with the best tip.
+    pub nodes_on_best_tip: f64,
+    pub blocks_mined: usize,
+    /// The blocks mined during the run that are not part of the strongest chain.
+    pub orphaned_blocks: usize,
+    pub orphan_rate: f64,
+    /// The orphan rate predicted by `1 - e^(-d/T)`, where `d` is the mean time for a block
+    /// to reach half of the nodes and `T` the mean block interval.
+    pub expected_orphan_rate: Option<f64>,
+    /// Whether the orphan rate is close to the expected one, within the larger of the
+    /// expected rate and one percentage point. Far from it, artefacts of the simulation such
+    /// as the scheduling of the nodes or the absence of latency may dominate.
+    pub orphan_rate_matches_model: Option<bool>,
+    /// The number of blocks known by every node that are not part of its best chain.
+    pub stale_blocks_per_node: Option<Distribution>,
+    /// The number of blocks of the side branches seen by the nodes.
+    pub fork_depths: Option<Distribution>,
+    /// The time between the first block of a side branch being seen by a node and the best
+    /// chain of the node overtaking it, in milliseconds.
+    pub fork_resolution_times: Option<Distribution>,
+    /// The side branches still tied with the best chain of a node at the end of the run.
+    pub unresolved_forks: usize,
+    /// The number of reorgs of every depth experienced by the nodes, the attacker left out.
+    pub reorg_depths: BTreeMap<u32, u32>,
+    /// The time it took for the blocks to reach a share of the nodes, in milliseconds.
+    pub propagation: Vec<Propagation>,
+    /// The intervals between the block timestamps of the strongest chain, in milliseconds,
+    /// for the blocks mined during the run.
+    pub block_intervals: Option<Distribution>,
+    pub blocks_mined_per_node: Option<Distribution>,
+    /// The number of blocks mined by every node, by node id.
+    pub blocks_mined_by_node: BTreeMap<u32, u32>,
+    /// The messages sent by the nodes, by message type.
+    pub traffic: BTreeMap<&'static str, MessageStats>,
+    /// The messages received beyond the rate limit of the connections, if there is one.
+    pub throttled_messages: Option<ThrottledMessages>,
+    /// The round-trip times to their peers measured by the nodes monitoring them, in
+    /// milliseconds.
+    pub round_trip_times: Option<Distribution>,
+    /// The peers dropped by the nodes because they did not answer their pings in time.
+    pub dead_peers_dropped: usize,
+    /// The dropped peers that answered a ping later on, and were relayed to again.
+    pub dead_peers_restored: usize,
+    /// The revenue of the selfish miner, if there is one.
+    pub selfish_mining: Option<SelfishMiningRevenue>,
+    /// The outcome of the double-spend attack, if there is one.
+    pub double_spend: Option<DoubleSpendAttempt>,
+    /// The blocks won by the mining pools, if there are some.
+    pub mining_pools: Option<MiningPools>,
+}
+
+/// The blocks of the strongest chain won by the mining pools and by the miners that are not in
+/// a pool.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MiningPools {
+    pub pools: Vec<PoolShare>,
+    pub solo_blocks: usize,
+    /// How long before the median of the other nodes the solo miners received their own
+    /// blocks, in milliseconds, for comparison with the head start of the operators.
+    pub solo_head_starts: Option<Distribution>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PoolShare {
+    pub operator_id: u32,
+    pub members: usize,
+    pub hashrate_share: f64,
+    pub block_share: f64,
+    pub blocks: usize,
+    /// How long before the median node outside the pool its operator received the blocks of
+    /// the pool, in milliseconds.
+    pub operator_head_starts: Option<Distribution>,
+}
+
+/// Whether the payment made in the block following the fork height of a double-spend attack
+/// was reverted.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DoubleSpendOutcome {
+    /// The payment was replaced by a block of the attacker in the honest nodes' chain.
+    Succeeded,
+    Failed,
+    /// The honest nodes' chain did not reach the height of the payment.
+    NotStarted,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DoubleSpendAttempt {
+    /// The share of the hashrate of the network owned by the attacker.
+    pub hashrate_share: f64,
+    pub confirmations: u32,
+    pub payment_height: u32,
+    pub outcome: DoubleSpendOutcome,
+    /// The probability of success predicted for an attacker that never gives up.
+    pub success_probability: f64,
+}
+
+/// The share of the blocks of the honest nodes' chain mined by the selfish miner, along with
+/// the share expected by Eyal and Sirer.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SelfishMiningRevenue {
+    /// The share of the hashrate of the network owned by the selfish miner.
+    pub hashrate_share: f64,
+    pub revenue_share: f64,
+    pub selfish_blocks: u32,
+    pub total_blocks: u32,
+    pub expected_revenue_share: f64,
+    /// The hashrate share above which selfish mining earns more than honest mining.
+    pub profitability_threshold: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ThrottledMessages {
+    pub dropped: usize,
+    pub delayed: usize,
+    /// The connections closed by the receiver of the message.
+    pub disconnections: usize,
+}
+
+impl ThrottledMessages {
+    pub fn new(stats: &ThrottleStats) -> ThrottledMessages {
+        ThrottledMessages {
+            dropped: stats.dropped(),
+            delayed: stats.delayed(),
+            disconnections: stats.disconnections(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Propagation {
+    /// The percentage of the nodes to reach.
+    pub share_of_nodes: u32,
+    /// The number of blocks that reached this share of the nodes.
+    pub blocks: usize,
+    pub time: Option<Distribution>,
+}
+
+/// A summary of a set of values.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Distribution {
+    pub count: usize,
+    pub mean: f64,
+    pub min: i64,
+    pub p50: i64,
+    pub p90: i64,
+    pub p99: i64,
+    pub max: i64,
+}
+
+impl Distribution {
+    /// Returns `None` if there are no values.
+    pub fn new(mut values: Vec<i64>) -> Option<Distribution> {
+        if values.is_empty() {
+            return None;
+        }
+
+        values.sort();
+        Some(Distribution {
+            count: values.len(),
+            mean: values.iter().sum::<i64>() as f64 / values.len() as f64,
+            min: values[0],
+            p50: percentile(&values, 50),
+            p90: percentile(&values, 90),
+            p99: percentile(&values, 99),
+            max: values[values.len() - 1],
+        })
+    }
+}
+
+/// The nearest-rank percentile of sorted values.
+fn percentile(sorted_values: &[i64], percent: u32) -> i64 {
+    sorted_values[rank(sorted_values.len(), percent) - 1]
+}
+
+/// The smallest number of items making at least the given percentage of the count, and at
+/// least one.
+fn rank(count: usize, percent: u32) -> usize {
+    (count * percent as usize).div_ceil(100).max(1)
+}
+
+impl Report {
+    /// The report on the nodes of a run started from a chain of the given height.
+    pub fn new(
+        records: &[NodeRecord],
+        root_height: u32,
+        traffic: BTreeMap<&'static str, MessageStats>,
+        throttle_stats: Option<&ThrottleStats>,
+    ) -> Report {
+        let best_chain = recorder::strongest_chain(records.iter())
+            .expect("There is no node to report on.")
+            .clone();
+        let best_tip = best_chain.head().hash();
+
+        let nodes_on_best_tip = records
+            .iter()
+            .filter(|record| record.best_chain.head().hash() == best_tip)
+            .count();
+
+        let best_chain_blocks: HashSet<_> =
+            best_chain.iter().map(|chain| chain.head().hash()).collect();
+        let mined_blocks = recorder::mined_blocks(records);
+        let orphaned_blocks = mined_blocks
+            .iter()
+            .filter(|(_miner_id, hash, _height)| !best_chain_blocks.contains(hash))
+            .count();
+
+        let forks: Vec<Fork> = records
+            .iter()
+            .flat_map(|record| record.block_tree.forks())
+            .collect();
+        let fork_resolution_times: Vec<i64> = forks
+            .iter()
+            .filter_map(|fork| {
+                fork.resolved_at
+                    .map(|resolved_at| millis(resolved_at) - millis(fork.seen_at))
+            })
+            .collect();
+
+        let blocks_mined_by_node: BTreeMap<u32, u32> = records
+            .iter()
+            .map(|record| (record.node_id, record.blocks_mined))
+            .collect();
+
+        let orphan_rate = percentage(orphaned_blocks, mined_blocks.len());
+        let propagation = propagation(records);
+        let block_intervals = Distribution::new(
+            best_chain
+                .block_intervals()
+                .split_off((root_height as usize).min(best_chain.height() as usize)),
+        );
+        let propagation_delay = propagation
+            .iter()
+            .find(|propagation| propagation.share_of_nodes == MODEL_PROPAGATION_SHARE)
+            .and_then(|propagation| propagation.time.as_ref());
+        let expected_orphan_rate = match (propagation_delay, &block_intervals) {
+            (Some(delay), Some(intervals)) if intervals.mean > 0f64 => {
+                Some(100f64 * expected_orphan_rate(
+                    Duration::from_secs_f64(delay.mean / 1000f64),
+                    Duration::from_secs_f64(intervals.mean / 1000f64),
+                ))
+            }
+            _ => None,
+        };
+        let orphan_rate_matches_model = expected_orphan_rate
+            .filter(|_expected| mined_blocks.len() >= MODEL_MIN_BLOCKS)
+            .map(|expected| (orphan_rate - expected).abs() <= expected.max(MODEL_TOLERANCE));
+
+        Report {
+            number_of_nodes: records.len(),
+            consensus_height: best_chain.height(),
+            best_tip: format!("{:?}", best_tip),
+            nodes_on_best_tip: percentage(nodes_on_best_tip, records.len()),
+            blocks_mined: mined_blocks.len(),
+            orphaned_blocks,
+            orphan_rate,
+            expected_orphan_rate,
+            orphan_rate_matches_model,
+            stale_blocks_per_node: Distribution::new(
+                records
+                    .iter()
+                    .map(|record| record.block_tree.stale_blocks() as i64)
+                    .collect(),
+            ),
+            fork_depths: Distribution::new(
+                forks.iter().map(|fork| i64::from(fork.depth)).collect(),
+            ),
+            unresolved_forks: forks.len() - fork_resolution_times.len(),
+            fork_resolution_times: Distribution::new(fork_resolution_times),
+            reorg_depths: recorder::reorg_histogram(records.iter()),
+            propagation,
+            block_intervals,
+            blocks_mined_per_node: Distribution::new(
+                blocks_mined_by_node
+                    .values()
+                    .map(|blocks| i64::from(*blocks))
+                    .collect(),
+            ),
+            blocks_mined_by_node,
+            traffic,
+            throttled_messages: throttle_stats.map(ThrottledMessages::new),
+            round_trip_times: Distribution::new(
+                records
+                    .iter()
+                    .flat_map(|record| record.round_trip_times.iter().cloned().map(millis))
+                    .collect(),
+            ),
+            dead_peers_dropped: records
+                .iter()
+                .map(|record| record.dead_peers_dropped as usize)
+                .sum(),
+            dead_peers_restored: records
+                .iter()
+                .map(|record| record.dead_peers_restored as usize)
+                .sum(),
+            selfish_mining: None,
+            double_spend: None,
+            mining_pools: None,
+        }
+    }
+
+    pub fn render(&self, format: ReportFormat) -> String {
+        match format {
+            ReportFormat::Text => self.to_string(),
+            ReportFormat::Json => {
+                serde_json::to_string_pretty(self).expect("Could not serialize the report")
+            }
+        }
+    }
+}
+
+/// Renders the reports of several runs together, by name of run: a JSON object keyed by the
+/// names, or the text reports under a heading each.
+pub fn render_comparison(reports: &[(&str, &Report)], format: ReportFormat) -> String {
+    match format {
+        ReportFormat::Text => reports
+            .iter()
+            .map(|(name, report)| format!("== {} ==\n{}", name, report))
+            .collect::<Vec<String>>()
+            .join("\n"),
+        ReportFormat::Json => {
+            let reports: BTreeMap<&str, &Report> = reports.iter().cloned().collect();
+            serde_json::to_string_pretty(&reports).expect("Could not serialize the reports")
+        }
+    }
+}
+
+/// The share of the blocks expected to be orphaned when they take `propagation_delay` to
+/// reach the other miners, one block being found every `block_interval` on average: the
+/// probability that another block is found in the meantime, `1 - e^(-d/T)`.
+pub fn expected_orphan_rate(propagation_delay: Duration, block_interval: Duration) -> f64 {
+    1f64 - (-propagation_delay.as_secs_f64() / block_interval.as_secs_f64()).exp()
+}
+
+fn percentage(part: usize, total: usize) -> f64 {
+    if total == 0 {
+        0f64
+    } else {
+        100f64 * part as f64 / total as f64
+    }
+}
+
+/// The time it took for every mined block to reach each share of the nodes, counted from
+/// the moment it was mined.
+fn propagation(records: &[NodeRecord]) -> Vec<Propagation> {
+    let mut delivery_times: HashMap<_, Vec<Duration>> = HashMap::new();
+    for record in records {
+        for (hash, delivery) in &record.deliveries {
+            delivery_times
+                .entry(hash.clone())
+                .or_default()
+                .push(delivery.at);
+        }
+    }
+
+    let mined_blocks = recorder::mined_blocks(records);
+    PROPAGATION_SHARES
+        .iter()
+        .map(|share_of_nodes| {
+            let nodes_to_reach = rank(records.len(), *share_of_nodes);
+            let times: Vec<i64> = mined_blocks
+                .iter()
+                .filter_map(|(_miner_id, hash, _height)| {
+                    let mut times = delivery_times[hash].clone();
+                    if times.len() < nodes_to_reach {
+                        return None;
+                    }
+
+                    // The miner is the first to know the block.
+                    times.sort();
+                    Some(millis(times[nodes_to_reach - 1] - times[0]))
+                })
+                .collect();
+
+            Propagation {
+                share_of_nodes: *share_of_nodes,
+                blocks: times.len(),
+                time: Distribution::new(times),
+            }
+        })
+        .collect()
+}
+
+fn millis(duration: Duration) -> i64 {
+    duration.as_secs() as i64 * 1000 + i64::from(duration.subsec_millis())
+}
+
+impl Display for Distribution {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "mean {:.1}, min {}, p50 {}, p90 {}, p99 {}, max {}",
+            self.mean, self.min, self.p50, self.p90, self.p99, self.max
+        )
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f, "Nodes: {}", self.number_of_nodes)?;
+        writeln!(f, "Consensus height: {}", self.consensus_height)?;
+        writeln!(f, "Best tip: {}", self.best_tip)?;
+        writeln!(f, "Nodes on the best tip: {:.1}%", self.nodes_on_best_tip)?;
+        writeln!(
+            f,
+            "Blocks mined: {}, orphaned: {}, orphan rate: {:.2}%",
+            self.blocks_mined, self.orphaned_blocks, self.orphan_rate
+        )?;
+        match self.expected_orphan_rate {
+            Some(expected_orphan_rate) => writeln!(
+                f,
+                "Expected orphan rate (1 - e^(-d/T)): {:.2}%",
+                expected_orphan_rate
+            )?,
+            None => writeln!(f, "Expected orphan rate (1 - e^(-d/T)): unknown")?,
+        }
+        if self.orphan_rate_matches_model == Some(false) {
+            writeln!(
+                f,
+                "  The orphan rate is far from the expected one, artefacts of the simulation such as the scheduling of the nodes or the absence of latency may dominate."
+            )?;
+        }
+
+        write!(f, "Stale blocks per node: ")?;
+        write_distribution(f, &self.stale_blocks_per_node)?;
+        write!(f, "Fork depths: ")?;
+        write_distribution(f, &self.fork_depths)?;
+        write!(f, "Fork resolution times (ms): ")?;
+        write_distribution(f, &self.fork_resolution_times)?;
+        writeln!(f, "Unresolved forks: {}", self.unresolved_forks)?;
+        if self.reorg_depths.is_empty() {
+            writeln!(f, "Reorgs: none")?;
+        } else {
+            let reorg_depths: Vec<String> = self
+                .reorg_depths
+                .iter()
+                .map(|(depth, count)| format!("depth {}: {}", depth, count))
+                .collect();
+            writeln!(f, "Reorgs: {}", reorg_depths.join(", "))?;
+        }
+
+        for propagation in &self.propagation {
+            write!(
+                f,
+                "Time to reach {}% of the nodes (ms, {} blocks): ",
+                propagation.share_of_nodes, propagation.blocks
+            )?;
+            write_distribution(f, &propagation.time)?;
+        }
+
+        write!(f, "Block intervals (ms): ")?;
+        write_distribution(f, &self.block_intervals)?;
+        write!(f, "Blocks mined per node: ")?;
+        write_distribution(f, &self.blocks_mined_per_node)?;
+
+        let total = self
+            .traffic
+            .values()
+            .fold(MessageStats::default(), |total, stats| MessageStats {
+                messages: total.messages + stats.messages,
+                bytes: total.bytes + stats.bytes,
+            });
+        writeln!(f, "Messages sent: {}, {} bytes", total.messages, total.bytes)?;
+        for (kind, stats) in &self.traffic {
+            writeln!(f, "  {}: {}, {} bytes", kind, stats.messages, stats.bytes)?;
+        }
+        if let Some(ref throttled) = self.throttled_messages {
+            writeln!(
+                f,
+                "Messages beyond the rate limit: {} dropped, {} delayed, {} disconnections",
+                throttled.dropped, throttled.delayed, throttled.disconnections
+            )?;
+        }
+        if self.round_trip_times.is_some() || self.dead_peers_dropped > 0 {
+            write!(f, "Round-trip times to the peers (ms): ")?;
+            write_distribution(f, &self.round_trip_times)?;
+            writeln!(
+                f,
+                "Dead peers dropped: {}, restored: {}",
+                self.dead_peers_dropped, self.dead_peers_restored
+            )?;
+        }
+        if let Some(ref mining_pools) = self.mining_pools {
+            for pool in &mining_pools.pools {
+                writeln!(
+                    f,
+                    "Pool #{} of {} members: hashrate share {:.3}, block share {:.3} ({} blocks)",
+                    pool.operator_id,
+                    pool.members,
+                    pool.hashrate_share,
+                    pool.block_share,
+                    pool.blocks
+                )?;
+                write!(f, "  Operator head start (ms): ")?;
+                write_distribution(f, &pool.operator_head_starts)?;
+            }
+            writeln!(f, "Solo miners: {} blocks", mining_pools.solo_blocks)?;
+            write!(f, "  Miner head start (ms): ")?;
+            write_distribution(f, &mining_pools.solo_head_starts)?;
+        }
+        if let Some(ref selfish_mining) = self.selfish_mining {
+            writeln!(
+                f,
+                "Selfish miner: hashrate share {:.3}, revenue share {:.3} ({} of {} blocks), expected {:.3}, profitability threshold {:.3}",
+                selfish_mining.hashrate_share,
+                selfish_mining.revenue_share,
+                selfish_mining.selfish_blocks,
+                selfish_mining.total_blocks,
+                selfish_mining.expected_revenue_share,
+                selfish_mining.profitability_threshold
+            )?;
+        }
+        if let Some(ref double_spend) = self.double_spend {
+            let outcome = match double_spend.outcome {
+                DoubleSpendOutcome::Succeeded => "succeeded, the payment was reverted",
+                DoubleSpendOutcome::Failed => "failed, the payment is in the main chain",
+                DoubleSpendOutcome::NotStarted => "not started, the main chain is too short",
+            };
+            writeln!(
+                f,
+                "Double-spend attack at height {}: {}",
+                double_spend.payment_height, outcome
+            )?;
+            writeln!(
+                f,
+                "  Hashrate share {:.3}, {} confirmations, success probability {:.3}",
+                double_spend.hashrate_share,
+                double_spend.confirmations,
+                double_spend.success_probability
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn write_distribution(f: &mut Formatter, distribution: &Option<Distribution>) -> fmt::Result {
+    match *distribution {
+        Some(ref distribution) => writeln!(f, "{}", distribution),
+        None => writeln!(f, "none"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::recorder::Recorder;
+    use blockchain::testing;
+    use blockchain::{Chain, Difficulty};
+    use std::sync::Arc;
+
+    #[test]
+    fn can_summarize_a_distribution() {
+        assert_eq!(None, Distribution::new(vec![]));
+
+        let distribution = Distribution::new((1..101).rev().collect()).unwrap();
+        assert_eq!(100, distribution.count);
+        assert_eq!(50.5, distribution.mean);
+        assert_eq!(
+            (1, 50, 90, 99, 100),
+            (
+                distribution.min,
+                distribution.p50,
+                distribution.p90,
+                distribution.p99,
+                distribution.max
+            )
+        );
+
+        let distribution = Distribution::new(vec![7]).unwrap();
+        assert_eq!((7, 7, 7), (distribution.min, distribution.p50, distribution.max));
+    }
+
+    #[test]
+    fn predicts_the_orphan_rate() {
+        let block_interval = Duration::from_secs(600);
+        assert_eq!(0f64, expected_orphan_rate(Duration::from_secs(0), block_interval));
+
+        // Another block is found during the propagation half of the time.
+        let propagation_delay = Duration::from_secs_f64(600f64 * 2f64.ln());
+        assert!((0.5 - expected_orphan_rate(propagation_delay, block_interval)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn counts_only_the_blocks_mined_during_the_run() {
+        let genesis = Arc::new(Chain::init_new(Difficulty::min_difficulty(), None));
+        let mut root = genesis;
+        for _ in 0..10 {
+            root = testing::expand(&root, 0);
+        }
+
+        let recorder = Recorder::new();
+        let miner = recorder.node_from(0, root.clone(), root.clone());
+        let peer = recorder.node_from(1, root.clone(), root.clone());
+        let chain = testing::expand(&root, 0);
+        miner.mined(&chain);
+        miner.adopted(&chain);
+        peer.received(&chain, 0);
+        peer.adopted(&chain);
+
+        let report = Report::new(&recorder.records(), root.height(), BTreeMap::new(), None);
+        assert_eq!(1, report.blocks_mined);
+        assert_eq!(
+            report.blocks_mined,
+            report.blocks_mined_by_node.values().sum::<u32>() as usize
+        );
+        assert_eq!(0, report.orphaned_blocks);
+    }
+}
@@ -0,0 +1,480 @@
+//! Scenario files: the parameters of a run, written in TOML instead of passed on the command
+//! line, along with the changes of the network conditions scheduled during the run.
+
+use blockchain::{ForkChoice, RelayProtocol};
+use netsim::network::conditions::NetworkEvent;
+use rand::{self, Rng};
+use report::ReportFormat;
+use serde::de::value::{Error as ValueError, StrDeserializer};
+use serde::de::{DeserializeOwned, IntoDeserializer};
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use toml;
+
+/// The parameters of a scenario, by the long names of the command line arguments they stand
+/// for.
+pub const PARAMETERS: [&str; 43] = [
+    "duration_in_seconds",
+    "mining_delay",
+    "fork_choice",
+    "max_clock_skew",
+    "max_future_drift",
+    "load_chain",
+    "resume",
+    "checkpoint_height",
+    "network_size",
+    "connections",
+    "latency",
+    "latency_jitter",
+    "difficulty",
+    "retarget_interval",
+    "target_block_interval",
+    "max_adjustment_factor",
+    "hashrate_distribution",
+    "pareto_shape",
+    "hashrates",
+    "pools",
+    "pool_members",
+    "selfish_hashrate_share",
+    "gamma",
+    "double_spend_hashrate_share",
+    "double_spend_fork_height",
+    "double_spend_confirmations",
+    "double_spend_max_deficit",
+    "relay",
+    "block_size",
+    "validation_delay",
+    "validation_delay_per_megabyte",
+    "relay_before_validation",
+    "compare_validation_modes",
+    "rate_limit",
+    "rate_limit_refill_interval",
+    "rate_limit_policy",
+    "heartbeat_interval",
+    "heartbeat_timeout",
+    "report_format",
+    "report_file",
+    "export_directory",
+    "save_chain",
+    "checkpoint",
+];
+
+/// The parameters of a run, grouped in sections. A missing parameter takes its default value,
+/// see `SimulationConfig::from_scenario`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Scenario {
+    pub simulation: SimulationSection,
+    pub topology: TopologySection,
+    pub latency: LatencySection,
+    pub difficulty: DifficultySection,
+    pub hashrate: HashrateSection,
+    pub nodes: NodesSection,
+    pub relay: RelaySection,
+    pub peers: PeersSection,
+    pub output: OutputSection,
+    pub events: Vec<ScenarioEvent>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulationSection {
+    pub duration_in_seconds: Option<u64>,
+    pub mining_delay: Option<u64>,
+    pub fork_choice: Option<ForkChoice>,
+    pub max_clock_skew: Option<u64>,
+    pub max_future_drift: Option<u64>,
+    pub load_chain: Option<PathBuf>,
+    pub resume: Option<PathBuf>,
+    pub checkpoint_height: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TopologySection {
+    pub network_size: Option<u32>,
+    pub connections: Option<u8>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LatencySection {
+    pub latency: Option<u64>,
+    pub latency_jitter: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DifficultySection {
+    pub difficulty: Option<u8>,
+    pub retarget_interval: Option<u32>,
+    pub target_block_interval: Option<u64>,
+    pub max_adjustment_factor: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HashrateSection {
+    pub hashrate_distribution: Option<HashrateDistributionName>,
+    pub pareto_shape: Option<f64>,
+    pub hashrates: Option<Vec<u32>>,
+}
+
+/// The hashrate distributions that can be chosen by name, the explicit one is given by its
+/// hashrates.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HashrateDistributionName {
+    Uniform,
+    Pareto,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodesSection {
+    pub pools: Option<u32>,
+    pub pool_members: Option<u32>,
+    pub selfish_hashrate_share: Option<f64>,
+    pub gamma: Option<f64>,
+    pub double_spend_hashrate_share: Option<f64>,
+    pub double_spend_fork_height: Option<u32>,
+    pub double_spend_confirmations: Option<u32>,
+    pub double_spend_max_deficit: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelaySection {
+    pub relay: Option<RelayProtocol>,
+    pub block_size: Option<u64>,
+    pub validation_delay: Option<u64>,
+    pub validation_delay_per_megabyte: Option<u64>,
+    pub relay_before_validation: Option<bool>,
+    pub compare_validation_modes: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeersSection {
+    pub rate_limit: Option<u32>,
+    pub rate_limit_refill_interval: Option<u64>,
+    pub rate_limit_policy: Option<RateLimitPolicyName>,
+    pub heartbeat_interval: Option<u64>,
+    pub heartbeat_timeout: Option<u64>,
+}
+
+/// What a connection does with the messages exceeding its rate limit, see `ExceededPolicy`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitPolicyName {
+    Drop,
+    Delay,
+    Disconnect,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputSection {
+    pub report_format: Option<ReportFormat>,
+    pub report_file: Option<PathBuf>,
+    pub export_directory: Option<PathBuf>,
+    pub save_chain: Option<PathBuf>,
+    pub checkpoint: Option<PathBuf>,
+}
+
+/// A change of the network conditions, `at_seconds` after the start of the run. The nodes
+/// concerned are drawn at random.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ScenarioEvent {
+    /// Cuts this share of the nodes from the rest of the network.
+    Partition { at_seconds: u64, share: f64 },
+    /// Ends the partition.
+    Heal { at_seconds: u64 },
+    /// This share of the nodes goes offline for a while.
+    Churn {
+        at_seconds: u64,
+        share: f64,
+        duration_seconds: u64,
+    },
+}
+
+impl Scenario {
+    pub fn load(path: &str) -> Result<Scenario, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| format!("Could not read the scenario {}: {}", path, err))?;
+        Scenario::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> Result<Scenario, String> {
+        toml::from_str(content).map_err(|err| format!("Invalid scenario: {}", err))
+    }
+
+    /// Sets a parameter by the long name of its command line argument, overriding the value
+    /// of the scenario. Flags are set with `true` or `false`, the elements of a list are
+    /// separated by commas.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "duration_in_seconds" => self.simulation.duration_in_seconds = Some(parse(key, value)?),
+            "mining_delay" => self.simulation.mining_delay = Some(parse(key, value)?),
+            "fork_choice" => self.simulation.fork_choice = Some(parse_name(key, value)?),
+            "max_clock_skew" => self.simulation.max_clock_skew = Some(parse(key, value)?),
+            "max_future_drift" => self.simulation.max_future_drift = Some(parse(key, value)?),
+            "load_chain" => self.simulation.load_chain = Some(parse(key, value)?),
+            "resume" => self.simulation.resume = Some(parse(key, value)?),
+            "checkpoint_height" => self.simulation.checkpoint_height = Some(parse(key, value)?),
+            "network_size" => self.topology.network_size = Some(parse(key, value)?),
+            "connections" => self.topology.connections = Some(parse(key, value)?),
+            "latency" => self.latency.latency = Some(parse(key, value)?),
+            "latency_jitter" => self.latency.latency_jitter = Some(parse(key, value)?),
+            "difficulty" => self.difficulty.difficulty = Some(parse(key, value)?),
+            "retarget_interval" => self.difficulty.retarget_interval = Some(parse(key, value)?),
+            "target_block_interval" => {
+                self.difficulty.target_block_interval = Some(parse(key, value)?)
+            }
+            "max_adjustment_factor" => {
+                self.difficulty.max_adjustment_factor = Some(parse(key, value)?)
+            }
+            "hashrate_distribution" => {
+                self.hashrate.hashrate_distribution = Some(parse_name(key, value)?)
+            }
+            "pareto_shape" => self.hashrate.pareto_shape = Some(parse(key, value)?),
+            "hashrates" => self.hashrate.hashrates = Some(parse_list(key, value)?),
+            "pools" => self.nodes.pools = Some(parse(key, value)?),
+            "pool_members" => self.nodes.pool_members = Some(parse(key, value)?),
+            "selfish_hashrate_share" => {
+                self.nodes.selfish_hashrate_share = Some(parse(key, value)?)
+            }
+            "gamma" => self.nodes.gamma = Some(parse(key, value)?),
+            "double_spend_hashrate_share" => {
+                self.nodes.double_spend_hashrate_share = Some(parse(key, value)?)
+            }
+            "double_spend_fork_height" => {
+                self.nodes.double_spend_fork_height = Some(parse(key, value)?)
+            }
+            "double_spend_confirmations" => {
+                self.nodes.double_spend_confirmations = Some(parse(key, value)?)
+            }
+            "double_spend_max_deficit" => {
+                self.nodes.double_spend_max_deficit = Some(parse(key, value)?)
+            }
+            "relay" => self.relay.relay = Some(parse_name(key, value)?),
+            "block_size" => self.relay.block_size = Some(parse(key, value)?),
+            "validation_delay" => self.relay.validation_delay = Some(parse(key, value)?),
+            "validation_delay_per_megabyte" => {
+                self.relay.validation_delay_per_megabyte = Some(parse(key, value)?)
+            }
+            "relay_before_validation" => {
+                self.relay.relay_before_validation = Some(parse(key, value)?)
+            }
+            "compare_validation_modes" => {
+                self.relay.compare_validation_modes = Some(parse(key, value)?)
+            }
+            "rate_limit" => self.peers.rate_limit = Some(parse(key, value)?),
+            "rate_limit_refill_interval" => {
+                self.peers.rate_limit_refill_interval = Some(parse(key, value)?)
+            }
+            "rate_limit_policy" => self.peers.rate_limit_policy = Some(parse_name(key, value)?),
+            "heartbeat_interval" => self.peers.heartbeat_interval = Some(parse(key, value)?),
+            "heartbeat_timeout" => self.peers.heartbeat_timeout = Some(parse(key, value)?),
+            "report_format" => self.output.report_format = Some(parse_name(key, value)?),
+            "report_file" => self.output.report_file = Some(parse(key, value)?),
+            "export_directory" => self.output.export_directory = Some(parse(key, value)?),
+            "save_chain" => self.output.save_chain = Some(parse(key, value)?),
+            "checkpoint" => self.output.checkpoint = Some(parse(key, value)?),
+            _ => return Err(format!("Unknown parameter: {}", key)),
+        }
+
+        Ok(())
+    }
+
+    /// The events of the scenario, for a network of the given size.
+    pub fn network_events(
+        &self,
+        number_of_nodes: u32,
+    ) -> Result<Vec<(Duration, NetworkEvent)>, String> {
+        let mut network_events = vec![];
+        for event in &self.events {
+            match *event {
+                ScenarioEvent::Partition { at_seconds, share } => network_events.push((
+                    Duration::from_secs(at_seconds),
+                    NetworkEvent::Partition(random_nodes(number_of_nodes, share)?),
+                )),
+                ScenarioEvent::Heal { at_seconds } => {
+                    network_events.push((Duration::from_secs(at_seconds), NetworkEvent::Heal))
+                }
+                ScenarioEvent::Churn {
+                    at_seconds,
+                    share,
+                    duration_seconds,
+                } => {
+                    let nodes = random_nodes(number_of_nodes, share)?;
+                    network_events.push((
+                        Duration::from_secs(at_seconds),
+                        NetworkEvent::Disconnect(nodes.clone()),
+                    ));
+                    network_events.push((
+                        Duration::from_secs(at_seconds + duration_seconds),
+                        NetworkEvent::Reconnect(nodes),
+                    ));
+                }
+            }
+        }
+
+        Ok(network_events)
+    }
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_err| format!("Invalid value for {}: {}", key, value))
+}
+
+fn parse_list<T: FromStr>(key: &str, value: &str) -> Result<Vec<T>, String> {
+    value.split(',').map(|value| parse(key, value)).collect()
+}
+
+/// Parses a value named like in the scenario files, such as `headers_first`.
+fn parse_name<T: DeserializeOwned>(key: &str, value: &str) -> Result<T, String> {
+    let deserializer: StrDeserializer<ValueError> = value.trim().into_deserializer();
+    T::deserialize(deserializer).map_err(|err| format!("Invalid value for {}: {}", key, err))
+}
+
+fn random_nodes(number_of_nodes: u32, share: f64) -> Result<Vec<u32>, String> {
+    if !(0f64..=1f64).contains(&share) {
+        return Err(
+            "Invalid share of the nodes in the scenario events, expected [0-1]".to_string(),
+        );
+    }
+
+    let mut nodes: Vec<u32> = (0..number_of_nodes).collect();
+    rand::thread_rng().shuffle(&mut nodes);
+    nodes.truncate((f64::from(number_of_nodes) * share).round() as usize);
+    Ok(nodes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_parameters_and_the_events() {
+        let scenario = Scenario::parse(
+            r#"
+            [topology]
+            network_size = 100
+
+            [hashrate]
+            hashrates = [10, 5]
+
+            [relay]
+            relay = "headers_first"
+            relay_before_validation = false
+
+            [peers]
+            rate_limit = 10
+            rate_limit_policy = "delay"
+
+            [[events]]
+            kind = "partition"
+            at_seconds = 10
+            share = 0.25
+
+            [[events]]
+            kind = "churn"
+            at_seconds = 20
+            share = 0.1
+            duration_seconds = 5
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(Some(100), scenario.topology.network_size);
+        assert_eq!(Some(vec![10, 5]), scenario.hashrate.hashrates);
+        assert_eq!(Some(RelayProtocol::HeadersFirst), scenario.relay.relay);
+        assert_eq!(Some(false), scenario.relay.relay_before_validation);
+        assert_eq!(Some(10), scenario.peers.rate_limit);
+        assert_eq!(
+            Some(RateLimitPolicyName::Delay),
+            scenario.peers.rate_limit_policy
+        );
+        assert_eq!(None, scenario.simulation.duration_in_seconds);
+
+        let events = scenario.network_events(100).unwrap();
+        assert_eq!(3, events.len());
+        match events[0] {
+            (at, NetworkEvent::Partition(ref nodes)) => {
+                assert_eq!(Duration::from_secs(10), at);
+                assert_eq!(25, nodes.len());
+            }
+            _ => panic!("Expected a partition"),
+        }
+        match (&events[1], &events[2]) {
+            ((_, NetworkEvent::Disconnect(offline)), (at, NetworkEvent::Reconnect(online))) => {
+                assert_eq!(10, offline.len());
+                assert_eq!(offline, online);
+                assert_eq!(Duration::from_secs(25), *at);
+            }
+            _ => panic!("Expected churn"),
+        }
+    }
+
+    #[test]
+    fn overrides_the_parameters() {
+        let mut scenario = Scenario::parse(
+            r#"
+            [topology]
+            network_size = 100
+
+            [hashrate]
+            hashrates = [10, 5]
+            "#,
+        )
+        .unwrap();
+
+        scenario.set("network_size", "8").unwrap();
+        scenario.set("hashrates", "1, 2,3").unwrap();
+        scenario.set("fork_choice", "lowest_hash").unwrap();
+        scenario.set("compare_validation_modes", "true").unwrap();
+        assert_eq!(Some(8), scenario.topology.network_size);
+        assert_eq!(Some(vec![1, 2, 3]), scenario.hashrate.hashrates);
+        assert_eq!(
+            Some(ForkChoice::LowestHash),
+            scenario.simulation.fork_choice
+        );
+        assert_eq!(Some(true), scenario.relay.compare_validation_modes);
+
+        assert!(scenario.set("network_size", "-1").is_err());
+        assert!(scenario.set("fork_choice", "switch").is_err());
+        assert!(scenario.set("unknown", "1").is_err());
+        for parameter in PARAMETERS.iter() {
+            // Every parameter is known, whatever its value.
+            if let Err(err) = scenario.set(parameter, "?") {
+                assert!(!err.starts_with("Unknown parameter"));
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_scenarios() {
+        assert!(Scenario::parse("[topology]\nnetwork_size = \"many\"").is_err());
+        assert!(Scenario::parse("[topology]\nunknown = 1").is_err());
+        assert!(Scenario::parse("[unknown]\nnetwork_size = 1").is_err());
+        assert!(Scenario::load("no_such_scenario.toml").is_err());
+
+        let scenario = Scenario::parse(
+            r#"
+            [[events]]
+            kind = "partition"
+            at_seconds = 10
+            share = 2.0
+            "#,
+        )
+        .unwrap();
+        assert!(scenario.network_events(100).is_err());
+    }
+}
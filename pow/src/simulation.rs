@@ -0,0 +1,1673 @@
+//! Runs a network of Proof-of-Work nodes, as configured, and reports on it.
+
+use blockchain::double_spend::{self, DoubleSpendAttack};
+use blockchain::hashrate::{self, HashrateDistribution};
+use blockchain::recorder::{self, NodeRecord, Recorder};
+use blockchain::selfish;
+use blockchain::store;
+use blockchain::{
+    Chain, Clock, Difficulty, DoubleSpendNode, ForkChoice, Pool, PoolMemberNode, PowNode,
+    RelayProtocol, Retarget, SelfishPowNode, StrategyNode, ValidationDelay,
+};
+use checkpoint::Checkpoint;
+use netsim::network::conditions::NetworkEvent;
+use netsim::network::heartbeat::Heartbeat;
+use netsim::network::latency::Latency;
+use netsim::network::rate_limit::{ExceededPolicy, RateLimit};
+use netsim::network::stop::Stop;
+use netsim::network::topology::Topology;
+use netsim::network::Network;
+use rand::{self, Rng};
+use report::{
+    DoubleSpendAttempt, DoubleSpendOutcome, Distribution, MiningPools, PoolShare, Report,
+    SelfishMiningRevenue,
+};
+use scenario::{HashrateDistributionName, RateLimitPolicyName, Scenario};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The node running an attack, if any.
+pub const ATTACKER_NODE_ID: u32 = 0;
+
+pub struct SimulationConfig {
+    pub number_of_nodes: u32,
+    pub initiated_connections_per_node: u8,
+    /// The messages are delivered instantly if there is none.
+    pub latency: Option<Latency>,
+    /// The partitions and the churn of the network, after some time since the start.
+    pub network_events: Vec<(Duration, NetworkEvent)>,
+    pub difficulty_factor: u8,
+    pub duration: Duration,
+    pub mining_attempt_delay: Duration,
+    /// The difficulty adjustment rules, the difficulty is constant if there are none.
+    pub retarget: Option<Retarget>,
+    /// The chain the nodes start from, a new genesis chain if there is none. Its own
+    /// difficulty and retarget rules replace the configured ones.
+    pub initial_chain: Option<Arc<Chain>>,
+    /// The state of the network the run resumes from, if any. It replaces the initial chain,
+    /// the connections and, unless a hashrate distribution is given, the hashrates of the
+    /// nodes. The network must have the size of the checkpoint, and no attack can be run:
+    /// the checkpoint does not keep the private chains of the attackers.
+    pub checkpoint: Option<Checkpoint>,
+    /// Stops the run before the end of its duration once a node adopts a chain of this height.
+    pub stop_at_height: Option<u32>,
+    /// Whether the miners stop at the end of the duration, or at the stop height, and the
+    /// network runs on until every chain in flight is delivered and validated. The run then
+    /// ends in a state a checkpoint can be taken of.
+    pub drain: bool,
+    pub fork_choice: ForkChoice,
+    /// The maximum difference between the clock of a node and the real time.
+    pub max_clock_skew: Duration,
+    pub max_future_drift: Duration,
+    /// The mining attempts per round of every node, one if there is no distribution and no
+    /// checkpoint. The hashrate of the attacker, if any, is derived from its hashrate share
+    /// instead.
+    pub hashrate_distribution: Option<HashrateDistribution>,
+    pub pools: Vec<Pool>,
+    /// The attack run by the first node, if any.
+    pub attack: Option<Attack>,
+    /// The attackers always relay whole chains.
+    pub relay_protocol: RelayProtocol,
+    /// The size of the transactions of every block, in bytes.
+    pub block_payload_size: u64,
+    /// The blocks received by the honest nodes are validated instantly if there is none.
+    pub validation_delay: Option<ValidationDelay>,
+    pub relay_before_validation: bool,
+    /// The rate limit of the messages received on every connection, if any.
+    pub rate_limit: Option<RateLimitConfig>,
+    /// The honest nodes do not monitor their peers if there is none.
+    pub heartbeat: Option<Heartbeat>,
+    /// Where to export the topology and the propagation trees, if anywhere.
+    pub export_directory: Option<PathBuf>,
+}
+
+impl SimulationConfig {
+    /// The configuration of a run described by a scenario, the missing parameters taking
+    /// their default values. The configuration is validated, and so are the parameters that
+    /// cannot be given together. The initial chain and the checkpoint are loaded.
+    pub fn from_scenario(scenario: &Scenario) -> Result<SimulationConfig, String> {
+        check_parameters(scenario)?;
+        let simulation = &scenario.simulation;
+        let nodes = &scenario.nodes;
+        let relay = &scenario.relay;
+
+        let checkpoint = simulation
+            .resume
+            .as_ref()
+            .map(|directory| {
+                Checkpoint::load(directory).map_err(|err| {
+                    format!(
+                        "Could not load the checkpoint {}: {}",
+                        directory.display(),
+                        err
+                    )
+                })
+            })
+            .transpose()?;
+
+        let number_of_nodes = match checkpoint {
+            Some(ref checkpoint) => match scenario.topology.network_size {
+                Some(network_size) if network_size != checkpoint.number_of_nodes() => {
+                    return Err(format!(
+                        "The network size {} does not match the {} nodes of the checkpoint",
+                        network_size,
+                        checkpoint.number_of_nodes()
+                    ))
+                }
+                _ => checkpoint.number_of_nodes(),
+            },
+            None => scenario.topology.network_size.unwrap_or(2048),
+        };
+
+        let latency = match (scenario.latency.latency, scenario.latency.latency_jitter) {
+            (None, None) => None,
+            (base, jitter) => {
+                let base = bounded(
+                    base,
+                    0,
+                    0,
+                    999999,
+                    "Invalid latency in milliseconds, expected [0-999999]",
+                )?;
+                let jitter = bounded(
+                    jitter,
+                    0,
+                    0,
+                    999999,
+                    "Invalid latency jitter in milliseconds, expected [0-999999]",
+                )?;
+                Some(Latency::new(
+                    Duration::from_millis(base),
+                    Duration::from_millis(jitter),
+                ))
+            }
+        };
+
+        // The retarget rules are checked before they are built.
+        let difficulty = &scenario.difficulty;
+        let retarget_interval = bounded(
+            difficulty.retarget_interval,
+            0,
+            0,
+            999999,
+            "Invalid retarget interval, expected [0-999999]",
+        )?;
+
+        let target_block_interval = bounded(
+            difficulty.target_block_interval,
+            1000,
+            1,
+            999999,
+            "Invalid target block interval in milliseconds, expected [1-999999]",
+        )?;
+
+        let max_adjustment_factor = bounded(
+            difficulty.max_adjustment_factor,
+            4,
+            1,
+            1000,
+            "Invalid maximum adjustment factor, expected [1-1000]",
+        )?;
+
+        let retarget = if retarget_interval > 0 {
+            Some(Retarget::new(
+                retarget_interval,
+                Duration::from_millis(target_block_interval),
+                max_adjustment_factor,
+            ))
+        } else {
+            None
+        };
+
+        let initial_chain = simulation
+            .load_chain
+            .as_ref()
+            .map(|path| {
+                store::load(path)
+                    .map_err(|err| format!("Could not load the chain {}: {}", path.display(), err))
+            })
+            .transpose()?;
+
+        let hashrate_distribution = match scenario.hashrate.hashrates {
+            Some(ref hashrates) => Some(HashrateDistribution::Explicit(hashrates.clone())),
+            None => match scenario.hashrate.hashrate_distribution {
+                Some(HashrateDistributionName::Pareto) => Some(HashrateDistribution::Pareto(
+                    scenario.hashrate.pareto_shape.unwrap_or(1.16),
+                )),
+                Some(HashrateDistributionName::Uniform) => Some(HashrateDistribution::Uniform(1)),
+                None => None,
+            },
+        };
+
+        let number_of_pools = bounded(
+            nodes.pools,
+            0,
+            0,
+            100000,
+            "Invalid number of pools, expected [0-100000]",
+        )?;
+
+        let members_per_pool = bounded(
+            nodes.pool_members,
+            10,
+            0,
+            100000,
+            "Invalid number of pool members, expected [0-100000]",
+        )?;
+
+        // The first node is left out of the pools, it may be an attacker.
+        let pools = Pool::layout(number_of_nodes, number_of_pools, members_per_pool)
+            .filter(|pools| !pools.iter().any(|pool| pool.contains(ATTACKER_NODE_ID)))
+            .ok_or_else(|| "Too many pool nodes for the size of the network".to_string())?;
+
+        let selfish_mining = nodes.selfish_hashrate_share.map(|hashrate_share| {
+            Attack::Selfish(SelfishMiningConfig {
+                hashrate_share,
+                gamma: nodes.gamma.unwrap_or(0f64),
+            })
+        });
+
+        let double_spend = nodes.double_spend_hashrate_share.map(|hashrate_share| {
+            Attack::DoubleSpend(DoubleSpendConfig {
+                hashrate_share,
+                fork_height: nodes.double_spend_fork_height.unwrap_or(1),
+                confirmations: nodes.double_spend_confirmations.unwrap_or(6),
+                max_deficit: nodes.double_spend_max_deficit,
+            })
+        });
+
+        let block_payload_size = relay.block_size.unwrap_or(0);
+        let validation_delay = match (relay.validation_delay_per_megabyte, relay.validation_delay) {
+            (Some(per_megabyte), _) => Some(ValidationDelay::Proportional {
+                per_megabyte: Duration::from_millis(per_megabyte),
+                block_payload_size,
+            }),
+            (None, Some(delay)) => Some(ValidationDelay::Fixed(Duration::from_millis(delay))),
+            (None, None) => None,
+        };
+
+        let peers = &scenario.peers;
+        let rate_limit = peers.rate_limit.map(|capacity| RateLimitConfig {
+            capacity,
+            refill_interval: Duration::from_millis(peers.rate_limit_refill_interval.unwrap_or(100)),
+            policy: match peers.rate_limit_policy {
+                Some(RateLimitPolicyName::Delay) => ExceededPolicy::Delay,
+                Some(RateLimitPolicyName::Disconnect) => ExceededPolicy::Disconnect,
+                Some(RateLimitPolicyName::Drop) | None => ExceededPolicy::Drop,
+            },
+        });
+
+        let heartbeat = match peers.heartbeat_interval {
+            Some(interval) => {
+                let interval = bounded(
+                    Some(interval),
+                    1,
+                    1,
+                    999999,
+                    "Invalid heartbeat interval in milliseconds, expected [1-999999]",
+                )?;
+                let timeout = bounded(
+                    peers.heartbeat_timeout,
+                    (3 * interval).min(999999),
+                    1,
+                    999999,
+                    "Invalid heartbeat timeout in milliseconds, expected [1-999999]",
+                )?;
+                Some(Heartbeat::new(
+                    Duration::from_millis(interval),
+                    Duration::from_millis(timeout),
+                ))
+            }
+            None => None,
+        };
+
+        let relay_before_validation = relay.relay_before_validation.unwrap_or(false);
+        if validation_delay.is_none() && relay.compare_validation_modes == Some(true) {
+            return Err(VALIDATION_DELAY_REQUIRED.to_string());
+        }
+
+        let mut config = SimulationConfig {
+            number_of_nodes,
+            initiated_connections_per_node: scenario.topology.connections.unwrap_or(3),
+            latency,
+            network_events: vec![],
+            difficulty_factor: difficulty.difficulty.unwrap_or(15),
+            duration: Duration::from_secs(simulation.duration_in_seconds.unwrap_or(30)),
+            mining_attempt_delay: Duration::from_millis(simulation.mining_delay.unwrap_or(10)),
+            retarget,
+            initial_chain,
+            checkpoint,
+            stop_at_height: simulation.checkpoint_height,
+            drain: scenario.output.checkpoint.is_some(),
+            fork_choice: simulation.fork_choice.unwrap_or(ForkChoice::FirstSeen),
+            max_clock_skew: Duration::from_millis(simulation.max_clock_skew.unwrap_or(0)),
+            max_future_drift: Duration::from_millis(
+                simulation.max_future_drift.unwrap_or(7200000),
+            ),
+            hashrate_distribution,
+            pools,
+            attack: selfish_mining.or(double_spend),
+            relay_protocol: relay.relay.unwrap_or(RelayProtocol::FullChain),
+            block_payload_size,
+            validation_delay,
+            relay_before_validation,
+            rate_limit,
+            heartbeat,
+            export_directory: scenario.output.export_directory.clone(),
+        };
+        config.validate()?;
+        // The nodes of the events are drawn once the size of the network is known to be valid.
+        config.network_events = scenario.network_events(number_of_nodes)?;
+        Ok(config)
+    }
+
+    /// Checks the values of the configuration, and the conflicts between them. The latency
+    /// and the heartbeat are checked when they are built from a scenario.
+    pub fn validate(&self) -> Result<(), String> {
+        in_range(
+            self.number_of_nodes,
+            1,
+            100000,
+            "Invalid number of nodes, expected [1-100000]",
+        )?;
+        in_range(
+            self.initiated_connections_per_node,
+            1,
+            255,
+            "Invalid number of initiated connections per node, expected [1-255]",
+        )?;
+        in_range(
+            self.difficulty_factor,
+            1,
+            224,
+            "Invalid difficulty factor, expected [1-224]",
+        )?;
+        in_range(
+            self.duration,
+            Duration::from_secs(1),
+            Duration::from_secs(999999),
+            "Invalid duration in seconds, expected [1-999999]",
+        )?;
+        in_range(
+            self.mining_attempt_delay,
+            Duration::from_millis(1),
+            Duration::from_millis(999999),
+            "Invalid hash duration in milliseconds, expected [1-999999]",
+        )?;
+        if let Some(height) = self.stop_at_height {
+            in_range(
+                height,
+                0,
+                999999,
+                "Invalid checkpoint height, expected [0-999999]",
+            )?;
+        }
+        in_range(
+            self.max_clock_skew,
+            Duration::from_millis(0),
+            Duration::from_millis(999999999),
+            "Invalid maximum clock skew in milliseconds, expected [0-999999999]",
+        )?;
+        in_range(
+            self.max_future_drift,
+            Duration::from_millis(0),
+            Duration::from_millis(999999999),
+            "Invalid maximum future drift in milliseconds, expected [0-999999999]",
+        )?;
+
+        match self.hashrate_distribution {
+            Some(HashrateDistribution::Explicit(ref hashrates)) => {
+                for hashrate in hashrates {
+                    in_range(
+                        *hashrate,
+                        1,
+                        hashrate::MAX_HASHRATE,
+                        "Invalid hashrate, expected [1-10000]",
+                    )?;
+                }
+            }
+            Some(HashrateDistribution::Pareto(shape)) if shape.is_nan() || shape <= 0f64 => {
+                return Err("Invalid Pareto shape, expected a positive number".to_string());
+            }
+            _ => {}
+        }
+
+        if self
+            .pools
+            .iter()
+            .any(|pool| pool.operator_id >= self.number_of_nodes
+                || pool.member_ids.iter().any(|id| *id >= self.number_of_nodes))
+        {
+            return Err("Too many pool nodes for the size of the network".to_string());
+        }
+
+        match self.attack {
+            Some(Attack::Selfish(ref selfish_mining)) => {
+                hashrate_share_in_range(
+                    selfish_mining.hashrate_share,
+                    "Invalid selfish hashrate share, expected ]0-1[",
+                )?;
+                share_in_range(selfish_mining.gamma, "Invalid gamma, expected [0-1]")?;
+            }
+            Some(Attack::DoubleSpend(ref double_spend)) => {
+                hashrate_share_in_range(
+                    double_spend.hashrate_share,
+                    "Invalid double-spend hashrate share, expected ]0-1[",
+                )?;
+                in_range(
+                    double_spend.fork_height,
+                    0,
+                    999999,
+                    "Invalid fork height, expected [0-999999]",
+                )?;
+                in_range(
+                    double_spend.confirmations,
+                    0,
+                    999999,
+                    "Invalid number of confirmations, expected [0-999999]",
+                )?;
+                if let Some(max_deficit) = double_spend.max_deficit {
+                    in_range(
+                        max_deficit,
+                        0,
+                        999999,
+                        "Invalid maximum deficit, expected [0-999999]",
+                    )?;
+                }
+                // The payment is made during the run, not in the chain it starts from.
+                if let Some(ref chain) = self.initial_chain {
+                    if double_spend.fork_height < chain.height() {
+                        return Err(format!(
+                            "The fork height {} is below the height {} of the initial chain",
+                            double_spend.fork_height,
+                            chain.height()
+                        ));
+                    }
+                }
+            }
+            None => {}
+        }
+        if self.attack.is_some()
+            && self.pools.iter().any(|pool| pool.contains(ATTACKER_NODE_ID))
+        {
+            return Err("The attacker cannot be part of a pool".to_string());
+        }
+
+        in_range(
+            self.block_payload_size,
+            0,
+            999999999,
+            "Invalid block size in bytes, expected [0-999999999]",
+        )?;
+        match self.validation_delay {
+            Some(ValidationDelay::Fixed(delay)) => {
+                in_range(
+                    delay,
+                    Duration::from_millis(0),
+                    Duration::from_millis(999999),
+                    "Invalid validation delay in milliseconds, expected [0-999999]",
+                )?;
+            }
+            Some(ValidationDelay::Proportional { per_megabyte, .. }) => {
+                in_range(
+                    per_megabyte,
+                    Duration::from_millis(0),
+                    Duration::from_millis(999999),
+                    "Invalid validation delay per megabyte in milliseconds, expected [0-999999]",
+                )?;
+            }
+            None => {}
+        }
+        if self.relay_before_validation && self.validation_delay.is_none() {
+            return Err(VALIDATION_DELAY_REQUIRED.to_string());
+        }
+
+        if let Some(ref rate_limit) = self.rate_limit {
+            in_range(
+                rate_limit.capacity,
+                1,
+                999999,
+                "Invalid rate limit in messages, expected [1-999999]",
+            )?;
+            in_range(
+                rate_limit.refill_interval,
+                Duration::from_millis(1),
+                Duration::from_millis(999999),
+                "Invalid rate limit refill interval in milliseconds, expected [1-999999]",
+            )?;
+        }
+
+        if let Some(Attack::Selfish(_)) = self.attack {
+            let retarget = match self.initial_chain {
+                Some(ref chain) => chain.retarget().is_some(),
+                None => self.retarget.is_some(),
+            };
+            if retarget {
+                return Err("Selfish mining requires a constant difficulty, the selfish miner \
+                            measures the work of the chains by their height"
+                    .to_string());
+            }
+        }
+        if let Some(ref checkpoint) = self.checkpoint {
+            if self.attack.is_some() {
+                return Err("A run with an attack cannot resume from a checkpoint, the \
+                            checkpoint does not keep the private chains of the attackers"
+                    .to_string());
+            }
+            if self.number_of_nodes != checkpoint.number_of_nodes() {
+                return Err(format!(
+                    "The network size {} does not match the {} nodes of the checkpoint",
+                    self.number_of_nodes,
+                    checkpoint.number_of_nodes()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+const VALIDATION_DELAY_REQUIRED: &str =
+    "The blocks can only be relayed before their validation with a validation delay";
+
+/// Checks the parameters that cannot be given together, and those that need another one.
+fn check_parameters(scenario: &Scenario) -> Result<(), String> {
+    let simulation = &scenario.simulation;
+    let hashrate = &scenario.hashrate;
+    let nodes = &scenario.nodes;
+    let relay = &scenario.relay;
+    let output = &scenario.output;
+    let selfish = nodes.selfish_hashrate_share.is_some();
+    let double_spend = nodes.double_spend_hashrate_share.is_some();
+    let compare_validation_modes = relay.compare_validation_modes == Some(true);
+
+    let conflicts = [
+        (
+            simulation.resume.is_some(),
+            simulation.load_chain.is_some(),
+            "resume",
+            "load_chain",
+        ),
+        (
+            hashrate.hashrates.is_some(),
+            hashrate.hashrate_distribution.is_some(),
+            "hashrates",
+            "hashrate_distribution",
+        ),
+        (
+            double_spend,
+            selfish,
+            "double_spend_hashrate_share",
+            "selfish_hashrate_share",
+        ),
+        (
+            simulation.resume.is_some(),
+            selfish,
+            "resume",
+            "selfish_hashrate_share",
+        ),
+        (
+            simulation.resume.is_some(),
+            double_spend,
+            "resume",
+            "double_spend_hashrate_share",
+        ),
+        (
+            relay.relay.is_some(),
+            selfish,
+            "relay",
+            "selfish_hashrate_share",
+        ),
+        (
+            scenario.difficulty.retarget_interval.is_some_and(|interval| interval > 0),
+            selfish,
+            "retarget_interval",
+            "selfish_hashrate_share",
+        ),
+        (
+            relay.relay.is_some(),
+            double_spend,
+            "relay",
+            "double_spend_hashrate_share",
+        ),
+        (
+            relay.validation_delay.is_some(),
+            relay.relay.is_some(),
+            "validation_delay",
+            "relay",
+        ),
+        (
+            relay.validation_delay_per_megabyte.is_some(),
+            relay.relay.is_some(),
+            "validation_delay_per_megabyte",
+            "relay",
+        ),
+        (
+            relay.validation_delay_per_megabyte.is_some(),
+            relay.validation_delay.is_some(),
+            "validation_delay_per_megabyte",
+            "validation_delay",
+        ),
+        (
+            compare_validation_modes,
+            relay.relay_before_validation == Some(true),
+            "compare_validation_modes",
+            "relay_before_validation",
+        ),
+        (
+            output.save_chain.is_some(),
+            compare_validation_modes,
+            "save_chain",
+            "compare_validation_modes",
+        ),
+        (
+            output.checkpoint.is_some(),
+            compare_validation_modes,
+            "checkpoint",
+            "compare_validation_modes",
+        ),
+    ];
+    for &(first, second, first_name, second_name) in conflicts.iter() {
+        if first && second {
+            return Err(format!(
+                "{} cannot be used with {}",
+                first_name, second_name
+            ));
+        }
+    }
+
+    let requirements = [
+        (
+            nodes.pool_members.is_some(),
+            nodes.pools.is_some(),
+            "pool_members",
+            "pools",
+        ),
+        (
+            nodes.gamma.is_some(),
+            selfish,
+            "gamma",
+            "selfish_hashrate_share",
+        ),
+        (
+            nodes.double_spend_fork_height.is_some(),
+            double_spend,
+            "double_spend_fork_height",
+            "double_spend_hashrate_share",
+        ),
+        (
+            nodes.double_spend_confirmations.is_some(),
+            double_spend,
+            "double_spend_confirmations",
+            "double_spend_hashrate_share",
+        ),
+        (
+            nodes.double_spend_max_deficit.is_some(),
+            double_spend,
+            "double_spend_max_deficit",
+            "double_spend_hashrate_share",
+        ),
+        (
+            scenario.peers.rate_limit_refill_interval.is_some(),
+            scenario.peers.rate_limit.is_some(),
+            "rate_limit_refill_interval",
+            "rate_limit",
+        ),
+        (
+            scenario.peers.rate_limit_policy.is_some(),
+            scenario.peers.rate_limit.is_some(),
+            "rate_limit_policy",
+            "rate_limit",
+        ),
+        (
+            scenario.peers.heartbeat_timeout.is_some(),
+            scenario.peers.heartbeat_interval.is_some(),
+            "heartbeat_timeout",
+            "heartbeat_interval",
+        ),
+    ];
+    for &(parameter, required, parameter_name, required_name) in requirements.iter() {
+        if parameter && !required {
+            return Err(format!("{} requires {}", parameter_name, required_name));
+        }
+    }
+
+    Ok(())
+}
+
+/// The value, or the default one if there is none, between the bounds included.
+fn bounded<T: PartialOrd>(
+    value: Option<T>,
+    default: T,
+    min: T,
+    max: T,
+    error_message: &str,
+) -> Result<T, String> {
+    let value = value.unwrap_or(default);
+    in_range(&value, &min, &max, error_message)?;
+    Ok(value)
+}
+
+/// Checks a value, between the bounds included.
+fn in_range<T: PartialOrd>(value: T, min: T, max: T, error_message: &str) -> Result<(), String> {
+    if value < min || value > max {
+        Err(error_message.to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// Checks a hashrate share, between 0 and 1 excluded.
+fn hashrate_share_in_range(value: f64, error_message: &str) -> Result<(), String> {
+    if value > 0f64 && value < 1f64 {
+        Ok(())
+    } else {
+        Err(error_message.to_string())
+    }
+}
+
+/// Checks a share, between 0 and 1.
+fn share_in_range(value: f64, error_message: &str) -> Result<(), String> {
+    if (0f64..=1f64).contains(&value) {
+        Ok(())
+    } else {
+        Err(error_message.to_string())
+    }
+}
+
+#[derive(Clone)]
+pub enum Attack {
+    Selfish(SelfishMiningConfig),
+    DoubleSpend(DoubleSpendConfig),
+}
+
+impl Attack {
+    /// The number of mining attempts per round giving the attacker its share of the
+    /// hashrate, given the total attempts per round of the honest nodes. The attacker gets
+    /// less than its share if that would exceed the maximum hashrate of a node.
+    fn hashrate(&self, honest_hashrate: u32) -> u32 {
+        let hashrate_share = match *self {
+            Attack::Selfish(ref selfish_mining) => selfish_mining.hashrate_share,
+            Attack::DoubleSpend(ref double_spend) => double_spend.hashrate_share,
+        };
+        assert!(
+            hashrate_share > 0f64 && hashrate_share < 1f64,
+            "The hashrate share of the attacker must be in ]0, 1[, got {}",
+            hashrate_share
+        );
+
+        let hashrate =
+            hashrate_share * f64::from(honest_hashrate) / (1f64 - hashrate_share);
+        if hashrate >= f64::from(hashrate::MAX_HASHRATE) {
+            hashrate::MAX_HASHRATE
+        } else {
+            (hashrate.round() as u32).max(1)
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SelfishMiningConfig {
+    /// The share of the hashrate of the network owned by the selfish miner.
+    pub hashrate_share: f64,
+    /// The probability for an honest node to switch to the selfish miner's block in a race.
+    pub gamma: f64,
+}
+
+/// A token bucket rate limit on the messages received from every connection.
+#[derive(Clone)]
+pub struct RateLimitConfig {
+    /// The number of messages that can be received in a burst.
+    pub capacity: u32,
+    /// The time it takes for a connection to be allowed one more message.
+    pub refill_interval: Duration,
+    pub policy: ExceededPolicy,
+}
+
+#[derive(Clone)]
+pub struct DoubleSpendConfig {
+    /// The share of the hashrate of the network owned by the attacker.
+    pub hashrate_share: f64,
+    /// The height of the last block shared by the public and the secret chains.
+    pub fork_height: u32,
+    /// The number of blocks the merchant waits for after the fork height.
+    pub confirmations: u32,
+    /// How far behind the public chain the attacker can fall before giving up.
+    pub max_deficit: Option<u32>,
+}
+
+/// The outcome of a run.
+pub struct SimulationResult {
+    pub report: Report,
+    /// The state of every node at the end of the run, by node id.
+    pub records: Vec<NodeRecord>,
+    /// The mining attempts per round of every node, by node id.
+    pub hashrates: Vec<u32>,
+    pub topology: Topology,
+    /// Whether the network settled before the end of the run, no chain in flight nor being
+    /// validated. Always false if the run is not drained.
+    pub drained: bool,
+}
+
+/// Runs the network until the end of the configured duration. Fails if the configuration is
+/// not valid or the export of the run cannot be written.
+pub fn run(config: &SimulationConfig) -> Result<SimulationResult, String> {
+    config.validate()?;
+
+    // Set up a chain.
+    let chain = match config.initial_chain {
+        Some(ref chain) => {
+            info!("Starting from a chain of height {}", chain.height());
+            chain.clone()
+        }
+        None => {
+            let mut difficulty = Difficulty::min_difficulty();
+            for _i in 0u8..config.difficulty_factor {
+                difficulty.increase();
+            }
+
+            info!("Chain difficulty threshold: {:?}", difficulty);
+
+            Arc::new(Chain::init_new(difficulty, config.retarget.clone()))
+        }
+    };
+    let node_chains = match config.checkpoint {
+        Some(ref checkpoint) => {
+            info!(
+                "Resuming from a checkpoint of {} nodes",
+                checkpoint.number_of_nodes()
+            );
+            checkpoint.chains.clone()
+        }
+        None => vec![chain; config.number_of_nodes as usize],
+    };
+    // The block trees of the nodes start from the last block shared by all of them.
+    let root = node_chains
+        .iter()
+        .fold(node_chains[0].clone(), |root, chain| {
+            Chain::ancestor(&root, root.common_ancestor_height(chain)).unwrap()
+        });
+    // Only the blocks mined during the run are reported on, not those it started from.
+    let root_height = root.height();
+    let node_id = AtomicUsize::new(0);
+    let mining_attempt_delay = config.mining_attempt_delay;
+    let fork_choice = match config.attack {
+        Some(Attack::Selfish(ref selfish_mining)) => ForkChoice::Switch(selfish_mining.gamma),
+        _ => config.fork_choice,
+    };
+    let hashrates = match (&config.hashrate_distribution, &config.checkpoint) {
+        (None, Some(checkpoint)) => checkpoint.hashrates.clone(),
+        (hashrate_distribution, _) => {
+            let mut hashrates = hashrate_distribution
+                .clone()
+                .unwrap_or(HashrateDistribution::Uniform(1))
+                .hashrates(config.number_of_nodes);
+            if let Some(ref attack) = config.attack {
+                let honest_hashrate = hashrates.iter().skip(1).sum();
+                hashrates[ATTACKER_NODE_ID as usize] = attack.hashrate(honest_hashrate);
+            }
+            hashrates
+        }
+    };
+    let node_hashrates = hashrates.clone();
+    let attack = config.attack.clone();
+    let pools = config.pools.clone();
+    let relay_protocol = config.relay_protocol;
+    let validation_delay = config.validation_delay;
+    let relay_before_validation = config.relay_before_validation;
+    let heartbeat = config.heartbeat;
+    let max_clock_skew = config.max_clock_skew.as_secs() as i64 * 1000
+        + i64::from(config.max_clock_skew.subsec_millis());
+    let max_future_drift = config.max_future_drift;
+
+    // Run the blockchain network.
+    let mut network = match config.checkpoint {
+        Some(ref checkpoint) => Network::from_topology(&checkpoint.topology),
+        None => Network::new(
+            config.number_of_nodes,
+            config.initiated_connections_per_node,
+        ),
+    };
+    for pool in &config.pools {
+        for member_id in &pool.member_ids {
+            network.connect(*member_id, pool.operator_id);
+        }
+    }
+    if let Some(latency) = config.latency {
+        network.set_latency(latency);
+    }
+    // Every run gets its own rate limit, so that the throttling of parallel runs is not mixed.
+    let rate_limit = config.rate_limit.as_ref().map(|rate_limit| {
+        RateLimit::new(
+            rate_limit.capacity,
+            rate_limit.refill_interval,
+            rate_limit.policy,
+        )
+    });
+    if let Some(ref rate_limit) = rate_limit {
+        network.set_rate_limit(rate_limit);
+    }
+    for (after, event) in &config.network_events {
+        network.schedule(*after, event.clone());
+    }
+    let topology = network.topology();
+    let recorder = Recorder::with_block_payload_size(config.block_payload_size);
+    let network_stop = network.stop_handle();
+    // A drained run only halts the miners at the stop height, the network settles first.
+    let recorder = match config.stop_at_height {
+        Some(height) if config.drain => {
+            let mining_halt = recorder.mining_halt();
+            recorder.stop_at_height(height, mining_halt)
+        }
+        Some(height) => recorder.stop_at_height(height, network_stop.clone()),
+        None => recorder,
+    };
+    let (drain, run_duration) = if config.drain {
+        let quiet_period = DRAIN_QUIET_PERIOD
+            + config.latency.map_or(Duration::from_secs(0), |latency| latency.max())
+            + config
+                .rate_limit
+                .as_ref()
+                .map_or(Duration::from_secs(0), |rate_limit| rate_limit.refill_interval);
+        let recorder = recorder.clone();
+        let network_stop = network_stop.clone();
+        let duration = config.duration;
+        let drain = thread::spawn(move || drain(&recorder, &network_stop, duration, quiet_period));
+        (Some(drain), config.duration + DRAIN_TIMEOUT)
+    } else {
+        (None, config.duration)
+    };
+    let node_recorder = recorder.clone();
+    network.run(
+        move || {
+            let node_id = node_id.fetch_add(1, Ordering::Relaxed) as u32;
+            let chain = &node_chains[node_id as usize];
+            let node_recorder = node_recorder.node_from(node_id, root.clone(), chain.clone());
+            let clock_skew = rand::thread_rng().gen_range(-max_clock_skew, max_clock_skew + 1);
+            let clock = Clock::new(clock_skew, max_future_drift);
+            let hashrate = node_hashrates[node_id as usize];
+            let pool = pools.iter().find(|pool| pool.contains(node_id));
+
+            match attack {
+                Some(Attack::Selfish(_)) if node_id == ATTACKER_NODE_ID => {
+                    StrategyNode::Selfish(Box::new(SelfishPowNode::new(
+                        node_id,
+                        chain.clone(),
+                        mining_attempt_delay,
+                        hashrate,
+                        clock,
+                        node_recorder,
+                    )))
+                }
+                Some(Attack::DoubleSpend(ref double_spend)) if node_id == ATTACKER_NODE_ID => {
+                    let double_spend_attack = DoubleSpendAttack::new(
+                        chain.clone(),
+                        double_spend.fork_height,
+                        double_spend.confirmations,
+                        double_spend.max_deficit,
+                    );
+                    StrategyNode::DoubleSpend(Box::new(DoubleSpendNode::new(
+                        node_id,
+                        mining_attempt_delay,
+                        hashrate,
+                        double_spend_attack,
+                        clock,
+                        node_recorder,
+                    )))
+                }
+                _ => match pool {
+                    Some(pool) if pool.operator_id != node_id => {
+                        StrategyNode::PoolMember(Box::new(PoolMemberNode::new(
+                            node_id,
+                            pool.operator_id,
+                            chain.clone(),
+                            mining_attempt_delay,
+                            hashrate,
+                            clock,
+                            node_recorder,
+                        )))
+                    }
+                    _ => {
+                        let node = PowNode::new(
+                            node_id,
+                            chain.clone(),
+                            mining_attempt_delay,
+                            hashrate,
+                            fork_choice,
+                            clock,
+                            node_recorder,
+                        );
+                        let node = match relay_protocol {
+                            RelayProtocol::HeadersFirst => node.relay_headers_first(),
+                            RelayProtocol::FullChain => node,
+                        };
+                        let node = match validation_delay {
+                            Some(delay) => node.delay_validation(delay, relay_before_validation),
+                            None => node,
+                        };
+                        let node = match heartbeat {
+                            Some(heartbeat) => node.monitor_peers(heartbeat),
+                            None => node,
+                        };
+                        match pool {
+                            Some(pool) => {
+                                StrategyNode::Honest(Box::new(node.operate_pool(&pool.member_ids)))
+                            }
+                            None => StrategyNode::Honest(Box::new(node)),
+                        }
+                    }
+                },
+            }
+        },
+        run_duration,
+    );
+    let drained = match drain {
+        Some(drain) => {
+            // Ends the drain if the network did not settle before the timeout.
+            network_stop.stop();
+            drain.join().expect("The drain of the network failed")
+        }
+        None => false,
+    };
+
+    let records = recorder.records();
+    log_block_shares(&records, &hashrates, root_height);
+    log_reorgs(&records);
+
+    if let Some(ref export_directory) = config.export_directory {
+        export(export_directory, &topology, &records).map_err(|err| {
+            format!("Could not export the run to {}: {}", export_directory.display(), err)
+        })?;
+    }
+
+    let mut report = Report::new(
+        &records,
+        root_height,
+        recorder.traffic().stats(),
+        rate_limit.as_ref().map(RateLimit::stats),
+    );
+    if !config.pools.is_empty() {
+        report.mining_pools = mining_pools(&records, &config.pools, &hashrates, root_height);
+    }
+    match config.attack {
+        Some(Attack::Selfish(ref selfish_mining)) => {
+            let hashrate_share = hashrate::expected_shares(&hashrates)[ATTACKER_NODE_ID as usize];
+            report.selfish_mining =
+                selfish_mining_revenue(&records, selfish_mining, hashrate_share, root_height);
+        }
+        Some(Attack::DoubleSpend(ref double_spend)) => {
+            report.double_spend = double_spend_attempt(&records, double_spend);
+        }
+        None => {}
+    }
+    if config.attack.is_some() {
+        // The reorgs of the attacker are those of its private chain, not of the network.
+        report.reorg_depths = recorder::reorg_histogram(
+            records
+                .iter()
+                .filter(|record| record.node_id != ATTACKER_NODE_ID),
+        );
+    }
+
+    Ok(SimulationResult {
+        report,
+        records,
+        hashrates,
+        topology,
+        drained,
+    })
+}
+
+/// How long a drained network can take to settle, after the miners are halted.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long the network must go without relaying a chain to be considered settled, on top of
+/// the longest latency and the refill interval of the rate limit.
+const DRAIN_QUIET_PERIOD: Duration = Duration::from_millis(200);
+/// How often the end of the mining is checked.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Halts the miners at the end of the duration, unless they were already halted at the stop
+/// height, then stops the network once no message but the heartbeat ones was sent during the
+/// quiet period and no chain is being validated. Returns whether the network was drained, or
+/// false as soon as the network stops first.
+fn drain(
+    recorder: &Recorder,
+    network_stop: &Stop,
+    duration: Duration,
+    quiet_period: Duration,
+) -> bool {
+    let start = Instant::now();
+    let mining_halt = recorder.mining_halt();
+    while !mining_halt.is_stopped() && start.elapsed() < duration {
+        if network_stop.is_stopped() {
+            return false;
+        }
+        thread::sleep(DRAIN_POLL_INTERVAL);
+    }
+    mining_halt.stop();
+    info!("Halted the miners, draining the network");
+
+    let mut relayed_messages = recorder.traffic().relayed_messages();
+    loop {
+        thread::sleep(quiet_period);
+        if network_stop.is_stopped() {
+            return false;
+        }
+
+        let now_relayed_messages = recorder.traffic().relayed_messages();
+        if now_relayed_messages == relayed_messages && recorder.pending_validations() == 0 {
+            info!("The network is drained");
+            network_stop.stop();
+            return true;
+        }
+        relayed_messages = now_relayed_messages;
+    }
+}
+
+/// Logs the share of the blocks of the strongest chain above the root height won by the nodes
+/// with the most hashrate, compared to their share of the hashrate.
+fn log_block_shares(records: &[NodeRecord], hashrates: &[u32], root_height: u32) {
+    let chain = match recorder::strongest_chain(records.iter()) {
+        Some(chain) if chain.height() > root_height => chain,
+        _ => return,
+    };
+
+    let blocks_by_miner = chain.blocks_by_miner(root_height);
+    let total_blocks = f64::from(chain.height() - root_height);
+    let block_share = |node_id: usize| {
+        f64::from(blocks_by_miner.get(&(node_id as u32)).cloned().unwrap_or(0)) / total_blocks
+    };
+    let expected_shares = hashrate::expected_shares(hashrates);
+
+    let mut node_ids: Vec<usize> = (0..hashrates.len()).collect();
+    node_ids.sort_by(|a, b| hashrates[*b].cmp(&hashrates[*a]));
+    for node_id in node_ids.iter().take(10) {
+        info!(
+            "[#{:05}] Hashrate {}, expected share {:.3}, block share {:.3}",
+            node_id,
+            hashrates[*node_id],
+            expected_shares[*node_id],
+            block_share(*node_id)
+        );
+    }
+
+    // Half the sum of the differences: the share of the blocks won by the "wrong" nodes.
+    let distance: f64 = expected_shares
+        .iter()
+        .enumerate()
+        .map(|(node_id, expected_share)| (block_share(node_id) - expected_share).abs())
+        .sum::<f64>()
+        / 2f64;
+    info!(
+        "Distance between the block shares and the hashrate shares: {:.3} over {} blocks",
+        distance,
+        chain.height() - root_height
+    );
+}
+
+/// The share of the blocks of the strongest chain above the root height won by every pool,
+/// and how long before the median node outside the pool its operator received them. The same
+/// head start of the miners that are not in a pool is given for comparison. `None` if no
+/// block was mined.
+fn mining_pools(
+    records: &[NodeRecord],
+    pools: &[Pool],
+    hashrates: &[u32],
+    root_height: u32,
+) -> Option<MiningPools> {
+    let chain = match recorder::strongest_chain(records.iter()) {
+        Some(chain) if chain.height() > root_height => chain,
+        _ => return None,
+    };
+
+    let blocks: Vec<_> = chain
+        .iter()
+        .take_while(|chain| chain.height() > root_height)
+        .map(|chain| (chain.head().node_id(), chain.head().hash().clone()))
+        .collect();
+    let total_hashrate: u32 = hashrates.iter().sum();
+
+    let pool_shares = pools
+        .iter()
+        .map(|pool| {
+            let pool_node_ids: Vec<u32> = pool
+                .member_ids
+                .iter()
+                .cloned()
+                .chain(Some(pool.operator_id))
+                .collect();
+            let pool_hashrate: u32 = pool_node_ids
+                .iter()
+                .map(|node_id| hashrates[*node_id as usize])
+                .sum();
+            let pool_blocks: Vec<_> = blocks
+                .iter()
+                .filter(|(miner_id, _hash)| pool.contains(*miner_id))
+                .collect();
+            let head_starts: Vec<i64> = pool_blocks
+                .iter()
+                .filter_map(|(_miner_id, hash)| {
+                    recorder::head_start(records, hash, pool.operator_id, &pool_node_ids)
+                })
+                .collect();
+
+            PoolShare {
+                operator_id: pool.operator_id,
+                members: pool.member_ids.len(),
+                hashrate_share: f64::from(pool_hashrate) / f64::from(total_hashrate),
+                block_share: pool_blocks.len() as f64 / blocks.len() as f64,
+                blocks: pool_blocks.len(),
+                operator_head_starts: Distribution::new(head_starts),
+            }
+        })
+        .collect();
+
+    let solo_blocks: Vec<_> = blocks
+        .iter()
+        .filter(|(miner_id, _hash)| !pools.iter().any(|pool| pool.contains(*miner_id)))
+        .collect();
+    let solo_head_starts: Vec<i64> = solo_blocks
+        .iter()
+        .filter_map(|(miner_id, hash)| recorder::head_start(records, hash, *miner_id, &[]))
+        .collect();
+
+    Some(MiningPools {
+        pools: pool_shares,
+        solo_blocks: solo_blocks.len(),
+        solo_head_starts: Distribution::new(solo_head_starts),
+    })
+}
+
+/// The number of hexadecimal digits of the block hashes in the names of the exported
+/// files, the full hash would make them too long.
+const EXPORTED_HASH_PREFIX_LENGTH: usize = 16;
+
+/// Writes the topology of the network and the propagation tree of every mined block,
+/// both in the DOT and GEXF formats.
+fn export(directory: &Path, topology: &Topology, records: &[NodeRecord]) -> io::Result<()> {
+    fs::create_dir_all(directory)?;
+
+    let attributes = recorder::node_attributes(records);
+    fs::write(directory.join("topology.dot"), topology.to_dot(&attributes))?;
+    fs::write(directory.join("topology.gexf"), topology.to_gexf(&attributes))?;
+
+    let mined_blocks = recorder::mined_blocks(records);
+    for (_miner, hash, height) in &mined_blocks {
+        let (tree, attributes) = recorder::propagation_tree(records, hash);
+        let hash = format!("{:?}", hash);
+        let name = format!(
+            "propagation_{:05}_{}",
+            height,
+            &hash[..EXPORTED_HASH_PREFIX_LENGTH]
+        );
+        fs::write(directory.join(format!("{}.dot", name)), tree.to_dot(&attributes))?;
+        fs::write(directory.join(format!("{}.gexf", name)), tree.to_gexf(&attributes))?;
+    }
+
+    info!(
+        "Exported the topology and {} propagation trees to {}",
+        mined_blocks.len(),
+        directory.display()
+    );
+    Ok(())
+}
+
+/// The share of the blocks of the honest nodes' chain mined by the selfish miner during the
+/// run, along with the share expected by Eyal and Sirer. `None` if there is no honest node.
+fn selfish_mining_revenue(
+    records: &[NodeRecord],
+    selfish_mining: &SelfishMiningConfig,
+    hashrate_share: f64,
+    root_height: u32,
+) -> Option<SelfishMiningRevenue> {
+    // The selfish miner may still hold private blocks, only the public chain matters.
+    let honest_records = records
+        .iter()
+        .filter(|record| record.node_id != ATTACKER_NODE_ID);
+    let chain = recorder::strongest_chain(honest_records)?;
+
+    let total_blocks = chain.height().saturating_sub(root_height);
+    let selfish_blocks = chain
+        .blocks_by_miner(root_height)
+        .get(&ATTACKER_NODE_ID)
+        .cloned()
+        .unwrap_or(0);
+    let revenue_share = if total_blocks > 0 {
+        f64::from(selfish_blocks) / f64::from(total_blocks)
+    } else {
+        0f64
+    };
+
+    Some(SelfishMiningRevenue {
+        hashrate_share,
+        revenue_share,
+        selfish_blocks,
+        total_blocks,
+        expected_revenue_share: selfish::expected_revenue_share(
+            hashrate_share,
+            selfish_mining.gamma,
+        ),
+        profitability_threshold: selfish::profitability_threshold(selfish_mining.gamma),
+    })
+}
+
+/// Logs the number of reorgs of every depth experienced by the honest nodes.
+fn log_reorgs(records: &[NodeRecord]) {
+    let honest_records = records
+        .iter()
+        .filter(|record| record.node_id != ATTACKER_NODE_ID);
+    let histogram = recorder::reorg_histogram(honest_records);
+
+    if histogram.is_empty() {
+        info!("The honest nodes experienced no reorg.");
+    } else {
+        let formatted: Vec<String> = histogram
+            .iter()
+            .map(|(depth, count)| format!("depth {}: {}", depth, count))
+            .collect();
+        info!("Reorgs experienced by the honest nodes: {}", formatted.join(", "));
+    }
+}
+
+/// Whether the payment made in the block following the fork height was reverted. Until the
+/// attacker releases its secret chain, all the blocks after the fork height are mined by
+/// honest nodes, so the attack succeeded if the main chain contains one of the attacker's.
+/// `None` if there is no honest node.
+fn double_spend_attempt(
+    records: &[NodeRecord],
+    double_spend: &DoubleSpendConfig,
+) -> Option<DoubleSpendAttempt> {
+    let honest_records = records
+        .iter()
+        .filter(|record| record.node_id != ATTACKER_NODE_ID);
+    let chain = recorder::strongest_chain(honest_records)?;
+
+    let payment_height = double_spend.fork_height + 1;
+    let outcome = match Chain::ancestor(chain, payment_height) {
+        Some(ref block) if block.head().node_id() == ATTACKER_NODE_ID => {
+            DoubleSpendOutcome::Succeeded
+        }
+        Some(_) => DoubleSpendOutcome::Failed,
+        None => DoubleSpendOutcome::NotStarted,
+    };
+
+    Some(DoubleSpendAttempt {
+        hashrate_share: double_spend.hashrate_share,
+        confirmations: double_spend.confirmations,
+        payment_height,
+        outcome,
+        success_probability: double_spend::success_probability(
+            double_spend.hashrate_share,
+            double_spend.confirmations,
+        ),
+    })
+}
+
+/// The configuration of a short run of a small network mining easy blocks, for the tests.
+#[cfg(test)]
+pub fn test_config() -> SimulationConfig {
+    SimulationConfig {
+        number_of_nodes: 8,
+        initiated_connections_per_node: 2,
+        latency: None,
+        network_events: vec![],
+        difficulty_factor: 8,
+        duration: Duration::from_secs(2),
+        mining_attempt_delay: Duration::from_millis(1),
+        retarget: None,
+        initial_chain: None,
+        checkpoint: None,
+        stop_at_height: None,
+        drain: false,
+        fork_choice: ForkChoice::FirstSeen,
+        max_clock_skew: Duration::from_millis(0),
+        max_future_drift: Duration::from_secs(7200),
+        hashrate_distribution: None,
+        pools: vec![],
+        attack: None,
+        relay_protocol: RelayProtocol::FullChain,
+        block_payload_size: 0,
+        validation_delay: None,
+        relay_before_validation: false,
+        rate_limit: None,
+        heartbeat: None,
+        export_directory: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::unix_time_millis;
+    use std::env;
+
+    /// A short run of a small network mining easy blocks.
+    fn small_scenario() -> Scenario {
+        let mut scenario = Scenario::default();
+        scenario.set("network_size", "8").unwrap();
+        scenario.set("connections", "2").unwrap();
+        scenario.set("difficulty", "8").unwrap();
+        scenario.set("duration_in_seconds", "2").unwrap();
+        scenario.set("mining_delay", "1").unwrap();
+        scenario
+    }
+
+    #[test]
+    fn returns_the_outcome_of_the_run() {
+        let result = run(&test_config()).unwrap();
+        assert_eq!(8, result.records.len());
+        assert_eq!(vec![1; 8], result.hashrates);
+        assert_eq!(8, result.topology.nodes().len());
+        assert_eq!(8, result.report.number_of_nodes);
+        assert!(result.report.blocks_mined > 0);
+        assert_eq!(None, result.report.throttled_messages);
+    }
+
+    #[test]
+    fn throttles_the_messages_beyond_the_rate_limit() {
+        let mut scenario = small_scenario();
+        // A single message per connection, the chains mined after the first one are dropped.
+        scenario.set("rate_limit", "1").unwrap();
+        scenario.set("rate_limit_refill_interval", "999999").unwrap();
+        let config = SimulationConfig::from_scenario(&scenario).unwrap();
+
+        let report = run(&config).unwrap().report;
+        assert!(report.blocks_mined > 0);
+        let throttled_messages = report.throttled_messages.unwrap();
+        assert!(throttled_messages.dropped > 0);
+        assert_eq!(0, throttled_messages.delayed);
+        assert_eq!(0, throttled_messages.disconnections);
+    }
+
+    #[test]
+    fn monitors_the_peers() {
+        let mut scenario = small_scenario();
+        scenario.set("heartbeat_interval", "50").unwrap();
+        scenario.set("heartbeat_timeout", "1000").unwrap();
+        let config = SimulationConfig::from_scenario(&scenario).unwrap();
+
+        let report = run(&config).unwrap().report;
+        assert!(report.round_trip_times.is_some());
+        assert!(report.traffic["ping"].messages > 0);
+        assert!(report.traffic["pong"].messages > 0);
+        assert_eq!(0, report.dead_peers_dropped);
+    }
+
+    #[test]
+    fn reports_the_revenue_of_the_selfish_miner() {
+        let mut scenario = small_scenario();
+        scenario.set("selfish_hashrate_share", "0.3").unwrap();
+        let mut config = SimulationConfig::from_scenario(&scenario).unwrap();
+
+        let selfish_mining = run(&config).unwrap().report.selfish_mining.unwrap();
+        assert!((selfish_mining.hashrate_share - 0.3).abs() < 0.05);
+        assert!(selfish_mining.selfish_blocks <= selfish_mining.total_blocks);
+        assert_eq!(
+            selfish::profitability_threshold(0f64),
+            selfish_mining.profitability_threshold
+        );
+
+        // The selfish miner compares the chains by height.
+        config.retarget = Some(Retarget::new(10, Duration::from_secs(1), 4));
+        assert!(run(&config).is_err());
+    }
+
+    #[test]
+    fn reports_the_outcome_of_the_double_spend() {
+        let mut scenario = small_scenario();
+        scenario.set("double_spend_hashrate_share", "0.3").unwrap();
+        scenario.set("double_spend_confirmations", "2").unwrap();
+        let config = SimulationConfig::from_scenario(&scenario).unwrap();
+
+        let result = run(&config).unwrap();
+        let double_spend = result.report.double_spend.unwrap();
+        assert_eq!(2, double_spend.payment_height);
+        assert_eq!(
+            double_spend::success_probability(0.3, 2),
+            double_spend.success_probability
+        );
+        assert_eq!(
+            recorder::reorg_histogram(result.records[1..].iter()),
+            result.report.reorg_depths
+        );
+    }
+
+    #[test]
+    fn reports_the_blocks_of_the_pools() {
+        let mut scenario = small_scenario();
+        scenario.set("pools", "1").unwrap();
+        scenario.set("pool_members", "3").unwrap();
+        let config = SimulationConfig::from_scenario(&scenario).unwrap();
+
+        let report = run(&config).unwrap().report;
+        let mining_pools = report.mining_pools.unwrap();
+        assert_eq!(1, mining_pools.pools.len());
+        assert_eq!(3, mining_pools.pools[0].members);
+        assert_eq!(0.5, mining_pools.pools[0].hashrate_share);
+        assert_eq!(
+            report.consensus_height as usize,
+            mining_pools.pools[0].blocks + mining_pools.solo_blocks
+        );
+    }
+
+    #[test]
+    fn reports_the_blocks_mined_during_the_run() {
+        let mut config = SimulationConfig::from_scenario(&small_scenario()).unwrap();
+        let records = run(&config).unwrap().records;
+        let initial_chain = recorder::strongest_chain(records.iter()).unwrap();
+        let initial_height = initial_chain.height();
+        assert!(initial_height > 0);
+
+        // The run starts from the chain saved by the first one.
+        let path = env::temp_dir().join(format!(
+            "pow_initial_chain_{}.bin",
+            initial_chain.head().timestamp()
+        ));
+        store::save(initial_chain, &path).unwrap();
+        config.initial_chain = Some(store::load(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+
+        let report = run(&config).unwrap().report;
+        let block_intervals = report.block_intervals.map_or(0, |intervals| intervals.count);
+        let blocks_added = (report.consensus_height - initial_height) as usize;
+        assert_eq!(blocks_added, block_intervals);
+        assert_eq!(
+            report.blocks_mined,
+            report.blocks_mined_by_node.values().sum::<u32>() as usize
+        );
+        assert_eq!(blocks_added, report.blocks_mined - report.orphaned_blocks);
+
+        // The payment cannot be in the chain the run starts from.
+        config.attack = Some(Attack::DoubleSpend(DoubleSpendConfig {
+            hashrate_share: 0.3,
+            fork_height: initial_height - 1,
+            confirmations: 2,
+            max_deficit: None,
+        }));
+        assert!(run(&config).is_err());
+    }
+
+    #[test]
+    fn reports_a_failed_export() {
+        let path = env::temp_dir().join(format!("pow_export_{}", unix_time_millis()));
+        fs::write(&path, "").unwrap();
+        let mut config = test_config();
+        config.export_directory = Some(path.clone());
+
+        let error = run(&config).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(error.starts_with("Could not export the run"));
+    }
+
+    #[test]
+    fn configures_a_run_from_a_scenario() {
+        let config = SimulationConfig::from_scenario(&Scenario::default()).unwrap();
+        assert_eq!(2048, config.number_of_nodes);
+        assert_eq!(Duration::from_secs(30), config.duration);
+        assert_eq!(None, config.hashrate_distribution);
+        assert!(config.attack.is_none());
+
+        let mut scenario = Scenario::parse(
+            r#"
+            [topology]
+            network_size = 16
+
+            [hashrate]
+            hashrates = [3, 2]
+
+            [nodes]
+            pools = 1
+            pool_members = 4
+            "#,
+        )
+        .unwrap();
+        scenario.set("relay", "headers_first").unwrap();
+        let config = SimulationConfig::from_scenario(&scenario).unwrap();
+        assert_eq!(16, config.number_of_nodes);
+        assert_eq!(
+            Some(HashrateDistribution::Explicit(vec![3, 2])),
+            config.hashrate_distribution
+        );
+        assert_eq!(1, config.pools.len());
+        assert_eq!(RelayProtocol::HeadersFirst, config.relay_protocol);
+
+        // Conflicting parameters.
+        scenario.set("selfish_hashrate_share", "0.3").unwrap();
+        assert!(SimulationConfig::from_scenario(&scenario).is_err());
+        scenario.relay.relay = None;
+        assert!(SimulationConfig::from_scenario(&scenario).unwrap().attack.is_some());
+        scenario.set("retarget_interval", "10").unwrap();
+        assert!(SimulationConfig::from_scenario(&scenario).is_err());
+        scenario.difficulty.retarget_interval = None;
+        // Out of range values.
+        scenario.set("selfish_hashrate_share", "1").unwrap();
+        assert!(SimulationConfig::from_scenario(&scenario).is_err());
+        scenario.nodes.selfish_hashrate_share = None;
+        scenario.set("hashrates", "3,20000").unwrap();
+        assert!(SimulationConfig::from_scenario(&scenario).is_err());
+        scenario.set("network_size", "0").unwrap();
+        assert!(SimulationConfig::from_scenario(&scenario).is_err());
+        scenario.topology.network_size = Some(16);
+        // A parameter requiring another one.
+        scenario.hashrate.hashrates = None;
+        scenario.set("gamma", "0.5").unwrap();
+        assert!(SimulationConfig::from_scenario(&scenario).is_err());
+        scenario.nodes.gamma = None;
+        scenario.set("rate_limit_policy", "delay").unwrap();
+        assert!(SimulationConfig::from_scenario(&scenario).is_err());
+        scenario.peers.rate_limit_policy = None;
+        scenario.set("heartbeat_timeout", "100").unwrap();
+        assert!(SimulationConfig::from_scenario(&scenario).is_err());
+        scenario.peers.heartbeat_timeout = None;
+        // An attack resumed from a checkpoint.
+        scenario.set("selfish_hashrate_share", "0.3").unwrap();
+        scenario.set("resume", "checkpoint").unwrap();
+        assert!(SimulationConfig::from_scenario(&scenario).is_err());
+    }
+
+    #[test]
+    fn validates_the_configuration() {
+        let mut config = SimulationConfig::from_scenario(&small_scenario()).unwrap();
+        assert_eq!(Ok(()), config.validate());
+
+        config.number_of_nodes = 0;
+        assert!(config.validate().is_err());
+        assert!(run(&config).is_err());
+        config.number_of_nodes = 8;
+        config.attack = Some(Attack::DoubleSpend(DoubleSpendConfig {
+            hashrate_share: 1f64,
+            fork_height: 1,
+            confirmations: 6,
+            max_deficit: None,
+        }));
+        assert!(run(&config).is_err());
+        // The attacker would still mine.
+        config.attack = Some(Attack::Selfish(SelfishMiningConfig {
+            hashrate_share: 0f64,
+            gamma: 0f64,
+        }));
+        assert!(config.validate().is_err());
+        config.attack = None;
+        config.relay_before_validation = true;
+        assert!(run(&config).is_err());
+    }
+
+    #[test]
+    fn bounds_the_hashrate_of_the_attacker() {
+        let attack = |hashrate_share| {
+            Attack::Selfish(SelfishMiningConfig {
+                hashrate_share,
+                gamma: 0f64,
+            })
+        };
+
+        assert_eq!(1, attack(0.001).hashrate(100));
+        assert_eq!(100, attack(0.5).hashrate(100));
+        assert_eq!(hashrate::MAX_HASHRATE, attack(0.999999).hashrate(100));
+    }
+}
@@ -0,0 +1,463 @@
+//! Parameter sweeps: the simulation is run for every combination of the values of some
+//! parameters, several times each, and the summaries of the runs are gathered in a CSV table.
+
+use report::{DoubleSpendOutcome, Report};
+use scenario::Scenario;
+use simulation::{self, SimulationConfig, SimulationResult};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// The two-sided 95% quantiles of the Student's t-distribution, by degrees of freedom.
+const STUDENT_T_95: [f64; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+    2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+    2.052, 2.048, 2.045, 2.042,
+];
+/// The quantile of the normal distribution, for more degrees of freedom.
+const NORMAL_95: f64 = 1.96;
+
+/// The statistics of a run gathered by a sweep.
+const METRICS: [&str; 11] = [
+    "consensus_height",
+    "blocks_mined",
+    "orphan_rate",
+    "expected_orphan_rate",
+    "nodes_on_best_tip",
+    "block_interval_ms",
+    "propagation_50_ms",
+    "propagation_90_ms",
+    "bytes_sent",
+    "selfish_revenue_share",
+    "double_spend_success",
+];
+
+/// A parameter of the simulation and the values it takes in the sweep.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Parameter {
+    /// The long name of the command line argument.
+    pub name: String,
+    pub values: Vec<String>,
+}
+
+impl Parameter {
+    /// Parses `name=start:end:step`, the end included, or `name=value,value,...`.
+    pub fn parse(raw_value: &str) -> Result<Parameter, String> {
+        let error_message = format!(
+            "Invalid sweep parameter {}, expected NAME=START:END:STEP or NAME=VALUE,VALUE,...",
+            raw_value
+        );
+        let mut split = raw_value.splitn(2, '=');
+        let name = split.next().unwrap_or("").trim().to_string();
+        let values = split.next().unwrap_or("").trim();
+        if name.is_empty() || values.is_empty() {
+            return Err(error_message);
+        }
+
+        let bounds: Vec<&str> = values.split(':').collect();
+        let values = match bounds.len() {
+            1 => values
+                .split(',')
+                .map(|value| value.trim().to_string())
+                .collect(),
+            3 => range(bounds[0], bounds[1], bounds[2]).ok_or(error_message)?,
+            _ => return Err(error_message),
+        };
+
+        Ok(Parameter { name, values })
+    }
+}
+
+/// The values from start to end by step, if the range is valid. Integers stay integers,
+/// decimals are rounded to hide the floating point errors.
+fn range(start: &str, end: &str, step: &str) -> Option<Vec<String>> {
+    let parse = |value: &str| value.trim().parse::<f64>().ok();
+    let (start_value, end_value, step_value) = (parse(start)?, parse(end)?, parse(step)?);
+    let finite = [start_value, end_value, step_value]
+        .iter()
+        .all(|value| value.is_finite());
+    if !finite || step_value <= 0f64 || end_value < start_value {
+        return None;
+    }
+    let integers = [start, end, step]
+        .iter()
+        .all(|value| value.trim().parse::<i64>().is_ok());
+
+    let mut values = vec![];
+    let mut index = 0f64;
+    loop {
+        let value = start_value + index * step_value;
+        if value > end_value + step_value * 1e-9 {
+            break;
+        }
+        if integers {
+            values.push((value as i64).to_string());
+        } else {
+            values.push(((value * 1e9).round() / 1e9).to_string());
+        }
+        index += 1f64;
+    }
+
+    Some(values)
+}
+
+/// Every combination of the values of the parameters, in the order of the parameters.
+pub fn combinations(parameters: &[Parameter]) -> Vec<Vec<String>> {
+    parameters.iter().fold(vec![vec![]], |combinations, parameter| {
+        combinations
+            .iter()
+            .flat_map(|combination| {
+                parameter.values.iter().map(move |value| {
+                    let mut combination = combination.clone();
+                    combination.push(value.clone());
+                    combination
+                })
+            })
+            .collect()
+    })
+}
+
+/// The runs of a combination of the values of the parameters.
+#[derive(Clone, Debug)]
+pub struct CombinationRuns {
+    /// The values of the parameters, in their order.
+    pub values: Vec<String>,
+    pub reports: Vec<Report>,
+    /// The errors of the runs that failed.
+    pub failures: Vec<String>,
+}
+
+/// Runs the simulation `repetitions` times for every combination of the values of the
+/// parameters, set on top of the scenario. The runs are spread over `parallel_runs` threads.
+/// Fails before any run if a combination does not make a valid configuration, or writes
+/// files: the runs would overwrite them. The runs that fail, or panic, are recorded along
+/// with their combination.
+pub fn run(
+    scenario: &Scenario,
+    parameters: &[Parameter],
+    repetitions: u32,
+    parallel_runs: usize,
+) -> Result<Vec<CombinationRuns>, String> {
+    if repetitions == 0 {
+        return Err("The number of repetitions must be at least 1".to_owned());
+    }
+
+    let combinations = combinations(parameters);
+    let mut jobs = vec![];
+    for (combination_index, combination) in combinations.iter().enumerate() {
+        let mut run_scenario = scenario.clone();
+        for (parameter, value) in parameters.iter().zip(combination) {
+            run_scenario.set(&parameter.name, value)?;
+        }
+        check_outputs(&run_scenario)?;
+        let config = Arc::new(SimulationConfig::from_scenario(&run_scenario)?);
+        for _i in 0..repetitions {
+            jobs.push((combination_index, config.clone()));
+        }
+    }
+
+    info!(
+        "Sweeping {} combinations, {} runs each, {} at a time",
+        combinations.len(),
+        repetitions,
+        parallel_runs
+    );
+    let reports = run_in_parallel(jobs, parallel_runs, |(combination_index, config)| {
+        (combination_index, catch_panic(|| simulation::run(&config)).map(|result| result.report))
+    });
+
+    let mut results: Vec<CombinationRuns> = combinations
+        .into_iter()
+        .map(|values| CombinationRuns {
+            values,
+            reports: vec![],
+            failures: vec![],
+        })
+        .collect();
+    for (combination_index, run_report) in reports {
+        let result = &mut results[combination_index];
+        match run_report {
+            Ok(report) => result.reports.push(report),
+            Err(err) => {
+                error!("A run of {} failed: {}", result.values.join(","), err);
+                result.failures.push(err);
+            }
+        }
+    }
+    Ok(results)
+}
+
+/// Runs the simulation, turning a panic into the failure of the run so that the other runs
+/// of the sweep go on.
+fn catch_panic<F>(run: F) -> Result<SimulationResult, String>
+where
+    F: FnOnce() -> Result<SimulationResult, String>,
+{
+    panic::catch_unwind(AssertUnwindSafe(run)).unwrap_or_else(|panic| {
+        Err(format!("The run panicked: {}", panic_message(&*panic)))
+    })
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown cause"
+    }
+}
+
+/// Checks that the runs write no file.
+fn check_outputs(scenario: &Scenario) -> Result<(), String> {
+    let output = &scenario.output;
+    let outputs = [
+        (output.report_file.is_some(), "report_file"),
+        (output.save_chain.is_some(), "save_chain"),
+        (output.checkpoint.is_some(), "checkpoint"),
+        (output.export_directory.is_some(), "export_directory"),
+    ];
+    for &(is_set, name) in outputs.iter() {
+        if is_set {
+            return Err(format!("{} cannot be used in a sweep", name));
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs the jobs on the given number of threads. The results keep the order of the jobs.
+pub fn run_in_parallel<J, R, F>(jobs: Vec<J>, parallel_runs: usize, run: F) -> Vec<R>
+where
+    J: Send,
+    R: Send,
+    F: Fn(J) -> R + Sync,
+{
+    let number_of_jobs = jobs.len();
+    let jobs = Mutex::new(jobs.into_iter().enumerate());
+    let results = Mutex::new(Vec::with_capacity(number_of_jobs));
+
+    thread::scope(|scope| {
+        for _i in 0..parallel_runs.max(1) {
+            scope.spawn(|| loop {
+                let job = jobs.lock().unwrap().next();
+                match job {
+                    Some((index, job)) => {
+                        let result = run(job);
+                        results.lock().unwrap().push((index, result));
+                    }
+                    None => break,
+                }
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _result)| *index);
+    results.into_iter().map(|(_index, result)| result).collect()
+}
+
+/// The statistics of a run, in the order of `METRICS`. Those that could not be measured
+/// are missing.
+fn metrics(report: &Report) -> Vec<Option<f64>> {
+    let propagation_mean = |share_of_nodes: u32| {
+        report
+            .propagation
+            .iter()
+            .find(|propagation| propagation.share_of_nodes == share_of_nodes)
+            .and_then(|propagation| propagation.time.as_ref())
+            .map(|time| time.mean)
+    };
+
+    vec![
+        Some(f64::from(report.consensus_height)),
+        Some(report.blocks_mined as f64),
+        Some(report.orphan_rate),
+        report.expected_orphan_rate,
+        Some(report.nodes_on_best_tip),
+        report.block_intervals.as_ref().map(|intervals| intervals.mean),
+        propagation_mean(50),
+        propagation_mean(90),
+        Some(report.traffic.values().map(|stats| stats.bytes as f64).sum()),
+        report
+            .selfish_mining
+            .as_ref()
+            .map(|selfish_mining| selfish_mining.revenue_share),
+        match report.double_spend {
+            Some(ref double_spend) => match double_spend.outcome {
+                DoubleSpendOutcome::Succeeded => Some(1f64),
+                DoubleSpendOutcome::Failed => Some(0f64),
+                DoubleSpendOutcome::NotStarted => None,
+            },
+            None => None,
+        },
+    ]
+}
+
+/// The mean of the samples and its 95% confidence interval. There is no interval for a
+/// single sample.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Estimate {
+    pub mean: f64,
+    pub confidence_interval: Option<(f64, f64)>,
+}
+
+impl Estimate {
+    /// Returns `None` if there are no samples.
+    pub fn new(samples: &[f64]) -> Option<Estimate> {
+        if samples.is_empty() {
+            return None;
+        }
+
+        let count = samples.len() as f64;
+        let mean = samples.iter().sum::<f64>() / count;
+        let confidence_interval = if samples.len() > 1 {
+            let variance = samples
+                .iter()
+                .map(|sample| (sample - mean).powi(2))
+                .sum::<f64>()
+                / (count - 1f64);
+            let quantile = STUDENT_T_95
+                .get(samples.len() - 2)
+                .cloned()
+                .unwrap_or(NORMAL_95);
+            let half_width = quantile * (variance / count).sqrt();
+            Some((mean - half_width, mean + half_width))
+        } else {
+            None
+        };
+
+        Some(Estimate {
+            mean,
+            confidence_interval,
+        })
+    }
+}
+
+/// A CSV table with a row per combination of the parameters: their values, the number of
+/// runs and of failed runs, then the mean of every statistic of the runs and its 95% confidence interval.
+pub fn to_csv(parameters: &[Parameter], results: &[CombinationRuns]) -> String {
+    let mut header: Vec<String> = parameters
+        .iter()
+        .map(|parameter| parameter.name.clone())
+        .collect();
+    header.push("runs".to_string());
+    header.push("failed_runs".to_string());
+    for metric in &METRICS {
+        header.push(format!("{}_mean", metric));
+        header.push(format!("{}_ci95_low", metric));
+        header.push(format!("{}_ci95_high", metric));
+    }
+
+    let mut lines = vec![header.join(",")];
+    for result in results {
+        let mut row = result.values.clone();
+        row.push(result.reports.len().to_string());
+        row.push(result.failures.len().to_string());
+
+        let runs_metrics: Vec<Vec<Option<f64>>> = result.reports.iter().map(metrics).collect();
+        for metric_index in 0..METRICS.len() {
+            let samples: Vec<f64> = runs_metrics
+                .iter()
+                .filter_map(|run_metrics| run_metrics[metric_index])
+                .collect();
+            let (mean, low, high) = match Estimate::new(&samples) {
+                Some(Estimate {
+                    mean,
+                    confidence_interval: Some((low, high)),
+                }) => (format!("{}", mean), format!("{}", low), format!("{}", high)),
+                Some(estimate) => (format!("{}", estimate.mean), String::new(), String::new()),
+                None => (String::new(), String::new(), String::new()),
+            };
+            row.push(mean);
+            row.push(low);
+            row.push(high);
+        }
+
+        lines.push(row.join(","));
+    }
+
+    lines.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn combines_the_parameter_ranges() {
+        let latency = Parameter::parse("latency=0:100:50").unwrap();
+        assert_eq!(vec!["0", "50", "100"], latency.values);
+        let share = Parameter::parse("selfish_hashrate_share=0.1:0.3:0.1").unwrap();
+        assert_eq!(vec!["0.1", "0.2", "0.3"], share.values);
+        let relay = Parameter::parse("relay=full_chain,headers_first").unwrap();
+        assert_eq!("relay", relay.name);
+        assert!(Parameter::parse("latency").is_err());
+        assert!(Parameter::parse("latency=100:0:50").is_err());
+        assert!(Parameter::parse("latency=0:100:0").is_err());
+
+        let combinations = combinations(&[latency, relay]);
+        assert_eq!(6, combinations.len());
+        assert_eq!(vec!["0", "full_chain"], combinations[0]);
+        assert_eq!(vec!["100", "headers_first"], combinations[5]);
+    }
+
+    #[test]
+    fn runs_every_combination() {
+        let scenario = Scenario::parse(
+            r#"
+            [simulation]
+            duration_in_seconds = 1
+            mining_delay = 1
+
+            [topology]
+            network_size = 4
+            connections = 2
+
+            [difficulty]
+            difficulty = 6
+            "#,
+        )
+        .unwrap();
+        let parameters = vec![Parameter::parse("latency=0,10").unwrap()];
+
+        let results = run(&scenario, &parameters, 2, 4).unwrap();
+        assert_eq!(2, results.len());
+        assert_eq!(vec!["10"], results[1].values);
+        assert!(results.iter().all(|result| result.reports.len() == 2));
+        assert!(results.iter().all(|result| result.failures.is_empty()));
+        let csv = to_csv(&parameters, &results);
+        assert_eq!(3, csv.lines().count());
+        assert!(csv.starts_with("latency,runs,failed_runs,"));
+
+        let unknown = vec![Parameter::parse("unknown=1").unwrap()];
+        assert!(run(&scenario, &unknown, 2, 4).is_err());
+        assert!(run(&scenario, &parameters, 0, 4).is_err());
+        // Every run would write the same file.
+        let report_file = vec![Parameter::parse("report_file=report.txt").unwrap()];
+        assert!(run(&scenario, &report_file, 2, 4).is_err());
+    }
+
+    #[test]
+    fn records_a_panicking_run_as_failed() {
+        let failure = catch_panic(|| panic!("Out of blocks")).err().unwrap();
+        assert_eq!("The run panicked: Out of blocks", failure);
+    }
+
+    #[test]
+    fn estimates_the_mean_with_a_confidence_interval() {
+        assert_eq!(None, Estimate::new(&[]));
+        assert_eq!(None, Estimate::new(&[3f64]).unwrap().confidence_interval);
+
+        let estimate = Estimate::new(&[1f64, 2f64, 3f64]).unwrap();
+        assert_eq!(2f64, estimate.mean);
+        // The standard error is 1/sqrt(3), with 2 degrees of freedom.
+        let (low, high) = estimate.confidence_interval.unwrap();
+        assert!((high - 2f64 - 4.303 / 3f64.sqrt()).abs() < 1e-9);
+        assert!((2f64 - low - 4.303 / 3f64.sqrt()).abs() < 1e-9);
+
+        let results = run_in_parallel((0..20).collect(), 4, |job: u32| job * 2);
+        assert_eq!((0..20).map(|job| job * 2).collect::<Vec<_>>(), results);
+    }
+}